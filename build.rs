@@ -0,0 +1,94 @@
+// Generates the opcode tables from resources/opcodes.csv (the NMOS 6502 and the 2A03) and
+// resources/opcodes_65c02.csv so the emulator doesn't need the csv files at runtime.  The output
+// is included by src/cpu/opcode.rs.
+//
+extern crate csv;
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+// (csv file, suffix of the generated tables)
+const OPCODE_TABLES: [(&str,&str);2] = [("resources/opcodes.csv",""),("resources/opcodes_65c02.csv","_65C02")];
+
+struct Row {
+    opcode: u8,
+    name: String,
+    mnemonic: String,
+    address_mode: String,
+    len: u8,
+    cycles: u8,
+    page_cycles: u8,
+    notes: String,
+}
+
+// columns are found by name, a table from before the Mnemonic column was added gets the one
+// opcode::load_from_file would give it
+fn parse_row(csv_path: &str, line_no: u64, headers: &csv::StringRecord, rec: &csv::StringRecord) -> Row {
+    let field = |column: &str| headers.iter().position(|h| h == column).map(|i| &rec[i]);
+    let required = |column: &str| field(column).unwrap_or_else(|| panic!("{}: the header has no {} column",csv_path,column));
+    let number = |column: &str| required(column).parse::<u8>().unwrap_or_else(|e| panic!("{}:{}: {}",csv_path,line_no,e));
+    let name = required("Name");
+
+    Row {
+        opcode: u8::from_str_radix(required("Opcode").trim_start_matches("0x"),16).unwrap_or_else(|e| panic!("{}:{}: {}",csv_path,line_no,e)),
+        name: name.to_string(),
+        mnemonic: field("Mnemonic").unwrap_or_else(|| name.trim_start_matches("ILL_").trim_end_matches(|c: char| c.is_ascii_digit())).to_string(),
+        address_mode: required("AddressMode").to_string(),
+        len: number("Len"),
+        cycles: number("Cycles"),
+        page_cycles: number("PageCycles"),
+        notes: required("Notes").to_string(),
+    }
+}
+
+fn table(csv_path: &str, suffix: &str, out: &mut String) {
+    println!("cargo:rerun-if-changed={}",csv_path);
+
+    // read the same way as opcode::load_from_file, so quoted fields work in both
+    let mut rdr = csv::ReaderBuilder::new().has_headers(true).flexible(false).trim(csv::Trim::All)
+        .from_path(csv_path).unwrap_or_else(|e| panic!("{}: {}",csv_path,e));
+    let headers = rdr.headers().unwrap_or_else(|e| panic!("{}: {}",csv_path,e)).clone();
+    let mut rows: Vec<Row> = rdr.records().map(|rec| {
+        let rec = rec.unwrap_or_else(|e| panic!("{}: {}",csv_path,e));
+        let line_no = rec.position().map_or(0,|p| p.line());
+        parse_row(csv_path,line_no,&headers,&rec)
+    }).collect();
+    rows.sort_by_key(|r| r.opcode);
+
+    // same checks as opcode::load_from_file
+    if rows.len() != 256 {
+        panic!("{}: expected 256 opcodes, found {}",csv_path,rows.len());
+    }
+    for (i,row) in rows.iter().enumerate() {
+        if i != row.opcode as usize {
+            panic!("{}: opcodes must be contiguous from 0 to 255, found gap or duplicate at opcode {:X}",csv_path,row.opcode);
+        }
+    }
+
+    out.push_str(&format!("// generated by build.rs from {}\n\n",csv_path));
+    out.push_str(&format!("pub static EXEC_INFO{}: [OpcodeExecInfo;256] = [\n",suffix));
+    for r in &rows {
+        out.push_str(&format!("    OpcodeExecInfo {{ opcode: 0x{:02X}, opcode_class: OpcodeClass::{}, address_mode: AddressMode::{}, len: {}, cycles: {}, page_cycles: {} }},\n",
+                              r.opcode,r.name,r.address_mode,r.len,r.cycles,r.page_cycles));
+    }
+    out.push_str("];\n\n");
+    out.push_str("// (name, mnemonic, address mode name, notes)\n");
+    out.push_str(&format!("pub static DEBUG_INFO{}: [(&str,&str,&str,&str);256] = [\n",suffix));
+    for r in &rows {
+        out.push_str(&format!("    ({:?},{:?},{:?},{:?}),\n",r.name,r.mnemonic,r.address_mode,r.notes));
+    }
+    out.push_str("];\n\n");
+}
+
+fn main() {
+    let mut out = String::new();
+    for &(csv_path,suffix) in OPCODE_TABLES.iter() {
+        table(csv_path,suffix,&mut out);
+    }
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let mut f = File::create(Path::new(&out_dir).join("opcode_table.rs")).unwrap();
+    f.write_all(out.as_bytes()).unwrap();
+}
@@ -0,0 +1,622 @@
+
+// http://wiki.nesdev.com/w/index.php/APU
+//
+// Pulse, triangle and noise channels with their envelopes, sweeps, length and linear counters,
+// and the frame counter.  The DMC only supports direct loads of its output level through $4011,
+// sample playback (and the DMA that goes with it) is not emulated yet.
+//
+// Output is mixed with the nonlinear formulas from the wiki and point sampled down to
+// sample_rate, embedders drain it with take_samples.
+//
+
+use std::io;
+use std::io::{Read,Write};
+use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
+use savestate::{self,Snapshot};
+
+pub const CPU_FREQUENCY: f64 = 1789773.0;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20,  2, 40,  4, 80,  6, 160,  8, 60, 10, 14, 12, 26, 14,
+    12,  16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10,  9,  8,  7,  6,  5,  4,  3,  2,  1,  0,
+     0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15,
+];
+const NOISE_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+// frame counter steps, in cpu cycles
+const FRAME_STEP_1: u32 = 7457;
+const FRAME_STEP_2: u32 = 14913;
+const FRAME_STEP_3: u32 = 22371;
+const FRAME_STEP_4: u32 = 29829;
+const FRAME_STEP_5: u32 = 37281;
+
+// http://wiki.nesdev.com/w/index.php/APU_Envelope
+//
+#[derive(Clone, Default)]
+pub struct Envelope {
+    pub start: bool,
+    pub looping: bool,
+    pub constant: bool,
+    pub volume: u8,
+    pub divider: u8,
+    pub decay: u8,
+}
+
+impl Envelope {
+    fn write(self: &mut Envelope, val: u8) {
+        self.looping = val & 0x20 != 0;
+        self.constant = val & 0x10 != 0;
+        self.volume = val & 0x0F;
+    }
+    fn clock(self: &mut Envelope) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 { self.decay -= 1; } else if self.looping { self.decay = 15; }
+        } else {
+            self.divider -= 1;
+        }
+    }
+    fn output(self: &Envelope) -> u8 {
+        if self.constant { self.volume } else { self.decay }
+    }
+}
+
+// http://wiki.nesdev.com/w/index.php/APU_Pulse
+//
+#[derive(Clone, Default)]
+pub struct Pulse {
+    // pulse 1 negates its sweep with one's complement, pulse 2 with two's complement
+    pub ones_complement: bool,
+    pub enabled: bool,
+    pub duty: u8,
+    pub duty_position: u8,
+    pub timer: u16,
+    pub timer_period: u16,
+    pub length: u8,
+    pub envelope: Envelope,
+    pub sweep_enabled: bool,
+    pub sweep_period: u8,
+    pub sweep_negate: bool,
+    pub sweep_shift: u8,
+    pub sweep_divider: u8,
+    pub sweep_reload: bool,
+}
+
+impl Pulse {
+    fn write(self: &mut Pulse, reg: u16, val: u8) {
+        match reg & 3 {
+            0 => {
+                self.duty = val >> 6;
+                self.envelope.write(val);
+            },
+            1 => {
+                self.sweep_enabled = val & 0x80 != 0;
+                self.sweep_period = (val >> 4) & 0x07;
+                self.sweep_negate = val & 0x08 != 0;
+                self.sweep_shift = val & 0x07;
+                self.sweep_reload = true;
+            },
+            2 => { self.timer_period = (self.timer_period & 0x0700) | val as u16; },
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((val as u16 & 0x07) << 8);
+                if self.enabled { self.length = LENGTH_TABLE[(val >> 3) as usize]; }
+                self.duty_position = 0;
+                self.envelope.start = true;
+            },
+        }
+    }
+    fn clock_timer(self: &mut Pulse) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_position = (self.duty_position + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+    fn sweep_target(self: &Pulse) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.timer_period + change
+        } else if self.ones_complement {
+            self.timer_period.saturating_sub(change + 1)
+        } else {
+            self.timer_period.saturating_sub(change)
+        }
+    }
+    fn muted(self: &Pulse) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x07FF
+    }
+    fn clock_sweep(self: &mut Pulse) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+    fn clock_length(self: &mut Pulse) {
+        if !self.envelope.looping && self.length > 0 { self.length -= 1; }
+    }
+    fn output(self: &Pulse) -> u8 {
+        if self.length == 0 || self.muted() || DUTY_TABLE[self.duty as usize][self.duty_position as usize] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+// http://wiki.nesdev.com/w/index.php/APU_Triangle
+//
+#[derive(Clone, Default)]
+pub struct Triangle {
+    pub enabled: bool,
+    pub control: bool,
+    pub timer: u16,
+    pub timer_period: u16,
+    pub length: u8,
+    pub linear_reload_value: u8,
+    pub linear_counter: u8,
+    pub linear_reload: bool,
+    pub sequence_position: u8,
+}
+
+impl Triangle {
+    fn write(self: &mut Triangle, reg: u16, val: u8) {
+        match reg & 3 {
+            0 => {
+                self.control = val & 0x80 != 0;
+                self.linear_reload_value = val & 0x7F;
+            },
+            1 => { },
+            2 => { self.timer_period = (self.timer_period & 0x0700) | val as u16; },
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((val as u16 & 0x07) << 8);
+                if self.enabled { self.length = LENGTH_TABLE[(val >> 3) as usize]; }
+                self.linear_reload = true;
+            },
+        }
+    }
+    fn clock_timer(self: &mut Triangle) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length > 0 && self.linear_counter > 0 {
+                self.sequence_position = (self.sequence_position + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+    fn clock_linear(self: &mut Triangle) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control { self.linear_reload = false; }
+    }
+    fn clock_length(self: &mut Triangle) {
+        if !self.control && self.length > 0 { self.length -= 1; }
+    }
+    fn output(self: &Triangle) -> u8 {
+        TRIANGLE_TABLE[self.sequence_position as usize]
+    }
+}
+
+// http://wiki.nesdev.com/w/index.php/APU_Noise
+//
+#[derive(Clone, Default)]
+pub struct Noise {
+    pub enabled: bool,
+    pub mode: bool,
+    pub shift_register: u16,
+    pub timer: u16,
+    pub timer_period: u16,
+    pub length: u8,
+    pub envelope: Envelope,
+}
+
+impl Noise {
+    fn write(self: &mut Noise, reg: u16, val: u8) {
+        match reg & 3 {
+            0 => { self.envelope.write(val); },
+            1 => { },
+            2 => {
+                self.mode = val & 0x80 != 0;
+                self.timer_period = NOISE_PERIOD_TABLE[(val & 0x0F) as usize];
+            },
+            _ => {
+                if self.enabled { self.length = LENGTH_TABLE[(val >> 3) as usize]; }
+                self.envelope.start = true;
+            },
+        }
+    }
+    fn clock_timer(self: &mut Noise) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+    fn clock_length(self: &mut Noise) {
+        if !self.envelope.looping && self.length > 0 { self.length -= 1; }
+    }
+    fn output(self: &Noise) -> u8 {
+        if self.length == 0 || self.shift_register & 1 != 0 { 0 } else { self.envelope.output() }
+    }
+}
+
+// http://wiki.nesdev.com/w/index.php/APU_DMC
+//
+#[derive(Clone, Default)]
+pub struct Dmc {
+    pub irq_enabled: bool,
+    pub looping: bool,
+    pub rate: u8,
+    pub output_level: u8,
+    pub sample_address: u8,
+    pub sample_length: u8,
+}
+
+impl Dmc {
+    fn write(self: &mut Dmc, reg: u16, val: u8) {
+        match reg & 3 {
+            0 => {
+                self.irq_enabled = val & 0x80 != 0;
+                self.looping = val & 0x40 != 0;
+                self.rate = val & 0x0F;
+            },
+            1 => { self.output_level = val & 0x7F; },
+            2 => { self.sample_address = val; },
+            _ => { self.sample_length = val; },
+        }
+    }
+}
+
+pub struct Apu {
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+
+    // http://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+    //
+    pub five_step_mode: bool,
+    pub irq_inhibit: bool,
+    pub frame_irq: bool,
+    pub frame_cycle: u32,
+    pub cycle: u64,
+
+    pub sample_rate: u32,
+    sample_clock: f64,
+    pub samples: Vec<f32>,
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            pulse1: Pulse { ones_complement: true, ..Default::default() },
+            pulse2: Default::default(),
+            triangle: Default::default(),
+            noise: Noise { shift_register: 1, ..Default::default() },
+            dmc: Default::default(),
+            five_step_mode: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            cycle: 0,
+            sample_rate: 44100,
+            sample_clock: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Silences every channel, like writing 0 to $4015.
+    pub fn reset(self: &mut Apu) {
+        self.write_register(0x4015,0);
+        self.frame_irq = false;
+        self.frame_cycle = 0;
+    }
+
+    /// The APU's IRQ line, the frame counter is the only source until DMC playback exists.
+    pub fn irq_pending(self: &Apu) -> bool {
+        self.frame_irq
+    }
+
+    /// Return everything sampled since the last call.
+    pub fn take_samples(self: &mut Apu) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    /// Read $4015, the only readable register.
+    pub fn read_status(self: &mut Apu) -> u8 {
+        let val = self.peek_status();
+        self.frame_irq = false;
+        val
+    }
+
+    pub fn peek_status(self: &Apu) -> u8 {
+        (( self.pulse1.length   > 0) as u8)
+      | ( (( self.pulse2.length   > 0) as u8) << 1)
+      | ( (( self.triangle.length > 0) as u8) << 2)
+      | ( (( self.noise.length    > 0) as u8) << 3)
+      | ( (  self.frame_irq            as u8) << 6)
+    }
+
+    /// Write one of $4000-$4013, $4015 or $4017.
+    pub fn write_register(self: &mut Apu, addr: u16, val: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr,val),
+            0x4004..=0x4007 => self.pulse2.write(addr,val),
+            0x4008..=0x400B => self.triangle.write(addr,val),
+            0x400C..=0x400F => self.noise.write(addr,val),
+            0x4010..=0x4013 => self.dmc.write(addr,val),
+            0x4015 => {
+                self.pulse1.enabled   = val & 0x01 != 0;
+                self.pulse2.enabled   = val & 0x02 != 0;
+                self.triangle.enabled = val & 0x04 != 0;
+                self.noise.enabled    = val & 0x08 != 0;
+                if !self.pulse1.enabled   { self.pulse1.length = 0; }
+                if !self.pulse2.enabled   { self.pulse2.length = 0; }
+                if !self.triangle.enabled { self.triangle.length = 0; }
+                if !self.noise.enabled    { self.noise.length = 0; }
+            },
+            0x4017 => {
+                self.five_step_mode = val & 0x80 != 0;
+                self.irq_inhibit = val & 0x40 != 0;
+                if self.irq_inhibit { self.frame_irq = false; }
+                self.frame_cycle = 0;
+                // 5 step mode clocks everything immediately
+                if self.five_step_mode {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            },
+            _ => { },
+        }
+    }
+
+    /// Advance one cpu cycle.
+    pub fn step(self: &mut Apu) {
+        self.cycle += 1;
+        self.triangle.clock_timer();
+        // pulse and noise timers run at half the cpu clock
+        if self.cycle % 2 == 0 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+            self.noise.clock_timer();
+        }
+        self.step_frame_counter();
+
+        self.sample_clock += self.sample_rate as f64;
+        if self.sample_clock >= CPU_FREQUENCY {
+            self.sample_clock -= CPU_FREQUENCY;
+            let sample = self.output();
+            self.samples.push(sample);
+        }
+    }
+
+    fn step_frame_counter(self: &mut Apu) {
+        self.frame_cycle += 1;
+        match self.frame_cycle {
+            FRAME_STEP_1 | FRAME_STEP_3 => self.clock_quarter_frame(),
+            FRAME_STEP_2 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            },
+            FRAME_STEP_4 if !self.five_step_mode => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                if !self.irq_inhibit { self.frame_irq = true; }
+                self.frame_cycle = 0;
+            },
+            FRAME_STEP_5 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.frame_cycle = 0;
+            },
+            _ => { },
+        }
+    }
+
+    fn clock_quarter_frame(self: &mut Apu) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    fn clock_half_frame(self: &mut Apu) {
+        self.pulse1.clock_length();
+        self.pulse2.clock_length();
+        self.triangle.clock_length();
+        self.noise.clock_length();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    // http://wiki.nesdev.com/w/index.php/APU_Mixer
+    //
+    pub fn output(self: &Apu) -> f32 {
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse_out = if pulse == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulse + 100.0) };
+
+        let tnd = self.triangle.output() as f32 / 8227.0
+                + self.noise.output() as f32 / 12241.0
+                + self.dmc.output_level as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+
+        pulse_out + tnd_out
+    }
+}
+
+impl Snapshot for Envelope {
+    fn save_state(self: &Envelope, writer: &mut dyn Write) -> io::Result<()> {
+        savestate::write_bool(writer,self.start)?;
+        savestate::write_bool(writer,self.looping)?;
+        savestate::write_bool(writer,self.constant)?;
+        writer.write_all(&[self.volume,self.divider,self.decay])
+    }
+    fn load_state(self: &mut Envelope, reader: &mut dyn Read) -> io::Result<()> {
+        self.start = savestate::read_bool(reader)?;
+        self.looping = savestate::read_bool(reader)?;
+        self.constant = savestate::read_bool(reader)?;
+        self.volume = reader.read_u8()?;
+        self.divider = reader.read_u8()?;
+        self.decay = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+// which way the sweep negates is wiring, not state, and isn't saved
+impl Snapshot for Pulse {
+    fn save_state(self: &Pulse, writer: &mut dyn Write) -> io::Result<()> {
+        savestate::write_bool(writer,self.enabled)?;
+        writer.write_all(&[self.duty,self.duty_position,self.length])?;
+        writer.write_u16::<LittleEndian>(self.timer)?;
+        writer.write_u16::<LittleEndian>(self.timer_period)?;
+        self.envelope.save_state(writer)?;
+        savestate::write_bool(writer,self.sweep_enabled)?;
+        savestate::write_bool(writer,self.sweep_negate)?;
+        savestate::write_bool(writer,self.sweep_reload)?;
+        writer.write_all(&[self.sweep_period,self.sweep_shift,self.sweep_divider])
+    }
+    fn load_state(self: &mut Pulse, reader: &mut dyn Read) -> io::Result<()> {
+        self.enabled = savestate::read_bool(reader)?;
+        self.duty = reader.read_u8()?;
+        self.duty_position = reader.read_u8()?;
+        self.length = reader.read_u8()?;
+        self.timer = reader.read_u16::<LittleEndian>()?;
+        self.timer_period = reader.read_u16::<LittleEndian>()?;
+        self.envelope.load_state(reader)?;
+        self.sweep_enabled = savestate::read_bool(reader)?;
+        self.sweep_negate = savestate::read_bool(reader)?;
+        self.sweep_reload = savestate::read_bool(reader)?;
+        self.sweep_period = reader.read_u8()?;
+        self.sweep_shift = reader.read_u8()?;
+        self.sweep_divider = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Triangle {
+    fn save_state(self: &Triangle, writer: &mut dyn Write) -> io::Result<()> {
+        savestate::write_bool(writer,self.enabled)?;
+        savestate::write_bool(writer,self.control)?;
+        savestate::write_bool(writer,self.linear_reload)?;
+        writer.write_u16::<LittleEndian>(self.timer)?;
+        writer.write_u16::<LittleEndian>(self.timer_period)?;
+        writer.write_all(&[self.length,self.linear_reload_value,self.linear_counter,self.sequence_position])
+    }
+    fn load_state(self: &mut Triangle, reader: &mut dyn Read) -> io::Result<()> {
+        self.enabled = savestate::read_bool(reader)?;
+        self.control = savestate::read_bool(reader)?;
+        self.linear_reload = savestate::read_bool(reader)?;
+        self.timer = reader.read_u16::<LittleEndian>()?;
+        self.timer_period = reader.read_u16::<LittleEndian>()?;
+        self.length = reader.read_u8()?;
+        self.linear_reload_value = reader.read_u8()?;
+        self.linear_counter = reader.read_u8()?;
+        self.sequence_position = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Noise {
+    fn save_state(self: &Noise, writer: &mut dyn Write) -> io::Result<()> {
+        savestate::write_bool(writer,self.enabled)?;
+        savestate::write_bool(writer,self.mode)?;
+        writer.write_u16::<LittleEndian>(self.shift_register)?;
+        writer.write_u16::<LittleEndian>(self.timer)?;
+        writer.write_u16::<LittleEndian>(self.timer_period)?;
+        writer.write_u8(self.length)?;
+        self.envelope.save_state(writer)
+    }
+    fn load_state(self: &mut Noise, reader: &mut dyn Read) -> io::Result<()> {
+        self.enabled = savestate::read_bool(reader)?;
+        self.mode = savestate::read_bool(reader)?;
+        self.shift_register = reader.read_u16::<LittleEndian>()?;
+        self.timer = reader.read_u16::<LittleEndian>()?;
+        self.timer_period = reader.read_u16::<LittleEndian>()?;
+        self.length = reader.read_u8()?;
+        self.envelope.load_state(reader)
+    }
+}
+
+impl Snapshot for Dmc {
+    fn save_state(self: &Dmc, writer: &mut dyn Write) -> io::Result<()> {
+        savestate::write_bool(writer,self.irq_enabled)?;
+        savestate::write_bool(writer,self.looping)?;
+        writer.write_all(&[self.rate,self.output_level,self.sample_address,self.sample_length])
+    }
+    fn load_state(self: &mut Dmc, reader: &mut dyn Read) -> io::Result<()> {
+        self.irq_enabled = savestate::read_bool(reader)?;
+        self.looping = savestate::read_bool(reader)?;
+        self.rate = reader.read_u8()?;
+        self.output_level = reader.read_u8()?;
+        self.sample_address = reader.read_u8()?;
+        self.sample_length = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+// the sample rate is the embedder's and samples not taken yet are dropped on load, the position
+// between samples is kept so the output lines up
+impl Snapshot for Apu {
+    fn save_state(self: &Apu, writer: &mut dyn Write) -> io::Result<()> {
+        self.pulse1.save_state(writer)?;
+        self.pulse2.save_state(writer)?;
+        self.triangle.save_state(writer)?;
+        self.noise.save_state(writer)?;
+        self.dmc.save_state(writer)?;
+        savestate::write_bool(writer,self.five_step_mode)?;
+        savestate::write_bool(writer,self.irq_inhibit)?;
+        savestate::write_bool(writer,self.frame_irq)?;
+        writer.write_u32::<LittleEndian>(self.frame_cycle)?;
+        writer.write_u64::<LittleEndian>(self.cycle)?;
+        writer.write_f64::<LittleEndian>(self.sample_clock)
+    }
+    fn load_state(self: &mut Apu, reader: &mut dyn Read) -> io::Result<()> {
+        self.pulse1.load_state(reader)?;
+        self.pulse2.load_state(reader)?;
+        self.triangle.load_state(reader)?;
+        self.noise.load_state(reader)?;
+        self.dmc.load_state(reader)?;
+        self.five_step_mode = savestate::read_bool(reader)?;
+        self.irq_inhibit = savestate::read_bool(reader)?;
+        self.frame_irq = savestate::read_bool(reader)?;
+        self.frame_cycle = reader.read_u32::<LittleEndian>()?;
+        self.cycle = reader.read_u64::<LittleEndian>()?;
+        self.sample_clock = reader.read_f64::<LittleEndian>()?;
+        self.samples.clear();
+        Ok(())
+    }
+}
@@ -3,28 +3,60 @@ pub mod rom_loader;
 pub mod memory;
 pub mod cpu;
 pub mod logger;
+pub mod input;
+pub mod cartridge;
+pub mod ppu;
+pub mod apu;
+pub mod nes;
+pub mod savestate;
+pub mod rewind;
+pub mod movie;
+pub mod headless;
+pub mod video;
+pub mod cli;
+#[cfg(feature = "windowed")]
+pub mod window;
 
-use cpu::CpuState;
-use memory::Memory;
+use cli::Command;
 
 fn main() {
-    let rom = rom_loader::load_ines("roms/nestest.nes").unwrap();
-    let mut cpu: CpuState = Default::default();
-    let mut mem:Memory = Memory::new();
-
-    // TODO: handle this properly instead of just unwrapping
-    let opcode_info = cpu::opcode::load_from_file("resources/opcodes.csv").unwrap();
-    let executor = cpu::CpuExecutor::new(opcode_info.0);
-    let mut logger = logger::NesTest::new("resources/nestest.out",opcode_info.1);
-
-    executor.power_on(&mut cpu, &mut mem);
-    cpu.pc = 0xC000;
-    mem.write(0,&rom);
-
-    for _ in 0..8991 {
-        executor.fetch_and_decode(&mut cpu,&mut mem).unwrap();
-        logger.log_after_fetch(&cpu,&mem);
-        executor.execute(&mut cpu,&mut mem).unwrap();
-    }
+    let code = match cli::parse_args(::std::env::args().skip(1)) {
+        Ok(Command::Help) => {
+            println!("{}",cli::USAGE);
+            cli::EXIT_SUCCESS
+        },
+        Ok(Command::Run(options)) => match cli::run(&options) {
+            Ok(summary) => {
+                for hash in summary.hashes.iter() {
+                    println!("{}",hash);
+                }
+                for path in summary.screenshots.iter() {
+                    eprintln!("saved {}",path);
+                }
+                let message = format!("ran {} instructions, {} cycles, {} frames",summary.instructions,summary.cycles,summary.frames);
+                // keep stdout clean when the trace is going there
+                if options.trace.as_ref().is_some_and(|path| path == "-") { eprintln!("{}",message); } else { println!("{}",message); }
+                cli::EXIT_SUCCESS
+            },
+            Err(err) => {
+                eprintln!("trustines: {}",err);
+                err.exit_code()
+            },
+        },
+        Ok(Command::Diff(options)) => match cli::diff(&options) {
+            Ok(result) => {
+                println!("{}",result.report().trim_end());
+                if result.is_same() { cli::EXIT_SUCCESS } else { cli::EXIT_TRACES_DIFFER }
+            },
+            Err(err) => {
+                eprintln!("trustines: {}",err);
+                err.exit_code()
+            },
+        },
+        Err(err) => {
+            eprintln!("trustines: {}\n\n{}",err,cli::USAGE);
+            err.exit_code()
+        },
+    };
+    ::std::process::exit(code);
 }
-
@@ -0,0 +1,94 @@
+use std::io;
+use std::io::{Read,Write};
+use savestate::{self,Snapshot};
+
+// http://wiki.nesdev.com/w/index.php/Mirroring#Nametable_Mirroring
+//
+#[derive(PartialEq,Clone,Copy,Debug)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+/// The contents of a cartridge and the hardware on it.  Only NROM (mapper 0) boards are
+/// supported for now, the cpu/ppu read and write functions are where mappers hook in.
+#[derive(Clone)]
+pub struct Cartridge {
+    pub prg_rom: Vec<u8>,
+    pub prg_ram: Vec<u8>,
+    pub chr: Vec<u8>,
+    pub chr_is_ram: bool,
+    pub mirroring: Mirroring,
+    pub mapper: u8,
+
+    /// NES 2.0 default expansion device id, see input::ExpansionDevice.
+    pub expansion_device: u8,
+}
+
+impl Cartridge {
+    /// CRC32 of the prg rom followed by the chr rom, save states are only loaded into the rom they
+    /// were saved from.
+    pub fn rom_hash(self: &Cartridge) -> u32 {
+        let chr: &[u8] = if self.chr_is_ram { &[] } else { &self.chr };
+        crc32(self.prg_rom.iter().chain(chr.iter()))
+    }
+
+    /// CPU read in $4020-$FFFF, None when nothing on the cartridge answers (open bus).
+    pub fn cpu_read(self: &Cartridge, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => Some(self.prg_ram[(addr - 0x6000) as usize % self.prg_ram.len()]),
+            // 16KB boards mirror their only bank into $C000
+            0x8000..=0xFFFF => Some(self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()]),
+            _ => None,
+        }
+    }
+    pub fn cpu_write(self: &mut Cartridge, addr: u16, val: u8) {
+        if (0x6000..=0x7FFF).contains(&addr) {
+            let len = self.prg_ram.len();
+            self.prg_ram[(addr - 0x6000) as usize % len] = val;
+        }
+    }
+
+    /// PPU read in $0000-$1FFF
+    pub fn ppu_read(self: &Cartridge, addr: u16) -> u8 {
+        self.chr[addr as usize % self.chr.len()]
+    }
+    pub fn ppu_write(self: &mut Cartridge, addr: u16, val: u8) {
+        if self.chr_is_ram {
+            let len = self.chr.len();
+            self.chr[addr as usize % len] = val;
+        }
+    }
+}
+
+// the battery backed (or not) prg ram and chr ram.  NROM has no mapper registers, mappers that do
+// save them here too
+impl Snapshot for Cartridge {
+    fn save_state(self: &Cartridge, writer: &mut dyn Write) -> io::Result<()> {
+        savestate::write_block(writer,&self.prg_ram)?;
+        savestate::write_block(writer,if self.chr_is_ram { &self.chr } else { &[] })
+    }
+
+    fn load_state(self: &mut Cartridge, reader: &mut dyn Read) -> io::Result<()> {
+        savestate::read_block(reader,&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            savestate::read_block(reader,&mut self.chr)
+        } else {
+            savestate::read_block(reader,&mut [])
+        }
+    }
+}
+
+// https://en.wikipedia.org/wiki/Cyclic_redundancy_check, the same CRC32 zip, png and the rom
+// databases use
+pub fn crc32<'a, I: Iterator<Item=&'a u8>>(bytes: I) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
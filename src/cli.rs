@@ -0,0 +1,571 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use cpu::ExecutionError;
+use headless;
+use headless::{Checkpoints,FrameHash,FrameHasher,HeadlessError};
+pub use logger::TraceFormat;
+use logger::diff;
+use logger::diff::{Column,DiffResult,Trace};
+use logger::{NesTest,Fceux,Mesen,TraceSink,FileSink,StdoutSink,BinarySink,RingBufferSink,GatedSink};
+use movie::{fm2,Movie,MovieError};
+use nes::Nes;
+use rom_loader;
+use rom_loader::InesError;
+use video::{BuiltinPalette,Connection,Image,ImageFormat,NtscFilter,NtscSettings,Palette,SignalSettings};
+use video::ntsc;
+#[cfg(feature = "windowed")]
+use ppu::{SCREEN_WIDTH,SCREEN_HEIGHT};
+#[cfg(feature = "windowed")]
+use window::{Window,WindowError};
+
+pub const USAGE: &str = "\
+usage: trustines [options] <rom>
+       trustines diff [diff options] <expected> <actual>
+
+options:
+    --pc <addr>               start executing at addr instead of the reset vector, e.g. C000
+    --instructions <n>        stop after n instructions
+    --cycles <n>              stop after n cpu cycles
+    --frames <n>              stop after n frames
+    --trace <path>            log every instruction executed to path, - for stdout
+    --trace-format <format>   format of the trace log: nestest (default), fceux, mesen or binary
+    --trace-range <from-to>   only log instructions at addresses from to to, e.g. C000-C0FF
+    --trace-after <addr>      start logging the first time the cpu reaches addr
+    --crash-dump <n>          print the last n instructions to stderr on an execution error
+    --cycle-accurate          run the cpu a cycle at a time, with its dummy reads and writes
+    --movie <path>            play an FM2 movie from power on, runs for the length of the movie
+                              unless --frames says otherwise
+    --hash-every <n>          print a hash of the video and audio every n frames
+    --hash-at <frames>        print a hash of the video and audio at these frames, e.g. 60,120
+    --screenshot-at <frames>  save a screenshot at these frames, runs to the last one unless
+                              --frames or --movie says otherwise
+    --screenshot <path>       where to save screenshots, {frame} is replaced with the frame
+                              number and the extension picks png or ppm (default <rom>-{frame}.png)
+    --palette <palette>       colors for screenshots: 2c02 (default), 2c03, 2c05, pal, ntsc (generated
+                              from the NTSC signal) or a .pal file of 64 or 512 colors
+    --filter <connection>     run screenshots through a model of a TV connected by composite,
+                              svideo or rgb, twice as wide and with the colors from the signal
+    --windowed                show the picture in a window and play controller 1 on the keyboard,
+                              runs until the window is closed unless --frames or --movie says
+                              otherwise.  Arrows, X (A), Z (B), Enter (Start), Right Shift
+                              (Select), Esc closes the window
+    --headless                run without a window (default)
+    -h, --help                print this message
+
+diff options, compare two traces and show where they first disagree:
+    --ignore <columns>        comma separated columns not to compare:
+                              pc, bytes, disasm, a, x, y, p, sp, scanline, dot, cyc
+    --context <n>             lines to show around the divergence (default 5)
+    --expected-format <f>     format of the expected trace, detected by default
+    --actual-format <f>       format of the actual trace, detected by default
+
+exit codes:
+    0  stopped at a limit, or the traces match
+    1  execution error
+    2  bad arguments
+    3  couldn't load the rom, a trace or a movie, create the trace log or open the window
+    4  the traces differ";
+
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_EXECUTION_ERROR: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_LOAD_ERROR: i32 = 3;
+pub const EXIT_TRACES_DIFFER: i32 = 4;
+
+#[derive(Debug)]
+pub enum CliError {
+    Usage(String),
+    Ines(InesError),
+    Io(io::Error),
+    Execution(ExecutionError),
+    Movie(MovieError),
+    #[cfg(feature = "windowed")]
+    Window(WindowError),
+}
+
+impl From<InesError> for CliError {
+    fn from(err: InesError) -> CliError {
+        CliError::Ines(err)
+    }
+}
+impl From<io::Error> for CliError {
+    fn from(err: io::Error) -> CliError {
+        CliError::Io(err)
+    }
+}
+impl From<ExecutionError> for CliError {
+    fn from(err: ExecutionError) -> CliError {
+        CliError::Execution(err)
+    }
+}
+impl From<MovieError> for CliError {
+    fn from(err: MovieError) -> CliError {
+        match err {
+            MovieError::Execution(err) => CliError::Execution(err),
+            err                        => CliError::Movie(err),
+        }
+    }
+}
+#[cfg(feature = "windowed")]
+impl From<WindowError> for CliError {
+    fn from(err: WindowError) -> CliError {
+        CliError::Window(err)
+    }
+}
+impl From<HeadlessError> for CliError {
+    fn from(err: HeadlessError) -> CliError {
+        match err {
+            HeadlessError::Io(err)        => CliError::Io(err),
+            HeadlessError::Ines(err)      => CliError::Ines(err),
+            HeadlessError::Movie(err)     => err.into(),
+            HeadlessError::Execution(err) => CliError::Execution(err),
+            err                           => CliError::Io(io::Error::new(io::ErrorKind::Other,err.to_string())),
+        }
+    }
+}
+
+impl CliError {
+    /// The process exit code for this error, see USAGE.
+    pub fn exit_code(self: &CliError) -> i32 {
+        match *self {
+            CliError::Usage(_)                         => EXIT_USAGE,
+            CliError::Ines(_) | CliError::Io(_)
+          | CliError::Movie(_)                         => EXIT_LOAD_ERROR,
+            #[cfg(feature = "windowed")]
+            CliError::Window(_)                        => EXIT_LOAD_ERROR,
+            CliError::Execution(_)                     => EXIT_EXECUTION_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(self: &CliError, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CliError::Usage(ref message) => write!(f,"{}",message),
+            CliError::Ines(ref err)      => write!(f,"{}",err),
+            CliError::Io(ref err)        => write!(f,"{}",err),
+            CliError::Execution(ref err) => write!(f,"{}",err),
+            CliError::Movie(ref err)     => write!(f,"{}",err),
+            #[cfg(feature = "windowed")]
+            CliError::Window(ref err)    => write!(f,"{}",err),
+        }
+    }
+}
+
+#[derive(PartialEq,Clone,Debug)]
+pub struct Options {
+    pub rom: String,
+    pub start_pc: Option<u16>,
+    pub max_instructions: Option<u64>,
+    pub max_cycles: Option<u64>,
+    pub max_frames: Option<u64>,
+    pub trace: Option<String>,
+    pub trace_format: TraceFormat,
+    pub trace_range: Option<(u16,u16)>,
+    pub trace_after: Option<u16>,
+    pub crash_dump: Option<usize>,
+    pub cycle_accurate: bool,
+    pub movie: Option<String>,
+    pub hash: Option<Checkpoints>,
+    pub screenshot: Option<String>,
+    pub screenshot_at: Vec<u64>,
+    pub palette: Option<String>,
+    pub filter: Option<Connection>,
+    pub windowed: bool,
+}
+
+impl Options {
+    pub fn new(rom: &str) -> Options {
+        Options {
+            rom: rom.to_string(),
+            start_pc: None,
+            max_instructions: None,
+            max_cycles: None,
+            max_frames: None,
+            trace: None,
+            trace_format: TraceFormat::Nestest,
+            trace_range: None,
+            trace_after: None,
+            crash_dump: None,
+            cycle_accurate: false,
+            movie: None,
+            hash: None,
+            screenshot: None,
+            screenshot_at: Vec::new(),
+            palette: None,
+            filter: None,
+            windowed: false,
+        }
+    }
+}
+
+#[derive(PartialEq,Clone,Debug)]
+pub struct DiffOptions {
+    pub expected: String,
+    pub actual: String,
+    pub expected_format: Option<TraceFormat>,
+    pub actual_format: Option<TraceFormat>,
+    pub ignore: Vec<Column>,
+    pub context: usize,
+}
+
+impl DiffOptions {
+    pub fn new(expected: &str, actual: &str) -> DiffOptions {
+        DiffOptions {
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+            expected_format: None,
+            actual_format: None,
+            ignore: Vec::new(),
+            context: 5,
+        }
+    }
+}
+
+#[derive(PartialEq,Debug)]
+pub enum Command {
+    Run(Options),
+    Diff(DiffOptions),
+    Help,
+}
+
+/// How far a run got before it stopped.
+#[derive(PartialEq,Clone,Debug,Default)]
+pub struct RunSummary {
+    pub instructions: u64,
+    pub cycles: u64,
+    pub frames: u64,
+    /// With --hash-every or --hash-at, the hashes at the checkpoints.
+    pub hashes: Vec<FrameHash>,
+    /// With --screenshot-at, the screenshots saved.
+    pub screenshots: Vec<String>,
+}
+
+// options that take a value
+const VALUE_OPTIONS: [&str;16] = ["--pc","--instructions","--cycles","--frames","--trace","--trace-format",
+                                  "--trace-range","--trace-after","--crash-dump","--movie","--hash-every","--hash-at",
+                                  "--screenshot","--screenshot-at","--palette","--filter"];
+
+const NOT_WINDOWED: &str = "--windowed isn't built in, build with the windowed feature";
+
+// accepts C000, $C000 and 0xC000
+fn parse_address(s: &str) -> Result<u16,CliError> {
+    let digits = s.trim_start_matches('$').trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits,16).map_err(|_| CliError::Usage(format!("'{}' is not a 16 bit hex address",s)))
+}
+// first-last, both addresses as parse_address takes them
+fn parse_range(s: &str) -> Result<(u16,u16),CliError> {
+    let mut ends = s.splitn(2,'-');
+    let first = parse_address(ends.next().unwrap_or(""))?;
+    let last = match ends.next() {
+        Some(last) => parse_address(last)?,
+        None => return Err(CliError::Usage(format!("'{}' is not an address range, expected e.g. C000-C0FF",s))),
+    };
+    if first > last {
+        return Err(CliError::Usage(format!("range '{}' ends before it starts",s)));
+    }
+    Ok((first,last))
+}
+fn parse_format(s: &str) -> Result<TraceFormat,CliError> {
+    s.parse().map_err(|_| CliError::Usage(format!("unknown trace format '{}'",s)))
+}
+fn parse_count(flag: &str, s: &str) -> Result<u64,CliError> {
+    s.parse::<u64>().map_err(|_| CliError::Usage(format!("{} expects a number, found '{}'",flag,s)))
+}
+
+fn parse_frames(flag: &str, s: &str) -> Result<Vec<u64>,CliError> {
+    s.split(',').map(|frame| parse_count(flag,frame.trim())).collect()
+}
+
+// a built in palette's name, ntsc, or a .pal file
+fn load_palette(s: &str) -> Result<Palette,CliError> {
+    if s.to_lowercase().ends_with(".pal") || Path::new(s).is_file() {
+        return Ok(Palette::load(s)?);
+    }
+    if s.eq_ignore_ascii_case("ntsc") {
+        return Ok(Palette::generate(&SignalSettings::default()));
+    }
+    s.parse::<BuiltinPalette>().map(Palette::builtin).map_err(CliError::Usage)
+}
+
+fn parse_columns(s: &str) -> Result<Vec<Column>,CliError> {
+    s.split(',').map(|column| column.trim().parse().map_err(|_| CliError::Usage(format!("unknown column '{}'",column)))).collect()
+}
+
+/// Parse the command line, not including the program name.
+pub fn parse_args<I: IntoIterator<Item=String>>(args: I) -> Result<Command,CliError> {
+    let mut args = args.into_iter().peekable();
+    if args.peek().is_some_and(|arg| arg == "diff") {
+        args.next();
+        return parse_diff_args(args);
+    }
+    let mut rom = None;
+    let mut options = Options::new("");
+
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(Command::Help);
+        }
+        if arg == "--headless" { options.windowed = false; continue; }
+        if arg == "--windowed" {
+            if !cfg!(feature = "windowed") {
+                return Err(CliError::Usage(NOT_WINDOWED.to_string()));
+            }
+            options.windowed = true;
+            continue;
+        }
+        if arg == "--cycle-accurate" { options.cycle_accurate = true; continue; }
+
+        if arg.starts_with('-') {
+            if !VALUE_OPTIONS.contains(&arg.as_ref()) {
+                return Err(CliError::Usage(format!("unknown option '{}'",arg)));
+            }
+            let value = match args.next() {
+                Some(value) => value,
+                None => return Err(CliError::Usage(format!("{} expects a value",arg))),
+            };
+            match arg.as_ref() {
+                "--pc"           => { options.start_pc = Some(parse_address(&value)?); },
+                "--instructions" => { options.max_instructions = Some(parse_count(&arg,&value)?); },
+                "--cycles"       => { options.max_cycles = Some(parse_count(&arg,&value)?); },
+                "--frames"       => { options.max_frames = Some(parse_count(&arg,&value)?); },
+                "--trace"        => { options.trace = Some(value); },
+                "--trace-format" => { options.trace_format = parse_format(&value)?; },
+                "--trace-range"  => { options.trace_range = Some(parse_range(&value)?); },
+                "--trace-after"  => { options.trace_after = Some(parse_address(&value)?); },
+                "--crash-dump"   => { options.crash_dump = Some(parse_count(&arg,&value)? as usize); },
+                "--movie"        => { options.movie = Some(value); },
+                "--hash-every"   => { options.hash = Some(Checkpoints::Every(parse_count(&arg,&value)?.max(1))); },
+                "--hash-at"      => { options.hash = Some(Checkpoints::At(parse_frames(&arg,&value)?)); },
+                "--screenshot"   => { options.screenshot = Some(value); },
+                "--screenshot-at" => { options.screenshot_at = parse_frames(&arg,&value)?; },
+                "--palette"      => { options.palette = Some(value); },
+                "--filter"       => { options.filter = Some(value.parse().map_err(CliError::Usage)?); },
+                _                => unreachable!(),
+            }
+            continue;
+        }
+
+        if rom.is_some() {
+            return Err(CliError::Usage(format!("unexpected argument '{}'",arg)));
+        }
+        rom = Some(arg);
+    }
+
+    match rom {
+        Some(rom) => { options.rom = rom; Ok(Command::Run(options)) },
+        None => Err(CliError::Usage("no rom given".to_string())),
+    }
+}
+
+const DIFF_VALUE_OPTIONS: [&str;4] = ["--ignore","--context","--expected-format","--actual-format"];
+
+fn parse_diff_args<I: Iterator<Item=String>>(mut args: I) -> Result<Command,CliError> {
+    let mut traces = Vec::new();
+    let mut options = DiffOptions::new("","");
+
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(Command::Help);
+        }
+        if arg.starts_with('-') {
+            if !DIFF_VALUE_OPTIONS.contains(&arg.as_ref()) {
+                return Err(CliError::Usage(format!("unknown diff option '{}'",arg)));
+            }
+            let value = match args.next() {
+                Some(value) => value,
+                None => return Err(CliError::Usage(format!("{} expects a value",arg))),
+            };
+            match arg.as_ref() {
+                "--ignore"          => { options.ignore.extend(parse_columns(&value)?); },
+                "--context"         => { options.context = parse_count(&arg,&value)? as usize; },
+                "--expected-format" => { options.expected_format = Some(parse_format(&value)?); },
+                "--actual-format"   => { options.actual_format = Some(parse_format(&value)?); },
+                _                   => unreachable!(),
+            }
+            continue;
+        }
+        traces.push(arg);
+    }
+
+    if traces.len() != 2 {
+        return Err(CliError::Usage(format!("diff expects 2 traces, found {}",traces.len())));
+    }
+    options.actual = traces.pop().unwrap();
+    options.expected = traces.pop().unwrap();
+    Ok(Command::Diff(options))
+}
+
+/// Read both traces and compare them, see diff::diff.
+pub fn diff(options: &DiffOptions) -> Result<DiffResult,CliError> {
+    let expected = Trace::read(&options.expected,options.expected_format)?;
+    let actual = Trace::read(&options.actual,options.actual_format)?;
+    Ok(diff::diff(&expected,&actual,&options.ignore,options.context))
+}
+
+/// Load the rom and run it until one of the limits is reached or the cpu errors.  Without any
+/// limits this runs until an error.  With a window, a movie, hashing or screenshots it runs whole
+/// frames, see headless::run, and a window runs until it's closed.
+pub fn run(options: &Options) -> Result<RunSummary,CliError> {
+    let by_frame = options.windowed || options.movie.is_some() || options.hash.is_some() || !options.screenshot_at.is_empty();
+    if by_frame && (options.start_pc.is_some() || options.max_instructions.is_some() || options.max_cycles.is_some()) {
+        return Err(CliError::Usage("--windowed, --movie, hashing and screenshots run whole frames from power on, use --frames instead of --pc, --instructions or --cycles".to_string()));
+    }
+    let screenshot = match options.screenshot {
+        Some(ref path) => path.clone(),
+        None           => format!("{}-{{frame}}.png",Path::new(&options.rom).file_stem().and_then(|stem| stem.to_str()).unwrap_or("screenshot")),
+    };
+    if options.filter.is_some() && options.palette.is_some() {
+        return Err(CliError::Usage("--filter makes its colors from the signal, it can't be used with --palette".to_string()));
+    }
+    if ImageFormat::from_path(&screenshot).is_none() {
+        return Err(CliError::Usage(format!("--screenshot '{}' should end in .png or .ppm",screenshot)));
+    }
+    let movie = match options.movie {
+        Some(ref path) => Some(fm2::load(path)?),
+        None           => None,
+    };
+    let frames = match (options.max_frames,movie.as_ref()) {
+        (Some(frames),_)    => frames,
+        (None,Some(movie))  => movie.frames.len() as u64,
+        (None,None) if options.windowed => u64::MAX,
+        (None,None) if options.hash.is_none() && by_frame => options.screenshot_at.iter().cloned().max().unwrap_or(0),
+        (None,None) if by_frame => return Err(CliError::Usage("hashing needs --frames to know when to stop".to_string())),
+        (None,None)         => 0,
+    };
+
+    let cartridge = rom_loader::load_cartridge(&options.rom)?;
+    let mut nes = if options.cycle_accurate { Nes::with_cycle_executor(cartridge,Default::default()) } else { Nes::new(cartridge) };
+
+    let mut sinks: Vec<Box<dyn TraceSink>> = Vec::new();
+    if let Some(ref path) = options.trace {
+        let sink: Box<dyn TraceSink> = match (options.trace_format,path.as_ref()) {
+            (TraceFormat::Nestest,"-") => Box::new(StdoutSink::stdout(NesTest::default())),
+            (TraceFormat::Nestest,_)   => Box::new(FileSink::create(path,NesTest::default())?),
+            (TraceFormat::Fceux,"-")   => Box::new(StdoutSink::stdout(Fceux::default())),
+            (TraceFormat::Fceux,_)     => Box::new(FileSink::create(path,Fceux::default())?),
+            (TraceFormat::Mesen,"-")   => Box::new(StdoutSink::stdout(Mesen::default())),
+            (TraceFormat::Mesen,_)     => Box::new(FileSink::create(path,Mesen::default())?),
+            (TraceFormat::Binary,"-")  => Box::new(BinarySink::new(io::stdout())?),
+            (TraceFormat::Binary,_)    => Box::new(BinarySink::create(path)?),
+        };
+        let mut gated = GatedSink::new(sink);
+        if let Some((first,last)) = options.trace_range {
+            gated = gated.pc_range(first,last);
+        }
+        if let Some(pc) = options.trace_after {
+            gated = gated.trigger_at(pc);
+        }
+        sinks.push(Box::new(gated));
+    }
+    let crash_dump = options.crash_dump.map(|n| Rc::new(RefCell::new(RingBufferSink::new(n,NesTest::default()))));
+    if let Some(ref ring) = crash_dump {
+        sinks.push(Box::new(ring.clone()));
+    }
+    if !sinks.is_empty() {
+        nes.set_trace_sink(sinks);
+    }
+
+    let dump_crash = || -> io::Result<()> {
+        if let Some(ref ring) = crash_dump {
+            eprintln!("last {} instructions:",ring.borrow().lines().len());
+            ring.borrow().dump(&mut io::stderr())?;
+        }
+        Ok(())
+    };
+
+    if by_frame {
+        let mut hasher = FrameHasher::new(options.hash.clone().unwrap_or(Checkpoints::At(Vec::new())));
+        let palette = match options.palette {
+            Some(ref palette) => load_palette(palette)?,
+            None              => Palette::default(),
+        };
+        let filter = options.filter.map(|connection| NtscFilter::new(NtscSettings::preset(connection)));
+        let picture = |frame: u64, nes: &Nes| match filter {
+            Some(ref filter) => filter.filter(nes.framebuffer(),ntsc::frame_phase(frame,nes.mem.ppu.rendering_enabled())),
+            None             => Image::from_framebuffer(nes.framebuffer(),&palette),
+        };
+        let mut screenshots = Vec::new();
+        let after_frame = |frame: u64, nes: &mut Nes| {
+            hasher.after_frame(frame,nes);
+            if options.screenshot_at.contains(&frame) {
+                let path = screenshot.replace("{frame}",&frame.to_string());
+                picture(frame,nes).save(&path)?;
+                screenshots.push(path);
+            }
+            Ok(())
+        };
+        let result = if options.windowed {
+            run_windowed(&mut nes,movie.as_ref(),frames,&options.rom,after_frame,picture)
+        } else {
+            headless::run_frames(&mut nes,movie.as_ref(),frames,after_frame).map(|()| frames).map_err(CliError::from)
+        };
+        let frames = match result {
+            Ok(frames) => frames,
+            Err(err) => {
+                if let CliError::Execution(_) = err {
+                    dump_crash()?;
+                }
+                return Err(err);
+            },
+        };
+        nes.take_trace_sink();
+        return Ok(RunSummary { instructions: nes.instructions(), cycles: nes.clock(), frames, hashes: hasher.into_hashes(), screenshots });
+    }
+
+    nes.power_cycle()?;
+    if let Some(pc) = options.start_pc {
+        nes.cpu.pc = pc;
+    }
+
+    let start_cycles = nes.cpu.cycles;
+    let start_frame = nes.mem.ppu.frame;
+    let mut summary = RunSummary::default();
+    let reached = |limit: Option<u64>, count: u64| limit.is_some_and(|limit| count >= limit);
+
+    while !reached(options.max_instructions,summary.instructions)
+       && !reached(options.max_cycles,summary.cycles)
+       && !reached(options.max_frames,summary.frames) {
+        if let Err(err) = nes.step_instruction() {
+            dump_crash()?;
+            return Err(err.into());
+        }
+        summary.instructions += 1;
+        summary.cycles = nes.cpu.cycles - start_cycles;
+        summary.frames = nes.mem.ppu.frame - start_frame;
+    }
+    // drop the sinks so the trace file is flushed before we return
+    nes.take_trace_sink();
+    Ok(summary)
+}
+
+/// Run frames frames in a window as headless::run_frames does, with controller 1 on the keyboard
+/// once the movie runs out, until the window is closed.  Returns the frames run.
+#[cfg(feature = "windowed")]
+fn run_windowed<F,P>(nes: &mut Nes, movie: Option<&Movie>, frames: u64, rom: &str, mut after_frame: F, picture: P) -> Result<u64,CliError>
+    where F: FnMut(u64,&mut Nes) -> Result<(),HeadlessError>, P: Fn(u64,&Nes) -> Image {
+    let title = format!("trustiNES - {}",Path::new(rom).file_name().and_then(|name| name.to_str()).unwrap_or(rom));
+    let mut window = Window::open(&title,SCREEN_WIDTH*2,SCREEN_HEIGHT*2)?;
+    headless::start(nes,movie)?;
+    for frame in 1..=frames {
+        let input = window.poll();
+        if input.closed {
+            return Ok(frame - 1);
+        }
+        // a movie sets the buttons itself while it lasts
+        nes.set_buttons(0,input.buttons);
+        headless::run_frame(nes,movie,frame)?;
+        after_frame(frame,nes)?;
+        window.show(&picture(frame,nes));
+        window.wait_for_next_frame();
+    }
+    Ok(frames)
+}
+#[cfg(not(feature = "windowed"))]
+fn run_windowed<F,P>(_nes: &mut Nes, _movie: Option<&Movie>, _frames: u64, _rom: &str, _after_frame: F, _picture: P) -> Result<u64,CliError>
+    where F: FnMut(u64,&mut Nes) -> Result<(),HeadlessError>, P: Fn(u64,&Nes) -> Image {
+    Err(CliError::Usage(NOT_WINDOWED.to_string()))
+}
@@ -0,0 +1,117 @@
+use cpu::{CpuState,Variant};
+
+// flag updates and arithmetic shared by the cpu cores
+
+macro_rules! set_zs {
+    ($cpu_state:expr,$val:expr) => (set_z!($cpu_state,$val);set_s!($cpu_state,$val););
+}
+macro_rules! set_z {
+    ($cpu_state:expr,$val:expr) => ($cpu_state.Z = $val == 0;);
+}
+macro_rules! set_s {
+    ($cpu_state:expr,$val:expr) => ($cpu_state.S = $val >= 0x80;);
+}
+macro_rules! compare {
+    ($cpu_state:expr,$a:expr,$b:expr) => {
+        {
+            set_zs!($cpu_state, ($a as i16 - $b as i16) as u8);
+            $cpu_state.C = $a >= $b;
+        }
+    }
+}
+
+// the unstable illegal opcodes (XAA, LAX #) OR the accumulator with a chip dependent constant
+// http://wiki.nesdev.com/w/index.php/Programming_with_unofficial_opcodes
+pub const UNSTABLE_MAGIC: u8 = 0xEE;
+
+pub fn asl(cpu_state: &mut CpuState, val: u8) -> u8 {
+    cpu_state.C = val & 0x80 != 0;
+    let result = val << 1;
+    set_zs!(cpu_state,result);
+    result
+}
+pub fn lsr(cpu_state: &mut CpuState, val: u8) -> u8 {
+    cpu_state.C = val & 0x01 != 0;
+    let result = val >> 1;
+    set_zs!(cpu_state,result);
+    result
+}
+pub fn rol(cpu_state: &mut CpuState, val: u8) -> u8 {
+    let result = (val << 1) | cpu_state.C as u8;
+    cpu_state.C = val & 0x80 != 0;
+    set_zs!(cpu_state,result);
+    result
+}
+pub fn ror(cpu_state: &mut CpuState, val: u8) -> u8 {
+    let result = (val >> 1) | ((cpu_state.C as u8) << 7);
+    cpu_state.C = val & 0x01 != 0;
+    set_zs!(cpu_state,result);
+    result
+}
+pub fn inc(cpu_state: &mut CpuState, val: u8) -> u8 {
+    let result = val.wrapping_add(1);
+    set_zs!(cpu_state,result);
+    result
+}
+pub fn dec(cpu_state: &mut CpuState, val: u8) -> u8 {
+    let result = val.wrapping_sub(1);
+    set_zs!(cpu_state,result);
+    result
+}
+// SBC is ADC of the complement, the carry acts as an inverted borrow
+pub fn adc(cpu_state: &mut CpuState, b: u8) {
+    let a = cpu_state.a;
+    let sum:u16 = a as u16 + b as u16 + cpu_state.C as u16;
+    cpu_state.a = sum as u8;
+    set_zs!(cpu_state,cpu_state.a);
+
+    cpu_state.C = sum > 0xFF;
+    cpu_state.V = ((a^b)&0x80) == 0 && ((a^cpu_state.a)&0x80) != 0;
+}
+
+// ADC and SBC with the D flag set, on the chips that have a decimal mode.  The NMOS 6502 sets
+// N, V and Z from the intermediate binary results, the 65C02 sets N and Z from the decimal
+// result.  Both only give valid results for valid BCD operands.
+// http://www.6502.org/tutorials/decimal_mode.html#A
+pub fn adc_decimal(cpu_state: &mut CpuState, b: u8, variant: Variant) {
+    let (a,b16,c) = (cpu_state.a as i16,b as i16,cpu_state.C as i16);
+    let binary = cpu_state.a.wrapping_add(b).wrapping_add(cpu_state.C as u8);
+
+    let mut low = (a & 0x0F) + (b16 & 0x0F) + c;
+    if low >= 0x0A {
+        low = ((low + 0x06) & 0x0F) + 0x10;
+    }
+    let mut sum = (a & 0xF0) + (b16 & 0xF0) + low;
+    // the same sum with the high digits signed gives V
+    let signed = (cpu_state.a & 0xF0) as i8 as i16 + (b & 0xF0) as i8 as i16 + low;
+    cpu_state.V = !(-128..=127).contains(&signed);
+    set_s!(cpu_state,(sum & 0xFF) as u8);
+    if sum >= 0xA0 {
+        sum += 0x60;
+    }
+    cpu_state.C = sum >= 0x100;
+    cpu_state.a = sum as u8;
+
+    if variant.is_cmos() { set_zs!(cpu_state,cpu_state.a); } else { set_z!(cpu_state,binary); }
+}
+pub fn sbc_decimal(cpu_state: &mut CpuState, b: u8, variant: Variant) {
+    let (a,b16,borrow) = (cpu_state.a as i16,b as i16,1 - cpu_state.C as i16);
+    let low = (a & 0x0F) - (b16 & 0x0F) - borrow;
+    let result = if variant.is_cmos() {
+        let mut result = a - b16 - borrow;
+        if result < 0 { result -= 0x60; }
+        if low < 0 { result -= 0x06; }
+        result
+    }
+    else {
+        let low = if low < 0 { ((low - 0x06) & 0x0F) - 0x10 } else { low };
+        let mut result = (a & 0xF0) - (b16 & 0xF0) + low;
+        if result < 0 { result -= 0x60; }
+        result
+    };
+
+    // the flags come from the binary subtraction, except N and Z on the 65C02
+    adc(cpu_state,!b);
+    cpu_state.a = result as u8;
+    if variant.is_cmos() { set_zs!(cpu_state,cpu_state.a); }
+}
@@ -0,0 +1,704 @@
+use std::collections::{BTreeMap,HashMap};
+use cpu::{AddressMode,OpcodeTables};
+use memory::Memory;
+
+// A small ca65 flavoured assembler, meant for writing cpu tests as source instead of bytes:
+//
+//          .org $0200
+//  ptr     = $10
+//  start:  LDX #0
+//  @loop:  LDA table,X     ; @ labels are local to the label before them
+//          STA (ptr),Y
+//          INX
+//          BNE @loop
+//          JMP (vector)
+//  table:  .byte 1, 2, "text", <start, >start
+//  vector: .word start + 3
+//          .res 4, $FF
+//
+// Expressions have + - * / & | ^ << >>, unary - ~ < (low byte) > (high byte), parentheses,
+// $hex, %binary, decimal and 'c' literals, and * for the current address.  Operands below $100
+// assemble as zero page when the instruction has it, a: and z: force the size.  Symbols used
+// before they're defined are assumed to be 16 bit.
+//
+
+#[derive(PartialEq,Debug)]
+pub enum AssembleError {
+    // all carry the 1 based source line
+    Syntax(usize,String),
+    UnknownMnemonic(usize,String),
+    UnknownSymbol(usize,String),
+    InvalidAddressMode(usize,String),
+    OutOfRange(usize,String),
+    DuplicateSymbol(usize,String),
+}
+
+/// Bytes assembled to consecutive addresses, a new one starts at every .org.
+#[derive(PartialEq,Clone,Debug)]
+pub struct Segment {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(PartialEq,Clone,Debug)]
+pub struct Program {
+    pub segments: Vec<Segment>,
+    pub symbols: BTreeMap<String,u16>,
+}
+
+impl Program {
+    /// Write every segment to memory.
+    pub fn load(self: &Program, mem: &mut Memory) {
+        for segment in &self.segments {
+            mem.write(segment.origin,&segment.bytes);
+        }
+    }
+
+    /// All the bytes, in source order.
+    pub fn bytes(self: &Program) -> Vec<u8> {
+        self.segments.iter().flat_map(|segment| segment.bytes.iter().cloned()).collect()
+    }
+
+    pub fn symbol(self: &Program, name: &str) -> Option<u16> {
+        self.symbols.get(name).cloned()
+    }
+}
+
+/// Assemble with the builtin opcode tables.  Code starts at $0000 until the first .org.
+pub fn assemble(source: &str) -> Result<Program,AssembleError> {
+    Assembler::default().assemble(source)
+}
+
+#[derive(Clone,Debug)]
+enum Expr {
+    Number(i32),
+    Symbol(String),
+    Pc,
+    Unary(char,Box<Expr>),
+    Binary(&'static str,Box<Expr>,Box<Expr>),
+}
+
+#[derive(PartialEq,Clone,Copy,Debug)]
+enum Size {
+    Zero,
+    Absolute,
+}
+
+#[derive(Clone,Debug)]
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    // index is X or Y
+    Direct(Expr,Option<char>,Option<Size>),
+    Indirect(Expr),
+    IndexedIndirect(Expr),
+    IndirectIndexed(Expr),
+}
+
+#[derive(Clone,Debug)]
+enum ByteItem {
+    Expr(Expr),
+    Text(String),
+}
+
+#[derive(Clone,Debug)]
+enum Statement {
+    Org(Expr),
+    Byte(Vec<ByteItem>),
+    Word(Vec<Expr>),
+    Res(Expr,Option<Expr>),
+    Constant(String,Expr),
+    Instruction(String,Operand),
+}
+
+struct Line {
+    number: usize,
+    label: Option<String>,
+    statement: Option<Statement>,
+}
+
+/// Turns source into machine code, see assemble.
+pub struct Assembler {
+    // (mnemonic, mode) to opcode
+    opcodes: HashMap<(String,AddressMode),u8>,
+    branches: Vec<String>,
+}
+
+impl Default for Assembler {
+    /// An assembler for the builtin opcode tables.
+    fn default() -> Assembler {
+        Assembler::new(&OpcodeTables::default())
+    }
+}
+
+impl Assembler {
+    /// Instructions go by their mnemonic and by their OpcodeClass name, e.g. both ISB and ISC.
+    /// Where several opcodes do the same thing the documented one wins, then the lowest.
+    pub fn new(tables: &OpcodeTables) -> Assembler {
+        let mut opcodes: HashMap<(String,AddressMode),u8> = HashMap::new();
+        let mut branches = Vec::new();
+        for opcode in 0..=255u8 {
+            let info = tables.exec(opcode);
+            let class = format!("{:?}",info.opcode_class);
+            let names = [tables.mnemonic(opcode).to_uppercase(),class.trim_start_matches("ILL_").to_string()];
+            // LAX1 and LAX2 are just LAX
+            for name in names.iter().filter(|name| name.chars().all(|c| c.is_ascii_alphabetic())) {
+                let key = (name.clone(),info.address_mode.clone());
+                let replace = match opcodes.get(&key) {
+                    Some(&existing) => tables.exec(existing).opcode_class.is_illegal() && !info.opcode_class.is_illegal(),
+                    None => true,
+                };
+                if replace {
+                    opcodes.insert(key,opcode);
+                }
+                if info.opcode_class.is_branch() && !branches.contains(name) {
+                    branches.push(name.clone());
+                }
+            }
+        }
+        Assembler { opcodes, branches }
+    }
+
+    pub fn assemble(self: &Assembler, source: &str) -> Result<Program,AssembleError> {
+        let lines = parse(source)?;
+
+        // pass 1, where everything goes
+        let mut symbols: BTreeMap<String,i32> = BTreeMap::new();
+        let mut modes: Vec<Option<AddressMode>> = Vec::with_capacity(lines.len());
+        let mut pc: i32 = 0;
+        for line in &lines {
+            if let Some(ref label) = line.label {
+                define(&mut symbols,label,pc,line.number)?;
+            }
+            let mut mode = None;
+            match line.statement {
+                Some(Statement::Org(ref expr)) => { pc = self.address(line.number,&symbols,expr,pc)?; },
+                Some(Statement::Constant(ref name,ref expr)) => {
+                    if let Some(value) = evaluate(expr,&symbols,pc,line.number)? {
+                        define(&mut symbols,name,value,line.number)?;
+                    }
+                },
+                Some(Statement::Instruction(ref mnemonic,ref operand)) => {
+                    let chosen = self.address_mode(line.number,mnemonic,operand,&symbols,pc)?;
+                    pc += instruction_len(&chosen);
+                    mode = Some(chosen);
+                },
+                Some(ref statement) => { pc += data_len(line.number,statement,&symbols,pc)?; },
+                None => { },
+            }
+            modes.push(mode);
+        }
+        // constants defined from symbols further down
+        for line in &lines {
+            if let Some(Statement::Constant(ref name,ref expr)) = line.statement {
+                if !symbols.contains_key(name) {
+                    let value = require(line.number,evaluate(expr,&symbols,0,line.number)?,expr)?;
+                    define(&mut symbols,name,value,line.number)?;
+                }
+            }
+        }
+
+        // pass 2, the bytes
+        let mut segments = vec![Segment { origin: 0, bytes: Vec::new() }];
+        let mut pc: i32 = 0;
+        for (line,mode) in lines.iter().zip(modes.iter()) {
+            let mut bytes = Vec::new();
+            let value = |expr: &Expr, pc: i32| -> Result<i32,AssembleError> { require(line.number,evaluate(expr,&symbols,pc,line.number)?,expr) };
+            match line.statement {
+                Some(Statement::Org(ref expr)) => {
+                    pc = self.address(line.number,&symbols,expr,pc)?;
+                    if segments.last().unwrap().bytes.is_empty() {
+                        segments.pop();
+                    }
+                    segments.push(Segment { origin: pc as u16, bytes: Vec::new() });
+                },
+                Some(Statement::Byte(ref items)) => {
+                    for item in items {
+                        match *item {
+                            ByteItem::Text(ref text) => bytes.extend(text.bytes()),
+                            ByteItem::Expr(ref expr) => bytes.push(byte(line.number,value(expr,pc)?)?),
+                        }
+                    }
+                },
+                Some(Statement::Word(ref exprs)) => {
+                    for expr in exprs {
+                        let word = word(line.number,value(expr,pc)?)?;
+                        bytes.push(word as u8);
+                        bytes.push((word >> 8) as u8);
+                    }
+                },
+                Some(Statement::Res(ref count,ref fill)) => {
+                    let fill = match *fill { Some(ref fill) => byte(line.number,value(fill,pc)?)?, None => 0 };
+                    bytes.extend(::std::iter::repeat(fill).take(value(count,pc)? as usize));
+                },
+                Some(Statement::Instruction(ref mnemonic,ref operand)) => {
+                    let mode = mode.clone().unwrap();
+                    bytes.push(self.opcodes[&(mnemonic.clone(),mode.clone())]);
+                    match (&mode,operand_expr(operand)) {
+                        (&AddressMode::Relative,Some(expr)) => {
+                            let offset = value(expr,pc)? - (pc + 2);
+                            if !(-128..=127).contains(&offset) {
+                                return Err(AssembleError::OutOfRange(line.number,format!("branch is {} bytes away, it can only go -128 to 127",offset)));
+                            }
+                            bytes.push(offset as u8);
+                        },
+                        (_,Some(expr)) if instruction_len(&mode) == 2 => bytes.push(byte(line.number,value(expr,pc)?)?),
+                        (_,Some(expr)) => {
+                            let word = word(line.number,value(expr,pc)?)?;
+                            bytes.push(word as u8);
+                            bytes.push((word >> 8) as u8);
+                        },
+                        (_,None) => { },
+                    }
+                },
+                Some(Statement::Constant(..)) | None => { },
+            }
+            pc += bytes.len() as i32;
+            if pc > 0x10000 {
+                return Err(AssembleError::OutOfRange(line.number,"assembled past $FFFF".to_string()));
+            }
+            segments.last_mut().unwrap().bytes.extend(bytes);
+        }
+
+        Ok(Program {
+            segments,
+            symbols: symbols.into_iter().map(|(name,value)| (name,value as u16)).collect(),
+        })
+    }
+
+    fn address(self: &Assembler, number: usize, symbols: &BTreeMap<String,i32>, expr: &Expr, pc: i32) -> Result<i32,AssembleError> {
+        let value = require(number,evaluate(expr,symbols,pc,number)?,expr)?;
+        word(number,value).map(|v| v as i32)
+    }
+
+    fn has(self: &Assembler, mnemonic: &str, mode: AddressMode) -> bool {
+        self.opcodes.contains_key(&(mnemonic.to_string(),mode))
+    }
+
+    fn address_mode(self: &Assembler, number: usize, mnemonic: &str, operand: &Operand, symbols: &BTreeMap<String,i32>, pc: i32) -> Result<AddressMode,AssembleError> {
+        if !self.opcodes.keys().any(|(name,_)| name == mnemonic) {
+            return Err(AssembleError::UnknownMnemonic(number,mnemonic.to_string()));
+        }
+
+        let candidates = match *operand {
+            Operand::None                  => vec![AddressMode::Implied,AddressMode::Accumulator],
+            Operand::Accumulator           => vec![AddressMode::Accumulator],
+            Operand::Immediate(_)          => vec![AddressMode::Immediate],
+            // (zp) and JMP (a,X) are 65C02 only
+            Operand::Indirect(_)           => vec![AddressMode::Indirect,AddressMode::ZeroPageIndirect],
+            Operand::IndexedIndirect(_)    => vec![AddressMode::IndexedIndirect,AddressMode::AbsoluteIndexedIndirect],
+            Operand::IndirectIndexed(_)    => vec![AddressMode::IndirectIndexed],
+            Operand::Direct(_,_,_) if self.branches.iter().any(|b| b == mnemonic) => vec![AddressMode::Relative],
+            Operand::Direct(ref expr,index,size) => {
+                let (zero_page,absolute) = match index {
+                    None      => (AddressMode::ZeroPage,AddressMode::Absolute),
+                    Some('X') => (AddressMode::ZeroPageX,AddressMode::AbsoluteX),
+                    _         => (AddressMode::ZeroPageY,AddressMode::AbsoluteY),
+                };
+                let value = evaluate(expr,symbols,pc,number)?;
+                match (size,value) {
+                    (Some(Size::Zero),_)     => vec![zero_page],
+                    (Some(Size::Absolute),_) => vec![absolute],
+                    (None,Some(value)) if (0..0x100).contains(&value) => vec![zero_page,absolute],
+                    (None,Some(_))           => vec![absolute],
+                    // STX zp,Y has no absolute form, so it's worth trying for forward references
+                    (None,None)              => vec![absolute,zero_page],
+                }
+            },
+        };
+        match candidates.into_iter().find(|mode| self.has(mnemonic,mode.clone())) {
+            Some(mode) => Ok(mode),
+            None => Err(AssembleError::InvalidAddressMode(number,format!("{} doesn't have that addressing mode",mnemonic))),
+        }
+    }
+}
+
+fn define(symbols: &mut BTreeMap<String,i32>, name: &str, value: i32, number: usize) -> Result<(),AssembleError> {
+    if symbols.insert(name.to_string(),value).is_some() {
+        return Err(AssembleError::DuplicateSymbol(number,name.to_string()));
+    }
+    Ok(())
+}
+
+fn instruction_len(mode: &AddressMode) -> i32 {
+    match *mode {
+        AddressMode::Implied | AddressMode::Accumulator | AddressMode::None => 1,
+        AddressMode::Absolute | AddressMode::AbsoluteX | AddressMode::AbsoluteY | AddressMode::Indirect |
+        AddressMode::AbsoluteIndexedIndirect => 3,
+        _ => 2,
+    }
+}
+
+fn data_len(number: usize, statement: &Statement, symbols: &BTreeMap<String,i32>, pc: i32) -> Result<i32,AssembleError> {
+    Ok(match *statement {
+        Statement::Byte(ref items) => items.iter().map(|item| match *item { ByteItem::Text(ref t) => t.len() as i32, ByteItem::Expr(_) => 1 }).sum(),
+        Statement::Word(ref exprs) => exprs.len() as i32 * 2,
+        // the count has to be known in the first pass
+        Statement::Res(ref count,_) => match require(number,evaluate(count,symbols,pc,number)?,count)? {
+            count if (0..=0x10000).contains(&count) => count,
+            count => return Err(AssembleError::OutOfRange(number,format!("can't reserve {} bytes",count))),
+        },
+        _ => 0,
+    })
+}
+
+fn operand_expr(operand: &Operand) -> Option<&Expr> {
+    match *operand {
+        Operand::None | Operand::Accumulator => None,
+        Operand::Immediate(ref e) | Operand::Direct(ref e,_,_) | Operand::Indirect(ref e) |
+        Operand::IndexedIndirect(ref e) | Operand::IndirectIndexed(ref e) => Some(e),
+    }
+}
+
+fn byte(number: usize, value: i32) -> Result<u8,AssembleError> {
+    if !(-128..=255).contains(&value) {
+        return Err(AssembleError::OutOfRange(number,format!("{} doesn't fit in a byte",value)));
+    }
+    Ok(value as u8)
+}
+
+fn word(number: usize, value: i32) -> Result<u16,AssembleError> {
+    if !(-32768..=65535).contains(&value) {
+        return Err(AssembleError::OutOfRange(number,format!("{} doesn't fit in a word",value)));
+    }
+    Ok(value as u16)
+}
+
+// the value of an expression that has to be known by now
+fn require(number: usize, value: Option<i32>, expr: &Expr) -> Result<i32,AssembleError> {
+    value.ok_or_else(|| AssembleError::UnknownSymbol(number,first_unknown(expr)))
+}
+
+fn first_unknown(expr: &Expr) -> String {
+    match *expr {
+        Expr::Symbol(ref name)    => name.clone(),
+        Expr::Unary(_,ref e)      => first_unknown(e),
+        Expr::Binary(_,ref l,ref r) => { let name = first_unknown(l); if name.is_empty() { first_unknown(r) } else { name } },
+        _ => String::new(),
+    }
+}
+
+// None when it uses a symbol that isn't defined yet
+fn evaluate(expr: &Expr, symbols: &BTreeMap<String,i32>, pc: i32, number: usize) -> Result<Option<i32>,AssembleError> {
+    Ok(match *expr {
+        Expr::Number(n)        => Some(n),
+        Expr::Pc               => Some(pc),
+        Expr::Symbol(ref name) => symbols.get(name).cloned(),
+        Expr::Unary(op,ref e)  => match evaluate(e,symbols,pc,number)? {
+            None    => None,
+            Some(v) => Some(match op {
+                '-' => v.checked_neg().ok_or_else(|| AssembleError::OutOfRange(number,format!("-({}) doesn't fit in 32 bits",v)))?,
+                '~' => !v,
+                '<' => v & 0xFF,
+                _   => (v >> 8) & 0xFF,
+            }),
+        },
+        Expr::Binary(op,ref l,ref r) => {
+            let (l,r) = match (evaluate(l,symbols,pc,number)?,evaluate(r,symbols,pc,number)?) {
+                (Some(l),Some(r)) => (l,r),
+                _ => return Ok(None),
+            };
+            Some(match op {
+                "+"  => l.wrapping_add(r),
+                "-"  => l.wrapping_sub(r),
+                "*"  => l.wrapping_mul(r),
+                "/" if r == 0 => return Err(AssembleError::Syntax(number,"division by zero".to_string())),
+                "/"  => l / r,
+                "&"  => l & r,
+                "|"  => l | r,
+                "^"  => l ^ r,
+                "<<" => l.wrapping_shl(r as u32),
+                _    => l.wrapping_shr(r as u32),
+            })
+        },
+    })
+}
+
+// ------------------ parsing ------------------ //
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '@'
+}
+fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '@'
+}
+
+// cut the comment off, minding ; in strings and character literals
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i,c) in line.char_indices() {
+        match (quote,c) {
+            (None,';')                  => return &line[..i],
+            (None,'"') | (None,'\'')    => quote = Some(c),
+            (Some(q),c) if q == c       => quote = None,
+            _ => { },
+        }
+    }
+    line
+}
+
+fn parse(source: &str) -> Result<Vec<Line>,AssembleError> {
+    // @ labels are scoped to the last normal label
+    let mut scope = String::new();
+    let mut lines = Vec::new();
+
+    for (i,text) in source.lines().enumerate() {
+        let number = i + 1;
+        let mut rest = strip_comment(text).trim();
+        let mut label = None;
+
+        let ident_len = rest.find(|c: char| !is_ident(c)).unwrap_or(rest.len());
+        if ident_len > 0 && rest.starts_with(is_ident_start) && rest[ident_len..].trim_start().starts_with(':') {
+            let name = &rest[..ident_len];
+            if !name.starts_with('@') {
+                scope = name.to_string();
+            }
+            label = Some(scoped(&scope,name));
+            rest = rest[ident_len..].trim_start()[1..].trim();
+        }
+
+        let statement = if rest.is_empty() {
+            None
+        } else if let Some(directive) = rest.strip_prefix('.') {
+            Some(parse_directive(number,directive,&scope)?)
+        } else {
+            let ident_len = rest.find(|c: char| !is_ident(c)).unwrap_or(rest.len());
+            let word = &rest[..ident_len];
+            let after = rest[ident_len..].trim();
+            if ident_len > 0 && after.starts_with('=') {
+                Some(Statement::Constant(scoped(&scope,word),parse_expr(number,after[1..].trim(),&scope)?))
+            } else if ident_len == 0 {
+                return Err(AssembleError::Syntax(number,format!("expected an instruction, found '{}'",rest)));
+            } else {
+                Some(Statement::Instruction(word.to_uppercase(),parse_operand(number,after,&scope)?))
+            }
+        };
+        lines.push(Line { number, label, statement });
+    }
+    Ok(lines)
+}
+
+fn scoped(scope: &str, name: &str) -> String {
+    if name.starts_with('@') { format!("{}{}",scope,name) } else { name.to_string() }
+}
+
+// split on commas that aren't in parentheses or quotes
+fn split_args(s: &str) -> Vec<&str> {
+    let mut args = Vec::new();
+    let (mut depth,mut quote,mut start) = (0,None,0);
+    for (i,c) in s.char_indices() {
+        match (quote,c) {
+            (Some(q),c) if q == c    => quote = None,
+            (Some(_),_)              => { },
+            (None,'"') | (None,'\'') => quote = Some(c),
+            (None,'(')               => depth += 1,
+            (None,')')               => depth -= 1,
+            (None,',') if depth == 0 => { args.push(s[start..i].trim()); start = i + 1; },
+            _ => { },
+        }
+    }
+    args.push(s[start..].trim());
+    args
+}
+
+fn parse_directive(number: usize, directive: &str, scope: &str) -> Result<Statement,AssembleError> {
+    let name_len = directive.find(char::is_whitespace).unwrap_or(directive.len());
+    let args = directive[name_len..].trim();
+    let exprs = |args: &str| -> Result<Vec<Expr>,AssembleError> { split_args(args).iter().map(|arg| parse_expr(number,arg,scope)).collect() };
+
+    match directive[..name_len].to_lowercase().as_ref() {
+        "org" => Ok(Statement::Org(parse_expr(number,args,scope)?)),
+        "byte" | "byt" | "db" => {
+            let items: Result<Vec<ByteItem>,AssembleError> = split_args(args).iter().map(|arg| {
+                if arg.len() >= 2 && arg.starts_with('"') && arg.ends_with('"') {
+                    Ok(ByteItem::Text(arg[1..arg.len()-1].to_string()))
+                } else {
+                    parse_expr(number,arg,scope).map(ByteItem::Expr)
+                }
+            }).collect();
+            Ok(Statement::Byte(items?))
+        },
+        "word" | "addr" | "dw" => Ok(Statement::Word(exprs(args)?)),
+        "res" | "ds" => {
+            let mut exprs = exprs(args)?.into_iter();
+            let count = exprs.next().unwrap();
+            Ok(Statement::Res(count,exprs.next()))
+        },
+        other => Err(AssembleError::Syntax(number,format!("unknown directive '.{}'",other))),
+    }
+}
+
+fn parse_operand(number: usize, s: &str, scope: &str) -> Result<Operand,AssembleError> {
+    // only ASCII is case insensitive, so offsets found in one spelling work in the other
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(Operand::None);
+    }
+    if s.eq_ignore_ascii_case("A") {
+        return Ok(Operand::Accumulator);
+    }
+    if let Some(value) = s.strip_prefix('#') {
+        return Ok(Operand::Immediate(parse_expr(number,value,scope)?));
+    }
+
+    let size = match s.get(..2).map(|prefix| prefix.to_ascii_uppercase()).as_deref() {
+        Some("A:") => Some(Size::Absolute),
+        Some("Z:") => Some(Size::Zero),
+        _          => None,
+    };
+    let s = if size.is_some() { s[2..].trim() } else { s };
+
+    // (d,X) (d),Y and (a), as long as the parentheses aren't just part of an expression
+    if s.starts_with('(') {
+        let close = matching_paren(s);
+        if close == Some(s.len()-1) {
+            let inner = &s[1..s.len()-1];
+            if inner.trim_end().to_ascii_uppercase().ends_with(",X") && split_args(inner).len() == 2 {
+                let comma = inner.rfind(',').unwrap();
+                return Ok(Operand::IndexedIndirect(parse_expr(number,&s[1..1+comma],scope)?));
+            }
+            return Ok(Operand::Indirect(parse_expr(number,&s[1..s.len()-1],scope)?));
+        }
+        if let Some(close) = close {
+            if s[close+1..].replace(' ',"").eq_ignore_ascii_case(",Y") {
+                return Ok(Operand::IndirectIndexed(parse_expr(number,&s[1..close],scope)?));
+            }
+        }
+    }
+
+    let args = split_args(s);
+    match args.len() {
+        1 => Ok(Operand::Direct(parse_expr(number,s,scope)?,None,size)),
+        2 => match args[1].to_ascii_uppercase().as_ref() {
+            "X" => Ok(Operand::Direct(parse_expr(number,args[0],scope)?,Some('X'),size)),
+            "Y" => Ok(Operand::Direct(parse_expr(number,args[0],scope)?,Some('Y'),size)),
+            _   => Err(AssembleError::Syntax(number,format!("can only index with X or Y, found '{}'",args[1]))),
+        },
+        _ => Err(AssembleError::Syntax(number,format!("too many operands in '{}'",s))),
+    }
+}
+
+fn matching_paren(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i,c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => { depth -= 1; if depth == 0 { return Some(i); } },
+            _ => { },
+        }
+    }
+    None
+}
+
+// precedence climbing over the binary operators, loosest first
+const BINARY_OPERATORS: [&[&str];5] = [&["|"],&["^"],&["&"],&["<<",">>"],&["+","-"]];
+const MULTIPLICATIVE: [&str;2] = ["*","/"];
+
+struct ExprParser<'a> {
+    number: usize,
+    s: &'a str,
+    pos: usize,
+    scope: &'a str,
+}
+
+fn parse_expr(number: usize, s: &str, scope: &str) -> Result<Expr,AssembleError> {
+    let mut parser = ExprParser { number, s, pos: 0, scope };
+    let expr = parser.binary(0)?;
+    parser.skip_space();
+    if parser.pos != s.len() || s.trim().is_empty() {
+        return Err(AssembleError::Syntax(number,format!("can't make sense of the expression '{}'",s)));
+    }
+    Ok(expr)
+}
+
+impl<'a> ExprParser<'a> {
+    fn skip_space(self: &mut ExprParser<'a>) {
+        while let Some(c) = self.s[self.pos..].chars().next().filter(|c| c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn eat(self: &mut ExprParser<'a>, ops: &[&'static str]) -> Option<&'static str> {
+        self.skip_space();
+        let op = ops.iter().find(|op| self.s[self.pos..].starts_with(**op))?;
+        self.pos += op.len();
+        Some(op)
+    }
+
+    fn binary(self: &mut ExprParser<'a>, level: usize) -> Result<Expr,AssembleError> {
+        if level == BINARY_OPERATORS.len() {
+            return self.multiplicative();
+        }
+        let mut lhs = self.binary(level+1)?;
+        while let Some(op) = self.eat(BINARY_OPERATORS[level]) {
+            let rhs = self.binary(level+1)?;
+            lhs = Expr::Binary(op,Box::new(lhs),Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn multiplicative(self: &mut ExprParser<'a>) -> Result<Expr,AssembleError> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.eat(&MULTIPLICATIVE) {
+            let rhs = self.unary()?;
+            lhs = Expr::Binary(op,Box::new(lhs),Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(self: &mut ExprParser<'a>) -> Result<Expr,AssembleError> {
+        self.skip_space();
+        let c = match self.s[self.pos..].chars().next() {
+            Some(c) => c,
+            None => return Err(AssembleError::Syntax(self.number,format!("expression '{}' ends early",self.s))),
+        };
+        if "-~<>".contains(c) {
+            self.pos += 1;
+            return Ok(Expr::Unary(c,Box::new(self.unary()?)));
+        }
+        self.primary(c)
+    }
+
+    fn primary(self: &mut ExprParser<'a>, c: char) -> Result<Expr,AssembleError> {
+        let rest = &self.s[self.pos..];
+        let number = self.number;
+        let syntax = |what: String| AssembleError::Syntax(number,what);
+
+        if c == '(' {
+            self.pos += 1;
+            let expr = self.binary(0)?;
+            self.skip_space();
+            if !self.s[self.pos..].starts_with(')') {
+                return Err(syntax(format!("missing ) in '{}'",self.s)));
+            }
+            self.pos += 1;
+            return Ok(expr);
+        }
+        if c == '*' {
+            self.pos += 1;
+            return Ok(Expr::Pc);
+        }
+        if c == '\'' {
+            let mut chars = rest.chars().skip(1);
+            return match (chars.next(),chars.next()) {
+                (Some(value),Some('\'')) => { self.pos += 2 + value.len_utf8(); Ok(Expr::Number(value as i32)) },
+                _ => Err(syntax(format!("bad character literal in '{}'",self.s))),
+            };
+        }
+
+        let (radix,skip) = match c { '$' => (16,1), '%' => (2,1), _ => (10,0) };
+        if skip == 1 || c.is_ascii_digit() {
+            let digits: String = rest[skip..].chars().take_while(|c| c.is_digit(radix)).collect();
+            let value = i32::from_str_radix(&digits,radix).map_err(|_| syntax(format!("bad number in '{}'",self.s)))?;
+            self.pos += skip + digits.len();
+            return Ok(Expr::Number(value));
+        }
+        if is_ident_start(c) {
+            let len = rest.find(|c: char| !is_ident(c)).unwrap_or(rest.len());
+            self.pos += len;
+            return Ok(Expr::Symbol(scoped(self.scope,&rest[..len])));
+        }
+        Err(syntax(format!("unexpected '{}' in '{}'",c,self.s)))
+    }
+}
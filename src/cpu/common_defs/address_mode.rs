@@ -1,7 +1,8 @@
 use std::str::FromStr;
 
-#[derive(PartialEq,Clone,Debug)]
+#[derive(PartialEq,Eq,Hash,Clone,Debug,Default)]
 pub enum AddressMode {
+    #[default]
     None = 0,
     Absolute,        AbsoluteX,
     AbsoluteY,       Accumulator,
@@ -10,6 +11,9 @@ pub enum AddressMode {
     IndirectIndexed, Relative,
     ZeroPage,        ZeroPageX,
     ZeroPageY,
+
+    // added by the 65C02, (zp) and JMP (abs,X)
+    ZeroPageIndirect, AbsoluteIndexedIndirect,
 }
 
 #[derive(Debug)]
@@ -30,11 +34,9 @@ impl FromStr for AddressMode {
             "ZeroPage"        => Ok(AddressMode::ZeroPage       ), "ZeroPageX"       => Ok(AddressMode::ZeroPageX),
             "ZeroPageY"       => Ok(AddressMode::ZeroPageY      ),
 
+            "ZeroPageIndirect" => Ok(AddressMode::ZeroPageIndirect), "AbsoluteIndexedIndirect" => Ok(AddressMode::AbsoluteIndexedIndirect),
+
             _ => Err(ParseError::InvalidString(s.to_string()))
         }
     }
 }
-
-impl Default for AddressMode {
-    fn default() -> AddressMode { AddressMode::None }
-}
@@ -11,6 +11,8 @@ use std::clone::Clone;
 pub struct OpcodeDebugInfo {
     pub opcode: u8,
     pub name: String,
+    // the name disassemblers use, illegal opcodes go by several
+    pub mnemonic: String,
     pub address_mode_name: String,
     pub notes: String,
 }
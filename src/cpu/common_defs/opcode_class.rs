@@ -1,8 +1,9 @@
 use std::str::FromStr;
 
-#[derive(PartialEq,Clone,Debug)]
+#[derive(PartialEq,Clone,Debug,Default)]
 #[allow(non_camel_case_types)]
 pub enum OpcodeClass {
+    #[default]
     None = 0,
     ADC, AND, ASL,BCC,
     BCS, BEQ, BIT, BMI,
@@ -19,6 +20,10 @@ pub enum OpcodeClass {
     STX, STY, TAX, TAY,
     TSX, TXA, TXS, TYA,
 
+    // added by the 65C02
+    BRA, PHX, PHY, PLX,
+    PLY, STZ, TRB, TSB,
+
     // illegal opcodes
     ILL_AHX,  ILL_ALR,  ILL_ANC,
     ILL_ARR,  ILL_AXS,  ILL_DCP,
@@ -30,6 +35,56 @@ pub enum OpcodeClass {
     ILL_XAA,
 }
 
+impl OpcodeClass {
+    /// http://wiki.nesdev.com/w/index.php/CPU_unofficial_opcodes
+    pub fn is_illegal(self: &OpcodeClass) -> bool {
+        matches!(*self,
+            OpcodeClass::ILL_AHX  | OpcodeClass::ILL_ALR  | OpcodeClass::ILL_ANC |
+            OpcodeClass::ILL_ARR  | OpcodeClass::ILL_AXS  | OpcodeClass::ILL_DCP |
+            OpcodeClass::ILL_ISC  | OpcodeClass::ILL_KIL  | OpcodeClass::ILL_LAS |
+            OpcodeClass::ILL_LAX1 | OpcodeClass::ILL_LAX2 | OpcodeClass::ILL_NOP |
+            OpcodeClass::ILL_RLA  | OpcodeClass::ILL_RRA  | OpcodeClass::ILL_SAX |
+            OpcodeClass::ILL_SBC  | OpcodeClass::ILL_SHX  | OpcodeClass::ILL_SHY |
+            OpcodeClass::ILL_SLO  | OpcodeClass::ILL_SRE  | OpcodeClass::ILL_TAS |
+            OpcodeClass::ILL_XAA)
+    }
+    /// Jumps use their operand as the new pc, they never access memory there.
+    pub fn is_jump(self: &OpcodeClass) -> bool {
+        matches!(*self, OpcodeClass::JMP | OpcodeClass::JSR)
+    }
+    /// Branches, their operand is relative to the next instruction.  All but the 65C02's BRA
+    /// are conditional.
+    pub fn is_branch(self: &OpcodeClass) -> bool {
+        matches!(*self,
+            OpcodeClass::BCC | OpcodeClass::BCS | OpcodeClass::BEQ | OpcodeClass::BMI |
+            OpcodeClass::BNE | OpcodeClass::BPL | OpcodeClass::BVC | OpcodeClass::BVS |
+            OpcodeClass::BRA)
+    }
+    /// Execution never carries on with the next instruction after these.  BRK does come back
+    /// through RTI, but whether it skips a byte is up to the handler.
+    pub fn ends_flow(self: &OpcodeClass) -> bool {
+        matches!(*self,
+            OpcodeClass::JMP | OpcodeClass::RTS | OpcodeClass::RTI |
+            OpcodeClass::BRK | OpcodeClass::BRA | OpcodeClass::ILL_KIL)
+    }
+    /// Stores write their target without reading it first.
+    pub fn is_store(self: &OpcodeClass) -> bool {
+        matches!(*self,
+            OpcodeClass::STA | OpcodeClass::STX | OpcodeClass::STY | OpcodeClass::STZ |
+            OpcodeClass::ILL_SAX | OpcodeClass::ILL_AHX | OpcodeClass::ILL_SHX |
+            OpcodeClass::ILL_SHY | OpcodeClass::ILL_TAS)
+    }
+    /// Read-modify-write instructions read their target, write it back unchanged, then write
+    /// the result.  In accumulator mode they don't touch memory at all.
+    pub fn is_read_modify_write(self: &OpcodeClass) -> bool {
+        matches!(*self,
+            OpcodeClass::ASL | OpcodeClass::LSR | OpcodeClass::ROL | OpcodeClass::ROR |
+            OpcodeClass::INC | OpcodeClass::DEC | OpcodeClass::TRB | OpcodeClass::TSB |
+            OpcodeClass::ILL_SLO | OpcodeClass::ILL_SRE | OpcodeClass::ILL_RLA |
+            OpcodeClass::ILL_RRA | OpcodeClass::ILL_DCP | OpcodeClass::ILL_ISC)
+    }
+}
+
 #[derive(Debug)]
 pub enum ParseError {
     InvalidString(String)
@@ -55,6 +110,9 @@ impl FromStr for OpcodeClass {
             "STX" => Ok(OpcodeClass::STX), "STY" => Ok(OpcodeClass::STY), "TAX" => Ok(OpcodeClass::TAX), "TAY" => Ok(OpcodeClass::TAY),
             "TSX" => Ok(OpcodeClass::TSX), "TXA" => Ok(OpcodeClass::TXA), "TXS" => Ok(OpcodeClass::TXS), "TYA" => Ok(OpcodeClass::TYA),
 
+            "BRA" => Ok(OpcodeClass::BRA), "PHX" => Ok(OpcodeClass::PHX), "PHY" => Ok(OpcodeClass::PHY), "PLX" => Ok(OpcodeClass::PLX),
+            "PLY" => Ok(OpcodeClass::PLY), "STZ" => Ok(OpcodeClass::STZ), "TRB" => Ok(OpcodeClass::TRB), "TSB" => Ok(OpcodeClass::TSB),
+
             "ILL_AHX"  => Ok(OpcodeClass::ILL_AHX ), "ILL_ALR"  => Ok(OpcodeClass::ILL_ALR ), "ILL_ANC" => Ok(OpcodeClass::ILL_ANC),
             "ILL_ARR"  => Ok(OpcodeClass::ILL_ARR ), "ILL_AXS"  => Ok(OpcodeClass::ILL_AXS ), "ILL_DCP" => Ok(OpcodeClass::ILL_DCP),
             "ILL_ISC"  => Ok(OpcodeClass::ILL_ISC ), "ILL_KIL"  => Ok(OpcodeClass::ILL_KIL ), "ILL_LAS" => Ok(OpcodeClass::ILL_LAS),
@@ -68,7 +126,3 @@ impl FromStr for OpcodeClass {
         }
     }
 }
-
-impl Default for OpcodeClass {
-    fn default() -> OpcodeClass { OpcodeClass::None }
-}
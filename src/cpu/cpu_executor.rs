@@ -1,244 +1,454 @@
+use std::error;
+use std::fmt;
+use std::io;
 use cpu::common_defs::OpcodeExecInfo;
 use cpu::CpuState;
 use cpu::DecodeRegister;
+use cpu::Variant;
 use cpu::common_defs::address_mode::AddressMode;
 use cpu::common_defs::opcode_class::OpcodeClass;
-use memory::Memory;
+use cpu::opcode;
+use cpu::alu::{asl,lsr,rol,ror,inc,dec,adc,adc_decimal,sbc_decimal,UNSTABLE_MAGIC};
+use memory::{Memory,MemoryError};
 
-macro_rules! set_zs {
-    ($cpu_state:expr,$val:expr) => (set_z!($cpu_state,$val);set_s!($cpu_state,$val););
-}
-macro_rules! set_z {
-    ($cpu_state:expr,$val:expr) => ($cpu_state.Z = $val == 0;);
-}
-macro_rules! set_s {
-    ($cpu_state:expr,$val:expr) => ($cpu_state.S = $val >= 0x80;);
-}
+// the stack is page $01, the stack pointer wraps around within it.  16 bit values go on high
+// byte first so they come off low byte first, little endian in memory unless they straddle
+// the wrap.
+// http://wiki.nesdev.com/w/index.php/Stack
 macro_rules! stack_push8 {
-    ($cpu_state:expr,$mem:expr,$val:expr) => (try!($mem.write8(0x0100 as u16 | $cpu_state.sp as u16,$val)); $cpu_state.sp-=1;);
+    ($cpu_state:expr,$mem:expr,$val:expr) => (
+        $mem.write8(0x0100 | $cpu_state.sp as u16,$val)?;
+        $cpu_state.sp = $cpu_state.sp.wrapping_sub(1);
+    );
 }
 macro_rules! stack_push16 {
-    ($cpu_state:expr,$mem:expr,$val:expr) => (try!($mem.write16(0x0100 as u16 | $cpu_state.sp as u16 -1,$val)); $cpu_state.sp-=2;);
+    ($cpu_state:expr,$mem:expr,$val:expr) => (
+        let val: u16 = $val;
+        stack_push8!($cpu_state,$mem,(val >> 8) as u8);
+        stack_push8!($cpu_state,$mem,val as u8);
+    );
 }
 macro_rules! stack_pull8 {
     ($cpu_state:expr,$mem:expr) => {
         {
-            $cpu_state.sp+=1;
-            $mem.read8(0x0100 as u16 | $cpu_state.sp as u16)
+            $cpu_state.sp = $cpu_state.sp.wrapping_add(1);
+            $mem.read8(0x0100 | $cpu_state.sp as u16)
         }
     }
 }
 macro_rules! stack_pull16 {
     ($cpu_state:expr,$mem:expr) => {
         {
-            $cpu_state.sp+=2;
-            $mem.read16(0x0100 as u16 | $cpu_state.sp as u16 -1)
+            let lo = stack_pull8!($cpu_state,$mem);
+            let hi = stack_pull8!($cpu_state,$mem);
+            lo.and_then(|lo| hi.map(|hi| ((hi as u16) << 8) | lo as u16))
+        }
+    }
+}
+macro_rules! branch {
+    ($cpu_state:expr,$cond:expr) => {
+        if $cond {
+            // taken branches cost a cycle, and another if the target is on a different page
+            // than the next instruction
+            let target = addr_final($cpu_state)?;
+            let next = $cpu_state.pc.wrapping_add(1);
+            $cpu_state.cycles += 1 + ((target & 0xFF00) != (next & 0xFF00)) as u64;
+            $cpu_state.pc = target;
         }
+        else { $cpu_state.pc = $cpu_state.pc.wrapping_add($cpu_state.decode_register.info.len as u16-1); }
     }
 }
-macro_rules! compare {
-    ($cpu_state:expr,$a:expr,$b:expr) => {
+// read-modify-write instructions work on the accumulator or on memory depending on the mode,
+// evaluates to the result
+macro_rules! modify {
+    ($cpu_state:expr,$mem:expr,$op:ident) => {
         {
-            set_zs!($cpu_state, ($a as i16 - $b as i16) as u8);
-            $cpu_state.C = $a >= $b;
+            if $cpu_state.decode_register.info.address_mode == AddressMode::Accumulator {
+                let a = $cpu_state.a;
+                $cpu_state.a = $op($cpu_state,a);
+                $cpu_state.a
+            }
+            else {
+                let val = value_final($cpu_state)?;
+                let result = $op($cpu_state,val);
+                $mem.write8(addr_final($cpu_state)?,result)?;
+                result
+            }
         }
     }
 }
+// the 6502 doesn't carry into the high byte when incrementing a pointer's address, so a pointer
+// at $xxFF takes its high byte from $xx00.  zero page pointers wrap within the zero page the
+// same way.
+// http://wiki.nesdev.com/w/index.php/Errata
+fn read_pointer(mem: &mut Memory, addr: u16) -> Result<u16,MemoryError> {
+    let lo = mem.read8(addr)? as u16;
+    let hi = mem.read8((addr & 0xFF00) | (addr.wrapping_add(1) & 0x00FF))? as u16;
+    Ok((hi << 8) | lo)
+}
 
+// SHX, SHY, AHX and TAS store the value ANDed with the high byte of the base address plus one.
+// when indexing crosses a page the value also replaces the high byte of the address.
+// http://wiki.nesdev.com/w/index.php/Programming_with_unofficial_opcodes
+fn unstable_store(cpu_state: &CpuState, mem: &mut Memory, val: u8) -> Result<(),ExecutionError> {
+    let dr = &cpu_state.decode_register;
+    let base = dr.addr_intermediate.ok_or_else(|| missing_operand(cpu_state))?;
+    let val = val & ((base >> 8) as u8).wrapping_add(1);
+    let mut addr = addr_final(cpu_state)?;
+    if dr.page_crossed {
+        addr = ((val as u16) << 8) | (addr & 0x00FF);
+    }
+    Ok(mem.write8(addr,val)?)
+}
 
+// indexed addressing, noting whether the index carried into the high byte
+fn index(dr: &mut DecodeRegister, base: u16, index: u8) -> u16 {
+    let addr = base.wrapping_add(index as u16);
+    dr.addr_intermediate = Some(base);
+    dr.page_crossed = (base & 0xFF00) != (addr & 0xFF00);
+    addr
+}
+
+// the address and value decode found for the current instruction.  execute runs after the pc
+// has moved past the opcode, so the instruction is at pc-1
+fn addr_final(cpu_state: &CpuState) -> Result<u16,ExecutionError> {
+    cpu_state.decode_register.addr_final.ok_or_else(|| missing_operand(cpu_state))
+}
+fn value_final(cpu_state: &CpuState) -> Result<u8,ExecutionError> {
+    cpu_state.decode_register.value_final.ok_or_else(|| missing_operand(cpu_state))
+}
+fn missing_operand(cpu_state: &CpuState) -> ExecutionError {
+    ExecutionError::MissingOperand { pc: cpu_state.pc.wrapping_sub(1), opcode: cpu_state.instruction_register }
+}
 
 #[derive(Debug)]
 pub enum ExecutionError {
-  MemoryError(::memory::MemoryError),
+  // an access outside of an instruction, e.g. pushing the return address for an interrupt
+  MemoryError(MemoryError),
+  // an access by the instruction at pc
+  Bus { pc: u16, opcode: u8, addr: u16, err: MemoryError },
   UnexpectedOpcode(String),
-  UnexpectedAddressMode(String)
+  UnexpectedAddressMode(String),
+  // decode didn't find the address or value the instruction needs, the opcode table is wrong
+  MissingOperand { pc: u16, opcode: u8 },
+  // a trace sink couldn't record the instruction
+  Trace(io::Error),
+}
+
+impl ExecutionError {
+    // attribute a memory error to the instruction that caused it
+    fn at(self: ExecutionError, pc: u16, opcode: u8) -> ExecutionError {
+        match self {
+            ExecutionError::MemoryError(err) => ExecutionError::Bus { pc, opcode, addr: err.addr(), err },
+            err => err,
+        }
+    }
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(self: &ExecutionError, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecutionError::MemoryError(ref err)               => write!(f,"{}",err),
+            ExecutionError::Bus { pc, opcode, addr, ref err }  => write!(f,"${:0>4X}: opcode ${:0>2X} couldn't access ${:0>4X}, {}",pc,opcode,addr,err),
+            ExecutionError::UnexpectedOpcode(ref message)      => write!(f,"{}",message),
+            ExecutionError::UnexpectedAddressMode(ref message) => write!(f,"{}",message),
+            ExecutionError::MissingOperand { pc, opcode }      => write!(f,"${:0>4X}: opcode ${:0>2X} was decoded without an operand",pc,opcode),
+            ExecutionError::Trace(ref err)                     => write!(f,"couldn't write the trace: {}",err),
+        }
+    }
 }
-impl From<::memory::MemoryError> for ExecutionError {
-    fn from(err: ::memory::MemoryError) -> ExecutionError {
+impl error::Error for ExecutionError {
+    fn source(self: &ExecutionError) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ExecutionError::MemoryError(ref err) | ExecutionError::Bus { ref err, .. } => Some(err),
+            ExecutionError::Trace(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+impl From<MemoryError> for ExecutionError {
+    fn from(err: MemoryError) -> ExecutionError {
         ExecutionError::MemoryError(err)
     }
 }
+impl From<io::Error> for ExecutionError {
+    fn from(err: io::Error) -> ExecutionError {
+        ExecutionError::Trace(err)
+    }
+}
+
+/// Runs the cpu an instruction at a time, whatever it does in between.
+pub trait Executor {
+    fn step(&self, cpu_state: &mut CpuState, mem: &mut Memory) -> Result<(),ExecutionError>;
+}
+
+impl Executor for CpuExecutor {
+    fn step(self: &CpuExecutor, cpu_state: &mut CpuState, mem: &mut Memory) -> Result<(),ExecutionError> {
+        CpuExecutor::step(self,cpu_state,mem)
+    }
+}
 
 pub struct CpuExecutor {
     op_table: Vec<OpcodeExecInfo>,
+    variant: Variant,
+}
+
+impl Default for CpuExecutor {
+	/// Construct a CpuExecutor using the builtin opcode table.
+    fn default() -> CpuExecutor {
+        CpuExecutor::new(opcode::builtin().0)
+    }
 }
 
 impl CpuExecutor {
-	/// Construct a new CpuExecutor.
+	/// Construct a new 2A03 CpuExecutor.
     pub fn new(opcodes: Vec<OpcodeExecInfo> ) -> CpuExecutor {
         CpuExecutor {
             op_table:  opcodes,
+            variant: Variant::Ricoh2A03,
+        }
+    }
+	/// Construct a CpuExecutor for another chip, using its builtin opcode table.
+    pub fn with_variant(variant: Variant) -> CpuExecutor {
+        CpuExecutor {
+            op_table:  opcode::builtin_variant(variant).0,
+            variant,
         }
     }
 
-	/// Reset the given cpu_state.
-    pub fn power_on(self: &CpuExecutor, cpu_state: &mut CpuState, mem:&mut Memory) {
-        self.reset(cpu_state,mem);
+    pub fn variant(self: &CpuExecutor) -> Variant {
+        self.variant
     }
-	/// Reset the given cpu_state.
-    pub fn reset(self: &CpuExecutor, cpu_state: &mut CpuState, mem:&mut Memory) {
-        cpu_state.pc = mem.read16(0xFFFC).unwrap();
+
+    /// The opcode table entry the executor decodes opcode with.
+    pub fn exec_info(self: &CpuExecutor, opcode: u8) -> &OpcodeExecInfo {
+        &self.op_table[opcode as usize]
+    }
+
+	/// Put the given cpu_state into its power on state.
+    pub fn power_on(self: &CpuExecutor, cpu_state: &mut CpuState, mem:&mut Memory) -> Result<(),ExecutionError> {
+        cpu_state.pc = mem.read16(0xFFFC)?;
         cpu_state.sp = 0xFD;
         cpu_state.pack_flags(0x24);
+        cpu_state.cycles = 7;
+        Ok(())
+    }
+	/// Reset the given cpu_state.
+    // http://wiki.nesdev.com/w/index.php/CPU_power_up_state
+    //
+    pub fn reset(self: &CpuExecutor, cpu_state: &mut CpuState, mem:&mut Memory) -> Result<(),ExecutionError> {
+        cpu_state.pc = mem.read16(0xFFFC)?;
+        cpu_state.sp = cpu_state.sp.wrapping_sub(3);
+        cpu_state.I = true;
+        // the 65C02 clears D on reset like it does on interrupts, the NMOS parts leave it alone
+        if self.variant.is_cmos() { cpu_state.D = false; }
+        cpu_state.cycles += 7;
+        Ok(())
+    }
+
+    /// Service a non-maskable interrupt.
+    pub fn nmi(self: &CpuExecutor, cpu_state: &mut CpuState, mem:&mut Memory) -> Result<(),ExecutionError> {
+        self.interrupt(cpu_state,mem,0xFFFA)
+    }
+    /// Service an interrupt request, the caller is responsible for checking the I flag.
+    pub fn irq(self: &CpuExecutor, cpu_state: &mut CpuState, mem:&mut Memory) -> Result<(),ExecutionError> {
+        self.interrupt(cpu_state,mem,0xFFFE)
+    }
+
+    // http://wiki.nesdev.com/w/index.php/CPU_interrupts
+    //
+    fn interrupt(self: &CpuExecutor, cpu_state: &mut CpuState, mem:&mut Memory, vector: u16) -> Result<(),ExecutionError> {
+        stack_push16!(cpu_state,mem,cpu_state.pc);
+        // http://wiki.nesdev.com/w/index.php/Status_flags
+        stack_push8!(cpu_state,mem,(cpu_state.unpack_flags() & 0xEF) | 0x20);
+        cpu_state.I = true;
+        // the 65C02 leaves decimal mode for the handler
+        if self.variant.is_cmos() { cpu_state.D = false; }
+        cpu_state.pc = mem.read16(vector)?;
+        cpu_state.cycles += 7;
+        Ok(())
     }
     
     /// Execute a single instruction in the given memory and cpu_state context.
     pub fn step(self: &CpuExecutor, cpu_state: &mut CpuState,mem:&mut Memory) -> Result<(),ExecutionError> {
-        try!(self.fetch_and_decode(cpu_state,mem));
-        try!(self.execute(cpu_state,mem));
+        self.fetch_and_decode(cpu_state,mem)?;
+        self.execute(cpu_state,mem)?;
         Ok(())
     }
 
 	/// Fetch the next instruction and perform address resolution.
     pub fn fetch_and_decode(self: &CpuExecutor, cpu_state: &mut CpuState,mem:&mut Memory) -> Result<(),ExecutionError> {
-        cpu_state.instruction_register = mem.read8(cpu_state.pc).unwrap();
-        cpu_state.decode_register = try!(self.decode(cpu_state,mem));
-        cpu_state.pc = cpu_state.pc+1;
+        let pc = cpu_state.pc;
+        cpu_state.instruction_register = mem.read8(pc).map_err(|err| ExecutionError::from(err).at(pc,0))?;
+        cpu_state.decode_register = self.decode(cpu_state,mem).map_err(|err| err.at(pc,cpu_state.instruction_register))?;
+        cpu_state.pc = cpu_state.pc.wrapping_add(1);
         Ok(())
     }
     
     /// Perform address resolution, returning the info in a DecodeRegister.
-    fn decode(self: &CpuExecutor, cpu_state: &CpuState, mem: &Memory) -> Result<DecodeRegister,ExecutionError> {
+    fn decode(self: &CpuExecutor, cpu_state: &CpuState, mem: &mut Memory) -> Result<DecodeRegister,ExecutionError> {
         let mut dr = DecodeRegister {
             info : self.op_table[cpu_state.instruction_register as usize].clone(),
             ..Default::default()
         };
-        match dr.info.address_mode {
+        // reading a memory mapped register can have side effects (clearing vblank, shifting the
+        // controllers), so instructions that only write or jump don't read their target
+        let reads_target = !dr.info.opcode_class.is_store() && !dr.info.opcode_class.is_jump();
+        let operand = cpu_state.pc.wrapping_add(1);
+        let target = match dr.info.address_mode {
             // no explicit addresses for the following modes
-            AddressMode::Accumulator  => { },
-            AddressMode::Implied      => { },
+            AddressMode::Accumulator  => None,
+            AddressMode::Implied      => None,
 
             // explicit addresses from here on out
-            AddressMode::Absolute => {
-                dr.addr_final  = Some(mem.read16(cpu_state.pc+1).unwrap());
-                dr.value_final = Some(mem.read8(dr.addr_final.unwrap()).unwrap());
-            },
+            AddressMode::Absolute        => Some(mem.read16(operand)?),
             AddressMode::AbsoluteX       => {
-                dr.addr_final  = Some( ((mem.read16(cpu_state.pc+1).unwrap() as u32 + cpu_state.x as u32) % 65536) as u16);
-                dr.value_final = Some(mem.read8(dr.addr_final.unwrap()).unwrap());
+                let base = mem.read16(operand)?;
+                Some(index(&mut dr,base,cpu_state.x))
             },
             AddressMode::AbsoluteY       => {
-                dr.addr_final  = Some( ((mem.read16(cpu_state.pc+1).unwrap() as u32 + cpu_state.y as u32) % 65536) as u16);
-                dr.value_final = Some(mem.read8(dr.addr_final.unwrap()).unwrap());
+                let base = mem.read16(operand)?;
+                Some(index(&mut dr,base,cpu_state.y))
             },
             AddressMode::Immediate       => {
-                dr.addr_final  = Some(cpu_state.pc+1);
-                dr.value_final = Some(mem.read8(dr.addr_final.unwrap()).unwrap());
+                dr.addr_final  = Some(operand);
+                dr.value_final = Some(mem.read8(operand)?);
+                None
             },
             AddressMode::Indirect        => {
-                dr.addr_intermediate = Some(mem.read16(cpu_state.pc+1).unwrap());
-                dr.addr_final        = Some(mem.read16(dr.addr_intermediate.unwrap()).unwrap());
+                let pointer = mem.read16(operand)?;
+                dr.addr_intermediate = Some(pointer);
+                // the 65C02 fixed the page wrapping bug
+                dr.addr_final = Some(if self.variant.is_cmos() { mem.read16(pointer)? } else { read_pointer(mem,pointer)? });
+                None
+            },
+            AddressMode::AbsoluteIndexedIndirect => {
+                let base = mem.read16(operand)?;
+                let pointer = base.wrapping_add(cpu_state.x as u16);
+                dr.addr_init         = Some(base);
+                dr.addr_intermediate = Some(pointer);
+                dr.addr_final        = Some(mem.read16(pointer)?);
+                None
             },
             AddressMode::IndexedIndirect => {
-                dr.addr_intermediate = Some( (mem.read8(cpu_state.pc+1).unwrap() + cpu_state.x) as u16);
-                dr.addr_final        = Some(mem.read16(dr.addr_intermediate.unwrap()).unwrap());
-                dr.value_final       = Some(mem.read8(dr.addr_final.unwrap()).unwrap());
+                let pointer = mem.read8(operand)?.wrapping_add(cpu_state.x) as u16;
+                dr.addr_intermediate = Some(pointer);
+                Some(read_pointer(mem,pointer)?)
+            },
+            AddressMode::ZeroPageIndirect => {
+                let pointer = mem.read8(operand)? as u16;
+                dr.addr_intermediate = Some(pointer);
+                Some(read_pointer(mem,pointer)?)
             },
             AddressMode::IndirectIndexed => {
-                dr.addr_init         = Some(mem.read8(cpu_state.pc+1).unwrap() as u16);
-                dr.addr_intermediate = Some(mem.read16(dr.addr_init.unwrap()).unwrap());
-                dr.addr_final        = Some( ((dr.addr_intermediate.unwrap() as u32 + cpu_state.y as u32) % 65536) as u16);
-                dr.value_final       = Some(mem.read8(dr.addr_final.unwrap()).unwrap());
+                let pointer = mem.read8(operand)? as u16;
+                let base = read_pointer(mem,pointer)?;
+                dr.addr_init = Some(pointer);
+                Some(index(&mut dr,base,cpu_state.y))
             },
             AddressMode::Relative        => {
-                dr.value_final = Some(mem.read8(cpu_state.pc+1).unwrap());
+                let offset = mem.read8(operand)?;
+                dr.value_final = Some(offset);
 
                 // NOTE: relative is from the end of the current instruction and relative
-                // instructions are 2 bytes long, so we add 2 before adding in the specified offset
+                // instructions are 2 bytes long, so we add 2 before adding in the specified offset.
+                // the offset is signed and the result wraps around the address space
                 //
-                if dr.value_final.unwrap() < 0x80 { dr.addr_final  = Some(cpu_state.pc + 2 + dr.value_final.unwrap() as u16); }
-                else                              { dr.addr_final  = Some(cpu_state.pc + 2 + dr.value_final.unwrap() as u16 - 0x100);}
-            },
-            AddressMode::ZeroPage        => {
-                dr.addr_final  = Some(mem.read8(cpu_state.pc+1).unwrap() as u16);
-                dr.value_final = Some(mem.read8(dr.addr_final.unwrap()).unwrap());
-            },
-            AddressMode::ZeroPageX       => {
-                dr.addr_final  = Some( (mem.read8(cpu_state.pc+1).unwrap() as u16 + cpu_state.x as u16) % 256);
-                dr.value_final = Some(mem.read8(dr.addr_final.unwrap()).unwrap());
-            },
-            AddressMode::ZeroPageY       => {
-                dr.addr_final  = Some( (mem.read8(cpu_state.pc+1).unwrap() as u16 + cpu_state.y as u16) % 256);
-                dr.value_final = Some(mem.read8(dr.addr_final.unwrap()).unwrap());
+                dr.addr_final = Some(cpu_state.pc.wrapping_add(2).wrapping_add(offset as i8 as u16));
+                None
             },
+            AddressMode::ZeroPage        => Some(mem.read8(operand)? as u16),
+            AddressMode::ZeroPageX       => Some(mem.read8(operand)?.wrapping_add(cpu_state.x) as u16),
+            AddressMode::ZeroPageY       => Some(mem.read8(operand)?.wrapping_add(cpu_state.y) as u16),
             _ => { return Err(ExecutionError::UnexpectedAddressMode(format!("unrecognized addressing mode '{:?}' while decoding instruction_register!",dr.info.address_mode))); }
-        }
+        };
 
-        return Ok(dr);
+        // the modes that end up at an address in memory, which most instructions read
+        if let Some(addr) = target {
+            dr.addr_final = Some(addr);
+            if reads_target { dr.value_final = Some(mem.read8(addr)?); }
+        }
+        Ok(dr)
     }
-    
+
     /// Perform the current instruction, returning the CpuState after execution.
     pub fn execute(self: &CpuExecutor, cpu_state: &mut CpuState, mem:&mut Memory) -> Result<(),ExecutionError> {
+        let (pc,opcode) = (cpu_state.pc.wrapping_sub(1),cpu_state.instruction_register);
+        self.execute_decoded(cpu_state,mem).map_err(|err| err.at(pc,opcode))
+    }
+
+    fn execute_decoded(self: &CpuExecutor, cpu_state: &mut CpuState, mem:&mut Memory) -> Result<(),ExecutionError> {
+        // taken branches add their own cycles, see branch!
+        cpu_state.cycles += cpu_state.decode_register.info.cycles as u64;
+        if cpu_state.decode_register.page_crossed {
+            cpu_state.cycles += cpu_state.decode_register.info.page_cycles as u64;
+        }
+
     	// Figure out which opcode is being executed.
     	match cpu_state.decode_register.info.opcode_class {
     		OpcodeClass::ADC => {
-                let a = cpu_state.a;
-                let b = cpu_state.decode_register.value_final.unwrap();
-                let sum:u16 = a as u16 + b as u16 + cpu_state.C as u16;
-                cpu_state.a = sum as u8;
-                set_zs!(cpu_state,cpu_state.a);
+                let b = value_final(cpu_state)?;
+                self.add(cpu_state,b);
 
-                cpu_state.C = sum > 0xFF;
-                cpu_state.V = ((a^b)&0x80) == 0 && ((a^cpu_state.a)&0x80) != 0;
-
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::AND => {
-				cpu_state.a = cpu_state.a & cpu_state.decode_register.value_final.unwrap();
+				cpu_state.a &= value_final(cpu_state)?;
                 set_zs!(cpu_state,cpu_state.a);
 				
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::ASL => {
-				cpu_state.a = cpu_state.a << 1;
-				cpu_state.C = (128 & cpu_state.a) > 0;
-    			if cpu_state.a == 0 { cpu_state.Z = true; }
-				
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                modify!(cpu_state,mem,asl);
+
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::BCC => {
-                if !cpu_state.C { cpu_state.pc = cpu_state.decode_register.addr_final.unwrap(); }
-                else            { cpu_state.pc += cpu_state.decode_register.info.len as u16-1;  }
+                branch!(cpu_state,!cpu_state.C);
     		},
     		OpcodeClass::BCS => {
-                if cpu_state.C { cpu_state.pc = cpu_state.decode_register.addr_final.unwrap(); }
-                else           { cpu_state.pc += cpu_state.decode_register.info.len as u16-1;  }
+                branch!(cpu_state,cpu_state.C);
     		},
     		OpcodeClass::BEQ => {
-                if cpu_state.Z { cpu_state.pc = cpu_state.decode_register.addr_final.unwrap(); }
-                else           { cpu_state.pc += cpu_state.decode_register.info.len as u16-1;  }
+                branch!(cpu_state,cpu_state.Z);
     		},
     		OpcodeClass::BIT => {
-                cpu_state.V = (cpu_state.decode_register.value_final.unwrap() >> 6) & 1 > 0;
-                set_z!(cpu_state,cpu_state.decode_register.value_final.unwrap() & cpu_state.a);
-                set_s!(cpu_state,cpu_state.decode_register.value_final.unwrap());
+                // the 65C02's BIT # only sets Z, there's no memory to test bits 6 and 7 of
+                if cpu_state.decode_register.info.address_mode != AddressMode::Immediate {
+                    cpu_state.V = (value_final(cpu_state)? >> 6) & 1 > 0;
+                    set_s!(cpu_state,value_final(cpu_state)?);
+                }
+                set_z!(cpu_state,value_final(cpu_state)? & cpu_state.a);
 
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::BMI => {
-                if cpu_state.S { cpu_state.pc = cpu_state.decode_register.addr_final.unwrap(); }
-                else           { cpu_state.pc += cpu_state.decode_register.info.len as u16-1;  }
+                branch!(cpu_state,cpu_state.S);
     		},
     		OpcodeClass::BNE => {
-                if !cpu_state.Z { cpu_state.pc = cpu_state.decode_register.addr_final.unwrap(); }
-                else            { cpu_state.pc += cpu_state.decode_register.info.len as u16-1;  }
+                branch!(cpu_state,!cpu_state.Z);
     		},
     		OpcodeClass::BPL => {
-                if !cpu_state.S { cpu_state.pc = cpu_state.decode_register.addr_final.unwrap(); }
-                else            { cpu_state.pc += cpu_state.decode_register.info.len as u16-1;  }
+                branch!(cpu_state,!cpu_state.S);
+    		},
+    		OpcodeClass::BRK => {
+                // the byte after BRK is skipped, the return address is BRK+2
+                stack_push16!(cpu_state,mem,cpu_state.pc.wrapping_add(1));
+                stack_push8!(cpu_state,mem,cpu_state.unpack_flags() | 0x30);
+                cpu_state.I = true;
+                if self.variant.is_cmos() { cpu_state.D = false; }
+                cpu_state.pc = mem.read16(0xFFFE)?;
     		},
     		OpcodeClass::BVC => {
-                if !cpu_state.V { cpu_state.pc = cpu_state.decode_register.addr_final.unwrap(); }
-                else            { cpu_state.pc += cpu_state.decode_register.info.len as u16-1;  }
+                branch!(cpu_state,!cpu_state.V);
     		},
     		OpcodeClass::BVS => {
-                if cpu_state.V { cpu_state.pc = cpu_state.decode_register.addr_final.unwrap(); }
-                else           { cpu_state.pc += cpu_state.decode_register.info.len as u16-1;  }
+                branch!(cpu_state,cpu_state.V);
     		},
     		OpcodeClass::CMP => {
-                compare!(cpu_state,cpu_state.a,cpu_state.decode_register.value_final.unwrap());
+                compare!(cpu_state,cpu_state.a,value_final(cpu_state)?);
 
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::CLC => {
                 cpu_state.C = false;
@@ -246,18 +456,26 @@ impl CpuExecutor {
     		OpcodeClass::CLD => {
                 cpu_state.D = false;
     		},
+    		OpcodeClass::CLI => {
+                cpu_state.I = false;
+    		},
     		OpcodeClass::CLV => {
                 cpu_state.V = false;
     		},
     		OpcodeClass::CPX => {
-                compare!(cpu_state,cpu_state.x,cpu_state.decode_register.value_final.unwrap());
+                compare!(cpu_state,cpu_state.x,value_final(cpu_state)?);
 
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::CPY => {
-                compare!(cpu_state,cpu_state.y,cpu_state.decode_register.value_final.unwrap());
+                compare!(cpu_state,cpu_state.y,value_final(cpu_state)?);
+
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::DEC => {
+                modify!(cpu_state,mem,dec);
 
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::DEX => {
                 cpu_state.x = (cpu_state.x as i16 - 1) as u8;
@@ -268,10 +486,15 @@ impl CpuExecutor {
                 set_zs!(cpu_state,cpu_state.y);
     		},
     		OpcodeClass::EOR => {
-				cpu_state.a = cpu_state.a ^ cpu_state.decode_register.value_final.unwrap();
+				cpu_state.a ^= value_final(cpu_state)?;
                 set_zs!(cpu_state,cpu_state.a);
 				
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::INC => {
+                modify!(cpu_state,mem,inc);
+
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::INX => {
                 cpu_state.x = (cpu_state.x as u16 + 1) as u8;
@@ -282,44 +505,43 @@ impl CpuExecutor {
                 set_zs!(cpu_state,cpu_state.y);
     		},
     		OpcodeClass::LDA => {
-    			cpu_state.a = cpu_state.decode_register.value_final.unwrap();
+    			cpu_state.a = value_final(cpu_state)?;
                 set_zs!(cpu_state,cpu_state.a);
 
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::LDX => {
-    			cpu_state.x = cpu_state.decode_register.value_final.unwrap();
+    			cpu_state.x = value_final(cpu_state)?;
                 set_zs!(cpu_state,cpu_state.x);
 
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::LDY => {
-    			cpu_state.y = cpu_state.decode_register.value_final.unwrap();
+    			cpu_state.y = value_final(cpu_state)?;
                 set_zs!(cpu_state,cpu_state.y);
 
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::LSR => {
-    			cpu_state.a = cpu_state.a >> 1;
-    			cpu_state.C = 1 & cpu_state.a > 0;
-    			if cpu_state.a == 0 { cpu_state.Z = true; }
-    			
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                modify!(cpu_state,mem,lsr);
+
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::JMP => {
-    			cpu_state.pc = cpu_state.decode_register.addr_final.unwrap();
+    			cpu_state.pc = addr_final(cpu_state)?;
     		},
     		OpcodeClass::JSR => {
-                stack_push16!(cpu_state,mem,cpu_state.pc+1);
-    			cpu_state.pc = cpu_state.decode_register.addr_final.unwrap();
+                stack_push16!(cpu_state,mem,cpu_state.pc.wrapping_add(1));
+    			cpu_state.pc = addr_final(cpu_state)?;
     		},
     		OpcodeClass::NOP => {
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
             },
     		OpcodeClass::ORA => {
-                cpu_state.a = cpu_state.a | cpu_state.decode_register.value_final.unwrap();
+                cpu_state.a |= value_final(cpu_state)?;
                 set_zs!(cpu_state,cpu_state.a);
 
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
             },
     		OpcodeClass::PHA => {
                 stack_push8!(cpu_state,mem,cpu_state.a);
@@ -329,33 +551,38 @@ impl CpuExecutor {
                 stack_push8!(cpu_state,mem,cpu_state.unpack_flags() | 0x30);
             },
     		OpcodeClass::PLA => {
-                cpu_state.a = stack_pull8!(cpu_state,mem).unwrap();
+                cpu_state.a = stack_pull8!(cpu_state,mem)?;
                 set_zs!(cpu_state,cpu_state.a);
             },
             //http://wiki.nesdev.com/w/index.php/Status_flags
     		OpcodeClass::PLP => {
-                let val = (stack_pull8!(cpu_state,mem).unwrap()&0xEF) | 0x20;
+                let val = (stack_pull8!(cpu_state,mem)?&0xEF) | 0x20;
                 cpu_state.pack_flags(val);
             },
+    		OpcodeClass::ROL => {
+                modify!(cpu_state,mem,rol);
+
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ROR => {
+                modify!(cpu_state,mem,ror);
+
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+            // http://wiki.nesdev.com/w/index.php/Status_flags
     		OpcodeClass::RTI => {
-                let p = stack_pull8!(cpu_state,mem).unwrap();
+                let p = (stack_pull8!(cpu_state,mem)?&0xEF) | 0x20;
                 cpu_state.pack_flags(p);
-                cpu_state.pc = stack_pull16!(cpu_state,mem).unwrap();
+                cpu_state.pc = stack_pull16!(cpu_state,mem)?;
             },
     		OpcodeClass::RTS => {
-                cpu_state.pc = stack_pull16!(cpu_state,mem).unwrap() + 1;
+                cpu_state.pc = stack_pull16!(cpu_state,mem)?.wrapping_add(1);
             },
     		OpcodeClass::SBC => {
-                let a = cpu_state.a;
-                let b = cpu_state.decode_register.value_final.unwrap();
-                let diff:i16 = a as i16 - b as i16 - !cpu_state.C as i16;
-                cpu_state.a = diff as u8;
-                set_zs!(cpu_state,cpu_state.a);
-
-                cpu_state.C = diff >= 0x00;
-                cpu_state.V = ((a^b)&0x80) != 0 && ((a^cpu_state.a)&0x80) != 0;
+                let b = value_final(cpu_state)?;
+                self.subtract(cpu_state,b);
 
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::SEC => {
                 cpu_state.C = true;
@@ -367,12 +594,16 @@ impl CpuExecutor {
                 cpu_state.I = true;
     		},
     		OpcodeClass::STA => {
-                try!(mem.write8(cpu_state.decode_register.addr_final.unwrap(),cpu_state.a));
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                mem.write8(addr_final(cpu_state)?,cpu_state.a)?;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::STX => {
-                try!(mem.write8(cpu_state.decode_register.addr_final.unwrap(),cpu_state.x));
-                cpu_state.pc += cpu_state.decode_register.info.len as u16-1;
+                mem.write8(addr_final(cpu_state)?,cpu_state.x)?;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::STY => {
+                mem.write8(addr_final(cpu_state)?,cpu_state.y)?;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
     		},
     		OpcodeClass::TAX => {
                 cpu_state.x = cpu_state.a;
@@ -397,11 +628,187 @@ impl CpuExecutor {
                 cpu_state.a = cpu_state.y;
                 set_zs!(cpu_state,cpu_state.a);
     		},
-    		
+
+            // http://6502.org/tutorials/65c02opcodes.html
+    		OpcodeClass::BRA => {
+                branch!(cpu_state,true);
+    		},
+    		OpcodeClass::PHX => {
+                stack_push8!(cpu_state,mem,cpu_state.x);
+            },
+    		OpcodeClass::PHY => {
+                stack_push8!(cpu_state,mem,cpu_state.y);
+            },
+    		OpcodeClass::PLX => {
+                cpu_state.x = stack_pull8!(cpu_state,mem)?;
+                set_zs!(cpu_state,cpu_state.x);
+            },
+    		OpcodeClass::PLY => {
+                cpu_state.y = stack_pull8!(cpu_state,mem)?;
+                set_zs!(cpu_state,cpu_state.y);
+            },
+    		OpcodeClass::STZ => {
+                mem.write8(addr_final(cpu_state)?,0)?;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+            // Z is set as BIT would, then the accumulator's bits are cleared or set in memory
+    		OpcodeClass::TRB => {
+                let val = value_final(cpu_state)?;
+                set_z!(cpu_state,val & cpu_state.a);
+                mem.write8(addr_final(cpu_state)?,val & !cpu_state.a)?;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::TSB => {
+                let val = value_final(cpu_state)?;
+                set_z!(cpu_state,val & cpu_state.a);
+                mem.write8(addr_final(cpu_state)?,val | cpu_state.a)?;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+
+            // http://wiki.nesdev.com/w/index.php/CPU_unofficial_opcodes
+    		OpcodeClass::ILL_AHX => {
+                let val = cpu_state.a & cpu_state.x;
+                unstable_store(cpu_state,mem,val)?;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_ALR => {
+                let a = cpu_state.a & value_final(cpu_state)?;
+                cpu_state.a = lsr(cpu_state,a);
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_ANC => {
+                cpu_state.a &= value_final(cpu_state)?;
+                set_zs!(cpu_state,cpu_state.a);
+                cpu_state.C = cpu_state.S;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_ARR => {
+                let a = cpu_state.a & value_final(cpu_state)?;
+                cpu_state.a = (a >> 1) | ((cpu_state.C as u8) << 7);
+                set_zs!(cpu_state,cpu_state.a);
+                cpu_state.C = cpu_state.a & 0x40 != 0;
+                cpu_state.V = ((cpu_state.a >> 6) ^ (cpu_state.a >> 5)) & 1 != 0;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_AXS => {
+                let ax = cpu_state.a & cpu_state.x;
+                let b = value_final(cpu_state)?;
+                compare!(cpu_state,ax,b);
+                cpu_state.x = ax.wrapping_sub(b);
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_DCP => {
+                let val = modify!(cpu_state,mem,dec);
+                compare!(cpu_state,cpu_state.a,val);
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_ISC => {
+                let val = modify!(cpu_state,mem,inc);
+                self.subtract(cpu_state,val);
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_LAS => {
+                let val = value_final(cpu_state)? & cpu_state.sp;
+                cpu_state.a = val;
+                cpu_state.x = val;
+                cpu_state.sp = val;
+                set_zs!(cpu_state,val);
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_LAX1 => {
+                let val = value_final(cpu_state)?;
+                cpu_state.a = val;
+                cpu_state.x = val;
+                set_zs!(cpu_state,val);
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_LAX2 => {
+                let val = (cpu_state.a | UNSTABLE_MAGIC) & value_final(cpu_state)?;
+                cpu_state.a = val;
+                cpu_state.x = val;
+                set_zs!(cpu_state,val);
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_NOP => {
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_RLA => {
+                let val = modify!(cpu_state,mem,rol);
+                cpu_state.a &= val;
+                set_zs!(cpu_state,cpu_state.a);
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_RRA => {
+                let val = modify!(cpu_state,mem,ror);
+                self.add(cpu_state,val);
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_SAX => {
+                mem.write8(addr_final(cpu_state)?,cpu_state.a & cpu_state.x)?;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_SBC => {
+                let b = value_final(cpu_state)?;
+                self.subtract(cpu_state,b);
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_SHX => {
+                let val = cpu_state.x;
+                unstable_store(cpu_state,mem,val)?;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_SHY => {
+                let val = cpu_state.y;
+                unstable_store(cpu_state,mem,val)?;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_SLO => {
+                let val = modify!(cpu_state,mem,asl);
+                cpu_state.a |= val;
+                set_zs!(cpu_state,cpu_state.a);
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_SRE => {
+                let val = modify!(cpu_state,mem,lsr);
+                cpu_state.a ^= val;
+                set_zs!(cpu_state,cpu_state.a);
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_TAS => {
+                cpu_state.sp = cpu_state.a & cpu_state.x;
+                let val = cpu_state.sp;
+                unstable_store(cpu_state,mem,val)?;
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+    		OpcodeClass::ILL_XAA => {
+                cpu_state.a = (cpu_state.a | UNSTABLE_MAGIC) & cpu_state.x & value_final(cpu_state)?;
+                set_zs!(cpu_state,cpu_state.a);
+                cpu_state.pc = cpu_state.pc.wrapping_add(cpu_state.decode_register.info.len as u16-1);
+    		},
+
 			_ => { return Err(ExecutionError::UnexpectedOpcode(format!("Unrecognised opcode class: {:?}", cpu_state.decode_register.info.opcode_class)));}
 
     	}
         Ok(())
     }
-}
 
+    // ADC, and the illegal opcodes built on it
+    fn add(self: &CpuExecutor, cpu_state: &mut CpuState, b: u8) {
+        if !(cpu_state.D && self.variant.has_decimal_mode()) {
+            adc(cpu_state,b);
+            return;
+        }
+        adc_decimal(cpu_state,b,self.variant);
+        // the 65C02 takes a cycle to fix up the flags
+        if self.variant.is_cmos() { cpu_state.cycles += 1; }
+    }
+    // SBC is ADC of the complement, the carry acts as an inverted borrow
+    fn subtract(self: &CpuExecutor, cpu_state: &mut CpuState, b: u8) {
+        if !(cpu_state.D && self.variant.has_decimal_mode()) {
+            adc(cpu_state,!b);
+            return;
+        }
+        sbc_decimal(cpu_state,b,self.variant);
+        if self.variant.is_cmos() { cpu_state.cycles += 1; }
+    }
+}
@@ -1,5 +1,9 @@
 use cpu::common_defs::OpcodeExecInfo;
 use std::option::Option;
+use std::io;
+use std::io::{Read,Write};
+use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
+use savestate::{self,Snapshot};
 
 #[derive(Clone, Default)]
 pub struct DecodeRegister {
@@ -11,9 +15,35 @@ pub struct DecodeRegister {
     pub value_intermediate: Option<u8>,
     pub value_final: Option<u8>,
 
+    // indexing carried the effective address into the next page, costs an extra cycle on reads
+    pub page_crossed: bool,
+
     pub info: OpcodeExecInfo,
 }
 
+// where the cycle accurate executor is within the current instruction, see CycleExecutor
+#[derive(Clone, Default)]
+pub struct MicroOpRegister {
+    // cycles of the current instruction done so far, 0 when the next cycle fetches an opcode
+    pub step: u8,
+    // the address being put together, then the effective address
+    pub addr: u16,
+    // the address before indexing, or the zero page pointer
+    pub base: u16,
+    // the operand, or the value being modified
+    pub value: u8,
+    pub page_crossed: bool,
+
+    // running the interrupt sequence instead of an instruction
+    pub interrupt: bool,
+    // take an interrupt once the current instruction finishes
+    pub interrupt_polled: bool,
+    // an nmi edge hasn't been serviced yet
+    pub nmi_pending: bool,
+    // the level of the irq line
+    pub irq_line: bool,
+}
+
 #[allow(non_snake_case)]
 #[derive(Clone, Default)]
 pub struct CpuState {
@@ -36,12 +66,16 @@ pub struct CpuState {
     // these are not strictly 6502 registers, but are useful for modeling the cpu
     pub instruction_register: u8,
     pub decode_register:DecodeRegister,
+    pub micro_op_register:MicroOpRegister,
+
+    // total cpu cycles executed since power on
+    pub cycles: u64,
 }
 
 // svubdizc
 impl CpuState {
     pub fn unpack_flags(self: &CpuState) -> u8 {
-        ( (self.C as u8) << 0)
+        (self.C as u8)
       | ( (self.Z as u8) << 1)
       | ( (self.I as u8) << 2)
       | ( (self.D as u8) << 3)
@@ -51,7 +85,7 @@ impl CpuState {
       | ( (self.S as u8) << 7)
     }
     pub fn pack_flags(self: &mut CpuState,flags:u8) {
-        self.C        = ( (flags >> 0) & 1) == 1;
+        self.C        = ( flags & 1) == 1;
         self.Z        = ( (flags >> 1) & 1) == 1; 
         self.I        = ( (flags >> 2) & 1) == 1; 
         self.D        = ( (flags >> 3) & 1) == 1; 
@@ -62,5 +96,65 @@ impl CpuState {
     }
 }
 
+// the decode register's info isn't saved, it's whatever the executor's opcode table has for the
+// instruction register and is looked up again by whoever loads the state
+impl Snapshot for CpuState {
+    fn save_state(self: &CpuState, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_u16::<LittleEndian>(self.pc)?;
+        writer.write_all(&[self.sp,self.a,self.x,self.y,self.unpack_flags(),self.instruction_register])?;
+        writer.write_u64::<LittleEndian>(self.cycles)?;
 
+        let dr = &self.decode_register;
+        savestate::write_option16(writer,dr.addr_init)?;
+        savestate::write_option16(writer,dr.addr_intermediate)?;
+        savestate::write_option16(writer,dr.addr_final)?;
+        savestate::write_option8(writer,dr.value_init)?;
+        savestate::write_option8(writer,dr.value_intermediate)?;
+        savestate::write_option8(writer,dr.value_final)?;
+        savestate::write_bool(writer,dr.page_crossed)?;
 
+        let mr = &self.micro_op_register;
+        writer.write_u8(mr.step)?;
+        writer.write_u16::<LittleEndian>(mr.addr)?;
+        writer.write_u16::<LittleEndian>(mr.base)?;
+        writer.write_u8(mr.value)?;
+        for flag in [mr.page_crossed,mr.interrupt,mr.interrupt_polled,mr.nmi_pending,mr.irq_line].iter() {
+            savestate::write_bool(writer,*flag)?;
+        }
+        Ok(())
+    }
+
+    fn load_state(self: &mut CpuState, reader: &mut dyn Read) -> io::Result<()> {
+        self.pc = reader.read_u16::<LittleEndian>()?;
+        let mut regs = [0u8;6];
+        reader.read_exact(&mut regs)?;
+        self.sp = regs[0];
+        self.a = regs[1];
+        self.x = regs[2];
+        self.y = regs[3];
+        self.pack_flags(regs[4]);
+        self.instruction_register = regs[5];
+        self.cycles = reader.read_u64::<LittleEndian>()?;
+
+        let dr = &mut self.decode_register;
+        dr.addr_init = savestate::read_option16(reader)?;
+        dr.addr_intermediate = savestate::read_option16(reader)?;
+        dr.addr_final = savestate::read_option16(reader)?;
+        dr.value_init = savestate::read_option8(reader)?;
+        dr.value_intermediate = savestate::read_option8(reader)?;
+        dr.value_final = savestate::read_option8(reader)?;
+        dr.page_crossed = savestate::read_bool(reader)?;
+
+        let mr = &mut self.micro_op_register;
+        mr.step = reader.read_u8()?;
+        mr.addr = reader.read_u16::<LittleEndian>()?;
+        mr.base = reader.read_u16::<LittleEndian>()?;
+        mr.value = reader.read_u8()?;
+        mr.page_crossed = savestate::read_bool(reader)?;
+        mr.interrupt = savestate::read_bool(reader)?;
+        mr.interrupt_polled = savestate::read_bool(reader)?;
+        mr.nmi_pending = savestate::read_bool(reader)?;
+        mr.irq_line = savestate::read_bool(reader)?;
+        Ok(())
+    }
+}
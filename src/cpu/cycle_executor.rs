@@ -0,0 +1,726 @@
+use cpu::common_defs::OpcodeExecInfo;
+use cpu::{CpuState,DecodeRegister,MicroOpRegister};
+use cpu::{ExecutionError,Executor};
+use cpu::common_defs::address_mode::AddressMode;
+use cpu::common_defs::opcode_class::OpcodeClass;
+use cpu::opcode;
+use cpu::alu::{asl,lsr,rol,ror,inc,dec,adc,UNSTABLE_MAGIC};
+use memory::Memory;
+
+// http://wiki.nesdev.com/w/index.php/CPU_memory_map
+const NMI_VECTOR: u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR: u16 = 0xFFFE;
+
+// how a cycle left the instruction
+enum Cycle {
+    Next,
+    // not done, but this is the cycle that decides whether an interrupt follows
+    Polled,
+    Last,
+    // done, keeping the decision from an earlier cycle
+    LastUnpolled,
+}
+
+// what an instruction does with its effective address
+#[derive(PartialEq)]
+enum Access {
+    Read,
+    Write,
+    Modify,
+}
+
+/// Runs the cpu one cycle at a time, making every bus access the 6502 makes in the order it
+/// makes them: the dummy reads of indexed addressing, the dummy writes of read-modify-write
+/// instructions, and the reads of the stack and the next opcode that go nowhere.  Interrupts are
+/// polled on the last cycle of each instruction, so an NMI or IRQ arriving mid-instruction waits
+/// for the right one.
+///
+/// The timing follows http://nesdev.com/6502_cpu.txt, the state between cycles is kept in the
+/// CpuState's micro_op_register.
+pub struct CycleExecutor {
+    op_table: Vec<OpcodeExecInfo>,
+}
+
+impl Default for CycleExecutor {
+    /// Construct a CycleExecutor using the builtin opcode table.
+    fn default() -> CycleExecutor {
+        CycleExecutor::new(opcode::builtin().0)
+    }
+}
+
+impl Executor for CycleExecutor {
+    fn step(self: &CycleExecutor, cpu_state: &mut CpuState, mem: &mut Memory) -> Result<(),ExecutionError> {
+        CycleExecutor::step(self,cpu_state,mem)
+    }
+}
+
+impl CycleExecutor {
+    /// Construct a new CycleExecutor.  Only the opcode classes and address modes are used, the
+    /// cycle counts in the table are ignored.
+    pub fn new(opcodes: Vec<OpcodeExecInfo>) -> CycleExecutor {
+        CycleExecutor {
+            op_table: opcodes,
+        }
+    }
+
+    /// The opcode table entry the executor decodes opcode with.
+    pub fn exec_info(self: &CycleExecutor, opcode: u8) -> &OpcodeExecInfo {
+        &self.op_table[opcode as usize]
+    }
+
+    /// Put the given cpu_state into its power on state.
+    pub fn power_on(self: &CycleExecutor, cpu_state: &mut CpuState, mem: &mut Memory) -> Result<(),ExecutionError> {
+        cpu_state.pc = mem.read16(RESET_VECTOR)?;
+        cpu_state.sp = 0xFD;
+        cpu_state.pack_flags(0x24);
+        cpu_state.cycles = 7;
+        cpu_state.micro_op_register = Default::default();
+        Ok(())
+    }
+    /// Reset the given cpu_state, abandoning the instruction in progress.
+    // http://wiki.nesdev.com/w/index.php/CPU_power_up_state
+    //
+    pub fn reset(self: &CycleExecutor, cpu_state: &mut CpuState, mem: &mut Memory) -> Result<(),ExecutionError> {
+        cpu_state.pc = mem.read16(RESET_VECTOR)?;
+        cpu_state.sp = cpu_state.sp.wrapping_sub(3);
+        cpu_state.I = true;
+        cpu_state.cycles += 7;
+        cpu_state.micro_op_register = Default::default();
+        Ok(())
+    }
+
+    /// Signal a non-maskable interrupt, it's serviced after the instruction in progress.
+    pub fn nmi(self: &CycleExecutor, cpu_state: &mut CpuState) {
+        cpu_state.micro_op_register.nmi_pending = true;
+    }
+    /// Set the level of the irq line, it's serviced while it's held and the I flag is clear.
+    pub fn set_irq(self: &CycleExecutor, cpu_state: &mut CpuState, level: bool) {
+        cpu_state.micro_op_register.irq_line = level;
+    }
+
+    /// Whether the last tick fetched an instruction's opcode, as opposed to being in the middle
+    /// of one or starting an interrupt.
+    pub fn fetched_opcode(self: &CycleExecutor, cpu_state: &CpuState) -> bool {
+        cpu_state.micro_op_register.step == 1 && !cpu_state.micro_op_register.interrupt
+    }
+
+    /// Run the rest of the current instruction, or all of the next one (or the interrupt
+    /// sequence if one is due).
+    pub fn step(self: &CycleExecutor, cpu_state: &mut CpuState, mem: &mut Memory) -> Result<(),ExecutionError> {
+        self.tick(cpu_state,mem)?;
+        while cpu_state.micro_op_register.step != 0 {
+            self.tick(cpu_state,mem)?;
+        }
+        Ok(())
+    }
+
+    /// Run one cpu cycle, which makes exactly one read or write.
+    pub fn tick(self: &CycleExecutor, cpu_state: &mut CpuState, mem: &mut Memory) -> Result<(),ExecutionError> {
+        let mut m = cpu_state.micro_op_register.clone();
+        cpu_state.cycles += 1;
+
+        if m.step == 0 {
+            self.fetch(cpu_state,mem,&mut m)?;
+            m.step = 1;
+        }
+        else {
+            // the I flag is sampled before the cycle changes it, which is why CLI, SEI and PLP
+            // only take effect after the next instruction
+            let poll = m.nmi_pending || (m.irq_line && !cpu_state.I);
+            match self.cycle(cpu_state,mem,&mut m)? {
+                Cycle::Next         => { m.step += 1; },
+                Cycle::Polled       => { m.step += 1; m.interrupt_polled = poll; },
+                Cycle::Last         => { m.step = 0; m.interrupt_polled = poll; },
+                Cycle::LastUnpolled => { m.step = 0; },
+            }
+        }
+        cpu_state.micro_op_register = m;
+        Ok(())
+    }
+
+    /// What CpuExecutor's decode resolves for the instruction that was just fetched, worked out
+    /// without touching the bus, for the trace loggers.
+    pub fn peek_decode(self: &CycleExecutor, cpu_state: &CpuState, mem: &Memory) -> DecodeRegister {
+        let mut dr = DecodeRegister { info: cpu_state.decode_register.info.clone(), ..Default::default() };
+        let peek = |addr: u16| mem.peek8(addr).unwrap_or(0);
+        let pointer = |addr: u16| (peek((addr & 0xFF00) | (addr.wrapping_add(1) & 0x00FF)) as u16) << 8 | peek(addr) as u16;
+        let pc = cpu_state.pc;
+        let operand8 = peek(pc);
+        let operand16 = (peek(pc.wrapping_add(1)) as u16) << 8 | operand8 as u16;
+
+        let indexed = |dr: &mut DecodeRegister, base: u16, index: u8| {
+            dr.addr_intermediate = Some(base);
+            dr.addr_final = Some(base.wrapping_add(index as u16));
+            dr.page_crossed = (base & 0xFF00) != (base.wrapping_add(index as u16) & 0xFF00);
+        };
+        match dr.info.address_mode {
+            AddressMode::Absolute        => { dr.addr_final = Some(operand16); },
+            AddressMode::AbsoluteX       => indexed(&mut dr,operand16,cpu_state.x),
+            AddressMode::AbsoluteY       => indexed(&mut dr,operand16,cpu_state.y),
+            AddressMode::Immediate       => { dr.addr_final = Some(pc); },
+            AddressMode::Indirect        => { dr.addr_intermediate = Some(operand16); dr.addr_final = Some(pointer(operand16)); },
+            AddressMode::IndexedIndirect => {
+                let zp = operand8.wrapping_add(cpu_state.x) as u16;
+                dr.addr_intermediate = Some(zp);
+                dr.addr_final = Some(pointer(zp));
+            },
+            AddressMode::IndirectIndexed => {
+                dr.addr_init = Some(operand8 as u16);
+                indexed(&mut dr,pointer(operand8 as u16),cpu_state.y);
+            },
+            AddressMode::Relative        => {
+                dr.value_final = Some(operand8);
+                dr.addr_final = Some(pc.wrapping_add(1).wrapping_add(operand8 as i8 as u16));
+            },
+            AddressMode::ZeroPage        => { dr.addr_final = Some(operand8 as u16); },
+            AddressMode::ZeroPageX       => { dr.addr_final = Some(operand8.wrapping_add(cpu_state.x) as u16); },
+            AddressMode::ZeroPageY       => { dr.addr_final = Some(operand8.wrapping_add(cpu_state.y) as u16); },
+            _ => { },
+        }
+        dr
+    }
+
+    // the first cycle of every instruction.  an interrupt reads the opcode but doesn't
+    // increment the pc, then runs BRK's sequence in its place.
+    // http://wiki.nesdev.com/w/index.php/CPU_interrupts
+    //
+    fn fetch(self: &CycleExecutor, cpu_state: &mut CpuState, mem: &mut Memory, m: &mut MicroOpRegister) -> Result<(),ExecutionError> {
+        let opcode = mem.read8(cpu_state.pc)?;
+        m.interrupt = m.interrupt_polled;
+        m.interrupt_polled = false;
+        m.page_crossed = false;
+
+        let opcode = if m.interrupt { 0x00 } else { cpu_state.pc = cpu_state.pc.wrapping_add(1); opcode };
+        cpu_state.instruction_register = opcode;
+        cpu_state.decode_register = DecodeRegister { info: self.op_table[opcode as usize].clone(), ..Default::default() };
+        Ok(())
+    }
+
+    fn cycle(self: &CycleExecutor, cpu_state: &mut CpuState, mem: &mut Memory, m: &mut MicroOpRegister) -> Result<Cycle,ExecutionError> {
+        let class = cpu_state.decode_register.info.opcode_class.clone();
+        let mode = cpu_state.decode_register.info.address_mode.clone();
+
+        match class {
+            OpcodeClass::BRK => return interrupt_cycle(cpu_state,mem,m),
+            OpcodeClass::JSR => return jsr_cycle(cpu_state,mem,m),
+            OpcodeClass::RTS => return rts_cycle(cpu_state,mem,m),
+            OpcodeClass::RTI => return rti_cycle(cpu_state,mem,m),
+            OpcodeClass::JMP => return jmp_cycle(cpu_state,mem,m,&mode),
+            OpcodeClass::PHA | OpcodeClass::PHP => return push_cycle(cpu_state,mem,m,&class),
+            OpcodeClass::PLA | OpcodeClass::PLP => return pull_cycle(cpu_state,mem,m,&class),
+            OpcodeClass::ILL_KIL => return Err(ExecutionError::UnexpectedOpcode(format!("Unrecognised opcode class: {:?}",class))),
+            _ if class.is_branch() => return branch_cycle(cpu_state,mem,m,&class),
+            _ => { },
+        }
+
+        let access = if class.is_store() { Access::Write } else if class.is_read_modify_write() { Access::Modify } else { Access::Read };
+        let step = m.step;
+        let pc = cpu_state.pc;
+
+        // put the effective address together, then hand over to the access for the mode's
+        // remaining cycles
+        match (mode,step) {
+            (AddressMode::Implied,1) | (AddressMode::Accumulator,1) => {
+                mem.read8(pc)?;
+                implied(cpu_state,&class)?;
+                Ok(Cycle::Last)
+            },
+            (AddressMode::Immediate,1) => {
+                let value = fetch_operand(cpu_state,mem)?;
+                read(cpu_state,&class,value)?;
+                Ok(Cycle::Last)
+            },
+
+            (AddressMode::ZeroPage,1) => {
+                m.addr = fetch_operand(cpu_state,mem)? as u16;
+                Ok(Cycle::Next)
+            },
+            (AddressMode::ZeroPage,_) => access_cycle(cpu_state,mem,m,&class,&access,step - 2),
+
+            (AddressMode::ZeroPageX,1) | (AddressMode::ZeroPageY,1) => {
+                m.addr = fetch_operand(cpu_state,mem)? as u16;
+                Ok(Cycle::Next)
+            },
+            (AddressMode::ZeroPageX,2) | (AddressMode::ZeroPageY,2) => {
+                // reads the unindexed address while adding the index, which stays in the zero page
+                mem.read8(m.addr)?;
+                let index = if cpu_state.decode_register.info.address_mode == AddressMode::ZeroPageX { cpu_state.x } else { cpu_state.y };
+                m.addr = (m.addr as u8).wrapping_add(index) as u16;
+                Ok(Cycle::Next)
+            },
+            (AddressMode::ZeroPageX,_) | (AddressMode::ZeroPageY,_) => access_cycle(cpu_state,mem,m,&class,&access,step - 3),
+
+            (AddressMode::Absolute,1) | (AddressMode::AbsoluteX,1) | (AddressMode::AbsoluteY,1) => {
+                m.addr = fetch_operand(cpu_state,mem)? as u16;
+                Ok(Cycle::Next)
+            },
+            (AddressMode::Absolute,2) => {
+                m.addr |= (fetch_operand(cpu_state,mem)? as u16) << 8;
+                Ok(Cycle::Next)
+            },
+            (AddressMode::Absolute,_) => access_cycle(cpu_state,mem,m,&class,&access,step - 3),
+
+            (AddressMode::AbsoluteX,2) | (AddressMode::AbsoluteY,2) => {
+                m.base = m.addr | (fetch_operand(cpu_state,mem)? as u16) << 8;
+                let index = if cpu_state.decode_register.info.address_mode == AddressMode::AbsoluteX { cpu_state.x } else { cpu_state.y };
+                index_base(m,index);
+                Ok(Cycle::Next)
+            },
+            (AddressMode::AbsoluteX,_) | (AddressMode::AbsoluteY,_) => indexed_access_cycle(cpu_state,mem,m,&class,&access,step - 3),
+
+            (AddressMode::IndexedIndirect,1) => {
+                m.base = fetch_operand(cpu_state,mem)? as u16;
+                Ok(Cycle::Next)
+            },
+            (AddressMode::IndexedIndirect,2) => {
+                mem.read8(m.base)?;
+                m.base = (m.base as u8).wrapping_add(cpu_state.x) as u16;
+                Ok(Cycle::Next)
+            },
+            (AddressMode::IndexedIndirect,3) => {
+                m.addr = mem.read8(m.base)? as u16;
+                Ok(Cycle::Next)
+            },
+            (AddressMode::IndexedIndirect,4) => {
+                // the pointer's high byte wraps around in the zero page
+                m.addr |= (mem.read8((m.base as u8).wrapping_add(1) as u16)? as u16) << 8;
+                Ok(Cycle::Next)
+            },
+            (AddressMode::IndexedIndirect,_) => access_cycle(cpu_state,mem,m,&class,&access,step - 5),
+
+            (AddressMode::IndirectIndexed,1) => {
+                m.base = fetch_operand(cpu_state,mem)? as u16;
+                Ok(Cycle::Next)
+            },
+            (AddressMode::IndirectIndexed,2) => {
+                m.addr = mem.read8(m.base)? as u16;
+                Ok(Cycle::Next)
+            },
+            (AddressMode::IndirectIndexed,3) => {
+                m.base = m.addr | (mem.read8((m.base as u8).wrapping_add(1) as u16)? as u16) << 8;
+                index_base(m,cpu_state.y);
+                Ok(Cycle::Next)
+            },
+            (AddressMode::IndirectIndexed,_) => indexed_access_cycle(cpu_state,mem,m,&class,&access,step - 4),
+
+            (mode,_) => Err(ExecutionError::UnexpectedAddressMode(format!("unrecognized addressing mode '{:?}' for {:?}",mode,class))),
+        }
+    }
+}
+
+// read the byte at the pc and move past it
+fn fetch_operand(cpu_state: &mut CpuState, mem: &mut Memory) -> Result<u8,ExecutionError> {
+    let value = mem.read8(cpu_state.pc)?;
+    cpu_state.pc = cpu_state.pc.wrapping_add(1);
+    Ok(value)
+}
+
+fn push(cpu_state: &mut CpuState, mem: &mut Memory, value: u8) -> Result<(),ExecutionError> {
+    mem.write8(0x0100 | cpu_state.sp as u16,value)?;
+    cpu_state.sp = cpu_state.sp.wrapping_sub(1);
+    Ok(())
+}
+
+fn stack_read(cpu_state: &CpuState, mem: &mut Memory) -> Result<u8,ExecutionError> {
+    Ok(mem.read8(0x0100 | cpu_state.sp as u16)?)
+}
+
+// the cpu adds the index to the low byte first and fixes the high byte a cycle later if it
+// carried, m.addr is the final address
+fn index_base(m: &mut MicroOpRegister, index: u8) {
+    m.addr = m.base.wrapping_add(index as u16);
+    m.page_crossed = (m.base & 0xFF00) != (m.addr & 0xFF00);
+}
+
+// the cycles after a non-indexed mode has its address
+fn access_cycle(cpu_state: &mut CpuState, mem: &mut Memory, m: &mut MicroOpRegister, class: &OpcodeClass, access: &Access, t: u8) -> Result<Cycle,ExecutionError> {
+    match (access,t) {
+        (&Access::Read,_) => {
+            let value = mem.read8(m.addr)?;
+            read(cpu_state,class,value)?;
+            Ok(Cycle::Last)
+        },
+        (&Access::Write,_) => {
+            let (addr,value) = store(cpu_state,class,m);
+            mem.write8(addr,value)?;
+            Ok(Cycle::Last)
+        },
+        (&Access::Modify,_) => modify_cycle(cpu_state,mem,m,class,t),
+    }
+}
+
+// the cycles after an indexed mode has its base address.  the first read is from the address
+// before the high byte is fixed, reads are done if it didn't need fixing.
+fn indexed_access_cycle(cpu_state: &mut CpuState, mem: &mut Memory, m: &mut MicroOpRegister, class: &OpcodeClass, access: &Access, t: u8) -> Result<Cycle,ExecutionError> {
+    if t == 0 {
+        let unfixed = (m.base & 0xFF00) | (m.addr & 0x00FF);
+        let value = mem.read8(unfixed)?;
+        if *access == Access::Read && !m.page_crossed {
+            read(cpu_state,class,value)?;
+            return Ok(Cycle::Last);
+        }
+        return Ok(Cycle::Next);
+    }
+    access_cycle(cpu_state,mem,m,class,access,t - 1)
+}
+
+// read the value, write it back unchanged while working out the result, then write the result
+fn modify_cycle(cpu_state: &mut CpuState, mem: &mut Memory, m: &mut MicroOpRegister, class: &OpcodeClass, t: u8) -> Result<Cycle,ExecutionError> {
+    match t {
+        0 => {
+            m.value = mem.read8(m.addr)?;
+            Ok(Cycle::Next)
+        },
+        1 => {
+            mem.write8(m.addr,m.value)?;
+            m.value = modify(cpu_state,class,m.value)?;
+            Ok(Cycle::Next)
+        },
+        _ => {
+            mem.write8(m.addr,m.value)?;
+            Ok(Cycle::Last)
+        },
+    }
+}
+
+// BRK, and the interrupt sequence that borrows it.  an NMI that arrives before the vector is
+// fetched takes over the sequence.
+// http://wiki.nesdev.com/w/index.php/CPU_interrupts#Interrupt_hijacking
+//
+fn interrupt_cycle(cpu_state: &mut CpuState, mem: &mut Memory, m: &mut MicroOpRegister) -> Result<Cycle,ExecutionError> {
+    match m.step {
+        1 => {
+            // the byte after BRK is skipped, the return address is BRK+2
+            mem.read8(cpu_state.pc)?;
+            if !m.interrupt { cpu_state.pc = cpu_state.pc.wrapping_add(1); }
+        },
+        2 => { let pc = cpu_state.pc; push(cpu_state,mem,(pc >> 8) as u8)?; },
+        3 => { let pc = cpu_state.pc; push(cpu_state,mem,pc as u8)?; },
+        4 => {
+            // http://wiki.nesdev.com/w/index.php/Status_flags#The_B_flag
+            let b = if m.interrupt { 0x20 } else { 0x30 };
+            let p = (cpu_state.unpack_flags() & 0xCF) | b;
+            push(cpu_state,mem,p)?;
+            m.base = if m.nmi_pending { m.nmi_pending = false; NMI_VECTOR } else { IRQ_VECTOR };
+        },
+        5 => {
+            m.addr = mem.read8(m.base)? as u16;
+            cpu_state.I = true;
+        },
+        _ => {
+            cpu_state.pc = m.addr | (mem.read8(m.base + 1)? as u16) << 8;
+            // the first instruction of the handler always runs
+            return Ok(Cycle::LastUnpolled);
+        },
+    }
+    Ok(Cycle::Next)
+}
+
+fn jsr_cycle(cpu_state: &mut CpuState, mem: &mut Memory, m: &mut MicroOpRegister) -> Result<Cycle,ExecutionError> {
+    match m.step {
+        1 => { m.addr = fetch_operand(cpu_state,mem)? as u16; },
+        2 => { stack_read(cpu_state,mem)?; },
+        // the return address pushed is the last byte of the JSR
+        3 => { let pc = cpu_state.pc; push(cpu_state,mem,(pc >> 8) as u8)?; },
+        4 => { let pc = cpu_state.pc; push(cpu_state,mem,pc as u8)?; },
+        _ => {
+            cpu_state.pc = m.addr | (mem.read8(cpu_state.pc)? as u16) << 8;
+            return Ok(Cycle::Last);
+        },
+    }
+    Ok(Cycle::Next)
+}
+
+fn rts_cycle(cpu_state: &mut CpuState, mem: &mut Memory, m: &mut MicroOpRegister) -> Result<Cycle,ExecutionError> {
+    match m.step {
+        1 => { mem.read8(cpu_state.pc)?; },
+        2 => { stack_read(cpu_state,mem)?; cpu_state.sp = cpu_state.sp.wrapping_add(1); },
+        3 => { m.addr = stack_read(cpu_state,mem)? as u16; cpu_state.sp = cpu_state.sp.wrapping_add(1); },
+        4 => { cpu_state.pc = m.addr | (stack_read(cpu_state,mem)? as u16) << 8; },
+        _ => {
+            mem.read8(cpu_state.pc)?;
+            cpu_state.pc = cpu_state.pc.wrapping_add(1);
+            return Ok(Cycle::Last);
+        },
+    }
+    Ok(Cycle::Next)
+}
+
+fn rti_cycle(cpu_state: &mut CpuState, mem: &mut Memory, m: &mut MicroOpRegister) -> Result<Cycle,ExecutionError> {
+    match m.step {
+        1 => { mem.read8(cpu_state.pc)?; },
+        2 => { stack_read(cpu_state,mem)?; cpu_state.sp = cpu_state.sp.wrapping_add(1); },
+        3 => {
+            // http://wiki.nesdev.com/w/index.php/Status_flags
+            let p = stack_read(cpu_state,mem)?;
+            cpu_state.pack_flags((p & 0xEF) | 0x20);
+            cpu_state.sp = cpu_state.sp.wrapping_add(1);
+        },
+        4 => { m.addr = stack_read(cpu_state,mem)? as u16; cpu_state.sp = cpu_state.sp.wrapping_add(1); },
+        _ => {
+            cpu_state.pc = m.addr | (stack_read(cpu_state,mem)? as u16) << 8;
+            return Ok(Cycle::Last);
+        },
+    }
+    Ok(Cycle::Next)
+}
+
+fn jmp_cycle(cpu_state: &mut CpuState, mem: &mut Memory, m: &mut MicroOpRegister, mode: &AddressMode) -> Result<Cycle,ExecutionError> {
+    match (mode,m.step) {
+        (_,1) => { m.addr = fetch_operand(cpu_state,mem)? as u16; },
+        (&AddressMode::Absolute,_) => {
+            cpu_state.pc = m.addr | (mem.read8(cpu_state.pc)? as u16) << 8;
+            return Ok(Cycle::Last);
+        },
+        (_,2) => { m.base = m.addr | (fetch_operand(cpu_state,mem)? as u16) << 8; },
+        (_,3) => { m.addr = mem.read8(m.base)? as u16; },
+        _ => {
+            // the pointer's high byte doesn't carry into the next page
+            // http://wiki.nesdev.com/w/index.php/Errata
+            let hi = (m.base & 0xFF00) | (m.base.wrapping_add(1) & 0x00FF);
+            cpu_state.pc = m.addr | (mem.read8(hi)? as u16) << 8;
+            return Ok(Cycle::Last);
+        },
+    }
+    Ok(Cycle::Next)
+}
+
+fn push_cycle(cpu_state: &mut CpuState, mem: &mut Memory, m: &MicroOpRegister, class: &OpcodeClass) -> Result<Cycle,ExecutionError> {
+    if m.step == 1 {
+        mem.read8(cpu_state.pc)?;
+        return Ok(Cycle::Next);
+    }
+    // http://wiki.nesdev.com/w/index.php/Status_flags
+    let value = if *class == OpcodeClass::PHA { cpu_state.a } else { cpu_state.unpack_flags() | 0x30 };
+    push(cpu_state,mem,value)?;
+    Ok(Cycle::Last)
+}
+
+fn pull_cycle(cpu_state: &mut CpuState, mem: &mut Memory, m: &MicroOpRegister, class: &OpcodeClass) -> Result<Cycle,ExecutionError> {
+    match m.step {
+        1 => { mem.read8(cpu_state.pc)?; },
+        2 => { stack_read(cpu_state,mem)?; cpu_state.sp = cpu_state.sp.wrapping_add(1); },
+        _ => {
+            let value = stack_read(cpu_state,mem)?;
+            if *class == OpcodeClass::PLA {
+                cpu_state.a = value;
+                set_zs!(cpu_state,value);
+            }
+            else {
+                cpu_state.pack_flags((value & 0xEF) | 0x20);
+            }
+            return Ok(Cycle::Last);
+        },
+    }
+    Ok(Cycle::Next)
+}
+
+// a taken branch spends a cycle adding the offset to the low byte of the pc, and another fixing
+// the high byte if it carried.  interrupts are polled while fetching the offset, and again only
+// if the high byte needed fixing.
+// http://wiki.nesdev.com/w/index.php/CPU_interrupts#Branch_instructions_and_interrupts
+//
+fn branch_cycle(cpu_state: &mut CpuState, mem: &mut Memory, m: &mut MicroOpRegister, class: &OpcodeClass) -> Result<Cycle,ExecutionError> {
+    match m.step {
+        1 => {
+            m.value = fetch_operand(cpu_state,mem)?;
+            let taken = match *class {
+                OpcodeClass::BCC => !cpu_state.C,
+                OpcodeClass::BCS => cpu_state.C,
+                OpcodeClass::BEQ => cpu_state.Z,
+                OpcodeClass::BNE => !cpu_state.Z,
+                OpcodeClass::BMI => cpu_state.S,
+                OpcodeClass::BPL => !cpu_state.S,
+                OpcodeClass::BVC => !cpu_state.V,
+                _                => cpu_state.V,
+            };
+            Ok(if taken { Cycle::Polled } else { Cycle::Last })
+        },
+        2 => {
+            mem.read8(cpu_state.pc)?;
+            m.addr = cpu_state.pc.wrapping_add(m.value as i8 as u16);
+            cpu_state.pc = (cpu_state.pc & 0xFF00) | (m.addr & 0x00FF);
+            Ok(if cpu_state.pc == m.addr { Cycle::LastUnpolled } else { Cycle::Next })
+        },
+        _ => {
+            mem.read8(cpu_state.pc)?;
+            cpu_state.pc = m.addr;
+            Ok(Cycle::Last)
+        },
+    }
+}
+
+// instructions that only work on registers
+fn implied(cpu_state: &mut CpuState, class: &OpcodeClass) -> Result<(),ExecutionError> {
+    match *class {
+        OpcodeClass::CLC => { cpu_state.C = false; },
+        OpcodeClass::CLD => { cpu_state.D = false; },
+        OpcodeClass::CLI => { cpu_state.I = false; },
+        OpcodeClass::CLV => { cpu_state.V = false; },
+        OpcodeClass::SEC => { cpu_state.C = true; },
+        OpcodeClass::SED => { cpu_state.D = true; },
+        OpcodeClass::SEI => { cpu_state.I = true; },
+        OpcodeClass::DEX => { cpu_state.x = cpu_state.x.wrapping_sub(1); set_zs!(cpu_state,cpu_state.x); },
+        OpcodeClass::DEY => { cpu_state.y = cpu_state.y.wrapping_sub(1); set_zs!(cpu_state,cpu_state.y); },
+        OpcodeClass::INX => { cpu_state.x = cpu_state.x.wrapping_add(1); set_zs!(cpu_state,cpu_state.x); },
+        OpcodeClass::INY => { cpu_state.y = cpu_state.y.wrapping_add(1); set_zs!(cpu_state,cpu_state.y); },
+        OpcodeClass::TAX => { cpu_state.x = cpu_state.a; set_zs!(cpu_state,cpu_state.x); },
+        OpcodeClass::TAY => { cpu_state.y = cpu_state.a; set_zs!(cpu_state,cpu_state.y); },
+        OpcodeClass::TSX => { cpu_state.x = cpu_state.sp; set_zs!(cpu_state,cpu_state.x); },
+        OpcodeClass::TXA => { cpu_state.a = cpu_state.x; set_zs!(cpu_state,cpu_state.a); },
+        OpcodeClass::TYA => { cpu_state.a = cpu_state.y; set_zs!(cpu_state,cpu_state.a); },
+        OpcodeClass::TXS => { cpu_state.sp = cpu_state.x; },
+        OpcodeClass::NOP | OpcodeClass::ILL_NOP => { },
+        // accumulator mode
+        _ => {
+            let a = cpu_state.a;
+            cpu_state.a = modify(cpu_state,class,a)?;
+        },
+    }
+    Ok(())
+}
+
+// instructions that read their operand
+// http://wiki.nesdev.com/w/index.php/CPU_unofficial_opcodes
+//
+fn read(cpu_state: &mut CpuState, class: &OpcodeClass, value: u8) -> Result<(),ExecutionError> {
+    match *class {
+        OpcodeClass::ADC => adc(cpu_state,value),
+        OpcodeClass::SBC | OpcodeClass::ILL_SBC => adc(cpu_state,!value),
+        OpcodeClass::AND => { cpu_state.a &= value; set_zs!(cpu_state,cpu_state.a); },
+        OpcodeClass::EOR => { cpu_state.a ^= value; set_zs!(cpu_state,cpu_state.a); },
+        OpcodeClass::ORA => { cpu_state.a |= value; set_zs!(cpu_state,cpu_state.a); },
+        OpcodeClass::BIT => {
+            cpu_state.V = value & 0x40 != 0;
+            set_z!(cpu_state,value & cpu_state.a);
+            set_s!(cpu_state,value);
+        },
+        OpcodeClass::CMP => compare!(cpu_state,cpu_state.a,value),
+        OpcodeClass::CPX => compare!(cpu_state,cpu_state.x,value),
+        OpcodeClass::CPY => compare!(cpu_state,cpu_state.y,value),
+        OpcodeClass::LDA => { cpu_state.a = value; set_zs!(cpu_state,value); },
+        OpcodeClass::LDX => { cpu_state.x = value; set_zs!(cpu_state,value); },
+        OpcodeClass::LDY => { cpu_state.y = value; set_zs!(cpu_state,value); },
+        OpcodeClass::NOP | OpcodeClass::ILL_NOP => { },
+
+        OpcodeClass::ILL_ALR => {
+            let a = cpu_state.a & value;
+            cpu_state.a = lsr(cpu_state,a);
+        },
+        OpcodeClass::ILL_ANC => {
+            cpu_state.a &= value;
+            set_zs!(cpu_state,cpu_state.a);
+            cpu_state.C = cpu_state.S;
+        },
+        OpcodeClass::ILL_ARR => {
+            let a = cpu_state.a & value;
+            cpu_state.a = (a >> 1) | ((cpu_state.C as u8) << 7);
+            set_zs!(cpu_state,cpu_state.a);
+            cpu_state.C = cpu_state.a & 0x40 != 0;
+            cpu_state.V = ((cpu_state.a >> 6) ^ (cpu_state.a >> 5)) & 1 != 0;
+        },
+        OpcodeClass::ILL_AXS => {
+            let ax = cpu_state.a & cpu_state.x;
+            compare!(cpu_state,ax,value);
+            cpu_state.x = ax.wrapping_sub(value);
+        },
+        OpcodeClass::ILL_LAS => {
+            let val = value & cpu_state.sp;
+            cpu_state.a = val;
+            cpu_state.x = val;
+            cpu_state.sp = val;
+            set_zs!(cpu_state,val);
+        },
+        OpcodeClass::ILL_LAX1 => {
+            cpu_state.a = value;
+            cpu_state.x = value;
+            set_zs!(cpu_state,value);
+        },
+        OpcodeClass::ILL_LAX2 => {
+            let val = (cpu_state.a | UNSTABLE_MAGIC) & value;
+            cpu_state.a = val;
+            cpu_state.x = val;
+            set_zs!(cpu_state,val);
+        },
+        OpcodeClass::ILL_XAA => {
+            cpu_state.a = (cpu_state.a | UNSTABLE_MAGIC) & cpu_state.x & value;
+            set_zs!(cpu_state,cpu_state.a);
+        },
+        _ => return Err(ExecutionError::UnexpectedOpcode(format!("Unrecognised opcode class: {:?}",class))),
+    }
+    Ok(())
+}
+
+// read-modify-write instructions, evaluates to the value written back
+fn modify(cpu_state: &mut CpuState, class: &OpcodeClass, value: u8) -> Result<u8,ExecutionError> {
+    Ok(match *class {
+        OpcodeClass::ASL => asl(cpu_state,value),
+        OpcodeClass::LSR => lsr(cpu_state,value),
+        OpcodeClass::ROL => rol(cpu_state,value),
+        OpcodeClass::ROR => ror(cpu_state,value),
+        OpcodeClass::INC => inc(cpu_state,value),
+        OpcodeClass::DEC => dec(cpu_state,value),
+        OpcodeClass::ILL_SLO => {
+            let result = asl(cpu_state,value);
+            cpu_state.a |= result;
+            set_zs!(cpu_state,cpu_state.a);
+            result
+        },
+        OpcodeClass::ILL_SRE => {
+            let result = lsr(cpu_state,value);
+            cpu_state.a ^= result;
+            set_zs!(cpu_state,cpu_state.a);
+            result
+        },
+        OpcodeClass::ILL_RLA => {
+            let result = rol(cpu_state,value);
+            cpu_state.a &= result;
+            set_zs!(cpu_state,cpu_state.a);
+            result
+        },
+        OpcodeClass::ILL_RRA => {
+            let result = ror(cpu_state,value);
+            adc(cpu_state,result);
+            result
+        },
+        OpcodeClass::ILL_DCP => {
+            let result = dec(cpu_state,value);
+            compare!(cpu_state,cpu_state.a,result);
+            result
+        },
+        OpcodeClass::ILL_ISC => {
+            let result = inc(cpu_state,value);
+            adc(cpu_state,!result);
+            result
+        },
+        _ => return Err(ExecutionError::UnexpectedOpcode(format!("Unrecognised opcode class: {:?}",class))),
+    })
+}
+
+// where stores write and what.  SHX, SHY, AHX and TAS AND the value with the high byte of the
+// base address plus one, and when indexing crosses a page the value replaces the high byte of
+// the address too.
+// http://wiki.nesdev.com/w/index.php/Programming_with_unofficial_opcodes
+//
+fn store(cpu_state: &mut CpuState, class: &OpcodeClass, m: &MicroOpRegister) -> (u16,u8) {
+    let unstable = |val: u8| {
+        let val = val & ((m.base >> 8) as u8).wrapping_add(1);
+        let addr = if m.page_crossed { ((val as u16) << 8) | (m.addr & 0x00FF) } else { m.addr };
+        (addr,val)
+    };
+    match *class {
+        OpcodeClass::STA     => (m.addr,cpu_state.a),
+        OpcodeClass::STX     => (m.addr,cpu_state.x),
+        OpcodeClass::STY     => (m.addr,cpu_state.y),
+        OpcodeClass::ILL_SAX => (m.addr,cpu_state.a & cpu_state.x),
+        OpcodeClass::ILL_SHX => unstable(cpu_state.x),
+        OpcodeClass::ILL_SHY => unstable(cpu_state.y),
+        OpcodeClass::ILL_AHX => unstable(cpu_state.a & cpu_state.x),
+        _ => {
+            // TAS
+            cpu_state.sp = cpu_state.a & cpu_state.x;
+            unstable(cpu_state.sp)
+        },
+    }
+}
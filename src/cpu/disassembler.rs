@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use cpu::{AddressMode,OpcodeTables};
+
+// bytes per .byte line for anything that isn't code
+const DATA_BYTES_PER_LINE: usize = 8;
+
+/// One line of a disassembly, an instruction or, for bytes that aren't code, a .byte directive.
+#[derive(PartialEq,Clone,Debug)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    // .byte for data
+    pub mnemonic: String,
+    // None for data
+    pub address_mode: AddressMode,
+    pub illegal: bool,
+    // in ca65 syntax, empty for implied instructions
+    pub operand: String,
+    // where a branch or absolute jump goes
+    pub target: Option<u16>,
+}
+
+impl Instruction {
+    pub fn is_data(self: &Instruction) -> bool {
+        self.address_mode == AddressMode::None
+    }
+
+    /// The source, e.g. "JMP $C5F5".
+    pub fn text(self: &Instruction) -> String {
+        if self.operand.is_empty() { self.mnemonic.clone() } else { format!("{} {}",self.mnemonic,self.operand) }
+    }
+}
+
+// C000  4C F5 C5  JMP $C5F5
+impl fmt::Display for Instruction {
+    fn fmt(self: &Instruction, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:0>2X}",b)).collect();
+        write!(f,"{:0>4X}  {:<8}  {}",self.address,bytes.join(" "),self.text())
+    }
+}
+
+/// Disassemble bytes loaded at origin from start to end, see Disassembler::linear_sweep.
+pub fn disassemble(bytes: &[u8], origin: u16) -> Vec<Instruction> {
+    Disassembler::default().linear_sweep(bytes,origin)
+}
+
+/// The NMI, reset and IRQ vectors of a bank that ends at $FFFF, empty if it doesn't.
+//
+// http://wiki.nesdev.com/w/index.php/CPU_memory_map
+//
+pub fn vectors(bytes: &[u8], origin: u16) -> Vec<u16> {
+    if bytes.len() < 6 || origin as usize + bytes.len() != 0x10000 {
+        return Vec::new();
+    }
+    bytes[bytes.len()-6..].chunks(2).map(|v| v[1] as u16 * 256 + v[0] as u16).collect()
+}
+
+/// Turns machine code back into ca65 style source.  Addresses with a symbol are shown by name.
+#[derive(Default)]
+pub struct Disassembler {
+    opcodes: OpcodeTables,
+    symbols: BTreeMap<u16,String>,
+}
+
+impl Disassembler {
+    pub fn new(opcodes: OpcodeTables) -> Disassembler {
+        Disassembler { opcodes, symbols: BTreeMap::new() }
+    }
+
+    /// Show addr as name from now on.
+    pub fn add_symbol(self: &mut Disassembler, addr: u16, name: &str) {
+        self.symbols.insert(addr,name.to_string());
+    }
+
+    pub fn symbols(self: &Disassembler) -> &BTreeMap<u16,String> {
+        &self.symbols
+    }
+
+    /// Decode the instruction at bytes[offset], None when it runs off the end.
+    pub fn decode(self: &Disassembler, bytes: &[u8], origin: u16, offset: usize) -> Option<Instruction> {
+        let opcode = *bytes.get(offset)?;
+        let info = self.opcodes.exec(opcode);
+        let bytes = bytes.get(offset..offset + info.len as usize)?;
+        let address = origin.wrapping_add(offset as u16);
+
+        let operand8 = if bytes.len() > 1 { bytes[1] } else { 0 };
+        let operand16 = if bytes.len() > 2 { bytes[2] as u16 * 256 + operand8 as u16 } else { operand8 as u16 };
+        let target = match info.address_mode {
+            // relative to the next instruction
+            AddressMode::Relative => Some(address.wrapping_add(2).wrapping_add(operand8 as i8 as u16)),
+            AddressMode::Absolute if info.opcode_class.is_jump() => Some(operand16),
+            _ => None,
+        };
+
+        let operand = match info.address_mode {
+            AddressMode::Implied         => String::new(),
+            AddressMode::Accumulator     => "A".to_string(),
+            AddressMode::Immediate       => format!("#${:0>2X}",operand8),
+            AddressMode::ZeroPage        => self.zero_page(operand8),
+            AddressMode::ZeroPageX       => format!("{},X",self.zero_page(operand8)),
+            AddressMode::ZeroPageY       => format!("{},Y",self.zero_page(operand8)),
+            AddressMode::Absolute        => self.absolute(operand16),
+            AddressMode::AbsoluteX       => format!("{},X",self.absolute(operand16)),
+            AddressMode::AbsoluteY       => format!("{},Y",self.absolute(operand16)),
+            AddressMode::Indirect        => format!("({})",self.absolute(operand16)),
+            AddressMode::IndexedIndirect => format!("({},X)",self.zero_page(operand8)),
+            AddressMode::IndirectIndexed => format!("({}),Y",self.zero_page(operand8)),
+            AddressMode::Relative        => self.absolute(target.unwrap()),
+            AddressMode::ZeroPageIndirect        => format!("({})",self.zero_page(operand8)),
+            AddressMode::AbsoluteIndexedIndirect => format!("({},X)",self.absolute(operand16)),
+            AddressMode::None            => String::new(),
+        };
+
+        Some(Instruction {
+            address,
+            bytes: bytes.to_vec(),
+            mnemonic: self.opcodes.mnemonic(opcode).to_string(),
+            address_mode: info.address_mode.clone(),
+            illegal: info.opcode_class.is_illegal(),
+            operand,
+            target,
+        })
+    }
+
+    /// Decode every byte as code, one instruction after another.  Fine for code, but data in
+    /// between throws the instructions after it out of step.  A last instruction that runs off
+    /// the end is shown as data.
+    pub fn linear_sweep(self: &Disassembler, bytes: &[u8], origin: u16) -> Vec<Instruction> {
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            match self.decode(bytes,origin,offset) {
+                Some(instruction) => {
+                    offset += instruction.bytes.len();
+                    lines.push(instruction);
+                },
+                None => {
+                    lines.push(data(&bytes[offset..],origin.wrapping_add(offset as u16)));
+                    offset = bytes.len();
+                },
+            }
+        }
+        lines
+    }
+
+    /// Only decode what's reachable from the entry points (see vectors), following branches,
+    /// jumps and subroutine calls.  Everything else in the bank is shown as data.  Indirect jumps
+    /// can't be followed without running the code, so add their targets as entry points.
+    pub fn recursive_descent(self: &Disassembler, bytes: &[u8], origin: u16, entry_points: &[u16]) -> Vec<Instruction> {
+        let mut code = BTreeMap::new();
+        let mut claimed = vec![false;bytes.len()];
+        let mut work: Vec<u16> = entry_points.to_vec();
+
+        while let Some(mut addr) = work.pop() {
+            loop {
+                let offset = addr.wrapping_sub(origin) as usize;
+                if offset >= bytes.len() || claimed[offset] {
+                    break;
+                }
+                let instruction = match self.decode(bytes,origin,offset) {
+                    Some(instruction) => instruction,
+                    None => break,
+                };
+                let len = instruction.bytes.len();
+                // overlapping an instruction we already have, one of them is wrong
+                if claimed[offset..offset+len].iter().any(|&c| c) {
+                    break;
+                }
+                for c in claimed[offset..offset+len].iter_mut() {
+                    *c = true;
+                }
+
+                let class = &self.opcodes.exec(instruction.bytes[0]).opcode_class;
+                if let Some(target) = instruction.target {
+                    work.push(target);
+                }
+                let ends_flow = class.ends_flow();
+                code.insert(offset,instruction);
+                if ends_flow {
+                    break;
+                }
+                addr = addr.wrapping_add(len as u16);
+            }
+        }
+
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            if let Some(instruction) = code.remove(&offset) {
+                offset += instruction.bytes.len();
+                lines.push(instruction);
+                continue;
+            }
+            let mut end = offset;
+            while end < bytes.len() && end - offset < DATA_BYTES_PER_LINE && !claimed[end] {
+                end += 1;
+            }
+            lines.push(data(&bytes[offset..end],origin.wrapping_add(offset as u16)));
+            offset = end;
+        }
+        lines
+    }
+
+    fn zero_page(self: &Disassembler, addr: u8) -> String {
+        match self.symbols.get(&(addr as u16)) {
+            Some(name) => name.clone(),
+            None       => format!("${:0>2X}",addr),
+        }
+    }
+
+    // ca65 would assemble an address below $100 as zero page, a: keeps it absolute
+    fn absolute(self: &Disassembler, addr: u16) -> String {
+        let prefix = if addr < 0x100 { "a:" } else { "" };
+        match self.symbols.get(&addr) {
+            Some(name) => format!("{}{}",prefix,name),
+            None       => format!("{}${:0>4X}",prefix,addr),
+        }
+    }
+}
+
+fn data(bytes: &[u8], address: u16) -> Instruction {
+    let values: Vec<String> = bytes.iter().map(|b| format!("${:0>2X}",b)).collect();
+    Instruction {
+        address,
+        bytes: bytes.to_vec(),
+        mnemonic: ".byte".to_string(),
+        address_mode: AddressMode::None,
+        illegal: false,
+        operand: values.join(","),
+        target: None,
+    }
+}
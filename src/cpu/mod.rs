@@ -1,19 +1,34 @@
 //public mods
 pub mod opcode;
+pub mod disassembler;
+pub mod assembler;
+#[cfg(feature = "processor-tests")]
+pub mod processor_tests;
 
 //private mods
+#[macro_use]
+mod alu;
 mod common_defs;
 mod cpu_executor;
+mod cycle_executor;
 mod cpu_state;
+mod variant;
 
 // hoisted interfaces
 pub use self::common_defs::OpcodeDebugInfo;
 pub use self::common_defs::OpcodeExecInfo;
 pub use self::common_defs::opcode_class::OpcodeClass;
 pub use self::common_defs::address_mode::AddressMode;
+pub use self::opcode::OpcodeTables;
 
 pub use self::cpu_executor::CpuExecutor;
+pub use self::cpu_executor::ExecutionError;
+pub use self::cpu_executor::Executor;
+pub use self::cycle_executor::CycleExecutor;
 
 pub use self::cpu_state::CpuState;
 pub use self::cpu_state::DecodeRegister;
+pub use self::cpu_state::MicroOpRegister;
+
+pub use self::variant::Variant;
 
@@ -1,5 +1,7 @@
 extern crate csv;
 
+use std::error;
+use std::fmt;
 use std::io;
 use std::path::Path;
 use std::num;
@@ -7,6 +9,16 @@ use cpu::common_defs::OpcodeExecInfo;
 use cpu::common_defs::OpcodeDebugInfo;
 use cpu::common_defs::opcode_class;
 use cpu::common_defs::address_mode;
+use cpu::Variant;
+
+// EXEC_INFO and DEBUG_INFO, generated by build.rs from resources/opcodes.csv
+mod table {
+    use cpu::common_defs::OpcodeExecInfo;
+    use cpu::common_defs::opcode_class::OpcodeClass;
+    use cpu::common_defs::address_mode::AddressMode;
+
+    include!(concat!(env!("OUT_DIR"),"/opcode_table.rs"));
+}
 
 #[derive(Debug)]
 pub enum OpcodeLoadError {
@@ -17,7 +29,8 @@ pub enum OpcodeLoadError {
     ParseAddressMode(address_mode::ParseError),
     DuplicateOpcode(String),
     IncorrectOpcodeCount(String),
-    NonContiguousOpcodes(String)
+    NonContiguousOpcodes(String),
+    MissingColumn(String),
 }
 
 impl From<csv::Error> for OpcodeLoadError {
@@ -41,38 +54,126 @@ impl From<address_mode::ParseError> for OpcodeLoadError {
     }
 }
 
+impl fmt::Display for OpcodeLoadError {
+    fn fmt(self: &OpcodeLoadError, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OpcodeLoadError::Io(ref err)       => write!(f,"couldn't read the opcode table: {}",err),
+            OpcodeLoadError::CSV(ref err)      => write!(f,"bad csv: {}",err),
+            OpcodeLoadError::ParseInt(ref err) => write!(f,"bad number: {}",err),
+            OpcodeLoadError::ParseOpcodeClass(opcode_class::ParseError::InvalidString(ref s)) => write!(f,"unknown opcode class \"{}\"",s),
+            OpcodeLoadError::ParseAddressMode(address_mode::ParseError::InvalidString(ref s)) => write!(f,"unknown address mode \"{}\"",s),
+            OpcodeLoadError::DuplicateOpcode(ref opcode) => write!(f,"opcode {} is in the table twice",opcode),
+            OpcodeLoadError::IncorrectOpcodeCount(ref message) | OpcodeLoadError::NonContiguousOpcodes(ref message) => write!(f,"{}",message),
+            OpcodeLoadError::MissingColumn(ref column) => write!(f,"the header has no {} column",column),
+        }
+    }
+}
+impl error::Error for OpcodeLoadError {
+    fn source(self: &OpcodeLoadError) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            OpcodeLoadError::Io(ref err)       => Some(err),
+            OpcodeLoadError::CSV(ref err)      => Some(err),
+            OpcodeLoadError::ParseInt(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Both opcode tables together, for the disassembler and the trace formats.
+pub struct OpcodeTables {
+    pub exec_info: Vec<OpcodeExecInfo>,
+    pub debug_info: Vec<OpcodeDebugInfo>,
+}
+
+impl Default for OpcodeTables {
+    /// The builtin opcode tables.
+    fn default() -> OpcodeTables {
+        let (exec_info,debug_info) = builtin();
+        OpcodeTables::new(exec_info,debug_info)
+    }
+}
+
+impl OpcodeTables {
+    pub fn new(exec_info: Vec<OpcodeExecInfo>, debug_info: Vec<OpcodeDebugInfo>) -> OpcodeTables {
+        OpcodeTables { exec_info, debug_info }
+    }
+    /// The builtin opcode tables of a cpu variant.
+    pub fn for_variant(variant: Variant) -> OpcodeTables {
+        let (exec_info,debug_info) = builtin_variant(variant);
+        OpcodeTables::new(exec_info,debug_info)
+    }
+
+    pub fn exec(self: &OpcodeTables, opcode: u8) -> &OpcodeExecInfo {
+        &self.exec_info[opcode as usize]
+    }
+    pub fn mnemonic(self: &OpcodeTables, opcode: u8) -> &str {
+        &self.debug_info[opcode as usize].mnemonic
+    }
+}
+
+/// The opcode tables compiled into the emulator, in the same form load_from_file returns them.
+pub fn builtin() -> (Vec<OpcodeExecInfo>,Vec<OpcodeDebugInfo>) {
+    builtin_variant(Variant::Ricoh2A03)
+}
+
+/// The builtin opcode tables of a cpu variant.  The 2A03 and the NMOS 6502 share one, the 65C02
+/// has its own, from resources/opcodes_65c02.csv.
+pub fn builtin_variant(variant: Variant) -> (Vec<OpcodeExecInfo>,Vec<OpcodeDebugInfo>) {
+    let (exec_info,debug_info) = match variant {
+        Variant::Ricoh2A03 | Variant::Nmos6502 => (&table::EXEC_INFO,&table::DEBUG_INFO),
+        Variant::Cmos65C02                     => (&table::EXEC_INFO_65C02,&table::DEBUG_INFO_65C02),
+    };
+    let exec_info_vec = exec_info.to_vec();
+    let debug_info_vec = debug_info.iter().enumerate().map(|(opcode,&(name,mnemonic,address_mode_name,notes))| {
+        OpcodeDebugInfo { opcode : opcode as u8, name : name.to_string(), mnemonic : mnemonic.to_string(), address_mode_name : address_mode_name.to_string(), notes : notes.to_string(), }
+    }).collect();
+    (exec_info_vec,debug_info_vec)
+}
+
+// What a table without a Mnemonic column (the layout before it was added) gets: the opcode
+// class without the ILL_ prefix or the digit telling LAX1 from LAX2.
+fn mnemonic_from_name(name: &str) -> String {
+    name.trim_start_matches("ILL_").trim_end_matches(|c: char| c.is_ascii_digit()).to_string()
+}
+
+/// Load opcode tables from a csv file in the format of resources/opcodes.csv, for experimenting
+/// with tables other than the builtin one.  Columns are found by the names in the header, and the
+/// Mnemonic column can be left out.
 pub fn load_from_file<P:AsRef<Path>>(file_path: P) -> Result<(Vec<OpcodeExecInfo>,Vec<OpcodeDebugInfo>),OpcodeLoadError> {
-    let mut rdr = try!(csv::Reader::from_file(file_path));
-    rdr = rdr.has_headers(true);
-    rdr = rdr.flexible(false); // all records are the same length
+    let mut rdr = csv::ReaderBuilder::new().has_headers(true).flexible(false).trim(csv::Trim::All).from_path(file_path)?;
+    let headers = rdr.headers()?.clone();
+    let find = |column: &str| headers.iter().position(|h| h == column);
+    let column = |column: &str| find(column).ok_or_else(|| OpcodeLoadError::MissingColumn(column.to_string()));
+    let (opcode_col,name_col,address_mode_col) = (column("Opcode")?,column("Name")?,column("AddressMode")?);
+    let (len_col,cycles_col,page_cycles_col,notes_col) = (column("Len")?,column("Cycles")?,column("PageCycles")?,column("Notes")?);
+    let mnemonic_col = find("Mnemonic");
 
     let expected_opcode_count = 256;
     let mut exec_info_vec = Vec::with_capacity(expected_opcode_count);
     let mut debug_info_vec = Vec::with_capacity(expected_opcode_count);
     let mut duplicate_check = Vec::<u8>::new();
 
-    let mut count = 0;
-
-    for rec in rdr.decode() {
-        let (opcode_string,name,address_mode_name,len,cycles,page_cycles,notes) : (String,String,String,u8,u8,u8,String) = rec.unwrap();
-        let opcode = try!(u8::from_str_radix(&opcode_string[2..],16)); // from_str_radix won't parse 0x
+    for rec in rdr.records() {
+        let rec = rec?;
+        let (opcode_string,name,address_mode_name,len,cycles,page_cycles,notes) = (rec[opcode_col].to_string(),rec[name_col].to_string(),rec[address_mode_col].to_string(),rec[len_col].parse::<u8>()?,rec[cycles_col].parse::<u8>()?,rec[page_cycles_col].parse::<u8>()?,rec[notes_col].to_string());
+        let mnemonic = mnemonic_col.map_or_else(|| mnemonic_from_name(&name),|col| rec[col].to_string());
+        let opcode = u8::from_str_radix(&opcode_string[2..],16)?; // from_str_radix won't parse 0x
 
         if duplicate_check.contains(&opcode) {
             return Err(OpcodeLoadError::DuplicateOpcode(format!("{:X}",opcode)));
         }
         duplicate_check.push(opcode);
 
-        let debug_info = OpcodeDebugInfo { opcode : opcode, name : name.trim().to_string(), address_mode_name : address_mode_name.trim().to_string(), notes : notes, };
+        let debug_info = OpcodeDebugInfo { opcode, name : name.trim().to_string(), mnemonic, address_mode_name : address_mode_name.trim().to_string(), notes, };
 
-        let address_mode = try!(debug_info.address_mode_name.parse::<address_mode::AddressMode>());
-        let opcode_class = try!(name.trim().to_string().parse::<opcode_class::OpcodeClass>());
+        let address_mode = debug_info.address_mode_name.parse::<address_mode::AddressMode>()?;
+        let opcode_class = name.trim().to_string().parse::<opcode_class::OpcodeClass>()?;
 
-        let exec_info = OpcodeExecInfo { opcode: opcode, len: len, cycles: cycles, page_cycles: page_cycles, address_mode: address_mode,opcode_class:opcode_class };
+        let exec_info = OpcodeExecInfo { opcode, len, cycles, page_cycles, address_mode,opcode_class };
 
         debug_info_vec.push(debug_info);
         exec_info_vec.push(exec_info);
 
-        count = count+1;
     }
     if exec_info_vec.len() != expected_opcode_count {
         return Err(OpcodeLoadError::IncorrectOpcodeCount(format!("Expected {} opcodes, found {}",expected_opcode_count,exec_info_vec.len())));
@@ -0,0 +1,277 @@
+extern crate serde_json;
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::panic;
+use std::path::Path;
+use self::serde_json::Value;
+use cpu::{CpuState,Executor};
+use memory::{BusCycle,Memory};
+
+// Runs the ProcessorTests single step corpus (https://github.com/SingleStepTests/ProcessorTests,
+// the nes6502 set) through a cpu core.  Each file is one opcode, e.g. a9.json, holding an array
+// of cases like:
+//
+// { "name": "a9 10 ea",
+//   "initial": { "pc": 4096, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36, "ram": [ [4096, 169], [4097, 16] ] },
+//   "final":   { "pc": 4098, "s": 253, "a": 16, "x": 0, "y": 0, "p": 36, "ram": [ [4096, 169], [4097, 16] ] },
+//   "cycles":  [ [4096, 169, "read"], [4097, 16, "read"] ] }
+//
+
+#[derive(Debug)]
+pub enum TestError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    // valid json, but not a test case
+    Format(String),
+}
+
+impl From<io::Error> for TestError {
+    fn from(err: io::Error) -> TestError {
+        TestError::Io(err)
+    }
+}
+impl From<serde_json::Error> for TestError {
+    fn from(err: serde_json::Error) -> TestError {
+        TestError::Json(err)
+    }
+}
+
+impl fmt::Display for TestError {
+    fn fmt(self: &TestError, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TestError::Io(ref err)          => write!(f,"couldn't read the tests: {}",err),
+            TestError::Json(ref err)        => write!(f,"not json: {}",err),
+            TestError::Format(ref message)  => write!(f,"not a ProcessorTests case: {}",message),
+        }
+    }
+}
+impl error::Error for TestError {
+    fn source(self: &TestError) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            TestError::Io(ref err)   => Some(err),
+            TestError::Json(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(PartialEq,Clone,Debug)]
+pub struct TestState {
+    pub pc: u16,
+    pub s: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub ram: Vec<(u16,u8)>,
+}
+
+#[derive(PartialEq,Clone,Debug)]
+pub struct ProcessorTest {
+    pub name: String,
+    pub initial: TestState,
+    pub expected: TestState,
+    pub cycles: Vec<BusCycle>,
+}
+
+/// What to compare besides the registers and ram.
+#[derive(PartialEq,Clone,Copy,Debug)]
+pub struct Checks {
+    // the number of cycles the instruction took
+    pub cycle_count: bool,
+    // every bus access, in order.  Only the CycleExecutor makes the dummy reads and writes
+    pub bus: bool,
+}
+
+impl Default for Checks {
+    fn default() -> Checks {
+        Checks { cycle_count: true, bus: false }
+    }
+}
+
+/// How one opcode's file went.
+#[derive(PartialEq,Clone,Debug)]
+pub struct OpcodeResult {
+    pub opcode: u8,
+    pub passed: usize,
+    pub failed: usize,
+    // the name of the first case that failed and what was wrong with it
+    pub first_failure: Option<(String,Vec<String>)>,
+}
+
+impl OpcodeResult {
+    pub fn is_pass(self: &OpcodeResult) -> bool {
+        self.failed == 0
+    }
+}
+
+// a9: 9990/10000 passed, first failure "a9 10 ea": A is $00, expected $10
+impl fmt::Display for OpcodeResult {
+    fn fmt(self: &OpcodeResult, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,"{:0>2x}: {}/{} passed",self.opcode,self.passed,self.passed + self.failed)?;
+        if let Some((ref name,ref differences)) = self.first_failure {
+            write!(f,", first failure \"{}\": {}",name,differences.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// The cases in one file of the corpus.
+pub fn parse(json: &str) -> Result<Vec<ProcessorTest>,TestError> {
+    let value: Value = serde_json::from_str(json)?;
+    let cases = value.as_array().ok_or_else(|| TestError::Format("expected an array of tests".to_string()))?;
+    cases.iter().map(parse_test).collect()
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<ProcessorTest>,TestError> {
+    parse(&fs::read_to_string(path)?)
+}
+
+/// Run one case on a flat 64KB bus with either cpu core, returning what came out different.
+/// Empty means it passed.
+pub fn run<E: Executor>(exec: &E, test: &ProcessorTest, checks: &Checks) -> Vec<String> {
+    let mut mem = Memory::flat();
+    for &(addr,value) in &test.initial.ram {
+        mem.mem[addr as usize] = value;
+    }
+    let mut cpu = CpuState {
+        pc: test.initial.pc,
+        sp: test.initial.s,
+        a: test.initial.a,
+        x: test.initial.x,
+        y: test.initial.y,
+        ..Default::default()
+    };
+    cpu.pack_flags(test.initial.p);
+    mem.bus_log = Some(Vec::new());
+
+    // a case the executor can't handle shouldn't stop the rest of the file
+    let step = panic::catch_unwind(panic::AssertUnwindSafe(|| exec.step(&mut cpu,&mut mem)));
+    let mut differences = Vec::new();
+    match step {
+        Ok(Ok(()))   => { },
+        Ok(Err(err)) => { differences.push(format!("execution error {:?}",err)); return differences; },
+        Err(_)       => { differences.push("the executor panicked".to_string()); return differences; },
+    }
+
+    let expected = &test.expected;
+    let registers = [("PC",cpu.pc,expected.pc),("S",cpu.sp as u16,expected.s as u16),("A",cpu.a as u16,expected.a as u16),
+                     ("X",cpu.x as u16,expected.x as u16),("Y",cpu.y as u16,expected.y as u16)];
+    for &(name,actual,expected) in registers.iter() {
+        if actual != expected {
+            differences.push(format!("{} is ${:0>2X}, expected ${:0>2X}",name,actual,expected));
+        }
+    }
+    // bits 4 and 5 aren't flags, they only exist in copies of P pushed on the stack
+    // http://wiki.nesdev.com/w/index.php/Status_flags#The_B_flag
+    let p = cpu.unpack_flags();
+    if p & 0xCF != expected.p & 0xCF {
+        differences.push(format!("P is ${:0>2X}, expected ${:0>2X}",p,expected.p));
+    }
+    for &(addr,value) in &expected.ram {
+        if mem.mem[addr as usize] != value {
+            differences.push(format!("${:0>4X} is ${:0>2X}, expected ${:0>2X}",addr,mem.mem[addr as usize],value));
+        }
+    }
+
+    if checks.cycle_count && cpu.cycles != test.cycles.len() as u64 {
+        differences.push(format!("took {} cycles, expected {}",cpu.cycles,test.cycles.len()));
+    }
+    if checks.bus {
+        let log = mem.bus_log.take().unwrap_or_default();
+        if let Some(cycle) = (0..log.len().max(test.cycles.len())).find(|&i| log.get(i) != test.cycles.get(i)) {
+            differences.push(format!("cycle {} was {}, expected {}",cycle + 1,bus_cycle(log.get(cycle)),bus_cycle(test.cycles.get(cycle))));
+        }
+    }
+    differences
+}
+
+/// Run every case in one file.
+pub fn run_file<E: Executor, P: AsRef<Path>>(exec: &E, path: P, checks: &Checks) -> Result<OpcodeResult,TestError> {
+    let tests = load(path)?;
+    // the opcode is the first byte of the instruction
+    let opcode = match tests.first() {
+        Some(test) => test.initial.ram.iter().find(|&&(addr,_)| addr == test.initial.pc).map(|&(_,value)| value).unwrap_or(0),
+        None => return Err(TestError::Format("no tests in the file".to_string())),
+    };
+
+    let mut result = OpcodeResult { opcode, passed: 0, failed: 0, first_failure: None };
+    for test in &tests {
+        let differences = run(exec,test,checks);
+        if differences.is_empty() {
+            result.passed += 1;
+            continue;
+        }
+        result.failed += 1;
+        if result.first_failure.is_none() {
+            result.first_failure = Some((test.name.clone(),differences));
+        }
+    }
+    Ok(result)
+}
+
+/// Run every .json file in dir, in file name order.
+pub fn run_dir<E: Executor, P: AsRef<Path>>(exec: &E, dir: P, checks: &Checks) -> Result<Vec<OpcodeResult>,TestError> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "json") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    paths.iter().map(|path| run_file(exec,path,checks)).collect()
+}
+
+fn bus_cycle(cycle: Option<&BusCycle>) -> String {
+    match cycle {
+        Some(cycle) => format!("{} ${:0>4X} = ${:0>2X}",if cycle.write { "write" } else { "read" },cycle.addr,cycle.value),
+        None        => "nothing".to_string(),
+    }
+}
+
+fn parse_test(value: &Value) -> Result<ProcessorTest,TestError> {
+    let cycles = field(value,"cycles")?.as_array().ok_or_else(|| format_error("cycles"))?;
+    Ok(ProcessorTest {
+        name: field(value,"name")?.as_str().unwrap_or("").to_string(),
+        initial: parse_state(field(value,"initial")?)?,
+        expected: parse_state(field(value,"final")?)?,
+        cycles: cycles.iter().map(|cycle| {
+            let write = match cycle.get(2).and_then(Value::as_str) {
+                Some("read")  => false,
+                Some("write") => true,
+                _ => return Err(format_error("cycles")),
+            };
+            Ok(BusCycle { addr: number(cycle,0)? as u16, value: number(cycle,1)? as u8, write })
+        }).collect::<Result<Vec<BusCycle>,TestError>>()?,
+    })
+}
+
+fn parse_state(value: &Value) -> Result<TestState,TestError> {
+    let register = |name: &str| -> Result<u64,TestError> { field(value,name)?.as_u64().ok_or_else(|| format_error(name)) };
+    let ram = field(value,"ram")?.as_array().ok_or_else(|| format_error("ram"))?;
+    Ok(TestState {
+        pc: register("pc")? as u16,
+        s: register("s")? as u8,
+        a: register("a")? as u8,
+        x: register("x")? as u8,
+        y: register("y")? as u8,
+        p: register("p")? as u8,
+        ram: ram.iter().map(|cell| Ok((number(cell,0)? as u16,number(cell,1)? as u8))).collect::<Result<Vec<(u16,u8)>,TestError>>()?,
+    })
+}
+
+fn field<'a>(value: &'a Value, name: &str) -> Result<&'a Value,TestError> {
+    value.get(name).ok_or_else(|| TestError::Format(format!("missing \"{}\"",name)))
+}
+
+fn number(value: &Value, index: usize) -> Result<u64,TestError> {
+    value.get(index).and_then(Value::as_u64).ok_or_else(|| TestError::Format(format!("expected a number in {}",value)))
+}
+
+fn format_error(name: &str) -> TestError {
+    TestError::Format(format!("\"{}\" isn't in the expected form",name))
+}
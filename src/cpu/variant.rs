@@ -0,0 +1,59 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Which chip the cpu core behaves like.
+//
+// http://wiki.nesdev.com/w/index.php/CPU
+// http://6502.org/tutorials/65c02opcodes.html
+//
+#[derive(PartialEq,Eq,Clone,Copy,Debug,Default)]
+pub enum Variant {
+    // the NES cpu, an NMOS 6502 with the decimal mode cut out.  SED and CLD still set and clear
+    // the D flag, ADC and SBC ignore it
+    #[default]
+    Ricoh2A03,
+    // the original 6502, the same opcodes as the 2A03 but with decimal arithmetic
+    Nmos6502,
+    // the CMOS 6502.  New instructions and addressing modes, no page wrapping bug in JMP (a),
+    // valid flags in decimal mode, and the illegal opcodes are all NOPs
+    Cmos65C02,
+}
+
+impl Variant {
+    /// ADC and SBC honor the D flag.
+    pub fn has_decimal_mode(self: &Variant) -> bool {
+        *self != Variant::Ricoh2A03
+    }
+    pub fn is_cmos(self: &Variant) -> bool {
+        *self == Variant::Cmos65C02
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    InvalidString(String)
+}
+
+impl FromStr for Variant {
+    type Err = ParseError;
+
+    fn from_str(s:&str) -> Result<Self,Self::Err> {
+        match s.to_lowercase().as_str() {
+            "2a03"  => Ok(Variant::Ricoh2A03),
+            "6502"  => Ok(Variant::Nmos6502),
+            "65c02" => Ok(Variant::Cmos65C02),
+
+            _ => Err(ParseError::InvalidString(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(self: &Variant, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Variant::Ricoh2A03 => write!(f,"2A03"),
+            Variant::Nmos6502  => write!(f,"6502"),
+            Variant::Cmos65C02 => write!(f,"65C02"),
+        }
+    }
+}
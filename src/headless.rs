@@ -0,0 +1,294 @@
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead,BufReader,BufWriter,Write};
+use std::path::{Path,PathBuf};
+use std::str::FromStr;
+use cpu::ExecutionError;
+use movie::{fm2,Movie,MovieError};
+use nes::Nes;
+use rom_loader;
+use rom_loader::InesError;
+
+/// Set in the environment to have check_golden write the golden file instead of comparing.
+pub const BLESS_VAR: &str = "TRUSTINES_BLESS";
+
+// Runs a rom without a display and hashes what comes out of it, so a regression test can
+// compare a few numbers instead of screenshots and recordings.
+//
+// A frame is what Nes::run_frame runs, and frame n is hashed once n frames have run.  The video
+// hash is of the framebuffer as it stands, palette indexes and emphasis bits, so it doesn't
+// depend on the palette.  The audio hash is of every sample made since the previous checkpoint,
+// so a sound that changes between checkpoints is still caught.  Both are 64 bit FNV-1a:
+//
+// http://www.isthe.com/chongo/tech/comp/fnv/index.html
+//
+// Golden hashes live next to the rom, game.nes has game.hashes, one FrameHash per line.
+//
+
+#[derive(Debug)]
+pub enum HeadlessError {
+  Io(io::Error),
+  Ines(InesError),
+  Movie(MovieError),
+  Execution(ExecutionError),
+  // a line of a golden file that isn't a FrameHash, line is 1 based
+  Parse { line: usize, message: String },
+  // the hashes don't match the golden ones, None where one side ran out
+  Mismatch { expected: Option<FrameHash>, actual: Option<FrameHash> },
+}
+
+impl From<io::Error> for HeadlessError {
+    fn from(err: io::Error) -> HeadlessError {
+        HeadlessError::Io(err)
+    }
+}
+impl From<InesError> for HeadlessError {
+    fn from(err: InesError) -> HeadlessError {
+        HeadlessError::Ines(err)
+    }
+}
+impl From<MovieError> for HeadlessError {
+    fn from(err: MovieError) -> HeadlessError {
+        HeadlessError::Movie(err)
+    }
+}
+impl From<ExecutionError> for HeadlessError {
+    fn from(err: ExecutionError) -> HeadlessError {
+        HeadlessError::Execution(err)
+    }
+}
+
+impl fmt::Display for HeadlessError {
+    fn fmt(self: &HeadlessError, f: &mut fmt::Formatter) -> fmt::Result {
+        let or_nothing = |hash: &Option<FrameHash>| hash.as_ref().map_or("nothing".to_string(),|hash| hash.to_string());
+        match *self {
+            HeadlessError::Io(ref err)                 => write!(f,"{}",err),
+            HeadlessError::Ines(ref err)               => write!(f,"{}",err),
+            HeadlessError::Movie(ref err)              => write!(f,"{}",err),
+            HeadlessError::Execution(ref err)          => write!(f,"{}",err),
+            HeadlessError::Parse { line, ref message } => write!(f,"golden hashes line {}: {}",line,message),
+            HeadlessError::Mismatch { ref expected, ref actual } =>
+                write!(f,"hashes differ, expected {}, found {}",or_nothing(expected),or_nothing(actual)),
+        }
+    }
+}
+impl error::Error for HeadlessError {
+    fn source(self: &HeadlessError) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            HeadlessError::Io(ref err)        => Some(err),
+            HeadlessError::Ines(ref err)      => Some(err),
+            HeadlessError::Movie(ref err)     => Some(err),
+            HeadlessError::Execution(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Which frames get hashed.
+#[derive(PartialEq,Clone,Debug)]
+pub enum Checkpoints {
+    /// Every n frames, 1 for every frame.
+    Every(u64),
+    /// Just these frames.
+    At(Vec<u64>),
+}
+
+impl Checkpoints {
+    pub fn includes(self: &Checkpoints, frame: u64) -> bool {
+        match *self {
+            Checkpoints::Every(n)      => frame % n.max(1) == 0,
+            Checkpoints::At(ref frames) => frames.contains(&frame),
+        }
+    }
+}
+
+/// The hashes at the end of a frame, written as 'frame video audio' with the hashes in hex.
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub struct FrameHash {
+    pub frame: u64,
+    pub video: u64,
+    pub audio: u64,
+}
+
+impl fmt::Display for FrameHash {
+    fn fmt(self: &FrameHash, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,"{} {:016x} {:016x}",self.frame,self.video,self.audio)
+    }
+}
+
+impl FromStr for FrameHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FrameHash,String> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 3 {
+            return Err(format!("expected 'frame video audio', found '{}'",s));
+        }
+        let hash = |field: &str| u64::from_str_radix(field,16).map_err(|_| format!("'{}' is not a hex hash",field));
+        Ok(FrameHash {
+            frame: fields[0].parse().map_err(|_| format!("'{}' is not a frame number",fields[0]))?,
+            video: hash(fields[1])?,
+            audio: hash(fields[2])?,
+        })
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(FNV_OFFSET)
+    }
+    fn write(self: &mut Fnv, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+/// Hash of a framebuffer, see Nes::framebuffer.
+pub fn hash_video(framebuffer: &[u16]) -> u64 {
+    let mut hash = Fnv::new();
+    for pixel in framebuffer {
+        hash.write(&pixel.to_le_bytes());
+    }
+    hash.0
+}
+
+/// Hash of some audio samples, see Apu::take_samples.
+pub fn hash_audio(samples: &[f32]) -> u64 {
+    let mut hash = Fnv::new();
+    for sample in samples {
+        hash.write(&sample.to_bits().to_le_bytes());
+    }
+    hash.0
+}
+
+/// Start the console, from power on or where the movie starts, and run frames frames of it,
+/// calling after_frame with each frame number as it finishes.  Past the end of the movie the
+/// controllers are left as they were.
+pub fn run_frames<F>(nes: &mut Nes, movie: Option<&Movie>, frames: u64, mut after_frame: F) -> Result<(),HeadlessError>
+    where F: FnMut(u64,&mut Nes) -> Result<(),HeadlessError> {
+    start(nes,movie)?;
+    for frame in 1..=frames {
+        run_frame(nes,movie,frame)?;
+        after_frame(frame,nes)?;
+    }
+    Ok(())
+}
+
+/// Put the console where run_frames starts, at power on or where the movie starts.
+pub fn start(nes: &mut Nes, movie: Option<&Movie>) -> Result<(),HeadlessError> {
+    match movie {
+        Some(movie) => movie.begin(nes)?,
+        None        => nes.power_cycle()?,
+    }
+    nes.mem.apu.take_samples();
+    Ok(())
+}
+
+/// Run frame frame, counting from 1, as run_frames does: the movie's input while it lasts, then
+/// the controllers as they are.
+pub fn run_frame(nes: &mut Nes, movie: Option<&Movie>, frame: u64) -> Result<(),HeadlessError> {
+    match movie {
+        Some(movie) if (frame as usize) <= movie.frames.len() => movie.play_frame(nes,frame as usize - 1)?,
+        _                                                     => nes.run_frame()?,
+    }
+    Ok(())
+}
+
+/// Hashes the checkpoints as frames finish, see run_frames.
+pub struct FrameHasher {
+    checkpoints: Checkpoints,
+    audio: Fnv,
+    hashes: Vec<FrameHash>,
+}
+
+impl FrameHasher {
+    pub fn new(checkpoints: Checkpoints) -> FrameHasher {
+        FrameHasher { checkpoints, audio: Fnv::new(), hashes: Vec::new() }
+    }
+
+    /// Take the frame's audio samples, and hash it if it's a checkpoint.
+    pub fn after_frame(self: &mut FrameHasher, frame: u64, nes: &mut Nes) {
+        for sample in nes.mem.apu.take_samples() {
+            self.audio.write(&sample.to_bits().to_le_bytes());
+        }
+        if self.checkpoints.includes(frame) {
+            self.hashes.push(FrameHash { frame, video: hash_video(nes.framebuffer()), audio: self.audio.0 });
+            self.audio = Fnv::new();
+        }
+    }
+
+    pub fn into_hashes(self: FrameHasher) -> Vec<FrameHash> {
+        self.hashes
+    }
+}
+
+/// Run frames frames, see run_frames, hashing the checkpoints.
+pub fn run(nes: &mut Nes, movie: Option<&Movie>, frames: u64, checkpoints: &Checkpoints) -> Result<Vec<FrameHash>,HeadlessError> {
+    let mut hasher = FrameHasher::new(checkpoints.clone());
+    run_frames(nes,movie,frames,|frame,nes| { hasher.after_frame(frame,nes); Ok(()) })?;
+    Ok(hasher.into_hashes())
+}
+
+/// Where the golden hashes for a rom are kept, the rom with a .hashes extension.
+pub fn golden_path<P:AsRef<Path>>(rom_path: P) -> PathBuf {
+    rom_path.as_ref().with_extension("hashes")
+}
+
+pub fn read_golden<P:AsRef<Path>>(file_path: P) -> Result<Vec<FrameHash>,HeadlessError> {
+    let reader = BufReader::new(File::open(&file_path)?);
+    let mut hashes = Vec::new();
+    for (i,line) in reader.lines().enumerate() {
+        let line = line?;
+        // blank lines and # comments are for people reading the file
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        hashes.push(line.parse().map_err(|message| HeadlessError::Parse { line: i + 1, message })?);
+    }
+    Ok(hashes)
+}
+
+pub fn write_golden<P:AsRef<Path>>(file_path: P, hashes: &[FrameHash]) -> Result<(),HeadlessError> {
+    let mut writer = BufWriter::new(File::create(&file_path)?);
+    for hash in hashes {
+        writeln!(writer,"{}",hash)?;
+    }
+    Ok(writer.flush()?)
+}
+
+/// The first place the hashes differ, None if they're the same.
+pub fn compare(expected: &[FrameHash], actual: &[FrameHash]) -> Option<(Option<FrameHash>,Option<FrameHash>)> {
+    (0..expected.len().max(actual.len()))
+        .map(|i| (expected.get(i).cloned(),actual.get(i).cloned()))
+        .find(|(expected,actual)| expected != actual)
+}
+
+/// For regression tests: run the rom, with the FM2 movie if there is one, and compare the hashes
+/// with the golden ones next to the rom.  With TRUSTINES_BLESS set the golden file is written
+/// instead, for adding a test or accepting a change in the output.
+pub fn check_golden<P:AsRef<Path>>(rom_path: P, movie_path: Option<P>, frames: u64, checkpoints: &Checkpoints) -> Result<(),HeadlessError> {
+    let mut nes = Nes::new(rom_loader::load_cartridge(&rom_path)?);
+    let movie = match movie_path {
+        Some(path) => Some(fm2::load(path)?),
+        None       => None,
+    };
+    let actual = run(&mut nes,movie.as_ref(),frames,checkpoints)?;
+
+    let golden = golden_path(&rom_path);
+    if env::var_os(BLESS_VAR).is_some() {
+        return write_golden(&golden,&actual);
+    }
+    match compare(&read_golden(&golden)?,&actual) {
+        Some((expected,actual)) => Err(HeadlessError::Mismatch { expected, actual }),
+        None                    => Ok(()),
+    }
+}
@@ -0,0 +1,72 @@
+use std::io;
+use std::io::{Read,Write};
+use byteorder::ReadBytesExt;
+use savestate::{self,Snapshot};
+
+// http://wiki.nesdev.com/w/index.php/Arkanoid_controller
+//
+// NES version, normally plugged into port 2.
+//
+// D3: fire button, 1 while pressed
+// D4: potentiometer position, 8 bits shifted out MSB first and inverted
+//
+// The position is latched while the strobe is high, like the standard controller.
+//
+
+#[derive(Clone)]
+pub struct ArkanoidPaddle {
+    /// Raw potentiometer value, real controllers report roughly $62 (left) to $F2 (right).
+    pub position: u8,
+    pub button: bool,
+    shift_register: u8,
+    strobe: bool,
+}
+
+impl Default for ArkanoidPaddle {
+    fn default() -> ArkanoidPaddle {
+        ArkanoidPaddle::new()
+    }
+}
+
+impl ArkanoidPaddle {
+    pub fn new() -> ArkanoidPaddle {
+        ArkanoidPaddle {
+            position: 0x62,
+            button: false,
+            shift_register: 0,
+            strobe: false,
+        }
+    }
+
+    pub fn write(self: &mut ArkanoidPaddle, val: u8) {
+        self.strobe = (val & 1) == 1;
+        if self.strobe { self.shift_register = !self.position; }
+    }
+
+    pub fn read(self: &mut ArkanoidPaddle) -> u8 {
+        let val = self.peek();
+        if !self.strobe { self.shift_register <<= 1; }
+        val
+    }
+
+    pub fn peek(self: &ArkanoidPaddle) -> u8 {
+        let bit = if self.strobe { !self.position >> 7 } else { self.shift_register >> 7 };
+        ( (self.button as u8) << 3)
+      | ( (bit & 1          ) << 4)
+    }
+}
+
+impl Snapshot for ArkanoidPaddle {
+    fn save_state(self: &ArkanoidPaddle, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(&[self.position,self.shift_register])?;
+        savestate::write_bool(writer,self.button)?;
+        savestate::write_bool(writer,self.strobe)
+    }
+    fn load_state(self: &mut ArkanoidPaddle, reader: &mut dyn Read) -> io::Result<()> {
+        self.position = reader.read_u8()?;
+        self.shift_register = reader.read_u8()?;
+        self.button = savestate::read_bool(reader)?;
+        self.strobe = savestate::read_bool(reader)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use input::standard_controller::StandardController;
+use std::io;
+use std::io::{Read,Write};
+use byteorder::{ReadBytesExt,WriteBytesExt};
+use savestate::{self,Snapshot};
+
+// http://wiki.nesdev.com/w/index.php/Four_player_adapters
+//
+// A four player adapter is plugged into both ports, each port sees two controllers.  The adapter
+// is modeled as one FourPlayerPort per port, port 0 holds players 1 and 3 and port 1 holds players
+// 2 and 4.
+//
+// FourScore:  players are read serially on D0, 8 reads for the first player, 8 for the second,
+//             then an 8 bit signature ($10 on $4016, $20 on $4017).
+// Hori:       same as the FourScore but on D1 and with the signatures swapped.
+// Simple:     the Famicom adapter's 'simple' mode, the first player is on D0 and the second on
+//             D1, read in parallel.  There's no signature.
+//
+
+#[derive(PartialEq,Clone,Copy,Debug)]
+pub enum FourPlayerProtocol {
+    FourScore,
+    Hori,
+    Simple,
+}
+
+#[derive(Clone)]
+pub struct FourPlayerPort {
+    pub controllers: [StandardController; 2],
+    protocol: FourPlayerProtocol,
+    signature: u8,
+    read_count: u8,
+    strobe: bool,
+}
+
+impl FourPlayerPort {
+    /// Construct the half of the adapter that's plugged into the given port (0 or 1).
+    pub fn new(protocol: FourPlayerProtocol, port: usize) -> FourPlayerPort {
+        let signature = match (protocol, port) {
+            (FourPlayerProtocol::FourScore, 0) => 0x10,
+            (FourPlayerProtocol::FourScore, _) => 0x20,
+            (FourPlayerProtocol::Hori,      0) => 0x20,
+            (FourPlayerProtocol::Hori,      _) => 0x10,
+            (FourPlayerProtocol::Simple,    _) => 0x00,
+        };
+        FourPlayerPort {
+            controllers: [StandardController::new(), StandardController::new()],
+            protocol,
+            signature,
+            read_count: 0,
+            strobe: false,
+        }
+    }
+
+    pub fn write(self: &mut FourPlayerPort, val: u8) {
+        self.strobe = (val & 1) == 1;
+        if self.strobe { self.read_count = 0; }
+        for controller in self.controllers.iter_mut() {
+            controller.write(val);
+        }
+    }
+
+    pub fn read(self: &mut FourPlayerPort) -> u8 {
+        if self.protocol == FourPlayerProtocol::Simple {
+            return self.controllers[0].read() | (self.controllers[1].read() << 1);
+        }
+
+        let bit = if self.strobe {
+            self.controllers[0].read()
+        } else {
+            let bit = match self.read_count {
+                0..=7   => self.controllers[0].read(),
+                8..=15  => self.controllers[1].read(),
+                // games shift the signature in with ROL, so the first bit read is the MSB
+                16..=23 => (self.signature >> (23 - self.read_count)) & 1,
+                _       => 1,
+            };
+            if self.read_count < 24 { self.read_count += 1; }
+            bit
+        };
+        self.place(bit)
+    }
+
+    pub fn peek(self: &FourPlayerPort) -> u8 {
+        if self.protocol == FourPlayerProtocol::Simple {
+            return self.controllers[0].peek() | (self.controllers[1].peek() << 1);
+        }
+
+        let bit = if self.strobe {
+            self.controllers[0].peek()
+        } else {
+            match self.read_count {
+                0..=7   => self.controllers[0].peek(),
+                8..=15  => self.controllers[1].peek(),
+                16..=23 => (self.signature >> (23 - self.read_count)) & 1,
+                _       => 1,
+            }
+        };
+        self.place(bit)
+    }
+
+    // put the serial bit on the data line the protocol uses
+    fn place(self: &FourPlayerPort, bit: u8) -> u8 {
+        match self.protocol {
+            FourPlayerProtocol::Hori => bit << 1,
+            _                        => bit,
+        }
+    }
+}
+
+// the protocol and signature come from how the adapter is set up, not saved
+impl Snapshot for FourPlayerPort {
+    fn save_state(self: &FourPlayerPort, writer: &mut dyn Write) -> io::Result<()> {
+        for controller in self.controllers.iter() {
+            controller.save_state(writer)?;
+        }
+        writer.write_u8(self.read_count)?;
+        savestate::write_bool(writer,self.strobe)
+    }
+    fn load_state(self: &mut FourPlayerPort, reader: &mut dyn Read) -> io::Result<()> {
+        for controller in self.controllers.iter_mut() {
+            controller.load_state(reader)?;
+        }
+        self.read_count = reader.read_u8()?;
+        self.strobe = savestate::read_bool(reader)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+use std::io;
+use std::io::{Read,Write};
+use byteorder::{ReadBytesExt,WriteBytesExt};
+use savestate::{self,Snapshot};
+
+//public mods
+pub mod standard_controller;
+pub mod four_player_adapter;
+pub mod zapper;
+pub mod arkanoid_paddle;
+pub mod power_pad;
+
+//private mods
+
+// hoisted interfaces
+pub use self::standard_controller::ButtonState;
+pub use self::standard_controller::StandardController;
+pub use self::four_player_adapter::FourPlayerPort;
+pub use self::four_player_adapter::FourPlayerProtocol;
+pub use self::zapper::Zapper;
+pub use self::arkanoid_paddle::ArkanoidPaddle;
+pub use self::power_pad::PowerPad;
+pub use self::power_pad::PowerPadSide;
+
+// http://wiki.nesdev.com/w/index.php/Input_devices
+//
+// $4016 write: bit 0 is the strobe shared by both ports
+// $4016 read:  port 1 serial data
+// $4017 read:  port 2 serial data
+//
+// Devices only drive the low 5 bits of a read, the rest is open bus and is filled in by Memory.
+//
+
+#[derive(Clone)]
+pub enum InputDevice {
+    Unconnected,
+    StandardController(StandardController),
+    FourPlayerAdapter(FourPlayerPort),
+    Zapper(Zapper),
+    ArkanoidPaddle(ArkanoidPaddle),
+    PowerPad(PowerPad),
+}
+
+impl InputDevice {
+    // identifies the device in save states
+    fn kind(self: &InputDevice) -> u8 {
+        match *self {
+            InputDevice::Unconnected           => 0,
+            InputDevice::StandardController(_) => 1,
+            InputDevice::FourPlayerAdapter(_)  => 2,
+            InputDevice::Zapper(_)             => 3,
+            InputDevice::ArkanoidPaddle(_)     => 4,
+            InputDevice::PowerPad(_)           => 5,
+        }
+    }
+
+    pub fn write(self: &mut InputDevice, val: u8) {
+        match *self {
+            InputDevice::Unconnected                   => { },
+            InputDevice::StandardController(ref mut d) => d.write(val),
+            InputDevice::FourPlayerAdapter(ref mut d)  => d.write(val),
+            InputDevice::Zapper(_)                     => { },
+            InputDevice::ArkanoidPaddle(ref mut d)     => d.write(val),
+            InputDevice::PowerPad(ref mut d)           => d.write(val),
+        }
+    }
+    pub fn read(self: &mut InputDevice) -> u8 {
+        match *self {
+            InputDevice::Unconnected                   => 0,
+            InputDevice::StandardController(ref mut d) => d.read(),
+            InputDevice::FourPlayerAdapter(ref mut d)  => d.read(),
+            InputDevice::Zapper(ref d)                 => d.read(),
+            InputDevice::ArkanoidPaddle(ref mut d)     => d.read(),
+            InputDevice::PowerPad(ref mut d)           => d.read(),
+        }
+    }
+    pub fn peek(self: &InputDevice) -> u8 {
+        match *self {
+            InputDevice::Unconnected                   => 0,
+            InputDevice::StandardController(ref d)     => d.peek(),
+            InputDevice::FourPlayerAdapter(ref d)      => d.peek(),
+            InputDevice::Zapper(ref d)                 => d.read(),
+            InputDevice::ArkanoidPaddle(ref d)         => d.peek(),
+            InputDevice::PowerPad(ref d)               => d.peek(),
+        }
+    }
+}
+
+// http://wiki.nesdev.com/w/index.php/NES_2.0#Default_Expansion_Device
+//
+// Only the devices we emulate are listed, anything else falls back to standard controllers.
+//
+#[derive(PartialEq,Clone,Copy,Debug)]
+pub enum ExpansionDevice {
+    Unspecified,
+    StandardControllers,
+    FourScore,
+    FamicomFourPlayers,
+    Zapper,
+    TwoZappers,
+    PowerPadSideA,
+    PowerPadSideB,
+    ArkanoidNes,
+}
+
+impl ExpansionDevice {
+    /// Map byte 15 of an NES 2.0 header to the device.
+    pub fn from_nes2_id(id: u8) -> ExpansionDevice {
+        match id & 0x3F {
+            0x01 => ExpansionDevice::StandardControllers,
+            0x02 => ExpansionDevice::FourScore,
+            0x03 => ExpansionDevice::FamicomFourPlayers,
+            0x08 => ExpansionDevice::Zapper,
+            0x09 => ExpansionDevice::TwoZappers,
+            0x0B => ExpansionDevice::PowerPadSideA,
+            0x0C => ExpansionDevice::PowerPadSideB,
+            0x0F => ExpansionDevice::ArkanoidNes,
+            _    => ExpansionDevice::Unspecified,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct InputPorts {
+    pub ports: [InputDevice; 2],
+}
+
+impl Default for InputPorts {
+    fn default() -> InputPorts {
+        InputPorts::new()
+    }
+}
+
+impl InputPorts {
+    /// Two standard controllers.
+    pub fn new() -> InputPorts {
+        InputPorts::for_expansion_device(ExpansionDevice::StandardControllers)
+    }
+
+    /// Connect the devices a cartridge asks for.
+    pub fn for_expansion_device(device: ExpansionDevice) -> InputPorts {
+        let controller = || InputDevice::StandardController(StandardController::new());
+        let four_player = |protocol| [InputDevice::FourPlayerAdapter(FourPlayerPort::new(protocol,0)),
+                                      InputDevice::FourPlayerAdapter(FourPlayerPort::new(protocol,1))];
+        let ports = match device {
+            ExpansionDevice::Unspecified         => [controller(), controller()],
+            ExpansionDevice::StandardControllers => [controller(), controller()],
+            ExpansionDevice::FourScore           => four_player(FourPlayerProtocol::FourScore),
+            ExpansionDevice::FamicomFourPlayers  => four_player(FourPlayerProtocol::Simple),
+            ExpansionDevice::Zapper              => [controller(), InputDevice::Zapper(Zapper::new())],
+            ExpansionDevice::TwoZappers          => [InputDevice::Zapper(Zapper::new()), InputDevice::Zapper(Zapper::new())],
+            ExpansionDevice::PowerPadSideA       => [controller(), InputDevice::PowerPad(PowerPad::new(PowerPadSide::A))],
+            ExpansionDevice::PowerPadSideB       => [controller(), InputDevice::PowerPad(PowerPad::new(PowerPadSide::B))],
+            ExpansionDevice::ArkanoidNes         => [controller(), InputDevice::ArkanoidPaddle(ArkanoidPaddle::new())],
+        };
+        InputPorts { ports }
+    }
+
+    /// Set the buttons held on a standard controller.  Meant to be called by the embedder once
+    /// per frame.  With a four player adapter connected ports 2 and 3 are players 3 and 4.
+    /// Ports without a standard controller ignore the call.
+    pub fn set_buttons(self: &mut InputPorts, port: usize, buttons: ButtonState) {
+        if port >= self.ports.len() * 2 {
+            panic!("there are at most {} controllers, controller {} does not exist",self.ports.len()*2,port)
+        }
+        let len = self.ports.len();
+        match self.ports[port % len] {
+            InputDevice::StandardController(ref mut d) if port < len => d.set_buttons(buttons),
+            InputDevice::FourPlayerAdapter(ref mut d)  => d.controllers[port / len].set_buttons(buttons),
+            _ => { },
+        }
+    }
+
+    /// Aim and fire a zapper, aim is None when pointed off screen.
+    pub fn set_zapper(self: &mut InputPorts, port: usize, aim: Option<(u8,u8)>, trigger: bool) {
+        if let InputDevice::Zapper(ref mut d) = self.ports[port] {
+            d.aim = aim;
+            d.trigger = trigger;
+        }
+    }
+
+    pub fn set_paddle(self: &mut InputPorts, port: usize, position: u8, button: bool) {
+        if let InputDevice::ArkanoidPaddle(ref mut d) = self.ports[port] {
+            d.position = position;
+            d.button = button;
+        }
+    }
+
+    /// Bit n-1 of buttons is set while power pad button n is pressed.
+    pub fn set_power_pad(self: &mut InputPorts, port: usize, buttons: u16) {
+        if let InputDevice::PowerPad(ref mut d) = self.ports[port] {
+            d.buttons = buttons;
+        }
+    }
+
+    /// Let light guns look at the picture, see Zapper::update_beam.
+    pub fn update_beam(self: &mut InputPorts, framebuffer: &[u16], scanline: u16, dot: u16) {
+        for port in self.ports.iter_mut() {
+            if let InputDevice::Zapper(ref mut d) = *port {
+                d.update_beam(framebuffer,scanline,dot);
+            }
+        }
+    }
+
+    /// Handle a write to $4016.
+    pub fn write_strobe(self: &mut InputPorts, val: u8) {
+        for port in self.ports.iter_mut() {
+            port.write(val);
+        }
+    }
+
+    /// Handle a read of $4016 (port 0) or $4017 (port 1).  Only the low bits are driven by the
+    /// devices, the caller is responsible for filling in the open bus bits.
+    pub fn read(self: &mut InputPorts, port: usize) -> u8 {
+        self.ports[port].read()
+    }
+
+    /// Same as read, but without side effects.
+    pub fn peek(self: &InputPorts, port: usize) -> u8 {
+        self.ports[port].peek()
+    }
+}
+
+// the state of whatever is plugged in, loading only works with the same devices connected
+impl Snapshot for InputDevice {
+    fn save_state(self: &InputDevice, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_u8(self.kind())?;
+        match *self {
+            InputDevice::Unconnected               => Ok(()),
+            InputDevice::StandardController(ref d) => d.save_state(writer),
+            InputDevice::FourPlayerAdapter(ref d)  => d.save_state(writer),
+            InputDevice::Zapper(ref d)             => d.save_state(writer),
+            InputDevice::ArkanoidPaddle(ref d)     => d.save_state(writer),
+            InputDevice::PowerPad(ref d)           => d.save_state(writer),
+        }
+    }
+    fn load_state(self: &mut InputDevice, reader: &mut dyn Read) -> io::Result<()> {
+        if reader.read_u8()? != self.kind() {
+            return Err(savestate::invalid_data("a different input device is connected"));
+        }
+        match *self {
+            InputDevice::Unconnected                   => Ok(()),
+            InputDevice::StandardController(ref mut d) => d.load_state(reader),
+            InputDevice::FourPlayerAdapter(ref mut d)  => d.load_state(reader),
+            InputDevice::Zapper(ref mut d)             => d.load_state(reader),
+            InputDevice::ArkanoidPaddle(ref mut d)     => d.load_state(reader),
+            InputDevice::PowerPad(ref mut d)           => d.load_state(reader),
+        }
+    }
+}
+
+impl Snapshot for InputPorts {
+    fn save_state(self: &InputPorts, writer: &mut dyn Write) -> io::Result<()> {
+        for port in self.ports.iter() {
+            port.save_state(writer)?;
+        }
+        Ok(())
+    }
+    fn load_state(self: &mut InputPorts, reader: &mut dyn Read) -> io::Result<()> {
+        for port in self.ports.iter_mut() {
+            port.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
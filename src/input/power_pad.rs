@@ -0,0 +1,98 @@
+use std::io;
+use std::io::{Read,Write};
+use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
+use savestate::{self,Snapshot};
+
+// http://wiki.nesdev.com/w/index.php/Power_Pad
+//
+// Normally plugged into port 2, the 12 buttons come out of two shift registers:
+//
+// D3: buttons 2, 1, 5, 9, 6, 10, 11, 7
+// D4: buttons 4, 3, 12, 8, then 1s
+//
+// Pressed buttons read as 1, and both lines return 1 once their register is empty.
+//
+
+const D3_ORDER: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const D4_ORDER: [u8; 4] = [4, 3, 12, 8];
+
+/// Which side of the mat is face up.  This doesn't change what the hardware reports, the buttons
+/// are always numbered as printed on side B, but embedders need it to lay out their controls.
+#[derive(PartialEq,Clone,Copy,Debug)]
+pub enum PowerPadSide {
+    A,
+    B,
+}
+
+#[derive(Clone)]
+pub struct PowerPad {
+    /// Bit n-1 is set while button n is pressed.
+    pub buttons: u16,
+    pub side: PowerPadSide,
+    d3_register: u8,
+    d4_register: u8,
+    strobe: bool,
+}
+
+impl PowerPad {
+    pub fn new(side: PowerPadSide) -> PowerPad {
+        PowerPad {
+            buttons: 0,
+            side,
+            d3_register: 0xFF,
+            d4_register: 0xFF,
+            strobe: false,
+        }
+    }
+
+    pub fn write(self: &mut PowerPad, val: u8) {
+        self.strobe = (val & 1) == 1;
+        if self.strobe { self.reload(); }
+    }
+
+    pub fn read(self: &mut PowerPad) -> u8 {
+        if self.strobe { self.reload(); }
+        let val = self.peek();
+        if !self.strobe {
+            self.d3_register = (self.d3_register >> 1) | 0x80;
+            self.d4_register = (self.d4_register >> 1) | 0x80;
+        }
+        val
+    }
+
+    pub fn peek(self: &PowerPad) -> u8 {
+        ( (self.d3_register & 1) << 3)
+      | ( (self.d4_register & 1) << 4)
+    }
+
+    fn reload(self: &mut PowerPad) {
+        self.d3_register = 0;
+        for (i,button) in D3_ORDER.iter().enumerate() {
+            self.d3_register |= self.pressed(*button) << i;
+        }
+        self.d4_register = 0xF0;
+        for (i,button) in D4_ORDER.iter().enumerate() {
+            self.d4_register |= self.pressed(*button) << i;
+        }
+    }
+
+    fn pressed(self: &PowerPad, button: u8) -> u8 {
+        ((self.buttons >> (button - 1)) & 1) as u8
+    }
+}
+
+// which side is up is the embedder's business, not saved
+impl Snapshot for PowerPad {
+    fn save_state(self: &PowerPad, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_u16::<LittleEndian>(self.buttons)?;
+        writer.write_all(&[self.d3_register,self.d4_register])?;
+        savestate::write_bool(writer,self.strobe)
+    }
+    fn load_state(self: &mut PowerPad, reader: &mut dyn Read) -> io::Result<()> {
+        self.buttons = reader.read_u16::<LittleEndian>()?;
+        self.d3_register = reader.read_u8()?;
+        self.d4_register = reader.read_u8()?;
+        self.strobe = savestate::read_bool(reader)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use std::io;
+use std::io::{Read,Write};
+use byteorder::ReadBytesExt;
+use savestate::{self,Snapshot};
+
+// http://wiki.nesdev.com/w/index.php/Standard_controller
+//
+// The controller is a 4021 parallel-in/serial-out shift register.  While the strobe bit written
+// to $4016 is high the button states are continuously reloaded into the register, when it goes
+// low the register holds its contents and each read of $4016/$4017 shifts out one button.
+//
+
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct ButtonState {
+    pub a:      bool,
+    pub b:      bool,
+    pub select: bool,
+    pub start:  bool,
+    pub up:     bool,
+    pub down:   bool,
+    pub left:   bool,
+    pub right:  bool,
+}
+
+// rldustba, the order the buttons are shifted out in is A first and Right last
+impl ButtonState {
+    pub fn to_byte(self: &ButtonState) -> u8 {
+        (self.a      as u8)
+      | ( (self.b      as u8) << 1)
+      | ( (self.select as u8) << 2)
+      | ( (self.start  as u8) << 3)
+      | ( (self.up     as u8) << 4)
+      | ( (self.down   as u8) << 5)
+      | ( (self.left   as u8) << 6)
+      | ( (self.right  as u8) << 7)
+    }
+    pub fn from_byte(buttons: u8) -> ButtonState {
+        ButtonState {
+            a      : ( buttons & 1) == 1,
+            b      : ( (buttons >> 1) & 1) == 1,
+            select : ( (buttons >> 2) & 1) == 1,
+            start  : ( (buttons >> 3) & 1) == 1,
+            up     : ( (buttons >> 4) & 1) == 1,
+            down   : ( (buttons >> 5) & 1) == 1,
+            left   : ( (buttons >> 6) & 1) == 1,
+            right  : ( (buttons >> 7) & 1) == 1,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct StandardController {
+    pub buttons: ButtonState,
+    shift_register: u8,
+    strobe: bool,
+}
+
+impl StandardController {
+    pub fn new() -> StandardController {
+        Default::default()
+    }
+
+    pub fn set_buttons(self: &mut StandardController, buttons: ButtonState) {
+        self.buttons = buttons;
+        if self.strobe { self.reload(); }
+    }
+
+    /// Handle a write to $4016, only bit 0 (the strobe) is connected.
+    pub fn write(self: &mut StandardController, val: u8) {
+        self.strobe = (val & 1) == 1;
+        if self.strobe { self.reload(); }
+    }
+
+    /// Shift out the next button, returned in bit 0.
+    pub fn read(self: &mut StandardController) -> u8 {
+        if self.strobe {
+            return self.buttons.a as u8;
+        }
+        let bit = self.shift_register & 1;
+
+        // the serial input of the 4021 is tied to ground and the output is inverted, so an
+        // official controller returns 1 for every read after the 8th
+        self.shift_register = (self.shift_register >> 1) | 0x80;
+        bit
+    }
+
+    /// Same as read but without shifting the register, for debuggers and loggers.
+    pub fn peek(self: &StandardController) -> u8 {
+        if self.strobe { self.buttons.a as u8 } else { self.shift_register & 1 }
+    }
+
+    fn reload(self: &mut StandardController) {
+        self.shift_register = self.buttons.to_byte();
+    }
+}
+
+impl Snapshot for StandardController {
+    fn save_state(self: &StandardController, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(&[self.buttons.to_byte(),self.shift_register])?;
+        savestate::write_bool(writer,self.strobe)
+    }
+    fn load_state(self: &mut StandardController, reader: &mut dyn Read) -> io::Result<()> {
+        self.buttons = ButtonState::from_byte(reader.read_u8()?);
+        self.shift_register = reader.read_u8()?;
+        self.strobe = savestate::read_bool(reader)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use std::io;
+use std::io::{Read,Write};
+use byteorder::ReadBytesExt;
+use savestate::{self,Snapshot};
+
+// http://wiki.nesdev.com/w/index.php/Zapper
+//
+// D3: light sense, 0 when light is detected
+// D4: trigger, 1 while pulled
+//
+// The photodiode only sees the pixel the gun is aimed at, and only for a short while after the
+// beam has drawn it.  The zapper has no shift register and ignores the strobe.
+//
+
+// how many scanlines the photodiode keeps reporting light after the aimed at pixel was drawn
+const LIGHT_PERSISTENCE_SCANLINES: u16 = 20;
+
+const SCREEN_WIDTH: usize = 256;
+
+#[derive(Clone, Default)]
+pub struct Zapper {
+    /// Framebuffer coordinates (x, y) the gun is aimed at, None when pointed off screen.
+    pub aim: Option<(u8, u8)>,
+    pub trigger: bool,
+    light_sensed: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Zapper {
+        Default::default()
+    }
+
+    pub fn read(self: &Zapper) -> u8 {
+        ( (!self.light_sensed as u8) << 3)
+      | ( (self.trigger       as u8) << 4)
+    }
+
+    /// Sample the framebuffer at the current beam position.  The framebuffer is 256x240 entries
+    /// in the PPU output format, with the palette index in the low 6 bits.  scanline and dot are
+    /// the PPU's current position, dot 1 draws pixel 0 of the scanline.
+    pub fn update_beam(self: &mut Zapper, framebuffer: &[u16], scanline: u16, dot: u16) {
+        self.light_sensed = match self.aim {
+            None => false,
+            Some((x, y)) => {
+                let (x, y) = (x as u16, y as u16);
+                let drawn = scanline > y || (scanline == y && dot > x);
+                let fresh = scanline >= y && scanline - y < LIGHT_PERSISTENCE_SCANLINES;
+                let pixel = framebuffer.get(y as usize * SCREEN_WIDTH + x as usize);
+                match pixel {
+                    Some(&pixel) => drawn && fresh && is_bright(pixel),
+                    None         => false,
+                }
+            }
+        };
+    }
+}
+
+// The palette is laid out as 4 rows of luminance by 16 columns of hue, columns $D-$F are black.
+// Only the two brightest rows are bright enough to trip the photodiode.
+//
+fn is_bright(pixel: u16) -> bool {
+    let hue = pixel & 0x0F;
+    let luminance = (pixel >> 4) & 0x03;
+    hue < 0x0D && luminance >= 2
+}
+
+impl Snapshot for Zapper {
+    fn save_state(self: &Zapper, writer: &mut dyn Write) -> io::Result<()> {
+        let (x,y) = self.aim.unwrap_or((0,0));
+        savestate::write_bool(writer,self.aim.is_some())?;
+        writer.write_all(&[x,y])?;
+        savestate::write_bool(writer,self.trigger)?;
+        savestate::write_bool(writer,self.light_sensed)
+    }
+    fn load_state(self: &mut Zapper, reader: &mut dyn Read) -> io::Result<()> {
+        let aimed = savestate::read_bool(reader)?;
+        let (x,y) = (reader.read_u8()?,reader.read_u8()?);
+        self.aim = if aimed { Some((x,y)) } else { None };
+        self.trigger = savestate::read_bool(reader)?;
+        self.light_sensed = savestate::read_bool(reader)?;
+        Ok(())
+    }
+}
@@ -3,3 +3,16 @@ pub mod rom_loader;
 pub mod memory;
 pub mod cpu;
 pub mod logger;
+pub mod input;
+pub mod cartridge;
+pub mod ppu;
+pub mod apu;
+pub mod nes;
+pub mod savestate;
+pub mod rewind;
+pub mod movie;
+pub mod headless;
+pub mod video;
+pub mod cli;
+#[cfg(feature = "windowed")]
+pub mod window;
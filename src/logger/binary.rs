@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader,BufWriter,Read,Write};
+use std::path::Path;
+use cpu::CpuState;
+use memory::Memory;
+use logger::{TraceSink,TraceRecord,LineFormat};
+use logger::record::RECORD_LEN;
+
+/// Starts every binary trace, the last byte is the version of the record layout.
+pub const MAGIC: [u8;8] = *b"TNTRACE\x01";
+
+// A binary trace is MAGIC followed by a fixed size record per instruction, see
+// TraceRecord::write_to for the layout.  At 28 bytes an instruction it's about a third the size
+// of the text formats, and much quicker to write.
+//
+
+/// Writes a binary trace.
+pub struct BinarySink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> BinarySink<W> {
+    /// Writes the header straight away.
+    pub fn new(mut writer: W) -> io::Result<BinarySink<W>> {
+        writer.write_all(&MAGIC)?;
+        Ok(BinarySink { writer })
+    }
+
+    /// Flush and give back the writer.
+    pub fn into_inner(mut self: BinarySink<W>) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl BinarySink<BufWriter<File>> {
+    /// Create (or truncate) the file at file_path.
+    pub fn create<P:AsRef<Path>>(file_path: P) -> io::Result<BinarySink<BufWriter<File>>> {
+        BinarySink::new(BufWriter::new(File::create(&file_path)?))
+    }
+}
+
+impl<W: Write> TraceSink for BinarySink<W> {
+    fn trace(self: &mut BinarySink<W>, cpu_state: &CpuState, mem: &Memory) -> io::Result<()> {
+        TraceRecord::capture(cpu_state,mem).write_to(&mut self.writer)
+    }
+}
+
+/// Reads the records of a binary trace back, in order.
+pub struct BinaryReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> BinaryReader<R> {
+    /// Checks the header, anything but a binary trace of this version is InvalidData.
+    pub fn new(mut reader: R) -> io::Result<BinaryReader<R>> {
+        let mut magic = [0u8;8];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,"not a trustines binary trace, or a different version"));
+        }
+        Ok(BinaryReader { reader })
+    }
+}
+
+impl BinaryReader<BufReader<File>> {
+    pub fn open<P:AsRef<Path>>(file_path: P) -> io::Result<BinaryReader<BufReader<File>>> {
+        BinaryReader::new(BufReader::new(File::open(&file_path)?))
+    }
+}
+
+impl<R: Read> Iterator for BinaryReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    // the trace ends cleanly between records, a partial record at the end is UnexpectedEof
+    fn next(self: &mut BinaryReader<R>) -> Option<io::Result<TraceRecord>> {
+        let mut buf = [0u8;RECORD_LEN];
+        let mut filled = 0;
+        while filled < RECORD_LEN {
+            match self.reader.read(&mut buf[filled..]) {
+                Ok(0) if filled == 0 => return None,
+                Ok(0)                => return Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof,"binary trace ends partway through a record"))),
+                Ok(n)                => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => { },
+                Err(e)               => return Some(Err(e)),
+            }
+        }
+        Some(TraceRecord::read_from(&mut &buf[..]))
+    }
+}
+
+/// Turn a binary trace into text, returns the number of instructions written.
+pub fn convert<R: Read, W: Write>(reader: BinaryReader<R>, writer: &mut W, format: &dyn LineFormat) -> io::Result<u64> {
+    let mut count = 0;
+    for record in reader {
+        writeln!(writer,"{}",format.format_record(&record?))?;
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,373 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use logger::{TraceFormat,ParseError,LineFormat,NesTest,BinaryReader};
+use logger::binary::MAGIC;
+
+// the pre-render scanline, Mesen numbers it -1
+const PRE_RENDER_SCANLINE: &str = "261";
+
+/// A column of a trace line that can be compared, or ignored.
+#[derive(PartialEq,Eq,PartialOrd,Ord,Clone,Copy,Debug)]
+pub enum Column {
+    Pc,
+    Bytes,
+    Disassembly,
+    A,
+    X,
+    Y,
+    P,
+    Sp,
+    Scanline,
+    Dot,
+    Cycles,
+}
+
+impl FromStr for Column {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Column,ParseError> {
+        match s.to_lowercase().as_ref() {
+            "pc"                      => Ok(Column::Pc),
+            "bytes"                   => Ok(Column::Bytes),
+            "disasm" | "disassembly"  => Ok(Column::Disassembly),
+            "a"                       => Ok(Column::A),
+            "x"                       => Ok(Column::X),
+            "y"                       => Ok(Column::Y),
+            "p"                       => Ok(Column::P),
+            "sp" | "s"                => Ok(Column::Sp),
+            "scanline" | "sl"         => Ok(Column::Scanline),
+            "dot"                     => Ok(Column::Dot),
+            "cyc" | "cycles"          => Ok(Column::Cycles),
+            _ => Err(ParseError::InvalidString(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(self: &Column, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Column::Pc          => "PC",
+            Column::Bytes       => "bytes",
+            Column::Disassembly => "disassembly",
+            Column::A           => "A",
+            Column::X           => "X",
+            Column::Y           => "Y",
+            Column::P           => "P",
+            Column::Sp          => "SP",
+            Column::Scanline    => "scanline",
+            Column::Dot         => "dot",
+            Column::Cycles      => "CYC",
+        };
+        write!(f,"{}",name)
+    }
+}
+
+/// An instruction from a trace.  The fields are normalized so traces in different formats can be
+/// compared: registers and PC are upper case hex, P is a byte even when the trace shows flag
+/// letters, and the rest are decimal.  Formats only have the columns they show.
+#[derive(PartialEq,Clone,Debug)]
+pub struct TraceLine {
+    // 1 based line in the trace file, or record for binary traces
+    pub number: usize,
+    pub text: String,
+    pub fields: BTreeMap<Column,String>,
+}
+
+pub struct Trace {
+    pub format: TraceFormat,
+    pub lines: Vec<TraceLine>,
+}
+
+/// Guess the format from the first line of a text trace.
+pub fn detect_format(line: &str) -> TraceFormat {
+    if line.contains("CPU Cycle:") {
+        TraceFormat::Mesen
+    } else if line.contains(" S:") && !line.contains("SP:") {
+        TraceFormat::Fceux
+    } else {
+        TraceFormat::Nestest
+    }
+}
+
+impl Trace {
+    /// Parse the lines of a text trace.  Lines that aren't instructions (blank lines, FCEUX's
+    /// "Log Start" and the like) are skipped.
+    pub fn parse<'a, I: IntoIterator<Item=&'a str>>(lines: I, format: TraceFormat) -> Trace {
+        let lines = lines.into_iter().enumerate().filter_map(|(i,text)| {
+            let text = text.trim_end();
+            parse_line(text,format).map(|fields| TraceLine { number: i+1, text: text.to_string(), fields })
+        }).collect();
+        Trace { format, lines }
+    }
+
+    /// Read a trace in any of the formats, format is detected when it's None.
+    pub fn read<P:AsRef<Path>>(file_path: P, format: Option<TraceFormat>) -> io::Result<Trace> {
+        let mut bytes = Vec::new();
+        File::open(&file_path)?.read_to_end(&mut bytes)?;
+
+        let text = String::from_utf8_lossy(&bytes);
+        let format = match format {
+            Some(format)                        => format,
+            None if bytes.starts_with(&MAGIC)   => TraceFormat::Binary,
+            None                                => detect_format(text.lines().find(|line| !line.trim().is_empty()).unwrap_or("")),
+        };
+        if format == TraceFormat::Binary {
+            // binary records have the same columns as nestest lines
+            let nestest = NesTest::default();
+            let mut text = Vec::new();
+            for record in BinaryReader::new(&bytes[..])? {
+                text.push(nestest.format_record(&record?));
+            }
+            let mut trace = Trace::parse(text.iter().map(|line| line.as_ref()),TraceFormat::Nestest);
+            trace.format = TraceFormat::Binary;
+            return Ok(trace);
+        }
+        Ok(Trace::parse(text.lines(),format))
+    }
+}
+
+// split "A:00 X:00 P:nvUbdIzc PPU:  0,  5 CPU Cycle:7" into key/value pairs
+fn key_values(s: &str) -> Vec<(String,String)> {
+    let mut s = s.to_string();
+    while s.contains(", ") {
+        s = s.replace(", ",",");
+    }
+    let mut tokens = s.split_whitespace();
+    let mut pairs = Vec::new();
+    while let Some(token) = tokens.next() {
+        if let Some(colon) = token.find(':') {
+            let key = token[..colon].to_string();
+            let value = if colon + 1 == token.len() { tokens.next().unwrap_or("").to_string() } else { token[colon+1..].to_string() };
+            pairs.push((key,value));
+        }
+    }
+    pairs
+}
+
+fn flags_from_letters(letters: &str) -> Option<u8> {
+    if letters.len() != 8 {
+        return None;
+    }
+    Some(letters.chars().enumerate().fold(0,|p,(i,c)| if c.is_uppercase() { p | (0x80 >> i) } else { p }))
+}
+
+fn hex_field(value: &str, digits: usize) -> Option<String> {
+    u16::from_str_radix(value,16).ok().map(|v| format!("{:0>1$X}",v,digits))
+}
+
+fn register_fields(s: &str, format: TraceFormat, fields: &mut BTreeMap<Column,String>) {
+    let pairs = key_values(s);
+    // older nestest logs have "CYC:  0 SL:241", where CYC is the ppu dot
+    let cyc_is_dot = format == TraceFormat::Mesen || pairs.iter().any(|(key,_)| key == "SL");
+
+    for (key,value) in pairs {
+        let field = match key.as_ref() {
+            "A"            => hex_field(&value,2).map(|v| (Column::A,v)),
+            "X"            => hex_field(&value,2).map(|v| (Column::X,v)),
+            "Y"            => hex_field(&value,2).map(|v| (Column::Y,v)),
+            "SP" | "S"     => hex_field(&value,2).map(|v| (Column::Sp,v)),
+            "P"            => flags_from_letters(&value).map(|p| format!("{:0>2X}",p)).or_else(|| hex_field(&value,2)).map(|v| (Column::P,v)),
+            "CYC" if cyc_is_dot => Some((Column::Dot,value)),
+            "CYC" | "Cycle" => Some((Column::Cycles,value)),
+            "SL" if value == "-1" => Some((Column::Scanline,PRE_RENDER_SCANLINE.to_string())),
+            "SL"           => Some((Column::Scanline,value)),
+            "PPU"          => {
+                let mut beam = value.splitn(2,',');
+                let scanline = beam.next().unwrap_or("").to_string();
+                fields.insert(Column::Scanline,scanline);
+                beam.next().map(|dot| (Column::Dot,dot.to_string()))
+            },
+            _ => None,
+        };
+        if let Some((column,value)) = field {
+            fields.insert(column,value);
+        }
+    }
+}
+
+// the instruction bytes are the leading tokens that are two hex digits, optionally prefixed by $,
+// the disassembly is the rest
+fn instruction_fields(s: &str, fields: &mut BTreeMap<Column,String>) {
+    let tokens: Vec<&str> = s.split_whitespace().collect();
+    let is_byte = |token: &str| {
+        let digits = token.trim_start_matches('$');
+        digits.len() == 2 && u8::from_str_radix(digits,16).is_ok()
+    };
+    let count = tokens.iter().take(3).take_while(|token| is_byte(token)).count();
+    let bytes: Vec<&str> = tokens[..count].iter().map(|token| token.trim_start_matches('$')).collect();
+    fields.insert(Column::Bytes,bytes.join(" ").to_uppercase());
+    fields.insert(Column::Disassembly,tokens[count..].join(" "));
+}
+
+fn parse_line(line: &str, format: TraceFormat) -> Option<BTreeMap<Column,String>> {
+    let mut fields = BTreeMap::new();
+    match format {
+        // C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+        // C000  $4C $F5 $C5  JMP $C5F5                  A:00 X:00 Y:00 P:24 SP:FD CYC:21  SL:0   CPU Cycle:7
+        TraceFormat::Nestest | TraceFormat::Binary | TraceFormat::Mesen => {
+            let pc = line.get(0..4).and_then(|pc| hex_field(pc,4))?;
+            // the registers come after the pc, which can itself end in "A:" on a line that isn't an
+            // instruction
+            let registers_at = line[4..].find("A:").map(|i| i + 4)?;
+            fields.insert(Column::Pc,pc);
+            instruction_fields(&line[4..registers_at],&mut fields);
+            register_fields(&line[registers_at..],format,&mut fields);
+        },
+        // c7          A:00 X:00 Y:00 S:FD P:nvUbdIzc  $C000:4C F5 C5  JMP $C5F5
+        TraceFormat::Fceux => {
+            let pc_at = line.find('$')?;
+            let pc = line.get(pc_at+1..pc_at+5).and_then(|pc| hex_field(pc,4))?;
+            fields.insert(Column::Pc,pc);
+            instruction_fields(line.get(pc_at+6..).unwrap_or(""),&mut fields);
+            register_fields(&line[..pc_at],format,&mut fields);
+            let cycles = line.split_whitespace().find(|token| token.starts_with('c') && token[1..].parse::<u64>().is_ok());
+            if let Some(cycles) = cycles {
+                fields.insert(Column::Cycles,cycles[1..].to_string());
+            }
+        },
+    }
+    Some(fields)
+}
+
+// formats that disassemble the same way can have their disassembly compared
+fn disassembly_style(format: TraceFormat) -> TraceFormat {
+    match format {
+        TraceFormat::Binary => TraceFormat::Nestest,
+        format              => format,
+    }
+}
+
+/// Where two traces first disagree.
+#[derive(PartialEq,Clone,Debug)]
+pub struct Divergence {
+    // instructions that matched before this one
+    pub instruction: usize,
+    pub columns: Vec<Column>,
+    // the flags that differ, as NV-BDIZC letters, when P differs
+    pub flags: Vec<char>,
+    pub expected: TraceLine,
+    pub actual: TraceLine,
+    // the lines leading up to the divergence, from the expected trace
+    pub before: Vec<TraceLine>,
+    // and what each trace did next
+    pub expected_after: Vec<TraceLine>,
+    pub actual_after: Vec<TraceLine>,
+}
+
+#[derive(PartialEq,Clone,Debug)]
+pub enum DiffResult {
+    Same { instructions: usize },
+    Diverged(Box<Divergence>),
+    // every aligned instruction matched, but one trace kept going
+    LengthDiffers { instructions: usize, expected_remaining: usize, actual_remaining: usize },
+}
+
+// The traces may start in different places, e.g. one from the reset vector and one from $C000,
+// so line up the first instruction of one with the first time the other reaches its pc.
+//
+fn align(expected: &Trace, actual: &Trace) -> (usize,usize) {
+    let pc = |line: &TraceLine| line.fields.get(&Column::Pc).cloned();
+    let (e,a) = match (expected.lines.first(),actual.lines.first()) {
+        (Some(e),Some(a)) => (pc(e),pc(a)),
+        _                 => return (0,0),
+    };
+    if let Some(i) = actual.lines.iter().position(|line| pc(line) == e) {
+        return (0,i);
+    }
+    if let Some(i) = expected.lines.iter().position(|line| pc(line) == a) {
+        return (i,0);
+    }
+    (0,0)
+}
+
+/// Compare two traces instruction by instruction, only looking at the columns both traces have
+/// and that aren't in ignore.  context is how many lines to keep on either side of a divergence.
+pub fn diff(expected: &Trace, actual: &Trace, ignore: &[Column], context: usize) -> DiffResult {
+    let (e_start,a_start) = align(expected,actual);
+    let compare_disassembly = disassembly_style(expected.format) == disassembly_style(actual.format);
+    let e_lines = &expected.lines[e_start..];
+    let a_lines = &actual.lines[a_start..];
+
+    for (i,(e,a)) in e_lines.iter().zip(a_lines.iter()).enumerate() {
+        let columns: Vec<Column> = e.fields.iter().filter(|&(column,value)| {
+            !ignore.contains(column)
+                && (compare_disassembly || *column != Column::Disassembly)
+                && a.fields.get(column).is_some_and(|actual_value| actual_value != value)
+        }).map(|(column,_)| *column).collect();
+        if columns.is_empty() {
+            continue;
+        }
+
+        let p = |line: &TraceLine| line.fields.get(&Column::P).and_then(|p| u8::from_str_radix(p,16).ok()).unwrap_or(0);
+        let changed = if columns.contains(&Column::P) { p(e) ^ p(a) } else { 0 };
+        let flags = "NV-BDIZC".chars().enumerate().filter(|&(bit,_)| changed & (0x80 >> bit) != 0).map(|(_,c)| c).collect();
+
+        let after = |lines: &[TraceLine]| lines.iter().skip(i+1).take(context).cloned().collect();
+        return DiffResult::Diverged(Box::new(Divergence {
+            instruction: i,
+            columns,
+            flags,
+            expected: e.clone(),
+            actual: a.clone(),
+            before: e_lines[i.saturating_sub(context)..i].to_vec(),
+            expected_after: after(e_lines),
+            actual_after: after(a_lines),
+        }));
+    }
+
+    let instructions = e_lines.len().min(a_lines.len());
+    if e_lines.len() == a_lines.len() {
+        DiffResult::Same { instructions }
+    } else {
+        DiffResult::LengthDiffers {
+            instructions,
+            expected_remaining: e_lines.len() - instructions,
+            actual_remaining: a_lines.len() - instructions,
+        }
+    }
+}
+
+impl DiffResult {
+    pub fn is_same(self: &DiffResult) -> bool {
+        matches!(*self,DiffResult::Same { .. })
+    }
+
+    /// A human readable description, for a divergence the lines around it labelled by trace.
+    pub fn report(self: &DiffResult) -> String {
+        match *self {
+            DiffResult::Same { instructions } => format!("traces match, {} instructions",instructions),
+            DiffResult::LengthDiffers { instructions, expected_remaining, actual_remaining } => {
+                format!("traces match for {} instructions, then the expected trace has {} more and the actual trace {} more",
+                        instructions,expected_remaining,actual_remaining)
+            },
+            DiffResult::Diverged(ref d) => {
+                let mut columns: Vec<String> = d.columns.iter().map(|column| column.to_string()).collect();
+                if !d.flags.is_empty() {
+                    let flags: Vec<String> = d.flags.iter().map(|flag| flag.to_string()).collect();
+                    let p = columns.iter().position(|column| column == "P").unwrap();
+                    columns[p] = format!("P ({})",flags.join(", "));
+                }
+
+                let mut s = format!("traces diverge after {} instructions, at expected line {} and actual line {}: {}\n",
+                                    d.instruction,d.expected.number,d.actual.number,columns.join(", "));
+                for line in &d.before {
+                    s.push_str(&format!("           {}\n",line.text));
+                }
+                s.push_str(&format!("expected:  {}\n",d.expected.text));
+                s.push_str(&format!("actual:    {}\n",d.actual.text));
+                for line in &d.expected_after {
+                    s.push_str(&format!("expected+  {}\n",line.text));
+                }
+                for line in &d.actual_after {
+                    s.push_str(&format!("actual+    {}\n",line.text));
+                }
+                s
+            },
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use cpu::AddressMode;
+use cpu::OpcodeTables;
+use logger::{LineFormat,TraceRecord};
+use logger::format::{hex_bytes,pad_to,flag_letters};
+
+/// Formats instructions the way the FCEUX trace logger does with cycle counting turned on.
+#[derive(Default)]
+pub struct Fceux {
+    opcodes: OpcodeTables,
+}
+
+impl Fceux {
+    pub fn new(opcodes: OpcodeTables) -> Fceux {
+        Fceux { opcodes }
+    }
+}
+
+impl LineFormat for Fceux {
+    // c7          A:00 X:00 Y:00 S:FD P:nvUbdIzc  $C000:4C F5 C5  JMP $C5F5
+    //
+    fn format_record(self: &Fceux, record: &TraceRecord) -> String {
+        let mut s = format!("c{}",record.cycles);
+        pad_to(&mut s,12);
+        s.push_str(&format!("A:{:0>2X} X:{:0>2X} Y:{:0>2X} S:{:0>2X} P:{}  ",record.a,record.x,record.y,record.sp,flag_letters(record.p)));
+
+        let column = s.len();
+        s.push_str(&format!("${:0>4X}:{}",record.pc,hex_bytes(record,"")));
+        pad_to(&mut s,column + 16);
+        s.push_str(self.opcodes.mnemonic(record.opcode()));
+        s.push_str(&operand(&self.opcodes,record));
+        s
+    }
+}
+
+// FCEUX shows effective addresses after an @ and values as immediates, e.g.
+// "$0300,X @ $0305 = #$89"
+//
+fn operand(opcodes: &OpcodeTables, record: &TraceRecord) -> String {
+    let info = opcodes.exec(record.opcode());
+    let (operand8,operand16,addr,value) = (record.operand8(),record.operand16(),record.addr,record.value);
+
+    match info.address_mode {
+        AddressMode::Implied         => String::new(),
+        AddressMode::Accumulator     => String::new(),
+        AddressMode::Immediate       => format!(" #${:0>2X}",operand8),
+        AddressMode::Relative        => format!(" ${:0>4X}",addr),
+        AddressMode::Absolute if info.opcode_class.is_jump() => format!(" ${:0>4X}",operand16),
+        AddressMode::Absolute        => format!(" ${:0>4X} = #${:0>2X}",operand16,value),
+        AddressMode::AbsoluteX       => format!(" ${:0>4X},X @ ${:0>4X} = #${:0>2X}",operand16,addr,value),
+        AddressMode::AbsoluteY       => format!(" ${:0>4X},Y @ ${:0>4X} = #${:0>2X}",operand16,addr,value),
+        AddressMode::Indirect        => format!(" (${:0>4X}) = ${:0>4X}",operand16,addr),
+        AddressMode::IndexedIndirect => format!(" (${:0>2X},X) @ ${:0>4X} = #${:0>2X}",operand8,addr,value),
+        AddressMode::IndirectIndexed => format!(" (${:0>2X}),Y @ ${:0>4X} = #${:0>2X}",operand8,addr,value),
+        AddressMode::ZeroPage        => format!(" ${:0>2X} = #${:0>2X}",operand8,value),
+        AddressMode::ZeroPageX       => format!(" ${:0>2X},X @ ${:0>4X} = #${:0>2X}",operand8,addr,value),
+        AddressMode::ZeroPageY       => format!(" ${:0>2X},Y @ ${:0>4X} = #${:0>2X}",operand8,addr,value),
+        AddressMode::ZeroPageIndirect        => format!(" (${:0>2X}) @ ${:0>4X} = #${:0>2X}",operand8,addr,value),
+        AddressMode::AbsoluteIndexedIndirect => format!(" (${:0>4X},X) = ${:0>4X}",operand16,addr),
+        AddressMode::None            => String::new(),
+    }
+}
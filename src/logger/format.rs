@@ -0,0 +1,63 @@
+use std::str::FromStr;
+use cpu::CpuState;
+use memory::Memory;
+use logger::TraceRecord;
+
+#[derive(Debug)]
+pub enum ParseError {
+    InvalidString(String)
+}
+
+/// The trace formats the logger can write, and the diff can read.
+#[derive(PartialEq,Clone,Copy,Debug)]
+pub enum TraceFormat {
+    Nestest,
+    Fceux,
+    Mesen,
+    Binary,
+}
+
+impl FromStr for TraceFormat {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<TraceFormat,ParseError> {
+        match s {
+            "nestest" => Ok(TraceFormat::Nestest),
+            "fceux"   => Ok(TraceFormat::Fceux),
+            "mesen"   => Ok(TraceFormat::Mesen),
+            "binary"  => Ok(TraceFormat::Binary),
+            _ => Err(ParseError::InvalidString(s.to_string())),
+        }
+    }
+}
+
+/// A text trace format, one line per instruction.
+pub trait LineFormat {
+    /// Format the record as a line (without the newline).
+    fn format_record(&self, record: &TraceRecord) -> String;
+
+    /// Format the instruction that was just fetched, and the registers before it executes.
+    fn format_line(&self, cpu_state: &CpuState, mem: &Memory) -> String {
+        self.format_record(&TraceRecord::capture(cpu_state,mem))
+    }
+}
+
+// the instruction bytes as hex, separated by spaces, each prefixed with prefix
+pub fn hex_bytes(record: &TraceRecord, prefix: &str) -> String {
+    let bytes: Vec<String> = record.bytes.iter().take(record.len as usize).map(|b| format!("{}{:0>2X}",prefix,b)).collect();
+    bytes.join(" ")
+}
+
+// pad s with spaces out to column
+pub fn pad_to(s: &mut String, column: usize) {
+    while s.len() < column {
+        s.push(' ');
+    }
+}
+
+// NV-BDIZC with set flags in upper case, FCEUX and Mesen both show P this way
+pub fn flag_letters(p: u8) -> String {
+    "NVUBDIZC".chars().enumerate().map(|(i,c)| {
+        if p & (0x80 >> i) != 0 { c } else { c.to_ascii_lowercase() }
+    }).collect()
+}
@@ -0,0 +1,63 @@
+use cpu::AddressMode;
+use cpu::OpcodeTables;
+use logger::{LineFormat,TraceRecord};
+use logger::format::{hex_bytes,pad_to};
+
+// the ppu's pre-render line, Mesen numbers it -1
+const PRE_RENDER_SCANLINE: u16 = 261;
+
+/// Formats instructions the way Mesen's trace logger does with its default columns.
+#[derive(Default)]
+pub struct Mesen {
+    opcodes: OpcodeTables,
+}
+
+impl Mesen {
+    pub fn new(opcodes: OpcodeTables) -> Mesen {
+        Mesen { opcodes }
+    }
+}
+
+impl LineFormat for Mesen {
+    // C000  $4C $F5 $C5  JMP $C5F5                  A:00 X:00 Y:00 P:24 SP:FD CYC:21  SL:0   CPU Cycle:7
+    //
+    fn format_record(self: &Mesen, record: &TraceRecord) -> String {
+        let mut s = format!("{:0>4X}  {}",record.pc,hex_bytes(record,"$"));
+        pad_to(&mut s,19);
+        s.push_str(self.opcodes.mnemonic(record.opcode()));
+        s.push_str(&operand(&self.opcodes,record));
+        pad_to(&mut s,46);
+
+        let scanline = if record.scanline == PRE_RENDER_SCANLINE { -1 } else { record.scanline as i32 };
+        s.push_str(&format!("A:{:0>2X} X:{:0>2X} Y:{:0>2X} P:{:0>2X} SP:{:0>2X}",record.a,record.x,record.y,record.p,record.sp));
+        s.push_str(&format!(" CYC:{:<3} SL:{:<3} CPU Cycle:{}",record.dot,scanline,record.cycles));
+        s
+    }
+}
+
+// Mesen shows effective addresses in brackets, e.g. "$0300,X [$0305] = $89"
+//
+fn operand(opcodes: &OpcodeTables, record: &TraceRecord) -> String {
+    let info = opcodes.exec(record.opcode());
+    let (operand8,operand16,addr,value) = (record.operand8(),record.operand16(),record.addr,record.value);
+
+    match info.address_mode {
+        AddressMode::Implied         => String::new(),
+        AddressMode::Accumulator     => String::new(),
+        AddressMode::Immediate       => format!(" #${:0>2X}",operand8),
+        AddressMode::Relative        => format!(" ${:0>4X}",addr),
+        AddressMode::Absolute if info.opcode_class.is_jump() => format!(" ${:0>4X}",operand16),
+        AddressMode::Absolute        => format!(" ${:0>4X} = ${:0>2X}",operand16,value),
+        AddressMode::AbsoluteX       => format!(" ${:0>4X},X [${:0>4X}] = ${:0>2X}",operand16,addr,value),
+        AddressMode::AbsoluteY       => format!(" ${:0>4X},Y [${:0>4X}] = ${:0>2X}",operand16,addr,value),
+        AddressMode::Indirect        => format!(" (${:0>4X}) [${:0>4X}]",operand16,addr),
+        AddressMode::IndexedIndirect => format!(" (${:0>2X},X) [${:0>4X}] = ${:0>2X}",operand8,addr,value),
+        AddressMode::IndirectIndexed => format!(" (${:0>2X}),Y [${:0>4X}] = ${:0>2X}",operand8,addr,value),
+        AddressMode::ZeroPage        => format!(" ${:0>2X} = ${:0>2X}",operand8,value),
+        AddressMode::ZeroPageX       => format!(" ${:0>2X},X [${:0>4X}] = ${:0>2X}",operand8,addr,value),
+        AddressMode::ZeroPageY       => format!(" ${:0>2X},Y [${:0>4X}] = ${:0>2X}",operand8,addr,value),
+        AddressMode::ZeroPageIndirect        => format!(" (${:0>2X}) [${:0>4X}] = ${:0>2X}",operand8,addr,value),
+        AddressMode::AbsoluteIndexedIndirect => format!(" (${:0>4X},X) [${:0>4X}]",operand16,addr),
+        AddressMode::None            => String::new(),
+    }
+}
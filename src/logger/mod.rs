@@ -0,0 +1,29 @@
+//public mods
+pub mod nestest;
+pub mod fceux;
+pub mod mesen;
+pub mod binary;
+pub mod record;
+pub mod sink;
+pub mod diff;
+
+//private mods
+mod format;
+
+// hoisted interfaces
+pub use self::format::TraceFormat;
+pub use self::format::ParseError;
+pub use self::format::LineFormat;
+pub use self::nestest::NesTest;
+pub use self::fceux::Fceux;
+pub use self::mesen::Mesen;
+pub use self::binary::BinarySink;
+pub use self::binary::BinaryReader;
+pub use self::record::TraceRecord;
+pub use self::sink::TraceSink;
+pub use self::sink::WriteSink;
+pub use self::sink::FileSink;
+pub use self::sink::StdoutSink;
+pub use self::sink::RingBufferSink;
+pub use self::sink::NullSink;
+pub use self::sink::GatedSink;
@@ -0,0 +1,70 @@
+use cpu::AddressMode;
+use cpu::OpcodeTables;
+use logger::{LineFormat,TraceRecord};
+use logger::format::{hex_bytes,pad_to};
+
+/// Formats instructions the way nestest.log (Nintendulator) does, see the sinks for where the
+/// lines go.
+#[derive(Default)]
+pub struct NesTest {
+    opcodes: OpcodeTables,
+}
+
+impl NesTest {
+    pub fn new(opcodes: OpcodeTables) -> NesTest {
+        NesTest { opcodes }
+    }
+}
+
+impl LineFormat for NesTest {
+    // C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+    //
+    fn format_record(self: &NesTest, record: &TraceRecord) -> String {
+        let info = self.opcodes.exec(record.opcode());
+
+        // nestest marks illegal opcodes with a * in place of the space before the mnemonic
+        let mark = if info.opcode_class.is_illegal() { '*' } else { ' ' };
+        let mut s = format!("{:0>4X}  {:<9}{}{}{}",record.pc,hex_bytes(record,""),mark,self.opcodes.mnemonic(record.opcode()),operand(&self.opcodes,record));
+
+        pad_to(&mut s,48);
+        s.push_str(&format!("A:{:0>2X}",record.a));
+        s.push_str(&format!(" X:{:0>2X}",record.x));
+        s.push_str(&format!(" Y:{:0>2X}",record.y));
+        s.push_str(&format!(" P:{:0>2X}",record.p));
+        s.push_str(&format!(" SP:{:0>2X}",record.sp));
+        s.push_str(&format!(" PPU:{:>3},{:>3}",record.scanline,record.dot));
+        s.push_str(&format!(" CYC:{}",record.cycles));
+        s
+    }
+}
+
+// The operand as nestest.log shows it.  Instructions that access memory are followed by the
+// value there before the instruction executes, e.g. "$0300,X @ 0305 = 89".  The emulator that
+// made nestest.log shows the apu and io registers as FF whatever they read as.
+//
+fn operand(opcodes: &OpcodeTables, record: &TraceRecord) -> String {
+    let info = opcodes.exec(record.opcode());
+    let (operand8,operand16,addr) = (record.operand8(),record.operand16(),record.addr);
+    let value = if (0x4000..=0x401F).contains(&addr) { 0xFF } else { record.value };
+
+    match info.address_mode {
+        AddressMode::Implied         => String::new(),
+        AddressMode::Accumulator     => " A".to_string(),
+        AddressMode::Immediate       => format!(" #${:0>2X}",operand8),
+        AddressMode::Relative        => format!(" ${:0>4X}",addr),
+        AddressMode::Absolute if info.opcode_class.is_jump() => format!(" ${:0>4X}",operand16),
+        AddressMode::Absolute        => format!(" ${:0>4X} = {:0>2X}",operand16,value),
+        AddressMode::AbsoluteX       => format!(" ${:0>4X},X @ {:0>4X} = {:0>2X}",operand16,addr,value),
+        AddressMode::AbsoluteY       => format!(" ${:0>4X},Y @ {:0>4X} = {:0>2X}",operand16,addr,value),
+        // nestest.log shows the pointer without the page wrapping bug, the jump itself goes to addr
+        AddressMode::Indirect        => format!(" (${:0>4X}) = {:0>4X}",operand16,record.pointer),
+        AddressMode::IndexedIndirect => format!(" (${:0>2X},X) @ {:0>2X} = {:0>4X} = {:0>2X}",operand8,record.pointer,addr,value),
+        AddressMode::IndirectIndexed => format!(" (${:0>2X}),Y = {:0>4X} @ {:0>4X} = {:0>2X}",operand8,record.pointer,addr,value),
+        AddressMode::ZeroPage        => format!(" ${:0>2X} = {:0>2X}",operand8,value),
+        AddressMode::ZeroPageX       => format!(" ${:0>2X},X @ {:0>2X} = {:0>2X}",operand8,addr,value),
+        AddressMode::ZeroPageY       => format!(" ${:0>2X},Y @ {:0>2X} = {:0>2X}",operand8,addr,value),
+        AddressMode::ZeroPageIndirect        => format!(" (${:0>2X}) = {:0>4X} = {:0>2X}",operand8,addr,value),
+        AddressMode::AbsoluteIndexedIndirect => format!(" (${:0>4X},X) @ {:0>4X} = {:0>4X}",operand16,record.pointer,addr),
+        AddressMode::None            => String::new(),
+    }
+}
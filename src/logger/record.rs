@@ -0,0 +1,139 @@
+use std::io;
+use std::io::{Read,Write};
+use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
+use cpu::AddressMode;
+use cpu::CpuState;
+use memory::Memory;
+
+/// Bytes in a binary trace record, see TraceRecord::write_to.
+pub const RECORD_LEN: usize = 28;
+
+/// Everything the trace formats show about one instruction, taken after it has been fetched and
+/// decoded but before it executes.  Keeping this separate from the cpu lets binary traces be
+/// turned back into any of the text formats.
+#[derive(PartialEq,Clone,Copy,Debug,Default)]
+pub struct TraceRecord {
+    pub pc: u16,
+    // the instruction, only the first len bytes are meaningful
+    pub bytes: [u8;3],
+    pub len: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    // the effective address, or the target of a branch or jump
+    pub addr: u16,
+    // the zero page pointer of (d,X), the base address of (d),Y, and where JMP (a) goes without
+    // the page wrapping bug
+    pub pointer: u16,
+    // what was at addr before the instruction executes
+    pub value: u8,
+    pub scanline: u16,
+    pub dot: u16,
+    pub cycles: u64,
+}
+
+impl TraceRecord {
+    /// Capture the instruction the cpu just fetched.
+    pub fn capture(cpu_state: &CpuState, mem: &Memory) -> TraceRecord {
+        let pc = cpu_state.pc.wrapping_sub(1);
+        let dr = &cpu_state.decode_register;
+
+        let mut bytes = [0u8;3];
+        for (i,byte) in bytes.iter_mut().enumerate().take(dr.info.len as usize) {
+            *byte = mem.peek8(pc.wrapping_add(i as u16)).unwrap_or(0);
+        }
+
+        // stores don't read their target while decoding, so look at what's there before the
+        // write
+        let value = match (dr.value_final,dr.addr_final) {
+            (Some(val),_) => val,
+            (None,addr)   => addr.and_then(|addr| mem.peek8(addr).ok()).unwrap_or(0),
+        };
+
+        let pointer = match dr.info.address_mode {
+            AddressMode::Indirect => {
+                let operand = bytes[2] as u16 * 256 + bytes[1] as u16;
+                mem.peek8(operand.wrapping_add(1)).unwrap_or(0) as u16 * 256 + mem.peek8(operand).unwrap_or(0) as u16
+            },
+            _ => dr.addr_intermediate.unwrap_or(0),
+        };
+
+        TraceRecord {
+            pc,
+            bytes,
+            len: dr.info.len,
+            a: cpu_state.a,
+            x: cpu_state.x,
+            y: cpu_state.y,
+            p: cpu_state.unpack_flags(),
+            sp: cpu_state.sp,
+            addr: dr.addr_final.unwrap_or(0),
+            pointer,
+            value,
+            scanline: mem.ppu.scanline,
+            dot: mem.ppu.dot,
+            cycles: cpu_state.cycles,
+        }
+    }
+
+    pub fn opcode(self: &TraceRecord) -> u8 {
+        self.bytes[0]
+    }
+    /// The one byte operand, or the low byte of a two byte one.
+    pub fn operand8(self: &TraceRecord) -> u8 {
+        self.bytes[1]
+    }
+    pub fn operand16(self: &TraceRecord) -> u16 {
+        self.bytes[2] as u16 * 256 + self.bytes[1] as u16
+    }
+
+    // all fields little endian:
+    //
+    //  0 pc        u16
+    //  2 bytes     [u8;3]
+    //  5 len       u8
+    //  6 a x y p sp
+    // 11 value     u8
+    // 12 addr      u16
+    // 14 pointer   u16
+    // 16 scanline  u16
+    // 18 dot       u16
+    // 20 cycles    u64
+    //
+    pub fn write_to<W: Write>(self: &TraceRecord, writer: &mut W) -> io::Result<()> {
+        writer.write_u16::<LittleEndian>(self.pc)?;
+        writer.write_all(&self.bytes)?;
+        writer.write_all(&[self.len,self.a,self.x,self.y,self.p,self.sp,self.value])?;
+        writer.write_u16::<LittleEndian>(self.addr)?;
+        writer.write_u16::<LittleEndian>(self.pointer)?;
+        writer.write_u16::<LittleEndian>(self.scanline)?;
+        writer.write_u16::<LittleEndian>(self.dot)?;
+        writer.write_u64::<LittleEndian>(self.cycles)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<TraceRecord> {
+        let pc = reader.read_u16::<LittleEndian>()?;
+        let mut bytes = [0u8;3];
+        reader.read_exact(&mut bytes)?;
+        let mut regs = [0u8;7];
+        reader.read_exact(&mut regs)?;
+        Ok(TraceRecord {
+            pc,
+            bytes,
+            len: regs[0],
+            a: regs[1],
+            x: regs[2],
+            y: regs[3],
+            p: regs[4],
+            sp: regs[5],
+            value: regs[6],
+            addr: reader.read_u16::<LittleEndian>()?,
+            pointer: reader.read_u16::<LittleEndian>()?,
+            scanline: reader.read_u16::<LittleEndian>()?,
+            dot: reader.read_u16::<LittleEndian>()?,
+            cycles: reader.read_u64::<LittleEndian>()?,
+        })
+    }
+}
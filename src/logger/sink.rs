@@ -0,0 +1,210 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::collections::vec_deque;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter,Stdout,Write};
+use std::path::Path;
+use std::rc::Rc;
+use cpu::CpuState;
+use memory::Memory;
+use logger::LineFormat;
+
+/// Where the trace goes.  Called once per instruction, after it has been fetched and decoded but
+/// before it executes, so the registers are the ones the instruction starts with.
+pub trait TraceSink {
+    fn trace(&mut self, cpu_state: &CpuState, mem: &Memory) -> io::Result<()>;
+}
+
+/// The address of the instruction being traced, the cpu has already fetched the opcode.
+pub fn instruction_pc(cpu_state: &CpuState) -> u16 {
+    cpu_state.pc.wrapping_sub(1)
+}
+
+// keeps a handle to the sink, e.g. to look at a ring buffer after the console stops
+impl<S: TraceSink> TraceSink for Rc<RefCell<S>> {
+    fn trace(self: &mut Rc<RefCell<S>>, cpu_state: &CpuState, mem: &Memory) -> io::Result<()> {
+        self.borrow_mut().trace(cpu_state,mem)
+    }
+}
+
+impl<S: TraceSink + ?Sized> TraceSink for Box<S> {
+    fn trace(self: &mut Box<S>, cpu_state: &CpuState, mem: &Memory) -> io::Result<()> {
+        (**self).trace(cpu_state,mem)
+    }
+}
+
+// trace to every sink in turn
+impl TraceSink for Vec<Box<dyn TraceSink>> {
+    fn trace(self: &mut Vec<Box<dyn TraceSink>>, cpu_state: &CpuState, mem: &Memory) -> io::Result<()> {
+        for sink in self.iter_mut() {
+            sink.trace(cpu_state,mem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes a line per instruction in any of the text formats.
+pub struct WriteSink<W: Write> {
+    writer: W,
+    format: Box<dyn LineFormat>,
+}
+
+pub type FileSink = WriteSink<BufWriter<File>>;
+pub type StdoutSink = WriteSink<Stdout>;
+
+impl<W: Write> WriteSink<W> {
+    pub fn new<F: LineFormat + 'static>(writer: W, format: F) -> WriteSink<W> {
+        WriteSink { writer, format: Box::new(format) }
+    }
+
+    /// Flush and give back the writer.
+    pub fn into_inner(mut self: WriteSink<W>) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl WriteSink<BufWriter<File>> {
+    /// Create (or truncate) the file at file_path.
+    pub fn create<P:AsRef<Path>,F: LineFormat + 'static>(file_path: P, format: F) -> io::Result<FileSink> {
+        Ok(WriteSink::new(BufWriter::new(File::create(&file_path)?),format))
+    }
+}
+
+impl WriteSink<Stdout> {
+    pub fn stdout<F: LineFormat + 'static>(format: F) -> StdoutSink {
+        WriteSink::new(io::stdout(),format)
+    }
+}
+
+impl<W: Write> TraceSink for WriteSink<W> {
+    fn trace(self: &mut WriteSink<W>, cpu_state: &CpuState, mem: &Memory) -> io::Result<()> {
+        writeln!(self.writer,"{}",self.format.format_line(cpu_state,mem))
+    }
+}
+
+/// Keeps the last capacity instructions in memory, meant for dumping what led up to a crash.
+pub struct RingBufferSink {
+    lines: VecDeque<String>,
+    capacity: usize,
+    format: Box<dyn LineFormat>,
+}
+
+impl RingBufferSink {
+    pub fn new<F: LineFormat + 'static>(capacity: usize, format: F) -> RingBufferSink {
+        RingBufferSink {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+            format: Box::new(format),
+        }
+    }
+
+    /// Oldest first.
+    pub fn lines(self: &RingBufferSink) -> vec_deque::Iter<'_,String> {
+        self.lines.iter()
+    }
+
+    /// Write the buffered lines, oldest first.
+    pub fn dump<W: Write>(self: &RingBufferSink, writer: &mut W) -> io::Result<()> {
+        for line in &self.lines {
+            writeln!(writer,"{}",line)?;
+        }
+        Ok(())
+    }
+}
+
+impl TraceSink for RingBufferSink {
+    fn trace(self: &mut RingBufferSink, cpu_state: &CpuState, mem: &Memory) -> io::Result<()> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(self.format.format_line(cpu_state,mem));
+        Ok(())
+    }
+}
+
+/// Throws everything away.
+#[derive(Default)]
+pub struct NullSink;
+
+impl TraceSink for NullSink {
+    fn trace(self: &mut NullSink, _: &CpuState, _: &Memory) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decides whether a GatedSink starts tracing at this instruction.
+pub type Trigger = Box<dyn FnMut(&CpuState,&Memory) -> bool>;
+
+/// Only passes instructions on to another sink when they're inside a pc range and/or after a
+/// trigger has fired.  With both set an instruction has to be in range after the trigger.
+pub struct GatedSink<S: TraceSink> {
+    sink: S,
+    pc_range: Option<(u16,u16)>,
+    trigger: Option<Trigger>,
+    triggered: bool,
+}
+
+impl<S: TraceSink> GatedSink<S> {
+    /// Without a range or trigger every instruction is passed on.
+    pub fn new(sink: S) -> GatedSink<S> {
+        GatedSink {
+            sink,
+            pc_range: None,
+            trigger: None,
+            triggered: false,
+        }
+    }
+
+    /// Only trace instructions at first..=last.
+    pub fn pc_range(mut self: GatedSink<S>, first: u16, last: u16) -> GatedSink<S> {
+        self.pc_range = Some((first,last));
+        self
+    }
+
+    /// Start tracing at the first instruction the condition is true for, and keep tracing from
+    /// then on.
+    pub fn trigger<F>(mut self: GatedSink<S>, condition: F) -> GatedSink<S>
+        where F: FnMut(&CpuState,&Memory) -> bool + 'static {
+        self.trigger = Some(Box::new(condition));
+        self.triggered = false;
+        self
+    }
+
+    /// Start tracing the first time the cpu reaches pc.
+    pub fn trigger_at(self: GatedSink<S>, pc: u16) -> GatedSink<S> {
+        self.trigger(move |cpu_state,_| instruction_pc(cpu_state) == pc)
+    }
+
+    pub fn is_triggered(self: &GatedSink<S>) -> bool {
+        self.triggered || self.trigger.is_none()
+    }
+
+    pub fn into_inner(self: GatedSink<S>) -> S {
+        self.sink
+    }
+}
+
+impl<S: TraceSink> TraceSink for GatedSink<S> {
+    fn trace(self: &mut GatedSink<S>, cpu_state: &CpuState, mem: &Memory) -> io::Result<()> {
+        if !self.triggered {
+            if let Some(ref mut condition) = self.trigger {
+                if !condition(cpu_state,mem) {
+                    return Ok(());
+                }
+                self.triggered = true;
+            }
+        }
+        if let Some((first,last)) = self.pc_range {
+            let pc = instruction_pc(cpu_state);
+            if pc < first || pc > last {
+                return Ok(());
+            }
+        }
+        self.sink.trace(cpu_state,mem)
+    }
+}
@@ -1,80 +1,237 @@
-extern crate byteorder;
-use std::io::Cursor;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use input::InputPorts;
+use ppu::Ppu;
+use apu::Apu;
+use cartridge::Cartridge;
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{Read,Write};
+use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
+use savestate::{self,Snapshot};
 
-#[derive(Debug)]
+// the address that couldn't be accessed
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
 pub enum MemoryError {
-  PPUAccessViolation(String),
-  APUAccessViolation(String),
+  PPUAccessViolation(u16),
+  APUAccessViolation(u16),
 }
 
+impl MemoryError {
+    pub fn addr(self: &MemoryError) -> u16 {
+        match *self {
+            MemoryError::PPUAccessViolation(addr) | MemoryError::APUAccessViolation(addr) => addr,
+        }
+    }
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(self: &MemoryError, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryError::PPUAccessViolation(addr) => write!(f,"${:0>4X} is a PPU register",addr),
+            MemoryError::APUAccessViolation(addr) => write!(f,"${:0>4X} is an APU/IO register that is not implemented",addr),
+        }
+    }
+}
+impl error::Error for MemoryError {}
+
+/// One access the cpu made to the bus.
+#[derive(PartialEq,Eq,Clone,Copy,Debug)]
+pub struct BusCycle {
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+// cpu cycles the OAM DMA at $4014 halts the cpu for, one more when the write lands on an odd
+// cycle and the DMA has to wait to line up with the APU's read/write cycles
+// http://wiki.nesdev.com/w/index.php/DMA#OAM_DMA
+const OAM_DMA_CYCLES: u64 = 513;
+
+// The cpu's view of the system.  Without a cartridge everything from $4020 up is plain ram,
+// which is what the tests and bin.rs use to load a flat 64KB image.
+//
 pub struct Memory {
     pub mem: Vec<u8>,
+    pub input: InputPorts,
+    pub ppu: Ppu,
+    pub apu: Apu,
+    pub cartridge: Option<Cartridge>,
+
+    // cycles the cpu has been stalled by DMA, collected by whoever is counting cycles
+    pub dma_cycles: u64,
+
+    // a $4014 write that align_dma hasn't been told the cycle of yet
+    dma_unaligned: bool,
+
+    // every read and write the cpu makes is appended here while it's Some
+    pub bus_log: Option<Vec<BusCycle>>,
+
+    // 64KB of ram and nothing else, no mirrors or registers
+    flat: bool,
+
+    // the last value driven on the data bus, undriven bits of a read return whatever was here
+    open_bus: u8,
 }
 
 
+impl Default for Memory {
+    fn default() -> Memory {
+        Memory::new()
+    }
+}
+
 impl Memory {
     pub fn new() -> Memory {
-        let mut m =  Memory {
-            mem: Vec::with_capacity(::std::u16::MAX as usize +1)
-        };
-        unsafe { &m.mem.set_len(::std::u16::MAX as usize +1); }
-        return m;
+        Memory {
+            // real ram powers up with indeterminate contents, zeroes match nestest.log
+            mem: vec![0; u16::MAX as usize +1],
+            input: InputPorts::new(),
+            ppu: Ppu::new(),
+            apu: Apu::new(),
+            cartridge: None,
+            dma_cycles: 0,
+            dma_unaligned: false,
+            bus_log: None,
+            flat: false,
+            open_bus: 0,
+        }
+    }
+
+    // a bare cpu bus for testing the cpu on its own, e.g. with the ProcessorTests corpus
+    //
+    pub fn flat() -> Memory {
+        Memory { flat: true, ..Memory::new() }
     }
 
     // meant for a 'raw' write interface, not meant to be used by the 6502 processor itself, more
-    // tests and other tools to be able to read blocks of memory quickly and easily
+    // tests and other tools to be able to read blocks of memory quickly and easily.  returns the
+    // number of bytes written, like io::Write
     //
-    pub fn write(self:&mut Memory, index:u16, inp: &[u8]) {
+    pub fn write(self:&mut Memory, index:u16, inp: &[u8]) -> usize {
         let idx = index as usize;
         if inp.len() + idx > self.mem.len() {
             panic!("memory vec length is {}, input array goes from {} to {}",self.mem.len(),index,inp.len()+idx)
         }
-        &self.mem[idx..(idx+inp.len())].clone_from_slice(inp);
+        self.mem[idx..(idx+inp.len())].clone_from_slice(inp);
+        inp.len()
     }
-    pub fn read8(self:&Memory,addr: u16) -> Result<u8,MemoryError> {
-        match self.resolve_address(addr) {
-            Ok(raddr) => Ok(self.mem[raddr]),
-            Err(err) => Err(err)
+    pub fn read8(self:&mut Memory,addr: u16) -> Result<u8,MemoryError> {
+        let val = match addr {
+            _ if self.flat  => self.mem[addr as usize],
+            0x2000..=0x3FFF => self.ppu.read_register(addr,self.cartridge.as_ref()),
+            // bit 5 of the apu status is open bus
+            0x4015          => (self.open_bus & 0x20) | self.apu.read_status(),
+            // controllers only drive the low bits, the upper 3 bits are open bus
+            0x4016 | 0x4017 => {
+                self.input.update_beam(&self.ppu.framebuffer,self.ppu.scanline,self.ppu.dot);
+                (self.open_bus & 0xE0) | self.input.read((addr - 0x4016) as usize)
+            },
+            // $4018-$401F is the cpu test mode, disabled on a retail console
+            0x4000..=0x4014 | 0x4018..=0x401F => self.open_bus,
+            0x4020..=0xFFFF if self.cartridge.is_some() => {
+                let open_bus = self.open_bus;
+                self.cartridge.as_ref().and_then(|c| c.cpu_read(addr)).unwrap_or(open_bus)
+            },
+            _ => self.mem[self.resolve_address(addr)?],
+        };
+        self.open_bus = val;
+        if let Some(ref mut log) = self.bus_log {
+            log.push(BusCycle { addr, value: val, write: false });
         }
+        Ok(val)
     }
-    pub fn read16(self:&Memory,addr: u16)  -> Result<u16,MemoryError> {
-        match self.resolve_address(addr) {
-            Ok(raddr) =>{
-                // splices are exclusive on the upper range (half open)
-                let mut rdr = Cursor::new(&self.mem[raddr..(raddr+2)]);
-                Ok(rdr.read_u16::<LittleEndian>().unwrap())
+    // same as read8, but without side effects (shifting controllers, clearing vblank, updating
+    // the open bus).  meant for loggers and debuggers that need to look at memory without
+    // disturbing it.
+    //
+    pub fn peek8(self:&Memory,addr: u16) -> Result<u8,MemoryError> {
+        match addr {
+            _ if self.flat  => Ok(self.mem[addr as usize]),
+            0x2000..=0x3FFF => Ok(self.ppu.peek_register(addr)),
+            0x4015          => Ok((self.open_bus & 0x20) | self.apu.peek_status()),
+            0x4016 | 0x4017 => Ok((self.open_bus & 0xE0) | self.input.peek((addr - 0x4016) as usize)),
+            0x4000..=0x4014 | 0x4018..=0x401F => Ok(self.open_bus),
+            0x4020..=0xFFFF if self.cartridge.is_some() => {
+                Ok(self.cartridge.as_ref().and_then(|c| c.cpu_read(addr)).unwrap_or(self.open_bus))
             },
-            Err(err) => Err(err)
+            _ => Ok(self.mem[self.resolve_address(addr)?]),
         }
     }
+    // little endian, the high byte comes from addr+1 wrapping around at $FFFF
+    pub fn read16(self:&mut Memory,addr: u16)  -> Result<u16,MemoryError> {
+        let lo = self.read8(addr)? as u16;
+        let hi = self.read8(addr.wrapping_add(1))? as u16;
+        Ok((hi << 8) | lo)
+    }
     pub fn write8(self:&mut Memory,addr: u16, val:u8) -> Result<(),MemoryError> {
-        match self.resolve_address(addr) {
-            Ok(raddr) => {
+        self.open_bus = val;
+        if let Some(ref mut log) = self.bus_log {
+            log.push(BusCycle { addr, value: val, write: true });
+        }
+        match addr {
+            _ if self.flat  => self.mem[addr as usize] = val,
+            0x2000..=0x3FFF => self.ppu.write_register(addr,val,self.cartridge.as_mut()),
+            // http://wiki.nesdev.com/w/index.php/PPU_registers#OAMDMA
+            0x4014          => {
+                let page = (val as u16) << 8;
+                for i in 0..256 {
+                    let byte = self.read8(page | i)?;
+                    self.ppu.write_oam(byte);
+                }
+                self.dma_cycles += OAM_DMA_CYCLES;
+                self.dma_unaligned = true;
+            },
+            0x4016          => self.input.write_strobe(val),
+            0x4000..=0x4017 => self.apu.write_register(addr,val),
+            0x4018..=0x401F => { },
+            0x4020..=0xFFFF if self.cartridge.is_some() => {
+                if let Some(ref mut c) = self.cartridge { c.cpu_write(addr,val); }
+            },
+            _ => {
+                let raddr = self.resolve_address(addr)?;
                 self.mem[raddr] = val;
-                Ok(())
             },
-            Err(err) => Err(err)
         }
+        Ok(())
     }
     pub fn write16(self:&mut Memory,addr: u16, val:u16) -> Result<(),MemoryError> {
-        match self.resolve_address(addr) {
-            Ok(raddr) => {
-                // splices are exclusive on the upper range (half open)
-                (&mut self.mem[raddr..(raddr+2)]).write_u16::<LittleEndian>(val).unwrap();
-                Ok(())
-            },
-            Err(err) => Err(err)
+        self.write8(addr,val as u8)?;
+        self.write8(addr.wrapping_add(1),(val >> 8) as u8)?;
+        Ok(())
+    }
+
+    /// Tell the bus which cpu cycle the last access landed on, for whoever is counting cycles.
+    /// Adds the alignment cycle to an OAM DMA started on an odd cycle.
+    pub fn align_dma(self: &mut Memory, cycle: u64) {
+        if self.dma_unaligned && cycle % 2 == 1 {
+            self.dma_cycles += 1;
         }
+        self.dma_unaligned = false;
     }
 
     // https://en.wikibooks.org/wiki/NES_Programming/Memory_Map
     //
+    // maps addresses that are backed by self.mem, the registers are handled by the callers
     fn resolve_address(self:&Memory,addr: u16) -> Result<usize,MemoryError> {
         if addr <= 0x1FFF { return Ok( (addr & 0x7FF) as usize); }
-        if addr <= 0x2007 { return Err(MemoryError::PPUAccessViolation("PPU Memory access is currently not implemented".to_string())); }
-        if addr <= 0x3FFF { return Ok( (addr & 0x7FF & 0x08) as usize); }
-        if addr <= 0x401F { return Err(MemoryError::APUAccessViolation("Memory access is currently not implemented".to_string())); }
+        if addr <= 0x3FFF { return Err(MemoryError::PPUAccessViolation(addr)); }
+        if addr <= 0x401F { return Err(MemoryError::APUAccessViolation(addr)); }
         Ok(addr as usize)
     }
 }
+
+// only what the cpu bus holds itself, the ppu, apu, controllers and cartridge save their own
+impl Snapshot for Memory {
+    fn save_state(self: &Memory, writer: &mut dyn Write) -> io::Result<()> {
+        savestate::write_block(writer,&self.mem)?;
+        writer.write_u8(self.open_bus)?;
+        writer.write_u64::<LittleEndian>(self.dma_cycles)
+    }
+
+    fn load_state(self: &mut Memory, reader: &mut dyn Read) -> io::Result<()> {
+        savestate::read_block(reader,&mut self.mem)?;
+        self.open_bus = reader.read_u8()?;
+        self.dma_cycles = reader.read_u64::<LittleEndian>()?;
+        Ok(())
+    }
+}
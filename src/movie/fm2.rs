@@ -0,0 +1,164 @@
+use std::fs::File;
+use std::io::{BufRead,BufReader,BufWriter,Write};
+use std::path::Path;
+use input::ButtonState;
+use movie::{Movie,MovieStart,MovieError,FrameInput};
+
+// http://fceux.com/web/help/fm2.html
+//
+// FCEUX's text movie format.  A header of 'key value' lines, then one line per frame:
+//
+//   |commands|port0|port1|port2|
+//
+// commands is a bit field, 1 is a soft reset and 2 a hard reset (power).  A gamepad is 8
+// characters for the buttons RLDUTSBA (T is start, S select), '.' or ' ' when released and
+// anything else when pressed.  With the four score the line has the 4 gamepads then port2.
+//
+// Only gamepads are supported, and only movies that start from power on.  An FM2 movie that
+// starts from a save state holds an FCEUX save state, which can't be loaded here.
+//
+
+const BUTTON_ORDER: &str = "RLDUTSBA";
+
+const COMMAND_RESET: u32 = 1;
+const COMMAND_POWER: u32 = 2;
+
+// header keys that are read into the Movie, everything else goes in extra_header
+const KNOWN_KEYS: [&str;11] = ["version","emuVersion","rerecordCount","palFlag","fourscore","port0","port1","port2",
+                               "FDS","binary","savestate"];
+
+/// Read an FM2 movie.
+pub fn read<R: BufRead>(reader: R) -> Result<Movie,MovieError> {
+    let mut movie = Movie::new();
+    let mut ports = [1,1];
+    for (i,line) in reader.lines().enumerate() {
+        let line = line?;
+        let number = i + 1;
+        let parse_error = |message: String| MovieError::Parse { line: number, message };
+
+        if line.starts_with('|') {
+            movie.frames.push(read_frame(&line,movie.four_score,ports).map_err(parse_error)?);
+            continue;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (key,value) = match line.find(' ') {
+            Some(space) => (&line[..space],line[space+1..].trim_end()),
+            None        => (line.trim_end(),""),
+        };
+        let number_value = || value.parse::<u32>().map_err(|_| parse_error(format!("{} should be a number, not '{}'",key,value)));
+        match key {
+            "version"       => if number_value()? != 3 { return Err(MovieError::Unsupported(format!("FM2 version {}",value))); },
+            "rerecordCount" => movie.rerecord_count = number_value()?,
+            "fourscore"     => movie.four_score = number_value()? != 0,
+            "port0" | "port1" => {
+                let port = number_value()?;
+                if port > 1 {
+                    return Err(MovieError::Unsupported(format!("input device {}, only gamepads are emulated",port)));
+                }
+                ports[if key == "port0" { 0 } else { 1 }] = port;
+            },
+            "palFlag"       => if number_value()? != 0 { return Err(MovieError::Unsupported("PAL movies".to_string())); },
+            "FDS"           => if number_value()? != 0 { return Err(MovieError::Unsupported("Famicom Disk System movies".to_string())); },
+            "binary"        => if number_value()? != 0 { return Err(MovieError::Unsupported("binary input".to_string())); },
+            "port2"         => if number_value()? != 0 { return Err(MovieError::Unsupported("expansion port devices".to_string())); },
+            "savestate"     => return Err(MovieError::Unsupported("movies that start from an FCEUX save state".to_string())),
+            "emuVersion"    => { },
+            _               => movie.extra_header.push((key.to_string(),value.to_string())),
+        }
+    }
+    Ok(movie)
+}
+
+pub fn load<P:AsRef<Path>>(file_path: P) -> Result<Movie,MovieError> {
+    read(BufReader::new(File::open(&file_path)?))
+}
+
+/// Write the movie as FM2.  Movies that start from a save state can't be written, see above.
+pub fn write<W: Write>(movie: &Movie, writer: &mut W) -> Result<(),MovieError> {
+    if movie.start != MovieStart::PowerOn {
+        return Err(MovieError::Unsupported("FM2 movies can't start from a trustines save state".to_string()));
+    }
+    writeln!(writer,"version 3")?;
+    writeln!(writer,"emuVersion 22020")?;
+    writeln!(writer,"rerecordCount {}",movie.rerecord_count)?;
+    writeln!(writer,"palFlag 0")?;
+    writeln!(writer,"fourscore {}",movie.four_score as u8)?;
+    writeln!(writer,"port0 1")?;
+    writeln!(writer,"port1 1")?;
+    writeln!(writer,"port2 0")?;
+    writeln!(writer,"FDS 0")?;
+    for (key,value) in movie.extra_header.iter().filter(|(key,_)| !KNOWN_KEYS.contains(&key.as_str())) {
+        writeln!(writer,"{} {}",key,value)?;
+    }
+
+    let controllers = if movie.four_score { 4 } else { 2 };
+    for frame in movie.frames.iter() {
+        let commands = (frame.reset as u32 * COMMAND_RESET) | (frame.power as u32 * COMMAND_POWER);
+        write!(writer,"|{}|",commands)?;
+        for buttons in frame.buttons.iter().take(controllers) {
+            write!(writer,"{}|",write_gamepad(buttons))?;
+        }
+        writeln!(writer,"|")?;
+    }
+    Ok(())
+}
+
+pub fn save<P:AsRef<Path>>(movie: &Movie, file_path: P) -> Result<(),MovieError> {
+    let mut writer = BufWriter::new(File::create(&file_path)?);
+    write(movie,&mut writer)?;
+    Ok(writer.flush()?)
+}
+
+fn read_frame(line: &str, four_score: bool, ports: [u32;2]) -> Result<FrameInput,String> {
+    let fields: Vec<&str> = line.split('|').collect();
+    // the empty strings before the first and after the last |, the commands, the controllers and port2
+    let controllers = if four_score { 4 } else { 2 };
+    if fields.len() < controllers + 3 {
+        return Err(format!("expected {} controllers, found '{}'",controllers,line));
+    }
+
+    let commands = fields[1].trim();
+    let commands = if commands.is_empty() { 0 } else { commands.parse::<u32>().map_err(|_| format!("bad commands '{}'",commands))? };
+    if commands & !(COMMAND_RESET | COMMAND_POWER) != 0 {
+        return Err(format!("commands {} aren't supported, only reset (1) and power (2) are",commands));
+    }
+
+    let mut frame = FrameInput {
+        reset: commands & COMMAND_RESET != 0,
+        power: commands & COMMAND_POWER != 0,
+        ..Default::default()
+    };
+    for (i,field) in fields[2..2+controllers].iter().enumerate() {
+        // an unplugged port has an empty field
+        if !four_score && ports[i] == 0 {
+            continue;
+        }
+        frame.buttons[i] = read_gamepad(field)?;
+    }
+    Ok(frame)
+}
+
+fn read_gamepad(field: &str) -> Result<ButtonState,String> {
+    if field.chars().count() != BUTTON_ORDER.len() {
+        return Err(format!("a gamepad is {} characters, not '{}'",BUTTON_ORDER.len(),field));
+    }
+    let pressed: Vec<bool> = field.chars().map(|c| c != '.' && c != ' ').collect();
+    Ok(ButtonState {
+        right:  pressed[0],
+        left:   pressed[1],
+        down:   pressed[2],
+        up:     pressed[3],
+        start:  pressed[4],
+        select: pressed[5],
+        b:      pressed[6],
+        a:      pressed[7],
+    })
+}
+
+fn write_gamepad(buttons: &ButtonState) -> String {
+    let pressed = [buttons.right,buttons.left,buttons.down,buttons.up,buttons.start,buttons.select,buttons.b,buttons.a];
+    BUTTON_ORDER.chars().zip(pressed.iter()).map(|(c,pressed)| if *pressed { c } else { '.' }).collect()
+}
@@ -0,0 +1,170 @@
+use std::error;
+use std::fmt;
+use std::io;
+use cpu::ExecutionError;
+use input::ButtonState;
+use nes::Nes;
+use savestate::SaveStateError;
+
+//public mods
+pub mod fm2;
+
+//private mods
+
+// hoisted interfaces
+
+// A movie is where the console starts from and the input for every frame after that.  The
+// console is deterministic, so playing the movie back from the same start gets to exactly the
+// same place, which makes movies good bug reports and regression tests.
+//
+// A frame is what Nes::run_frame runs: the input is applied, then the console runs to the start
+// of the next vblank.
+//
+
+#[derive(Debug)]
+pub enum MovieError {
+  Io(io::Error),
+  // the movie file is malformed, line is 1 based
+  Parse { line: usize, message: String },
+  // the movie uses something that isn't emulated, e.g. a zapper in an FM2 movie
+  Unsupported(String),
+  SaveState(SaveStateError),
+  Execution(ExecutionError),
+}
+
+impl From<io::Error> for MovieError {
+    fn from(err: io::Error) -> MovieError {
+        MovieError::Io(err)
+    }
+}
+impl From<SaveStateError> for MovieError {
+    fn from(err: SaveStateError) -> MovieError {
+        MovieError::SaveState(err)
+    }
+}
+impl From<ExecutionError> for MovieError {
+    fn from(err: ExecutionError) -> MovieError {
+        MovieError::Execution(err)
+    }
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(self: &MovieError, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::Io(ref err)                   => write!(f,"couldn't read the movie: {}",err),
+            MovieError::Parse { line, ref message }   => write!(f,"line {}: {}",line,message),
+            MovieError::Unsupported(ref message)      => write!(f,"unsupported movie: {}",message),
+            MovieError::SaveState(ref err)            => write!(f,"couldn't start the movie: {}",err),
+            MovieError::Execution(ref err)            => write!(f,"{}",err),
+        }
+    }
+}
+impl error::Error for MovieError {
+    fn source(self: &MovieError) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            MovieError::Io(ref err)        => Some(err),
+            MovieError::SaveState(ref err) => Some(err),
+            MovieError::Execution(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Where a movie starts.
+#[derive(PartialEq,Clone,Debug)]
+pub enum MovieStart {
+    PowerOn,
+    // a state written by Nes::save_state
+    SaveState(Vec<u8>),
+}
+
+/// The input for one frame.  Power and reset happen before the buttons are set.
+#[derive(PartialEq,Clone,Debug,Default)]
+pub struct FrameInput {
+    pub power: bool,
+    pub reset: bool,
+    /// Controllers 1-4, 3 and 4 are only connected through a four player adapter.
+    pub buttons: [ButtonState;4],
+}
+
+#[derive(PartialEq,Clone,Debug)]
+pub struct Movie {
+    pub start: MovieStart,
+    pub frames: Vec<FrameInput>,
+    /// Controllers 3 and 4 are in use.
+    pub four_score: bool,
+    /// Times the movie was rewound and recorded over, kept for tools that show it.
+    pub rerecord_count: u32,
+    /// Header lines of an imported movie that aren't used here (the rom name and checksum, the
+    /// guid, comments), written back out on export.
+    pub extra_header: Vec<(String,String)>,
+}
+
+impl Default for Movie {
+    fn default() -> Movie {
+        Movie::new()
+    }
+}
+
+impl Movie {
+    /// An empty movie starting from power on.
+    pub fn new() -> Movie {
+        Movie {
+            start: MovieStart::PowerOn,
+            frames: Vec::new(),
+            four_score: false,
+            rerecord_count: 0,
+            extra_header: Vec::new(),
+        }
+    }
+
+    /// An empty movie starting from where the console is now.
+    pub fn from_save_state(nes: &Nes) -> io::Result<Movie> {
+        let mut state = Vec::new();
+        nes.save_state(&mut state)?;
+        Ok(Movie { start: MovieStart::SaveState(state), ..Movie::new() })
+    }
+
+    /// Put the console where the movie starts, ready for frame 0.
+    pub fn begin(self: &Movie, nes: &mut Nes) -> Result<(),MovieError> {
+        match self.start {
+            MovieStart::PowerOn             => nes.power_cycle()?,
+            MovieStart::SaveState(ref state) => nes.load_state(&mut &state[..])?,
+        }
+        Ok(())
+    }
+
+    /// Run frame index of the movie.
+    pub fn play_frame(self: &Movie, nes: &mut Nes, index: usize) -> Result<(),MovieError> {
+        run_frame(nes,&self.frames[index])
+    }
+
+    /// Play the whole movie from the start.
+    pub fn play(self: &Movie, nes: &mut Nes) -> Result<(),MovieError> {
+        self.begin(nes)?;
+        for index in 0..self.frames.len() {
+            self.play_frame(nes,index)?;
+        }
+        Ok(())
+    }
+
+    /// Run a frame with the given input and add it to the end of the movie.
+    pub fn record_frame(self: &mut Movie, nes: &mut Nes, input: FrameInput) -> Result<(),MovieError> {
+        run_frame(nes,&input)?;
+        self.frames.push(input);
+        Ok(())
+    }
+}
+
+fn run_frame(nes: &mut Nes, input: &FrameInput) -> Result<(),MovieError> {
+    if input.power {
+        nes.power_cycle()?;
+    }
+    if input.reset {
+        nes.reset()?;
+    }
+    for (port,buttons) in input.buttons.iter().enumerate() {
+        nes.set_buttons(port,buttons.clone());
+    }
+    Ok(nes.run_frame()?)
+}
@@ -0,0 +1,357 @@
+use std::io;
+use std::io::{Read,Write};
+use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
+use cpu::{CpuState,CpuExecutor,CycleExecutor};
+use cpu::ExecutionError;
+use memory::Memory;
+use cartridge::Cartridge;
+use input::{ButtonState,InputPorts,ExpansionDevice};
+use logger::TraceSink;
+use savestate::{Snapshot,StateReader,StateWriter,SaveStateError};
+
+// the ppu runs 3 dots for every cpu cycle on NTSC systems
+const PPU_DOTS_PER_CPU_CYCLE: u32 = 3;
+
+// save state chunks, see savestate
+const CPU_CHUNK: &[u8;4] = b"CPU ";
+const RAM_CHUNK: &[u8;4] = b"RAM ";
+const PPU_CHUNK: &[u8;4] = b"PPU ";
+const APU_CHUNK: &[u8;4] = b"APU ";
+const INPUT_CHUNK: &[u8;4] = b"INPT";
+const CARTRIDGE_CHUNK: &[u8;4] = b"CART";
+const CONSOLE_CHUNK: &[u8;4] = b"NES ";
+
+/// The whole console: the cpu and everything on its bus (ram, ppu, apu, controllers and the
+/// cartridge), clocked together.
+///
+/// By default the cpu executes whole instructions, so the rest of the system is caught up
+/// afterwards one cpu cycle at a time.  step_cycle hides this by running the next instruction on
+/// the first cycle it's owed and then idling for the rest.  With a CycleExecutor the cpu really
+/// does run a cycle at a time, so register reads and writes land on the right ppu dot.
+pub struct Nes {
+    pub cpu: CpuState,
+    pub mem: Memory,
+    core: Core,
+    trace_sink: Option<Box<dyn TraceSink>>,
+
+    // cycles of the current instruction the rest of the system hasn't been clocked for yet
+    cycles_owed: u64,
+    // cpu cycles the console has been clocked for, and opcodes fetched, since power on
+    clock: u64,
+    instructions: u64,
+}
+
+// the cpu core running the console
+enum Core {
+    Instruction(CpuExecutor),
+    Cycle(CycleExecutor),
+}
+
+impl Nes {
+    /// Construct a console with the cartridge inserted, powered off.  Call power_cycle before
+    /// running it.
+    pub fn new(cartridge: Cartridge) -> Nes {
+        Nes::with_executor(cartridge,Default::default())
+    }
+
+    /// Same as new, but executing with the given cpu, e.g. one built from an opcode table loaded
+    /// with opcode::load_from_file.
+    pub fn with_executor(cartridge: Cartridge, executor: CpuExecutor) -> Nes {
+        Nes::with_core(cartridge,Core::Instruction(executor))
+    }
+
+    /// Same as new, but with the cycle accurate cpu core.
+    pub fn with_cycle_executor(cartridge: Cartridge, executor: CycleExecutor) -> Nes {
+        Nes::with_core(cartridge,Core::Cycle(executor))
+    }
+
+    fn with_core(cartridge: Cartridge, core: Core) -> Nes {
+        let mut mem = Memory::new();
+        mem.input = InputPorts::for_expansion_device(ExpansionDevice::from_nes2_id(cartridge.expansion_device));
+        mem.cartridge = Some(cartridge);
+        Nes {
+            cpu: Default::default(),
+            mem,
+            core,
+            trace_sink: None,
+            cycles_owed: 0,
+            clock: 0,
+            instructions: 0,
+        }
+    }
+
+    /// Trace every instruction executed from now on.  A sink that fails to record an
+    /// instruction stops execution with ExecutionError::Trace.
+    pub fn set_trace_sink<S: TraceSink + 'static>(self: &mut Nes, sink: S) {
+        self.trace_sink = Some(Box::new(sink));
+    }
+
+    /// Stop tracing, giving back the sink so it can be flushed or inspected.
+    pub fn take_trace_sink(self: &mut Nes) -> Option<Box<dyn TraceSink>> {
+        self.trace_sink.take()
+    }
+
+    /// Set the buttons on a standard controller, see InputPorts::set_buttons.
+    pub fn set_buttons(self: &mut Nes, port: usize, buttons: ButtonState) {
+        self.mem.input.set_buttons(port,buttons);
+    }
+
+    /// The picture from the last completed frame, see Ppu::framebuffer.
+    pub fn framebuffer(self: &Nes) -> &[u16] {
+        &self.mem.ppu.framebuffer
+    }
+
+    /// Cpu cycles since power on, counting the ones spent on DMA.  Every step_cycle is one.
+    pub fn clock(self: &Nes) -> u64 {
+        self.clock
+    }
+
+    /// Instructions started since power on.  Interrupts aren't instructions and don't count.
+    pub fn instructions(self: &Nes) -> u64 {
+        self.instructions
+    }
+
+    /// CRC32 of the rom, see Cartridge::rom_hash.  0 without a cartridge.
+    pub fn rom_hash(self: &Nes) -> u32 {
+        self.mem.cartridge.as_ref().map_or(0,|c| c.rom_hash())
+    }
+
+    /// Save everything needed to carry on from this exact cycle, even partway through an
+    /// instruction or a frame.  The trace sink and the samples the apu hasn't handed out aren't
+    /// part of it.
+    pub fn save_state<W: Write>(self: &Nes, writer: &mut W) -> io::Result<()> {
+        let mut state = StateWriter::new(writer,self.rom_hash())?;
+        state.chunk(CONSOLE_CHUNK,&self.console_state())?;
+        state.chunk(CPU_CHUNK,&self.cpu)?;
+        state.chunk(RAM_CHUNK,&self.mem)?;
+        state.chunk(PPU_CHUNK,&self.mem.ppu)?;
+        state.chunk(APU_CHUNK,&self.mem.apu)?;
+        state.chunk(INPUT_CHUNK,&self.mem.input)?;
+        if let Some(ref cartridge) = self.mem.cartridge {
+            state.chunk(CARTRIDGE_CHUNK,cartridge)?;
+        }
+        Ok(())
+    }
+
+    /// Carry on from a state written by save_state.  A state for another rom, from a console
+    /// running the other cpu core or with other input devices connected, or one that's corrupt,
+    /// is rejected and the console is left as it was.
+    pub fn load_state<R: Read>(self: &mut Nes, reader: &mut R) -> Result<(),SaveStateError> {
+        let state = StateReader::new(reader,self.rom_hash())?;
+        state.require(&[CONSOLE_CHUNK,CPU_CHUNK,RAM_CHUNK,PPU_CHUNK,APU_CHUNK,INPUT_CHUNK])?;
+
+        let mut console = ConsoleState::default();
+        state.chunk(CONSOLE_CHUNK,&mut console)?;
+        if console.cycle_core != self.console_state().cycle_core {
+            return Err(SaveStateError::Incompatible("the save state is from the other cpu core".to_string()));
+        }
+
+        // everything goes into copies first, so a bad chunk doesn't leave the console half loaded
+        let mut cpu = self.cpu.clone();
+        let mut mem = Memory::new();
+        mem.apu.sample_rate = self.mem.apu.sample_rate;
+        mem.input = self.mem.input.clone();
+        mem.cartridge = self.mem.cartridge.clone();
+        state.chunk(CPU_CHUNK,&mut cpu)?;
+        state.chunk(RAM_CHUNK,&mut mem)?;
+        state.chunk(PPU_CHUNK,&mut mem.ppu)?;
+        state.chunk(APU_CHUNK,&mut mem.apu)?;
+        state.chunk(INPUT_CHUNK,&mut mem.input)?;
+        if let Some(ref mut cartridge) = mem.cartridge {
+            state.chunk(CARTRIDGE_CHUNK,cartridge)?;
+        }
+
+        cpu.decode_register.info = match self.core {
+            Core::Instruction(ref executor) => executor.exec_info(cpu.instruction_register).clone(),
+            Core::Cycle(ref executor)       => executor.exec_info(cpu.instruction_register).clone(),
+        };
+        mem.bus_log = self.mem.bus_log.take();
+        self.cpu = cpu;
+        self.mem = mem;
+        self.cycles_owed = console.cycles_owed;
+        self.clock = console.clock;
+        self.instructions = console.instructions;
+        Ok(())
+    }
+
+    fn console_state(self: &Nes) -> ConsoleState {
+        ConsoleState {
+            cycle_core: matches!(self.core,Core::Cycle(_)),
+            cycles_owed: self.cycles_owed,
+            clock: self.clock,
+            instructions: self.instructions,
+        }
+    }
+
+    /// Turn the console off and on again, everything except the cartridge and the connected
+    /// input devices starts over.
+    pub fn power_cycle(self: &mut Nes) -> Result<(),ExecutionError> {
+        let mut mem = Memory::new();
+        mem.cartridge = self.mem.cartridge.take();
+        mem.input = ::std::mem::take(&mut self.mem.input);
+        self.mem = mem;
+        self.cpu = Default::default();
+        self.clock = 0;
+        self.instructions = 0;
+        match self.core {
+            Core::Instruction(ref executor) => executor.power_on(&mut self.cpu,&mut self.mem)?,
+            Core::Cycle(ref executor)       => executor.power_on(&mut self.cpu,&mut self.mem)?,
+        }
+
+        // the rest of the system runs during the cpu's reset sequence
+        self.cycles_owed = self.cpu.cycles;
+        Ok(())
+    }
+
+    /// Press the reset button.
+    pub fn reset(self: &mut Nes) -> Result<(),ExecutionError> {
+        self.mem.ppu.reset();
+        self.mem.apu.reset();
+        let before = self.cpu.cycles;
+        match self.core {
+            Core::Instruction(ref executor) => executor.reset(&mut self.cpu,&mut self.mem)?,
+            Core::Cycle(ref executor)       => executor.reset(&mut self.cpu,&mut self.mem)?,
+        }
+        self.cycles_owed = self.cpu.cycles - before;
+        Ok(())
+    }
+
+    /// Run until the ppu finishes the current frame, stopping at the start of vblank.
+    pub fn run_frame(self: &mut Nes) -> Result<(),ExecutionError> {
+        let frame = self.mem.ppu.frame;
+        while self.mem.ppu.frame == frame {
+            self.step_cycle()?;
+        }
+        Ok(())
+    }
+
+    /// Finish the instruction in progress, if any, then run the next one.
+    pub fn step_instruction(self: &mut Nes) -> Result<(),ExecutionError> {
+        while self.in_instruction() {
+            self.step_cycle()?;
+        }
+        self.step_cycle()?;
+        while self.in_instruction() {
+            self.step_cycle()?;
+        }
+        Ok(())
+    }
+
+    /// An instruction, or the DMA it started, still has cycles to run.
+    pub fn in_instruction(self: &Nes) -> bool {
+        match self.core {
+            Core::Instruction(_) => self.cycles_owed > 0,
+            Core::Cycle(_)       => self.cycles_owed > 0 || self.cpu.micro_op_register.step != 0 || self.mem.dma_cycles > 0,
+        }
+    }
+
+    /// Advance one cpu cycle.
+    pub fn step_cycle(self: &mut Nes) -> Result<(),ExecutionError> {
+        if let Core::Cycle(_) = self.core {
+            // the cycle core only owes the reset sequence
+            if self.cycles_owed == 0 {
+                self.tick()?;
+            }
+        }
+        else if self.cycles_owed == 0 {
+            let before = self.cpu.cycles;
+            self.begin_instruction()?;
+            // a write to $4014 is always the last cycle of the instruction
+            let last_cycle = self.clock + (self.cpu.cycles - before) - 1;
+            self.mem.align_dma(last_cycle);
+            self.cpu.cycles += self.mem.dma_cycles;
+            self.mem.dma_cycles = 0;
+            self.cycles_owed = self.cpu.cycles - before;
+        }
+
+        for _ in 0..PPU_DOTS_PER_CPU_CYCLE {
+            self.mem.ppu.step(self.mem.cartridge.as_ref());
+        }
+        self.mem.apu.step();
+        self.cycles_owed = self.cycles_owed.saturating_sub(1);
+        self.clock += 1;
+        Ok(())
+    }
+
+    // one cycle of the cycle accurate core.  the cpu is halted while OAM DMA runs.
+    fn tick(self: &mut Nes) -> Result<(),ExecutionError> {
+        let executor = match self.core {
+            Core::Cycle(ref executor) => executor,
+            Core::Instruction(_)      => return Ok(()),
+        };
+        if self.mem.dma_cycles > 0 {
+            self.mem.dma_cycles -= 1;
+            self.cpu.cycles += 1;
+            return Ok(());
+        }
+
+        if self.mem.ppu.take_nmi() {
+            executor.nmi(&mut self.cpu);
+        }
+        executor.set_irq(&mut self.cpu,self.mem.apu.irq_pending());
+        executor.tick(&mut self.cpu,&mut self.mem)?;
+        self.mem.align_dma(self.clock);
+        if executor.fetched_opcode(&self.cpu) {
+            self.instructions += 1;
+        }
+
+        if let Some(ref mut sink) = self.trace_sink {
+            if executor.fetched_opcode(&self.cpu) {
+                // traces show the cycle count from before the opcode fetch
+                let mut cpu = self.cpu.clone();
+                cpu.cycles -= 1;
+                cpu.decode_register = executor.peek_decode(&self.cpu,&self.mem);
+                sink.trace(&cpu,&self.mem)?;
+            }
+        }
+        Ok(())
+    }
+
+    // interrupts are polled between instructions
+    fn begin_instruction(self: &mut Nes) -> Result<(),ExecutionError> {
+        let executor = match self.core {
+            Core::Instruction(ref executor) => executor,
+            Core::Cycle(_)                  => return Ok(()),
+        };
+        if self.mem.ppu.take_nmi() {
+            return executor.nmi(&mut self.cpu,&mut self.mem);
+        }
+        if self.mem.apu.irq_pending() && !self.cpu.I {
+            return executor.irq(&mut self.cpu,&mut self.mem);
+        }
+
+        executor.fetch_and_decode(&mut self.cpu,&mut self.mem)?;
+        self.instructions += 1;
+        if let Some(ref mut sink) = self.trace_sink {
+            sink.trace(&self.cpu,&self.mem)?;
+        }
+        executor.execute(&mut self.cpu,&mut self.mem)
+    }
+}
+
+// the console's own bookkeeping, saved in the NES chunk
+#[derive(Default)]
+struct ConsoleState {
+    // the cycle core keeps its place in an instruction in the cpu state, the instruction core
+    // can't pick up from there
+    cycle_core: bool,
+    cycles_owed: u64,
+    clock: u64,
+    instructions: u64,
+}
+
+impl Snapshot for ConsoleState {
+    fn save_state(self: &ConsoleState, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_u8(self.cycle_core as u8)?;
+        writer.write_u64::<LittleEndian>(self.cycles_owed)?;
+        writer.write_u64::<LittleEndian>(self.clock)?;
+        writer.write_u64::<LittleEndian>(self.instructions)
+    }
+    fn load_state(self: &mut ConsoleState, reader: &mut dyn Read) -> io::Result<()> {
+        self.cycle_core = reader.read_u8()? != 0;
+        self.cycles_owed = reader.read_u64::<LittleEndian>()?;
+        self.clock = reader.read_u64::<LittleEndian>()?;
+        self.instructions = reader.read_u64::<LittleEndian>()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,510 @@
+use std::io;
+use std::io::{Read,Write};
+use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
+use cartridge::{Cartridge,Mirroring};
+use savestate::{self,Snapshot};
+
+// http://wiki.nesdev.com/w/index.php/PPU
+//
+// The renderer works a scanline at a time rather than a dot at a time, so mid-scanline
+// register writes only take effect on the next scanline.  The scroll registers (v, t, fine_x)
+// are updated on the same dots as the real hardware so split screens line up.
+//
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+const DOTS_PER_SCANLINE: u16 = 341;
+const VBLANK_SCANLINE: u16 = 241;
+const PRE_RENDER_SCANLINE: u16 = 261;
+
+// PPUCTRL
+const CTRL_INCREMENT_32: u8 = 0x04;
+const CTRL_SPRITE_TABLE: u8 = 0x08;
+const CTRL_BACKGROUND_TABLE: u8 = 0x10;
+const CTRL_SPRITE_8X16: u8 = 0x20;
+const CTRL_NMI: u8 = 0x80;
+
+// PPUMASK
+const MASK_GRAYSCALE: u8 = 0x01;
+const MASK_BACKGROUND_LEFT: u8 = 0x02;
+const MASK_SPRITES_LEFT: u8 = 0x04;
+const MASK_BACKGROUND: u8 = 0x08;
+const MASK_SPRITES: u8 = 0x10;
+
+// PPUSTATUS
+const STATUS_OVERFLOW: u8 = 0x20;
+const STATUS_SPRITE_ZERO: u8 = 0x40;
+const STATUS_VBLANK: u8 = 0x80;
+
+pub struct Ppu {
+    pub ctrl: u8,
+    pub mask: u8,
+    pub status: u8,
+    pub oam_addr: u8,
+
+    // http://wiki.nesdev.com/w/index.php/PPU_scrolling
+    pub v: u16,
+    pub t: u16,
+    pub fine_x: u8,
+    pub w: bool,
+
+    // $2007 reads are delayed by one read, except for the palette
+    pub read_buffer: u8,
+    // the last value put on the PPU data bus, reads of write-only bits return it
+    pub io_latch: u8,
+
+    // 4KB to cover four screen boards, everything else only uses the first 2KB
+    pub nametables: Vec<u8>,
+    pub palette: Vec<u8>,
+    pub oam: Vec<u8>,
+
+    pub scanline: u16,
+    pub dot: u16,
+    /// Number of frames completed, incremented at the start of vblank.
+    pub frame: u64,
+    pub odd_frame: bool,
+    pub nmi_pending: bool,
+
+    /// 256x240 pixels, palette index in bits 0-5 and the PPUMASK emphasis bits in bits 6-8.
+    pub framebuffer: Vec<u16>,
+}
+
+impl Default for Ppu {
+    fn default() -> Ppu {
+        Ppu::new()
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu {
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
+            read_buffer: 0,
+            io_latch: 0,
+            nametables: vec![0;0x1000],
+            palette: vec![0;32],
+            oam: vec![0;256],
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            odd_frame: false,
+            nmi_pending: false,
+            framebuffer: vec![0;SCREEN_WIDTH*SCREEN_HEIGHT],
+        }
+    }
+
+    // http://wiki.nesdev.com/w/index.php/PPU_power_up_state
+    //
+    pub fn reset(self: &mut Ppu) {
+        self.ctrl = 0;
+        self.mask = 0;
+        self.w = false;
+        self.fine_x = 0;
+        self.t = 0;
+        self.read_buffer = 0;
+        self.odd_frame = false;
+        self.nmi_pending = false;
+        self.scanline = 0;
+        self.dot = 0;
+    }
+
+    /// Returns true once per NMI the PPU has raised.
+    pub fn take_nmi(self: &mut Ppu) -> bool {
+        let nmi = self.nmi_pending;
+        self.nmi_pending = false;
+        nmi
+    }
+
+    pub fn rendering_enabled(self: &Ppu) -> bool {
+        self.mask & (MASK_BACKGROUND | MASK_SPRITES) != 0
+    }
+
+    /// Read one of the 8 registers at $2000-$2007.
+    pub fn read_register(self: &mut Ppu, reg: u16, cart: Option<&Cartridge>) -> u8 {
+        let val = match reg & 7 {
+            2 => {
+                let val = (self.status & 0xE0) | (self.io_latch & 0x1F);
+                self.status &= !STATUS_VBLANK;
+                self.w = false;
+                val
+            },
+            4 => self.read_oam(),
+            7 => {
+                let addr = self.v & 0x3FFF;
+                let val = if addr < 0x3F00 {
+                    let val = self.read_buffer;
+                    self.read_buffer = self.vram_read(addr,cart);
+                    val
+                } else {
+                    // palette reads are immediate, the buffer gets the nametable 'underneath'
+                    self.read_buffer = self.vram_read(addr - 0x1000,cart);
+                    (self.vram_read(addr,cart) & 0x3F) | (self.io_latch & 0xC0)
+                };
+                self.increment_v();
+                val
+            },
+            _ => self.io_latch,
+        };
+        self.io_latch = val;
+        val
+    }
+
+    /// Same as read_register but without side effects.
+    pub fn peek_register(self: &Ppu, reg: u16) -> u8 {
+        match reg & 7 {
+            2 => (self.status & 0xE0) | (self.io_latch & 0x1F),
+            4 => self.read_oam(),
+            7 => self.read_buffer,
+            _ => self.io_latch,
+        }
+    }
+
+    /// Write one of the 8 registers at $2000-$2007.
+    pub fn write_register(self: &mut Ppu, reg: u16, val: u8, cart: Option<&mut Cartridge>) {
+        self.io_latch = val;
+        match reg & 7 {
+            0 => {
+                // enabling NMI during vblank raises one immediately
+                if self.ctrl & CTRL_NMI == 0 && val & CTRL_NMI != 0 && self.status & STATUS_VBLANK != 0 {
+                    self.nmi_pending = true;
+                }
+                self.ctrl = val;
+                self.t = (self.t & 0xF3FF) | ((val as u16 & 0x03) << 10);
+            },
+            1 => { self.mask = val; },
+            3 => { self.oam_addr = val; },
+            4 => { self.write_oam(val); },
+            5 => {
+                if !self.w {
+                    self.t = (self.t & 0xFFE0) | (val as u16 >> 3);
+                    self.fine_x = val & 0x07;
+                } else {
+                    self.t = (self.t & 0x8C1F) | ((val as u16 & 0x07) << 12) | ((val as u16 & 0xF8) << 2);
+                }
+                self.w = !self.w;
+            },
+            6 => {
+                if !self.w {
+                    self.t = (self.t & 0x80FF) | ((val as u16 & 0x3F) << 8);
+                } else {
+                    self.t = (self.t & 0xFF00) | val as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            },
+            7 => {
+                let addr = self.v & 0x3FFF;
+                self.vram_write(addr,val,cart);
+                self.increment_v();
+            },
+            _ => { },
+        }
+    }
+
+    /// $2004 writes and OAM DMA both go through here.
+    pub fn write_oam(self: &mut Ppu, val: u8) {
+        self.oam[self.oam_addr as usize] = val;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    fn read_oam(self: &Ppu) -> u8 {
+        let val = self.oam[self.oam_addr as usize];
+        // bits 2-4 of the sprite attribute byte don't exist
+        if self.oam_addr & 0x03 == 0x02 { val & 0xE3 } else { val }
+    }
+
+    fn increment_v(self: &mut Ppu) {
+        let step = if self.ctrl & CTRL_INCREMENT_32 != 0 { 32 } else { 1 };
+        self.v = (self.v + step) & 0x7FFF;
+    }
+
+    // http://wiki.nesdev.com/w/index.php/PPU_memory_map
+    //
+    pub fn vram_read(self: &Ppu, addr: u16, cart: Option<&Cartridge>) -> u8 {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000..=0x1FFF => cart.map_or(0, |c| c.ppu_read(addr)),
+            0x2000..=0x3EFF => self.nametables[nametable_index(addr,cart.map(|c| c.mirroring))],
+            _               => self.palette[palette_index(addr)],
+        }
+    }
+
+    pub fn vram_write(self: &mut Ppu, addr: u16, val: u8, cart: Option<&mut Cartridge>) {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000..=0x1FFF => { if let Some(c) = cart { c.ppu_write(addr,val); } },
+            0x2000..=0x3EFF => {
+                let mirroring = cart.map(|c| c.mirroring);
+                self.nametables[nametable_index(addr,mirroring)] = val;
+            },
+            _               => { self.palette[palette_index(addr)] = val & 0x3F; },
+        }
+    }
+
+    /// Advance one dot.
+    pub fn step(self: &mut Ppu, cart: Option<&Cartridge>) {
+        let rendering = self.rendering_enabled();
+
+        if self.scanline < SCREEN_HEIGHT as u16 && self.dot == 256 {
+            self.render_scanline(cart);
+        }
+
+        if rendering && (self.scanline < SCREEN_HEIGHT as u16 || self.scanline == PRE_RENDER_SCANLINE) {
+            match self.dot {
+                256 => self.increment_y(),
+                257 => { self.v = (self.v & !0x041F) | (self.t & 0x041F); },
+                280..=304 if self.scanline == PRE_RENDER_SCANLINE => {
+                    self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+                },
+                _ => { },
+            }
+        }
+
+        if self.scanline == VBLANK_SCANLINE && self.dot == 1 {
+            self.status |= STATUS_VBLANK;
+            self.frame += 1;
+            if self.ctrl & CTRL_NMI != 0 { self.nmi_pending = true; }
+        }
+        if self.scanline == PRE_RENDER_SCANLINE && self.dot == 1 {
+            self.status &= !(STATUS_VBLANK | STATUS_SPRITE_ZERO | STATUS_OVERFLOW);
+        }
+
+        // odd frames skip the last dot of the pre-render scanline when rendering
+        if self.scanline == PRE_RENDER_SCANLINE && self.dot == 339 && self.odd_frame && rendering {
+            self.dot = 340;
+        }
+
+        self.dot += 1;
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline > PRE_RENDER_SCANLINE {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+
+    fn increment_y(self: &mut Ppu) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    fn render_scanline(self: &mut Ppu, cart: Option<&Cartridge>) {
+        let y = self.scanline as usize;
+        let emphasis = ((self.mask as u16) >> 5) << 6;
+
+        if !self.rendering_enabled() {
+            // with rendering off the backdrop is shown, unless v points into the palette
+            let addr = if self.v & 0x3F00 == 0x3F00 { self.v } else { 0x3F00 };
+            let color = self.output_color(addr);
+            for x in 0..SCREEN_WIDTH {
+                self.framebuffer[y*SCREEN_WIDTH + x] = color | emphasis;
+            }
+            return;
+        }
+
+        // each entry is palette << 2 | color, color 0 is transparent
+        let mut background = [0u8; SCREEN_WIDTH];
+        if self.mask & MASK_BACKGROUND != 0 {
+            self.fetch_background(cart,&mut background);
+            if self.mask & MASK_BACKGROUND_LEFT == 0 {
+                for px in background.iter_mut().take(8) { *px = 0; }
+            }
+        }
+
+        // each entry is 0x10 | palette << 2 | color, 0 when there's no sprite
+        let mut sprites = [0u8; SCREEN_WIDTH];
+        let mut behind_background = [false; SCREEN_WIDTH];
+        let mut sprite_zero = [false; SCREEN_WIDTH];
+        if self.mask & MASK_SPRITES != 0 {
+            self.fetch_sprites(cart,&mut sprites,&mut behind_background,&mut sprite_zero);
+            if self.mask & MASK_SPRITES_LEFT == 0 {
+                for px in sprites.iter_mut().take(8) { *px = 0; }
+            }
+        }
+
+        for x in 0..SCREEN_WIDTH {
+            let bg = background[x];
+            let sp = sprites[x];
+            if sprite_zero[x] && bg & 0x03 != 0 && sp & 0x03 != 0 && x != 255 {
+                self.status |= STATUS_SPRITE_ZERO;
+            }
+            let addr = if sp & 0x03 != 0 && (bg & 0x03 == 0 || !behind_background[x]) {
+                sp as u16 & 0x1F
+            } else if bg & 0x03 != 0 {
+                bg as u16
+            } else {
+                0
+            };
+            self.framebuffer[y*SCREEN_WIDTH + x] = self.output_color(0x3F00 | addr) | emphasis;
+        }
+    }
+
+    fn output_color(self: &Ppu, addr: u16) -> u16 {
+        let color = self.palette[palette_index(addr)];
+        (if self.mask & MASK_GRAYSCALE != 0 { color & 0x30 } else { color & 0x3F }) as u16
+    }
+
+    fn fetch_background(self: &Ppu, cart: Option<&Cartridge>, background: &mut [u8; SCREEN_WIDTH]) {
+        let pattern_base = if self.ctrl & CTRL_BACKGROUND_TABLE != 0 { 0x1000 } else { 0 };
+        let fine_y = (self.v >> 12) & 0x07;
+        let mut v = self.v;
+
+        // 33 tiles, fine x scrolling pulls in part of the 33rd
+        for tile in 0..33 {
+            let tile_index = self.vram_read(0x2000 | (v & 0x0FFF),cart) as u16;
+            let attribute_addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+            let shift = ((v >> 4) & 0x04) | (v & 0x02);
+            let palette = (self.vram_read(attribute_addr,cart) >> shift) & 0x03;
+
+            let lo = self.vram_read(pattern_base + tile_index*16 + fine_y,cart);
+            let hi = self.vram_read(pattern_base + tile_index*16 + fine_y + 8,cart);
+            for px in 0..8 {
+                let x = (tile*8 + px) as isize - self.fine_x as isize;
+                if x < 0 || x >= SCREEN_WIDTH as isize { continue; }
+                let bit = 7 - px;
+                let color = ((lo >> bit) & 1) | (((hi >> bit) & 1) << 1);
+                background[x as usize] = if color == 0 { 0 } else { (palette << 2) | color };
+            }
+
+            // coarse x increment, wrapping into the horizontally adjacent nametable
+            if v & 0x001F == 31 { v &= !0x001F; v ^= 0x0400; } else { v += 1; }
+        }
+    }
+
+    // http://wiki.nesdev.com/w/index.php/PPU_sprite_evaluation
+    //
+    fn fetch_sprites(self: &mut Ppu, cart: Option<&Cartridge>, sprites: &mut [u8; SCREEN_WIDTH],
+                     behind_background: &mut [bool; SCREEN_WIDTH], sprite_zero: &mut [bool; SCREEN_WIDTH]) {
+        let y = self.scanline;
+        let height = if self.ctrl & CTRL_SPRITE_8X16 != 0 { 16 } else { 8 };
+        let mut count = 0;
+
+        for i in 0..64 {
+            // sprites are drawn one scanline below their Y coordinate
+            let top = self.oam[i*4] as u16 + 1;
+            if y < top || y >= top + height { continue; }
+            if count == 8 {
+                self.status |= STATUS_OVERFLOW;
+                break;
+            }
+            count += 1;
+
+            let tile = self.oam[i*4 + 1] as u16;
+            let attributes = self.oam[i*4 + 2];
+            let left = self.oam[i*4 + 3] as usize;
+
+            let mut row = y - top;
+            if attributes & 0x80 != 0 { row = height - 1 - row; }
+            let addr = if height == 8 {
+                let table = if self.ctrl & CTRL_SPRITE_TABLE != 0 { 0x1000 } else { 0 };
+                table + tile*16 + row
+            } else {
+                (tile & 1)*0x1000 + (tile & 0xFE)*16 + (row & 0x08)*2 + (row & 0x07)
+            };
+            let lo = self.vram_read(addr,cart);
+            let hi = self.vram_read(addr + 8,cart);
+
+            for px in 0..8 {
+                let x = left + px;
+                // earlier sprites in OAM have priority, even if they're behind the background
+                if x >= SCREEN_WIDTH || sprites[x] != 0 { continue; }
+                let bit = if attributes & 0x40 != 0 { px } else { 7 - px };
+                let color = ((lo >> bit) & 1) | (((hi >> bit) & 1) << 1);
+                if color == 0 { continue; }
+                sprites[x] = 0x10 | ((attributes & 0x03) << 2) | color;
+                behind_background[x] = attributes & 0x20 != 0;
+                sprite_zero[x] = i == 0;
+            }
+        }
+    }
+}
+
+// the framebuffer is saved too, so a state saved mid-frame finishes drawing the same picture and
+// light guns see what was on screen
+impl Snapshot for Ppu {
+    fn save_state(self: &Ppu, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(&[self.ctrl,self.mask,self.status,self.oam_addr,self.fine_x,self.read_buffer,self.io_latch])?;
+        writer.write_u16::<LittleEndian>(self.v)?;
+        writer.write_u16::<LittleEndian>(self.t)?;
+        savestate::write_bool(writer,self.w)?;
+        savestate::write_block(writer,&self.nametables)?;
+        savestate::write_block(writer,&self.palette)?;
+        savestate::write_block(writer,&self.oam)?;
+        writer.write_u16::<LittleEndian>(self.scanline)?;
+        writer.write_u16::<LittleEndian>(self.dot)?;
+        writer.write_u64::<LittleEndian>(self.frame)?;
+        savestate::write_bool(writer,self.odd_frame)?;
+        savestate::write_bool(writer,self.nmi_pending)?;
+        for pixel in self.framebuffer.iter() {
+            writer.write_u16::<LittleEndian>(*pixel)?;
+        }
+        Ok(())
+    }
+
+    fn load_state(self: &mut Ppu, reader: &mut dyn Read) -> io::Result<()> {
+        let mut regs = [0u8;7];
+        reader.read_exact(&mut regs)?;
+        self.ctrl = regs[0];
+        self.mask = regs[1];
+        self.status = regs[2];
+        self.oam_addr = regs[3];
+        self.fine_x = regs[4];
+        self.read_buffer = regs[5];
+        self.io_latch = regs[6];
+        self.v = reader.read_u16::<LittleEndian>()?;
+        self.t = reader.read_u16::<LittleEndian>()?;
+        self.w = savestate::read_bool(reader)?;
+        savestate::read_block(reader,&mut self.nametables)?;
+        savestate::read_block(reader,&mut self.palette)?;
+        savestate::read_block(reader,&mut self.oam)?;
+        self.scanline = reader.read_u16::<LittleEndian>()?;
+        self.dot = reader.read_u16::<LittleEndian>()?;
+        self.frame = reader.read_u64::<LittleEndian>()?;
+        self.odd_frame = savestate::read_bool(reader)?;
+        self.nmi_pending = savestate::read_bool(reader)?;
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel = reader.read_u16::<LittleEndian>()?;
+        }
+        Ok(())
+    }
+}
+
+fn nametable_index(addr: u16, mirroring: Option<Mirroring>) -> usize {
+    let table = (addr >> 10) & 0x03;
+    let offset = (addr & 0x03FF) as usize;
+    let page = match mirroring.unwrap_or(Mirroring::Horizontal) {
+        Mirroring::Horizontal => table >> 1,
+        Mirroring::Vertical   => table & 1,
+        Mirroring::FourScreen => table,
+    };
+    page as usize * 0x400 + offset
+}
+
+// $3F10/$3F14/$3F18/$3F1C mirror the backdrop entries below them
+fn palette_index(addr: u16) -> usize {
+    let index = (addr & 0x1F) as usize;
+    if index >= 0x10 && index & 0x03 == 0 { index - 0x10 } else { index }
+}
@@ -0,0 +1,297 @@
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::io;
+use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
+use cpu::ExecutionError;
+use input::ButtonState;
+use nes::Nes;
+use savestate::SaveStateError;
+
+// every this many checkpoints one is kept whole, so restoring never patches more than this many
+// deltas
+const KEYFRAME_INTERVAL: usize = 32;
+
+// Rewind keeps save states (checkpoints) of the console taken every few frames, along with the
+// controller input given to it.  Going back restores the nearest checkpoint before the point
+// asked for and replays the input from there, which lands on exactly the same cycle since the
+// console is deterministic.
+//
+// Checkpoints are stored as the bytes that changed since the previous one, with every
+// KEYFRAME_INTERVAL'th one and the oldest kept whole.  Once they take up more than the budget the
+// oldest are dropped.
+//
+
+#[derive(Debug)]
+pub enum RewindError {
+  SaveState(SaveStateError),
+  Execution(ExecutionError),
+  // the point asked for is older than the oldest checkpoint, or hasn't happened yet
+  OutOfRange(String),
+}
+
+impl From<SaveStateError> for RewindError {
+    fn from(err: SaveStateError) -> RewindError {
+        RewindError::SaveState(err)
+    }
+}
+impl From<ExecutionError> for RewindError {
+    fn from(err: ExecutionError) -> RewindError {
+        RewindError::Execution(err)
+    }
+}
+impl From<io::Error> for RewindError {
+    fn from(err: io::Error) -> RewindError {
+        RewindError::SaveState(SaveStateError::Io(err))
+    }
+}
+
+impl fmt::Display for RewindError {
+    fn fmt(self: &RewindError, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RewindError::SaveState(ref err)    => write!(f,"couldn't restore a checkpoint: {}",err),
+            RewindError::Execution(ref err)    => write!(f,"couldn't replay: {}",err),
+            RewindError::OutOfRange(ref message) => write!(f,"{}",message),
+        }
+    }
+}
+impl error::Error for RewindError {
+    fn source(self: &RewindError) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            RewindError::SaveState(ref err) => Some(err),
+            RewindError::Execution(ref err) => Some(err),
+            RewindError::OutOfRange(_)      => None,
+        }
+    }
+}
+
+// a save state, and where the console was when it was taken
+struct Checkpoint {
+    frame: u64,
+    clock: u64,
+    instructions: u64,
+    // the whole state, or a delta against the checkpoint before it, see diff
+    data: Vec<u8>,
+    whole: bool,
+}
+
+// buttons set on a controller, at the clock they were set
+struct InputEvent {
+    clock: u64,
+    port: usize,
+    buttons: ButtonState,
+}
+
+/// A ring buffer of checkpoints for stepping the console backwards.  Drive the console through
+/// set_buttons and run_frame so the input can be replayed.
+pub struct Rewind {
+    interval: u64,
+    budget: usize,
+    checkpoints: VecDeque<Checkpoint>,
+    events: VecDeque<InputEvent>,
+    // the newest checkpoint whole, what the next one is diffed against
+    newest: Vec<u8>,
+    size: usize,
+}
+
+impl Rewind {
+    /// Take a checkpoint every interval frames, keeping at most budget bytes of them.  The newest
+    /// checkpoint is always kept, even if it's over budget on its own.
+    pub fn new(interval: u64, budget: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            checkpoints: VecDeque::new(),
+            events: VecDeque::new(),
+            newest: Vec::new(),
+            size: 0,
+        }
+    }
+
+    /// Number of checkpoints held.
+    pub fn len(self: &Rewind) -> usize {
+        self.checkpoints.len()
+    }
+    pub fn is_empty(self: &Rewind) -> bool {
+        self.checkpoints.is_empty()
+    }
+
+    /// Bytes the checkpoints take up.
+    pub fn size(self: &Rewind) -> usize {
+        self.size
+    }
+
+    /// The earliest frame the console can be rewound to.
+    pub fn oldest_frame(self: &Rewind) -> Option<u64> {
+        self.checkpoints.front().map(|c| c.frame)
+    }
+
+    /// Set the buttons on a standard controller, see Nes::set_buttons, and remember them for
+    /// replaying.
+    pub fn set_buttons(self: &mut Rewind, nes: &mut Nes, port: usize, buttons: ButtonState) {
+        nes.set_buttons(port,buttons.clone());
+        self.events.push_back(InputEvent { clock: nes.clock(), port, buttons });
+    }
+
+    /// Run the console to the end of the frame, taking a checkpoint first if one is due.
+    pub fn run_frame(self: &mut Rewind, nes: &mut Nes) -> Result<(),RewindError> {
+        let due = match self.checkpoints.back() {
+            Some(newest) => nes.mem.ppu.frame >= newest.frame + self.interval,
+            None         => true,
+        };
+        if due {
+            self.checkpoint(nes)?;
+        }
+        Ok(nes.run_frame()?)
+    }
+
+    /// Take a checkpoint now.
+    pub fn checkpoint(self: &mut Rewind, nes: &Nes) -> io::Result<()> {
+        let mut state = Vec::new();
+        nes.save_state(&mut state)?;
+
+        let since_whole = self.checkpoints.iter().rev().take_while(|c| !c.whole).count();
+        let whole = self.checkpoints.is_empty() || since_whole + 1 >= KEYFRAME_INTERVAL;
+        let data = if whole { state.clone() } else { diff(&self.newest,&state)? };
+        self.size += data.len();
+        self.checkpoints.push_back(Checkpoint {
+            frame: nes.mem.ppu.frame,
+            clock: nes.clock(),
+            instructions: nes.instructions(),
+            data,
+            whole,
+        });
+        self.newest = state;
+
+        while self.size > self.budget && self.checkpoints.len() > 1 {
+            self.drop_oldest()?;
+        }
+        Ok(())
+    }
+
+    /// Go back frames frames, to where run_frame returned (or was called, for the frame a
+    /// checkpoint was taken in).
+    pub fn rewind_frames(self: &mut Rewind, nes: &mut Nes, frames: u64) -> Result<(),RewindError> {
+        let target = nes.mem.ppu.frame.checked_sub(frames)
+            .ok_or_else(|| RewindError::OutOfRange(format!("can't go back {} frames from frame {}",frames,nes.mem.ppu.frame)))?;
+        let index = self.checkpoints.iter().rposition(|c| c.frame <= target)
+            .ok_or_else(|| RewindError::OutOfRange(format!("frame {} is older than the oldest checkpoint",target)))?;
+        self.restore(nes,index,|nes| nes.mem.ppu.frame >= target)
+    }
+
+    /// Go back to just after the given instruction finished, counting from power on.  See
+    /// Nes::instructions.
+    pub fn rewind_to_instruction(self: &mut Rewind, nes: &mut Nes, instruction: u64) -> Result<(),RewindError> {
+        if instruction > nes.instructions() {
+            return Err(RewindError::OutOfRange(format!("instruction {} hasn't run yet",instruction)));
+        }
+        let index = self.checkpoints.iter().rposition(|c| c.instructions <= instruction)
+            .ok_or_else(|| RewindError::OutOfRange(format!("instruction {} is older than the oldest checkpoint",instruction)))?;
+        self.restore(nes,index,|nes| nes.instructions() == instruction && !nes.in_instruction())
+    }
+
+    // load the checkpoint at index and replay the input until done says the console is there.
+    // everything after that point is forgotten, new input starts a new history.
+    fn restore<F: Fn(&Nes) -> bool>(self: &mut Rewind, nes: &mut Nes, index: usize, done: F) -> Result<(),RewindError> {
+        let state = self.state(index)?;
+        nes.load_state(&mut &state[..])?;
+
+        let start = nes.clock();
+        let mut events = self.events.iter().skip_while(|e| e.clock < start).peekable();
+        while !done(nes) {
+            let clock = nes.clock();
+            while let Some(event) = events.next_if(|e| e.clock == clock) {
+                nes.set_buttons(event.port,event.buttons.clone());
+            }
+            nes.step_cycle()?;
+        }
+
+        self.checkpoints.truncate(index + 1);
+        self.size = self.checkpoints.iter().map(|c| c.data.len()).sum();
+        while self.events.back().is_some_and(|e| e.clock >= nes.clock()) {
+            self.events.pop_back();
+        }
+        self.newest = state;
+        Ok(())
+    }
+
+    // the whole save state of the checkpoint at index
+    fn state(self: &Rewind, index: usize) -> io::Result<Vec<u8>> {
+        // the oldest checkpoint is always whole
+        let keyframe = (0..=index).rev().find(|i| self.checkpoints[*i].whole).unwrap_or(0);
+        let mut state = self.checkpoints[keyframe].data.clone();
+        for checkpoint in self.checkpoints.range(keyframe+1..=index) {
+            state = patch(&state,&checkpoint.data)?;
+        }
+        Ok(state)
+    }
+
+    fn drop_oldest(self: &mut Rewind) -> io::Result<()> {
+        let oldest = match self.checkpoints.pop_front() {
+            Some(oldest) => oldest,
+            None         => return Ok(()),
+        };
+        self.size -= oldest.data.len();
+        if let Some(next) = self.checkpoints.front_mut() {
+            if !next.whole {
+                let state = patch(&oldest.data,&next.data)?;
+                self.size = self.size - next.data.len() + state.len();
+                next.data = state;
+                next.whole = true;
+            }
+        }
+
+        // input from before the oldest checkpoint can't be replayed any more
+        let oldest_clock = self.checkpoints.front().map_or(u64::MAX,|c| c.clock);
+        while self.events.front().is_some_and(|e| e.clock < oldest_clock) {
+            self.events.pop_front();
+        }
+        Ok(())
+    }
+}
+
+// A delta is the length of the new state, then runs of unchanged and changed bytes:
+//
+//   u32 length
+//   repeated: u32 unchanged, u32 changed, the changed bytes
+//
+// Save states of the same console are the same length and most of the bytes (ram, nametables,
+// the framebuffer) don't change from one frame to the next, so this is a small fraction of the
+// whole.
+//
+fn diff(prev: &[u8], next: &[u8]) -> io::Result<Vec<u8>> {
+    let same = |i: usize| i < prev.len() && prev[i] == next[i];
+    let mut delta = Vec::new();
+    delta.write_u32::<LittleEndian>(next.len() as u32)?;
+    let mut i = 0;
+    while i < next.len() {
+        let start = i;
+        while i < next.len() && same(i) { i += 1; }
+        let unchanged = i - start;
+        let start = i;
+        while i < next.len() && !same(i) { i += 1; }
+        delta.write_u32::<LittleEndian>(unchanged as u32)?;
+        delta.write_u32::<LittleEndian>((i - start) as u32)?;
+        delta.extend_from_slice(&next[start..i]);
+    }
+    Ok(delta)
+}
+
+fn patch(prev: &[u8], delta: &[u8]) -> io::Result<Vec<u8>> {
+    let mut reader = delta;
+    let len = reader.read_u32::<LittleEndian>()? as usize;
+    let mut next = prev.to_vec();
+    next.resize(len,0);
+    let mut i = 0;
+    while !reader.is_empty() {
+        i += reader.read_u32::<LittleEndian>()? as usize;
+        let changed = reader.read_u32::<LittleEndian>()? as usize;
+        if i + changed > len || changed > reader.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,"the checkpoint delta is corrupt"));
+        }
+        next[i..i+changed].copy_from_slice(&reader[..changed]);
+        reader = &reader[changed..];
+        i += changed;
+    }
+    Ok(next)
+}
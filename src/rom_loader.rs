@@ -4,6 +4,9 @@ use std::path::Path;
 use std::io;
 use std::io::Seek;
 use std::io::SeekFrom;
+use std::error;
+use std::fmt;
+use cartridge::{Cartridge,Mirroring};
 
 #[derive(Debug)]
 pub enum InesError {
@@ -17,6 +20,24 @@ impl From<io::Error> for InesError {
         InesError::Io(err)
     }
 }
+
+impl fmt::Display for InesError {
+    fn fmt(self: &InesError, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InesError::Io(ref err)              => write!(f,"couldn't read the rom: {}",err),
+            InesError::InesFormat(ref message)  => write!(f,"not an ines rom: {}",message),
+            InesError::Unsupported(ref message) => write!(f,"unsupported rom: {}",message),
+        }
+    }
+}
+impl error::Error for InesError {
+    fn source(self: &InesError) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            InesError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
 #[repr(C,packed)]
 pub struct InesHeader {
   ines_identifier: u32,
@@ -28,38 +49,62 @@ pub struct InesHeader {
   padding: [u8;7],
 }
 
+impl InesHeader {
+    // http://wiki.nesdev.com/w/index.php/NES_2.0
+    //
+    pub fn is_nes2(self: &InesHeader) -> bool {
+        self.flags_7 & 0x0C == 0x08
+    }
+    /// Byte 15 of an NES 2.0 header, 0 (unspecified) for plain ines headers.
+    pub fn default_expansion_device(self: &InesHeader) -> u8 {
+        if self.is_nes2() { self.padding[6] & 0x3F } else { 0 }
+    }
+}
+
+fn read_header(file: &mut File) -> Result<InesHeader,InesError> {
+    let mut header_buf = [0u8;16];
+    file.read_exact(&mut header_buf)?;
+    let header = unsafe { ::std::mem::transmute::<[u8;16],InesHeader>(header_buf) };
+
+    if header.ines_identifier != 0x1A53454E {
+        return Err(InesError::InesFormat("Did not find ines header identifier".to_string()));
+    }
+    Ok(header)
+}
+
+pub fn load_ines_header<P:AsRef<Path>>(file_path: P) -> Result<InesHeader,InesError> {
+    let mut file = File::open(&file_path)?;
+    read_header(&mut file)
+}
+
 // http://wiki.nesdev.com/w/index.php/INES
 //
 pub fn load_ines<P:AsRef<Path>>(file_path: P) -> Result<Vec<u8>,InesError> {
-    let mut file = try!(File::open(&file_path));
-    let metadata = try!(::std::fs::metadata(&file_path));
+    let mut file = File::open(&file_path)?;
+    let metadata = ::std::fs::metadata(&file_path)?;
     if metadata.len() < ::std::mem::size_of::<InesHeader>() as u64 {
         return Err(InesError::InesFormat("File is smaller than the ines header.".to_string()));
     }
     
-    let mut header_buf = [0u8;16];
-    try!(file.read(&mut header_buf));
-    let header = unsafe { ::std::mem::transmute::<_,InesHeader>(header_buf) };
+    let header = read_header(&mut file)?;
 
-    if header.ines_identifier != 0x1A53454E {
-        return Err(InesError::InesFormat("Did not find ines header identifier".to_string()));
-    }
-    if header.flags_7 != 0 {
+    // NES 2.0 only adds information to the header, the layout we load is the same
+    let flags_7 = if header.is_nes2() { header.flags_7 & !0x0C } else { header.flags_7 };
+    if flags_7 != 0 {
         return Err(InesError::Unsupported("This version of the ines format is not supported".to_string()));
     }
     if header.flags_6 & 0x04 != 0 {
         return Err(InesError::Unsupported("Loading trainers is not supported".to_string()));
     }
 
-    try!(file.seek(SeekFrom::Start(0)));
+    file.seek(SeekFrom::Start(0))?;
 
     let mut file_bytes = Vec::<u8>::new();
-    try!(file.read_to_end(&mut file_bytes));
+    file.read_to_end(&mut file_bytes)?;
 
     // https://en.wikibooks.org/wiki/NES_Programming/Memory_Map
     //
-    let mut nes_bytes = Vec::<u8>::with_capacity(::std::u16::MAX as usize);
-    nes_bytes.resize(::std::u16::MAX as usize,0);
+    let mut nes_bytes = vec![0; u16::MAX as usize];
 
     let prg_addr = 16;
     let prg_page_len = 0xBFFF - 0x8000;
@@ -73,9 +118,54 @@ pub fn load_ines<P:AsRef<Path>>(file_path: P) -> Result<Vec<u8>,InesError> {
         &file_bytes[prg_addr..(prg_addr+prg_page_len+prg_addr)]
     };
     
-    &nes_bytes[0x8000..(prg_page_len+0x8000)].clone_from_slice(file_page);
-    &nes_bytes[0xC000..(prg_page_len+0xC000)].clone_from_slice(file_page2);
+    nes_bytes[0x8000..(prg_page_len+0x8000)].clone_from_slice(file_page);
+    nes_bytes[0xC000..(prg_page_len+0xC000)].clone_from_slice(file_page2);
     
     Ok(nes_bytes)
 }
 
+
+/// Load an ines file into a Cartridge, unlike load_ines this keeps PRG and CHR separate so the
+/// cartridge can be put on both the CPU and PPU buses.
+pub fn load_cartridge<P:AsRef<Path>>(file_path: P) -> Result<Cartridge,InesError> {
+    let mut file = File::open(&file_path)?;
+    let header = read_header(&mut file)?;
+
+    if header.flags_6 & 0x04 != 0 {
+        return Err(InesError::Unsupported("Loading trainers is not supported".to_string()));
+    }
+    let mapper = (header.flags_6 >> 4) | (header.flags_7 & 0xF0);
+    if mapper != 0 {
+        return Err(InesError::Unsupported(format!("Mapper {} is not supported",mapper)));
+    }
+
+    let prg_len = header.prg_rom_banks as usize * 0x4000;
+    let chr_len = header.chr_rom_banks as usize * 0x2000;
+
+    let mut file_bytes = Vec::<u8>::new();
+    file.read_to_end(&mut file_bytes)?;
+    if file_bytes.len() < prg_len + chr_len {
+        return Err(InesError::InesFormat(format!("Header specifies {} bytes of PRG and CHR rom, file only has {}",prg_len+chr_len,file_bytes.len())));
+    }
+    if prg_len == 0 {
+        return Err(InesError::InesFormat("Header specifies no PRG rom".to_string()));
+    }
+
+    // boards without CHR rom have 8KB of CHR ram instead
+    let chr_is_ram = chr_len == 0;
+    let chr = if chr_is_ram { vec![0;0x2000] } else { file_bytes[prg_len..(prg_len+chr_len)].to_vec() };
+
+    let mirroring = if header.flags_6 & 0x08 != 0 { Mirroring::FourScreen }
+               else if header.flags_6 & 0x01 != 0 { Mirroring::Vertical }
+               else                               { Mirroring::Horizontal };
+
+    Ok(Cartridge {
+        prg_rom: file_bytes[0..prg_len].to_vec(),
+        prg_ram: vec![0;0x2000],
+        chr,
+        chr_is_ram,
+        mirroring,
+        mapper,
+        expansion_device: header.default_expansion_device(),
+    })
+}
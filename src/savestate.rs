@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{Read,Write};
+use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
+
+/// Starts every save state.
+pub const MAGIC: [u8;8] = *b"TNSTATE\x1A";
+
+/// The layout of the chunks, bumped whenever a component saves something different.
+pub const VERSION: u32 = 2;
+
+// A save state is a header followed by chunks, all little endian:
+//
+//  0 magic     [u8;8]
+//  8 version   u32
+// 12 rom hash  u32, see Cartridge::rom_hash, 0 without a cartridge
+// 16 chunks    tag [u8;4], payload length u32, payload
+//
+// Each component writes its own chunk with Snapshot::save_state.  Chunks can come in any order
+// and ones a reader doesn't know are skipped, so states stay readable when a chunk is added.
+//
+
+#[derive(Debug)]
+pub enum SaveStateError {
+  Io(io::Error),
+  NotASaveState,
+  UnsupportedVersion(u32),
+  // the state was saved with a different rom inserted
+  RomMismatch { expected: u32, found: u32 },
+  MissingChunk(String),
+  // a chunk was cut short or holds something the console can't take
+  BadChunk { tag: String, err: io::Error },
+  // the state was saved from a console set up differently, e.g. with the other cpu core
+  Incompatible(String),
+}
+
+impl From<io::Error> for SaveStateError {
+    fn from(err: io::Error) -> SaveStateError {
+        SaveStateError::Io(err)
+    }
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(self: &SaveStateError, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SaveStateError::Io(ref err)                   => write!(f,"couldn't read the save state: {}",err),
+            SaveStateError::NotASaveState                 => write!(f,"not a trustines save state"),
+            SaveStateError::UnsupportedVersion(version)   => write!(f,"save state version {} is not supported, expected {}",version,VERSION),
+            SaveStateError::RomMismatch { expected, found } => write!(f,"the save state is for rom {:0>8X}, rom {:0>8X} is inserted",found,expected),
+            SaveStateError::MissingChunk(ref tag)         => write!(f,"the save state has no '{}' chunk",tag),
+            SaveStateError::BadChunk { ref tag, ref err } => write!(f,"the save state's '{}' chunk is corrupt: {}",tag,err),
+            SaveStateError::Incompatible(ref message)     => write!(f,"{}",message),
+        }
+    }
+}
+impl error::Error for SaveStateError {
+    fn source(self: &SaveStateError) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            SaveStateError::Io(ref err) | SaveStateError::BadChunk { ref err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Something with state that goes in a save state.  load_state reads back exactly what
+/// save_state wrote.  Configuration (the rom, the sample rate, which devices are connected) is
+/// not saved, loading into something configured differently fails with InvalidData.
+pub trait Snapshot {
+    fn save_state(&self, writer: &mut dyn Write) -> io::Result<()>;
+    fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()>;
+}
+
+/// Writes the header, then the chunks one at a time.
+pub struct StateWriter<'a> {
+    writer: &'a mut dyn Write,
+}
+
+impl<'a> StateWriter<'a> {
+    pub fn new(writer: &'a mut dyn Write, rom_hash: u32) -> io::Result<StateWriter<'a>> {
+        writer.write_all(&MAGIC)?;
+        writer.write_u32::<LittleEndian>(VERSION)?;
+        writer.write_u32::<LittleEndian>(rom_hash)?;
+        Ok(StateWriter { writer })
+    }
+
+    pub fn chunk(self: &mut StateWriter<'a>, tag: &[u8;4], component: &dyn Snapshot) -> io::Result<()> {
+        // the length comes first, so the payload is put together before any of it is written
+        let mut payload = Vec::new();
+        component.save_state(&mut payload)?;
+        self.writer.write_all(tag)?;
+        self.writer.write_u32::<LittleEndian>(payload.len() as u32)?;
+        self.writer.write_all(&payload)
+    }
+}
+
+/// A whole save state read into memory and checked against the rom, ready for the chunks to be
+/// handed out.
+pub struct StateReader {
+    chunks: HashMap<[u8;4],Vec<u8>>,
+}
+
+impl StateReader {
+    pub fn new(reader: &mut dyn Read, rom_hash: u32) -> Result<StateReader,SaveStateError> {
+        let mut magic = [0u8;8];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(SaveStateError::NotASaveState);
+        }
+        let version = reader.read_u32::<LittleEndian>()?;
+        if version != VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        let found = reader.read_u32::<LittleEndian>()?;
+        if found != rom_hash {
+            return Err(SaveStateError::RomMismatch { expected: rom_hash, found });
+        }
+
+        let mut chunks = HashMap::new();
+        loop {
+            let mut tag = [0u8;4];
+            match reader.read_exact(&mut tag) {
+                Ok(())                                                 => { },
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e)                                                 => return Err(SaveStateError::Io(e)),
+            }
+            // the length can't be trusted to allocate up front, a corrupt one could be 4GB
+            let len = reader.read_u32::<LittleEndian>()? as u64;
+            let mut payload = Vec::new();
+            (&mut *reader).take(len).read_to_end(&mut payload)?;
+            if payload.len() as u64 != len {
+                return Err(SaveStateError::BadChunk { tag: tag_name(&tag), err: io::Error::new(io::ErrorKind::UnexpectedEof,"the chunk is cut short") });
+            }
+            chunks.insert(tag,payload);
+        }
+        Ok(StateReader { chunks })
+    }
+
+    /// Check every chunk the console needs is there before any of them are loaded.
+    pub fn require(self: &StateReader, tags: &[&[u8;4]]) -> Result<(),SaveStateError> {
+        match tags.iter().find(|tag| !self.chunks.contains_key(**tag)) {
+            Some(tag) => Err(SaveStateError::MissingChunk(tag_name(tag))),
+            None      => Ok(()),
+        }
+    }
+
+    /// Load the chunk into component.  The whole payload has to be used.
+    pub fn chunk(self: &StateReader, tag: &[u8;4], component: &mut dyn Snapshot) -> Result<(),SaveStateError> {
+        let payload = self.chunks.get(tag).ok_or_else(|| SaveStateError::MissingChunk(tag_name(tag)))?;
+        let mut reader = &payload[..];
+        let result = component.load_state(&mut reader).and_then(|_| {
+            if reader.is_empty() { Ok(()) } else { Err(invalid_data("the chunk is longer than expected")) }
+        });
+        result.map_err(|err| SaveStateError::BadChunk { tag: tag_name(tag), err })
+    }
+}
+
+fn tag_name(tag: &[u8;4]) -> String {
+    String::from_utf8_lossy(tag).trim_end().to_string()
+}
+
+// helpers for the Snapshot implementations
+
+pub fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,message.to_string())
+}
+
+pub fn write_bool(writer: &mut dyn Write, val: bool) -> io::Result<()> {
+    writer.write_u8(val as u8)
+}
+pub fn read_bool(reader: &mut dyn Read) -> io::Result<bool> {
+    match reader.read_u8()? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(invalid_data("a flag is neither 0 nor 1")),
+    }
+}
+
+// a flag byte, then the value if there is one
+pub fn write_option8(writer: &mut dyn Write, val: Option<u8>) -> io::Result<()> {
+    write_bool(writer,val.is_some())?;
+    writer.write_u8(val.unwrap_or(0))
+}
+pub fn read_option8(reader: &mut dyn Read) -> io::Result<Option<u8>> {
+    let some = read_bool(reader)?;
+    let val = reader.read_u8()?;
+    Ok(if some { Some(val) } else { None })
+}
+pub fn write_option16(writer: &mut dyn Write, val: Option<u16>) -> io::Result<()> {
+    write_bool(writer,val.is_some())?;
+    writer.write_u16::<LittleEndian>(val.unwrap_or(0))
+}
+pub fn read_option16(reader: &mut dyn Read) -> io::Result<Option<u16>> {
+    let some = read_bool(reader)?;
+    let val = reader.read_u16::<LittleEndian>()?;
+    Ok(if some { Some(val) } else { None })
+}
+
+// ram and the like, the length goes first and has to match on the way back in
+pub fn write_block(writer: &mut dyn Write, block: &[u8]) -> io::Result<()> {
+    writer.write_u32::<LittleEndian>(block.len() as u32)?;
+    writer.write_all(block)
+}
+pub fn read_block(reader: &mut dyn Read, block: &mut [u8]) -> io::Result<()> {
+    if reader.read_u32::<LittleEndian>()? as usize != block.len() {
+        return Err(invalid_data("a block of memory is a different size"));
+    }
+    reader.read_exact(block)
+}
@@ -0,0 +1,21 @@
+//public mods
+pub mod palette;
+pub mod screenshot;
+pub mod ntsc;
+
+//private mods
+
+// hoisted interfaces
+pub use self::palette::Palette;
+pub use self::palette::BuiltinPalette;
+pub use self::palette::SignalSettings;
+pub use self::screenshot::Image;
+pub use self::screenshot::ImageFormat;
+pub use self::ntsc::NtscFilter;
+pub use self::ntsc::NtscSettings;
+pub use self::ntsc::Connection;
+
+// Turning the framebuffer into something to look at.  The ppu writes palette indexes and
+// emphasis bits, see Ppu::framebuffer, a Palette maps them to RGB, or an NtscFilter runs them
+// through a model of the TV, and an Image of that can be saved as a screenshot.
+//
@@ -0,0 +1,192 @@
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+use ppu::{SCREEN_WIDTH,SCREEN_HEIGHT};
+use video::{Image,Palette,SignalSettings};
+use video::palette;
+
+// http://wiki.nesdev.com/w/index.php/NTSC_video
+//
+// Rebuilds the signal the ppu sends to the TV and decodes it again, so the picture has the
+// blur, color fringes and dot crawl a CRT showed instead of the palette's flat colors.
+//
+// The ppu puts out 8 samples of its square wave per pixel, 12 to a cycle of the color
+// subcarrier (see palette::signal_level), so the phase moves on 8 samples a pixel and
+// 341*8 = 4 (mod 12) a scanline.  Where a frame starts depends on the frames before it, see
+// frame_phase.  The decoder averages the signal over luma_window samples for brightness and
+// correlates it with the subcarrier over chroma_window samples for color.  A window of 12 is a
+// whole subcarrier cycle, which cancels the other out completely on a flat color.  Composite
+// video carries both in one signal, so wherever the picture changes inside a window some of the
+// chroma gets into the luma as dots, and they crawl as the phase changes.  S-video carries luma
+// on its own wire, so it can be sharper and nothing leaks.  RGB skips the signal and just
+// stretches the generated palette.
+//
+
+/// Samples of the signal per pixel.
+pub const SAMPLES_PER_PIXEL: usize = 8;
+/// Output pixels per pixel of the framebuffer, the filtered image is this much wider.
+pub const OUTPUT_PER_PIXEL: usize = 2;
+pub const OUTPUT_WIDTH: usize = SCREEN_WIDTH * OUTPUT_PER_PIXEL;
+
+// samples per scanline, and per output pixel
+const LINE_SAMPLES: usize = SCREEN_WIDTH * SAMPLES_PER_PIXEL;
+const OUTPUT_SAMPLES: usize = SAMPLES_PER_PIXEL / OUTPUT_PER_PIXEL;
+
+/// How the console is connected to the TV.
+#[derive(PartialEq,Eq,Clone,Copy,Debug,Default)]
+pub enum Connection {
+    #[default]
+    Composite,
+    SVideo,
+    Rgb,
+}
+
+impl FromStr for Connection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Connection,String> {
+        match s.to_lowercase().as_ref() {
+            "composite"          => Ok(Connection::Composite),
+            "svideo" | "s-video" => Ok(Connection::SVideo),
+            "rgb"                => Ok(Connection::Rgb),
+            _                    => Err(format!("unknown connection '{}', expected composite, svideo or rgb",s)),
+        }
+    }
+}
+
+impl fmt::Display for Connection {
+    fn fmt(self: &Connection, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Connection::Composite => write!(f,"composite"),
+            Connection::SVideo    => write!(f,"s-video"),
+            Connection::Rgb       => write!(f,"rgb"),
+        }
+    }
+}
+
+#[derive(PartialEq,Clone,Debug)]
+pub struct NtscSettings {
+    pub connection: Connection,
+    /// The TV's knobs.
+    pub signal: SignalSettings,
+    /// Samples averaged for brightness, narrower is sharper.
+    pub luma_window: usize,
+    /// Samples correlated for color, wider smears the color more.
+    pub chroma_window: usize,
+}
+
+impl Default for NtscSettings {
+    fn default() -> NtscSettings {
+        NtscSettings::preset(Connection::Composite)
+    }
+}
+
+impl NtscSettings {
+    /// Settings that look like the connection does on an ordinary TV.
+    pub fn preset(connection: Connection) -> NtscSettings {
+        let (luma_window,chroma_window) = match connection {
+            Connection::Composite => (12,24),
+            Connection::SVideo    => (2,12),
+            Connection::Rgb       => (1,1),
+        };
+        NtscSettings { connection, signal: SignalSettings::default(), luma_window, chroma_window }
+    }
+}
+
+/// Where the subcarrier is at the first pixel of a frame, counting from a frame at phase 0.
+/// Frames are 4 samples apart, except that with rendering on odd frames skip a dot and are 8
+/// apart, so a game that keeps rendering on flips between two phases and one that doesn't cycles
+/// through three.
+pub fn frame_phase(frame: u64, rendering: bool) -> usize {
+    if rendering {
+        (frame % 2) as usize * 4
+    } else {
+        (frame % 3) as usize * 4
+    }
+}
+
+/// Turns framebuffers into what the TV would have shown.
+pub struct NtscFilter {
+    settings: NtscSettings,
+    // what Rgb shows
+    palette: Palette,
+    // the cosine and sine of the subcarrier at each phase, with the hue knob applied
+    carrier: [(f32,f32);12],
+}
+
+impl NtscFilter {
+    pub fn new(settings: NtscSettings) -> NtscFilter {
+        let mut carrier = [(0.0,0.0);12];
+        for (phase,wave) in carrier.iter_mut().enumerate() {
+            let angle = PI * (phase as f32 + palette::DECODE_PHASE + settings.signal.hue / 30.0) / 6.0;
+            *wave = (angle.cos(),angle.sin());
+        }
+        let palette = Palette::generate(&settings.signal);
+        NtscFilter { settings, palette, carrier }
+    }
+
+    pub fn settings(self: &NtscFilter) -> &NtscSettings {
+        &self.settings
+    }
+
+    /// Filter a framebuffer (see Nes::framebuffer) that starts at the given subcarrier phase, see
+    /// frame_phase.  The image is OUTPUT_WIDTH wide and as tall as the framebuffer.
+    pub fn filter(self: &NtscFilter, framebuffer: &[u16], phase: usize) -> Image {
+        let mut rgb = Vec::with_capacity(OUTPUT_WIDTH*SCREEN_HEIGHT*3);
+        for (y,line) in framebuffer.chunks(SCREEN_WIDTH).enumerate() {
+            match self.settings.connection {
+                Connection::Rgb => for pixel in line {
+                    for _ in 0..OUTPUT_PER_PIXEL {
+                        rgb.extend_from_slice(&self.palette.rgb(*pixel));
+                    }
+                },
+                _ => self.filter_line(line,(phase + 4*y) % 12,&mut rgb),
+            }
+        }
+        Image { width: OUTPUT_WIDTH, height: framebuffer.len() / SCREEN_WIDTH, rgb }
+    }
+
+    fn filter_line(self: &NtscFilter, line: &[u16], phase: usize, rgb: &mut Vec<u8>) {
+        let phase_at = |n: usize| (phase + n) % 12;
+        let composite = self.settings.connection == Connection::Composite;
+
+        // running sums of the luma signal and of the chroma signal times the subcarrier, so each
+        // window is a subtraction.  outside the picture is black.
+        let mut luma_sum = vec![0.0f32;LINE_SAMPLES + 1];
+        let mut i_sum = vec![0.0f32;LINE_SAMPLES + 1];
+        let mut q_sum = vec![0.0f32;LINE_SAMPLES + 1];
+        // s-video splits the signal into each pixel's average and what's left over
+        let averages: Vec<f32> = if composite { Vec::new() } else {
+            line.iter().map(|pixel| (0..12).map(|p| palette::signal_level(*pixel as usize,p)).sum::<f32>() / 12.0).collect()
+        };
+        for n in 0..LINE_SAMPLES {
+            let pixel = line[n / SAMPLES_PER_PIXEL] as usize;
+            let level = palette::signal_level(pixel,phase_at(n));
+            let (luma,chroma) = if composite {
+                (level,level)
+            } else {
+                let average = averages[n / SAMPLES_PER_PIXEL];
+                (average,level - average)
+            };
+            let (cos,sin) = self.carrier[phase_at(n)];
+            luma_sum[n+1] = luma_sum[n] + luma;
+            i_sum[n+1] = i_sum[n] + chroma * cos;
+            q_sum[n+1] = q_sum[n] + chroma * sin;
+        }
+        let window = |sum: &[f32], center: usize, width: usize| {
+            let start = center as isize - (width / 2) as isize;
+            let clamp = |n: isize| n.clamp(0,LINE_SAMPLES as isize) as usize;
+            (sum[clamp(start + width as isize)] - sum[clamp(start)]) / width as f32
+        };
+
+        let signal = &self.settings.signal;
+        let chroma_gain = palette::CHROMA_GAIN * signal.saturation * signal.contrast;
+        for out in 0..OUTPUT_WIDTH {
+            let center = out*OUTPUT_SAMPLES + OUTPUT_SAMPLES/2;
+            let y = window(&luma_sum,center,self.settings.luma_window.max(1));
+            let i = window(&i_sum,center,self.settings.chroma_window.max(1));
+            let q = window(&q_sum,center,self.settings.chroma_window.max(1));
+            rgb.extend_from_slice(&palette::yiq_to_rgb(y * signal.contrast + signal.brightness,i * chroma_gain,q * chroma_gain));
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::f32::consts::PI;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use ppu::{SCREEN_WIDTH,SCREEN_HEIGHT};
+
+// http://wiki.nesdev.com/w/index.php/PPU_palettes
+//
+// The ppu doesn't output RGB, it outputs one of 64 colors as an NTSC signal, and PPUMASK's
+// emphasis bits darken parts of that signal.  The framebuffer keeps the color in bits 0-5 and
+// the emphasis in bits 6-8 (red, green, blue on an NTSC ppu), which is 512 combinations, so a
+// palette is 512 RGB colors indexed by the framebuffer's pixels.
+//
+// Palettes come from a table of the 64 colors with the emphasis worked out from them, from a .pal
+// file of 64 or all 512 colors (3 bytes each, RGB), or are generated from a model of the NTSC
+// signal, see generate.
+//
+
+/// Colors in a palette without emphasis.
+pub const COLORS: usize = 64;
+/// Colors in a palette with every emphasis combination.
+pub const EMPHASIS_COLORS: usize = 512;
+
+// the 2C02's colors as measured from its video output, from the nesdev wiki
+const NTSC_2C02: [[u8;3];COLORS] = [
+    [ 84, 84, 84],[  0, 30,116],[  8, 16,144],[ 48,  0,136],[ 68,  0,100],[ 92,  0, 48],[ 84,  4,  0],[ 60, 24,  0],
+    [ 32, 42,  0],[  8, 58,  0],[  0, 64,  0],[  0, 60,  0],[  0, 50, 60],[  0,  0,  0],[  0,  0,  0],[  0,  0,  0],
+    [152,150,152],[  8, 76,196],[ 48, 50,236],[ 92, 30,228],[136, 20,176],[160, 20,100],[152, 34, 32],[120, 60,  0],
+    [ 84, 90,  0],[ 40,114,  0],[  8,124,  0],[  0,118, 40],[  0,102,120],[  0,  0,  0],[  0,  0,  0],[  0,  0,  0],
+    [236,238,236],[ 76,154,236],[120,124,236],[176, 98,236],[228, 84,236],[236, 88,180],[236,106,100],[212,136, 32],
+    [160,170,  0],[116,196,  0],[ 76,208, 32],[ 56,204,108],[ 56,180,204],[ 60, 60, 60],[  0,  0,  0],[  0,  0,  0],
+    [236,238,236],[168,204,236],[188,188,236],[212,178,236],[236,174,236],[236,174,212],[236,180,176],[228,196,144],
+    [204,210,120],[180,222,120],[168,226,144],[152,226,180],[160,214,228],[160,162,160],[  0,  0,  0],[  0,  0,  0],
+];
+
+// http://wiki.nesdev.com/w/index.php/PPU_palettes#2C03_and_2C05
+//
+// the arcade RGB ppus, 3 bits a channel.  the 2C05 has the same colors, it just swaps PPUCTRL
+// and PPUMASK
+const RGB_2C03: [u16;COLORS] = [
+    0o333,0o014,0o006,0o326,0o403,0o503,0o510,0o420,0o320,0o120,0o031,0o040,0o022,0o000,0o000,0o000,
+    0o555,0o036,0o027,0o407,0o507,0o704,0o700,0o630,0o430,0o140,0o040,0o053,0o044,0o000,0o000,0o000,
+    0o777,0o357,0o447,0o637,0o707,0o737,0o740,0o750,0o660,0o360,0o070,0o276,0o077,0o444,0o000,0o000,
+    0o777,0o567,0o657,0o757,0o747,0o755,0o764,0o772,0o773,0o572,0o473,0o276,0o467,0o666,0o000,0o000,
+];
+
+// how much an emphasis bit darkens the channels it doesn't emphasize, roughly what the 2C02 does
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+/// The palettes that come with the emulator.
+#[derive(PartialEq,Eq,Clone,Copy,Debug,Default)]
+pub enum BuiltinPalette {
+    // the NTSC ppu, measured
+    #[default]
+    Ppu2C02,
+    // the RGB ppus in the PlayChoice-10 and Vs. System, emphasis turns a channel fully on
+    Ppu2C03,
+    Ppu2C05,
+    // the PAL ppu, generated, with the red and green emphasis bits swapped like the 2C07 has them
+    Pal,
+}
+
+impl FromStr for BuiltinPalette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BuiltinPalette,String> {
+        match s.to_lowercase().as_ref() {
+            "2c02" => Ok(BuiltinPalette::Ppu2C02),
+            "2c03" => Ok(BuiltinPalette::Ppu2C03),
+            "2c05" => Ok(BuiltinPalette::Ppu2C05),
+            "pal"  => Ok(BuiltinPalette::Pal),
+            _      => Err(format!("unknown palette '{}', expected 2c02, 2c03, 2c05 or pal",s)),
+        }
+    }
+}
+
+impl fmt::Display for BuiltinPalette {
+    fn fmt(self: &BuiltinPalette, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuiltinPalette::Ppu2C02 => write!(f,"2C02"),
+            BuiltinPalette::Ppu2C03 => write!(f,"2C03"),
+            BuiltinPalette::Ppu2C05 => write!(f,"2C05"),
+            BuiltinPalette::Pal     => write!(f,"PAL"),
+        }
+    }
+}
+
+/// The knobs on the TV, for generate.
+#[derive(PartialEq,Clone,Copy,Debug)]
+pub struct SignalSettings {
+    /// Degrees the hue is rotated by.
+    pub hue: f32,
+    /// 1 is normal, 0 is black and white.
+    pub saturation: f32,
+    /// 1 is normal.
+    pub contrast: f32,
+    /// Added to the brightness, 0 is normal and 1 turns black into white.
+    pub brightness: f32,
+}
+
+impl Default for SignalSettings {
+    fn default() -> SignalSettings {
+        SignalSettings { hue: 0.0, saturation: 1.0, contrast: 1.0, brightness: 0.0 }
+    }
+}
+
+/// Maps framebuffer pixels to RGB.
+#[derive(PartialEq,Clone,Debug)]
+pub struct Palette {
+    colors: Vec<[u8;3]>,
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::builtin(BuiltinPalette::Ppu2C02)
+    }
+}
+
+impl Palette {
+    pub fn builtin(builtin: BuiltinPalette) -> Palette {
+        match builtin {
+            BuiltinPalette::Ppu2C02 => Palette::from_colors(&NTSC_2C02).unwrap(),
+            BuiltinPalette::Ppu2C03 | BuiltinPalette::Ppu2C05 => {
+                let level = |octal: u16, shift: u16| (((octal >> shift) & 7) * 255 / 7) as u8;
+                let colors: Vec<[u8;3]> = RGB_2C03.iter().map(|octal| [level(*octal,6),level(*octal,3),level(*octal,0)]).collect();
+                // the RGB ppus don't darken, emphasis turns the channel all the way up
+                Palette { colors: (0..EMPHASIS_COLORS).map(|pixel| {
+                    let mut color = colors[pixel % COLORS];
+                    for (channel,value) in color.iter_mut().enumerate() {
+                        if (pixel / COLORS) & (1 << channel) != 0 { *value = 255; }
+                    }
+                    color
+                }).collect() }
+            },
+            BuiltinPalette::Pal => {
+                // a little hue shift from the PAL decoding, and the 2C07 has PPUMASK's red and green
+                // emphasis bits the other way round
+                let settings = SignalSettings { hue: -15.0, ..Default::default() };
+                let ntsc = Palette::generate(&settings);
+                let swap = |emphasis: usize| (emphasis & 4) | ((emphasis & 1) << 1) | ((emphasis & 2) >> 1);
+                Palette { colors: (0..EMPHASIS_COLORS).map(|pixel| ntsc.colors[swap(pixel / COLORS)*COLORS + pixel % COLORS]).collect() }
+            },
+        }
+    }
+
+    /// A palette from 64 colors, with the emphasis combinations worked out from them, or from
+    /// all 512 colors.  Returns None for any other number of colors.
+    pub fn from_colors(colors: &[[u8;3]]) -> Option<Palette> {
+        match colors.len() {
+            COLORS          => Some(Palette { colors: (0..EMPHASIS_COLORS).map(|pixel| emphasize(colors[pixel % COLORS],pixel / COLORS)).collect() }),
+            EMPHASIS_COLORS => Some(Palette { colors: colors.to_vec() }),
+            _               => None,
+        }
+    }
+
+    /// Read a .pal file, 64 or 512 RGB triples.
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Palette> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        if bytes.len() % 3 != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,format!("a palette is RGB triples, {} bytes isn't",bytes.len())));
+        }
+        let colors: Vec<[u8;3]> = bytes.chunks(3).map(|rgb| [rgb[0],rgb[1],rgb[2]]).collect();
+        Palette::from_colors(&colors).ok_or_else(||
+            io::Error::new(io::ErrorKind::InvalidData,format!("a palette has {} or {} colors, not {}",COLORS,EMPHASIS_COLORS,colors.len())))
+    }
+
+    pub fn load<P:AsRef<Path>>(file_path: P) -> io::Result<Palette> {
+        Palette::read(&mut File::open(&file_path)?)
+    }
+
+    /// Generate the palette by modelling the signal the 2C02 puts out and decoding it like a TV
+    /// would.  With the default settings this comes out close to the measured 2C02 palette.
+    pub fn generate(settings: &SignalSettings) -> Palette {
+        Palette { colors: (0..EMPHASIS_COLORS).map(|pixel| decode(signal(pixel),settings)).collect() }
+    }
+
+    /// All 512 colors, indexed by framebuffer pixel.
+    pub fn colors(self: &Palette) -> &[[u8;3]] {
+        &self.colors
+    }
+
+    /// The color of one framebuffer pixel.
+    pub fn rgb(self: &Palette, pixel: u16) -> [u8;3] {
+        self.colors[pixel as usize % EMPHASIS_COLORS]
+    }
+
+    /// A whole framebuffer as packed RGB bytes.
+    pub fn to_rgb(self: &Palette, framebuffer: &[u16]) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(SCREEN_WIDTH*SCREEN_HEIGHT*3);
+        for pixel in framebuffer {
+            rgb.extend_from_slice(&self.rgb(*pixel));
+        }
+        rgb
+    }
+}
+
+// emphasis is bit 0 red, bit 1 green, bit 2 blue.  each bit set darkens the other two channels.
+fn emphasize(color: [u8;3], emphasis: usize) -> [u8;3] {
+    let mut out = color;
+    for (channel,value) in out.iter_mut().enumerate() {
+        let darkened = (0..3).filter(|bit| *bit != channel && emphasis & (1 << bit) != 0).count();
+        *value = (*value as f32 * EMPHASIS_ATTENUATION.powi(darkened as i32)).round() as u8;
+    }
+    out
+}
+
+// http://wiki.nesdev.com/w/index.php/NTSC_video
+//
+// The ppu makes a square wave between a low and a high voltage, 12 samples to a cycle of the
+// color subcarrier.  The luma (bits 4-5 of the color) picks the voltages and the hue (bits 0-3)
+// the phase: hue 0 is always high, 1-12 are high for 6 of the 12 samples starting at a different
+// phase each, 13 is always low and 14-15 are black.  Each emphasis bit darkens the samples in
+// phase with one of the hues.
+//
+
+// volts, by luma
+pub const SIGNAL_LOW: [f32;4] = [0.350,0.518,0.962,1.550];
+pub const SIGNAL_HIGH: [f32;4] = [1.094,1.506,1.962,1.962];
+pub const SIGNAL_BLACK: f32 = 0.518;
+pub const SIGNAL_WHITE: f32 = 1.962;
+
+// the hue each emphasis bit darkens the samples in phase with
+const EMPHASIS_HUES: [usize;3] = [0,4,8];
+
+/// Whether the hue's square wave is high at this sample, 0-11.
+pub fn in_color_phase(hue: usize, phase: usize) -> bool {
+    (hue + phase) % 12 < 6
+}
+
+/// The signal for a framebuffer pixel at each of the 12 phases, 0 at black and 1 at white.
+pub fn signal_level(pixel: usize, phase: usize) -> f32 {
+    let hue = pixel & 0x0F;
+    let luma = (pixel >> 4) & 0x03;
+    let emphasis = (pixel >> 6) & 0x07;
+    let mut volts = match hue {
+        0         => SIGNAL_HIGH[luma],
+        13        => SIGNAL_LOW[luma],
+        14 | 15   => SIGNAL_BLACK,
+        _         => if in_color_phase(hue,phase) { SIGNAL_HIGH[luma] } else { SIGNAL_LOW[luma] },
+    };
+    let darkened = (0..3).any(|bit| emphasis & (1 << bit) != 0 && in_color_phase(EMPHASIS_HUES[bit],phase));
+    if darkened && hue < 14 {
+        volts *= EMPHASIS_ATTENUATION;
+    }
+    (volts - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+fn signal(pixel: usize) -> [f32;12] {
+    let mut levels = [0.0;12];
+    for (phase,level) in levels.iter_mut().enumerate() {
+        *level = signal_level(pixel,phase);
+    }
+    levels
+}
+
+/// Where the subcarrier's phase lines up with the I and Q axes, in samples.
+pub const DECODE_PHASE: f32 = 4.0;
+/// Correlating a square wave with a sine picks up about 2/3 of its swing, this makes up for it.
+pub const CHROMA_GAIN: f32 = 1.5;
+
+// average the samples for Y, correlate them with the subcarrier for I and Q, adjust them the way
+// the TV's knobs do, then convert to RGB
+fn decode(levels: [f32;12], settings: &SignalSettings) -> [u8;3] {
+    let (mut y,mut i,mut q) = (0.0,0.0,0.0);
+    for (phase,level) in levels.iter().enumerate() {
+        let angle = PI * (phase as f32 + DECODE_PHASE + settings.hue / 30.0) / 6.0;
+        y += level / 12.0;
+        i += level * angle.cos() / 12.0;
+        q += level * angle.sin() / 12.0;
+    }
+    let chroma = CHROMA_GAIN * settings.saturation * settings.contrast;
+    yiq_to_rgb(y * settings.contrast + settings.brightness,i * chroma,q * chroma)
+}
+
+/// FCC NTSC YIQ to RGB, each 0-1 before clamping.
+pub fn yiq_to_rgb(y: f32, i: f32, q: f32) -> [u8;3] {
+    let clamp = |value: f32| (value * 255.0).round().clamp(0.0,255.0) as u8;
+    [clamp(y + 0.946882*i + 0.623557*q),
+     clamp(y - 0.274788*i - 0.635691*q),
+     clamp(y - 1.108545*i + 1.709007*q)]
+}
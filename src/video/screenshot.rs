@@ -0,0 +1,226 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter,Write};
+use std::path::Path;
+use std::str::FromStr;
+use byteorder::{BigEndian,WriteBytesExt};
+use cartridge::crc32;
+use ppu::{SCREEN_WIDTH,SCREEN_HEIGHT};
+use video::Palette;
+
+// Screenshots are written as PPM, which is a short header and the raw pixels, or PNG.  PNG needs
+// zlib compressed data, which is done here with deflate's fixed Huffman codes and a simple
+// matcher, good enough for frames that are mostly runs of the same few colors:
+//
+// http://netpbm.sourceforge.net/doc/ppm.html
+// https://www.w3.org/TR/png/
+// https://www.rfc-editor.org/rfc/rfc1950 (zlib) and rfc1951 (deflate)
+//
+
+#[derive(PartialEq,Clone,Copy,Debug)]
+pub enum ImageFormat {
+    Png,
+    Ppm,
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ImageFormat,String> {
+        match s.to_lowercase().as_ref() {
+            "png" => Ok(ImageFormat::Png),
+            "ppm" => Ok(ImageFormat::Ppm),
+            _     => Err(format!("unknown image format '{}', expected png or ppm",s)),
+        }
+    }
+}
+
+impl ImageFormat {
+    /// The format a file name's extension asks for.
+    pub fn from_path<P:AsRef<Path>>(file_path: P) -> Option<ImageFormat> {
+        file_path.as_ref().extension().and_then(|ext| ext.to_str()).and_then(|ext| ext.parse().ok())
+    }
+}
+
+/// An RGB image, 3 bytes a pixel, rows top to bottom.
+#[derive(PartialEq,Clone,Debug)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+impl Image {
+    /// The framebuffer, see Nes::framebuffer, through the palette.
+    pub fn from_framebuffer(framebuffer: &[u16], palette: &Palette) -> Image {
+        Image { width: SCREEN_WIDTH, height: SCREEN_HEIGHT, rgb: palette.to_rgb(framebuffer) }
+    }
+
+    pub fn write<W: Write>(self: &Image, writer: &mut W, format: ImageFormat) -> io::Result<()> {
+        match format {
+            ImageFormat::Png => self.write_png(writer),
+            ImageFormat::Ppm => self.write_ppm(writer),
+        }
+    }
+
+    /// Save to a file, as PNG or PPM going by its extension.
+    pub fn save<P:AsRef<Path>>(self: &Image, file_path: P) -> io::Result<()> {
+        let format = ImageFormat::from_path(&file_path).ok_or_else(||
+            io::Error::new(io::ErrorKind::InvalidInput,format!("{} doesn't end in .png or .ppm",file_path.as_ref().display())))?;
+        let mut writer = BufWriter::new(File::create(&file_path)?);
+        self.write(&mut writer,format)?;
+        writer.flush()
+    }
+
+    /// Binary PPM (P6).
+    pub fn write_ppm<W: Write>(self: &Image, writer: &mut W) -> io::Result<()> {
+        write!(writer,"P6\n{} {}\n255\n",self.width,self.height)?;
+        writer.write_all(&self.rgb)
+    }
+
+    /// 8 bit RGB PNG.
+    pub fn write_png<W: Write>(self: &Image, writer: &mut W) -> io::Result<()> {
+        writer.write_all(b"\x89PNG\r\n\x1A\n")?;
+
+        let mut header = Vec::new();
+        header.write_u32::<BigEndian>(self.width as u32)?;
+        header.write_u32::<BigEndian>(self.height as u32)?;
+        // 8 bits per channel, truecolor, deflate, adaptive filtering, not interlaced
+        header.extend_from_slice(&[8,2,0,0,0]);
+        write_chunk(writer,b"IHDR",&header)?;
+
+        // each row starts with its filter type, always 0 (none) here
+        let stride = self.width*3;
+        let mut scanlines = Vec::with_capacity((stride + 1)*self.height);
+        for row in self.rgb.chunks(stride) {
+            scanlines.push(0);
+            scanlines.extend_from_slice(row);
+        }
+        write_chunk(writer,b"IDAT",&zlib(&scanlines))?;
+        write_chunk(writer,b"IEND",&[])
+    }
+}
+
+// length, tag, data, then the CRC of the tag and data
+fn write_chunk<W: Write>(writer: &mut W, tag: &[u8;4], data: &[u8]) -> io::Result<()> {
+    writer.write_u32::<BigEndian>(data.len() as u32)?;
+    writer.write_all(tag)?;
+    writer.write_all(data)?;
+    writer.write_u32::<BigEndian>(crc32(tag.iter().chain(data.iter())))
+}
+
+fn zlib(data: &[u8]) -> Vec<u8> {
+    // deflate with a 32K window, no dictionary
+    let mut out = vec![0x78,0x01];
+    out.extend(deflate(data));
+    let adler = adler32(data);
+    out.extend_from_slice(&[(adler >> 24) as u8,(adler >> 16) as u8,(adler >> 8) as u8,adler as u8]);
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a,mut b) = (1u32,0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+const WINDOW: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+// length codes 257-285, the shortest length each one covers and how many extra bits follow it
+const LENGTH_BASE: [u16;29] = [3,4,5,6,7,8,9,10,11,13,15,17,19,23,27,31,35,43,51,59,67,83,99,115,131,163,195,227,258];
+const LENGTH_EXTRA: [u8;29] = [0,0,0,0,0,0,0,0,1,1,1,1,2,2,2,2,3,3,3,3,4,4,4,4,5,5,5,5,0];
+// distance codes 0-29
+const DISTANCE_BASE: [u16;30] = [1,2,3,4,5,7,9,13,17,25,33,49,65,97,129,193,257,385,513,769,1025,1537,2049,3073,
+                                 4097,6145,8193,12289,16385,24577];
+const DISTANCE_EXTRA: [u8;30] = [0,0,0,0,1,1,2,2,3,3,4,4,5,5,6,6,7,7,8,8,9,9,10,10,11,11,12,12,13,13];
+
+// bits go out least significant first, Huffman codes most significant first
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn bits(self: &mut BitWriter, value: u32, count: u32) {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+    fn code(self: &mut BitWriter, code: u32, count: u32) {
+        let reversed = code.reverse_bits() >> (32 - count);
+        self.bits(reversed,count);
+    }
+    // the fixed literal/length code for symbol 0-287
+    fn symbol(self: &mut BitWriter, symbol: u32) {
+        match symbol {
+            0..=143   => self.code(0x30 + symbol,8),
+            144..=255 => self.code(0x190 + symbol - 144,9),
+            256..=279 => self.code(symbol - 256,7),
+            _         => self.code(0xC0 + symbol - 280,8),
+        }
+    }
+    fn finish(mut self: BitWriter) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+// one block with the fixed codes, matches found through the most recent place each 3 byte
+// sequence was seen
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter { out: Vec::new(), bits: 0, count: 0 };
+    // final block, fixed codes
+    writer.bits(1,1);
+    writer.bits(1,2);
+
+    let hash = |i: usize| ((data[i] as usize) << 10 ^ (data[i+1] as usize) << 5 ^ data[i+2] as usize) & 0x7FFF;
+    let mut last_seen = vec![usize::MAX;0x8000];
+    let mut i = 0;
+    while i < data.len() {
+        let mut length = 0;
+        let mut distance = 0;
+        if i + MIN_MATCH <= data.len() {
+            let candidate = last_seen[hash(i)];
+            if candidate != usize::MAX && i - candidate <= WINDOW {
+                let longest = MAX_MATCH.min(data.len() - i);
+                length = (0..longest).take_while(|n| data[candidate + n] == data[i + n]).count();
+                distance = i - candidate;
+            }
+            last_seen[hash(i)] = i;
+        }
+
+        if length < MIN_MATCH {
+            writer.symbol(data[i] as u32);
+            i += 1;
+            continue;
+        }
+        let code = LENGTH_BASE.iter().rposition(|base| *base as usize <= length).unwrap();
+        writer.symbol(257 + code as u32);
+        writer.bits((length - LENGTH_BASE[code] as usize) as u32,LENGTH_EXTRA[code] as u32);
+        let code = DISTANCE_BASE.iter().rposition(|base| *base as usize <= distance).unwrap();
+        writer.code(code as u32,5);
+        writer.bits((distance - DISTANCE_BASE[code] as usize) as u32,DISTANCE_EXTRA[code] as u32);
+
+        // remember where the sequences inside the match were too
+        for j in i+1..i+length {
+            if j + MIN_MATCH <= data.len() {
+                last_seen[hash(j)] = j;
+            }
+        }
+        i += length;
+    }
+    writer.symbol(256);
+    writer.finish()
+}
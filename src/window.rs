@@ -0,0 +1,233 @@
+extern crate x11_dl;
+
+use std::error;
+use std::ffi::CString;
+use std::fmt;
+use std::mem;
+use std::os::raw::{c_char,c_int,c_uint};
+use std::ptr;
+use std::thread;
+use std::time::{Duration,Instant};
+use self::x11_dl::error::OpenError;
+use self::x11_dl::keysym;
+use self::x11_dl::xlib;
+use input::ButtonState;
+use video::Image;
+
+// The window --windowed shows the picture in, and the keyboard as controller 1, through Xlib.
+// libX11 is loaded when a window is opened rather than linked, so building doesn't need it and a
+// build without a display can still run headless.
+//
+// https://www.x.org/releases/current/doc/libX11/libX11/libX11.html
+//
+// The picture is scaled to the window with nearest neighbor and drawn with XPutImage, which wants
+// it in the server's pixel format.  That's taken to be 0x00RRGGBB in 32 bits, which is what every
+// 24 bit TrueColor visual in use has, and anything else is refused when the window opens.
+//
+// The keys are:
+//
+//   arrows         the d-pad
+//   X              A
+//   Z              B
+//   Enter          Start
+//   Right Shift    Select
+//   Esc            close the window
+//
+
+/// How long an NTSC frame lasts, the ppu's 60.0988 frames a second.
+pub const FRAME_DURATION: Duration = Duration::from_nanos(16_639_267);
+
+#[derive(Debug)]
+pub enum WindowError {
+  // libX11 couldn't be loaded
+  Library(OpenError),
+  // XOpenDisplay failed, usually because DISPLAY isn't set
+  Display,
+  // the screen's depth, when it isn't 24 bit color
+  Depth(i32),
+}
+
+impl From<OpenError> for WindowError {
+    fn from(err: OpenError) -> WindowError {
+        WindowError::Library(err)
+    }
+}
+
+impl fmt::Display for WindowError {
+    fn fmt(self: &WindowError, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WindowError::Library(ref err) => write!(f,"couldn't load libX11: {}",err),
+            WindowError::Display          => write!(f,"couldn't open the X display, is DISPLAY set?"),
+            WindowError::Depth(depth)     => write!(f,"the screen is {} bit, the window needs 24 bit color",depth),
+        }
+    }
+}
+impl error::Error for WindowError {
+    fn source(self: &WindowError) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            WindowError::Library(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// What the keyboard did since the last poll.
+#[derive(PartialEq,Clone,Debug,Default)]
+pub struct WindowInput {
+    /// The buttons held down now.
+    pub buttons: ButtonState,
+    /// The window was closed, or Esc pressed.
+    pub closed: bool,
+}
+
+pub struct Window {
+    xlib: xlib::Xlib,
+    display: *mut xlib::Display,
+    window: xlib::Window,
+    gc: xlib::GC,
+    visual: *mut xlib::Visual,
+    depth: c_int,
+    // the WM_DELETE_WINDOW message the window manager sends when the close button is clicked
+    delete_window: xlib::Atom,
+    width: usize,
+    height: usize,
+    // the scaled picture, in the server's pixel format
+    pixels: Vec<u32>,
+    input: WindowInput,
+    next_frame: Instant,
+}
+
+impl Window {
+    /// Open a width by height window with the title.
+    pub fn open(title: &str, width: usize, height: usize) -> Result<Window,WindowError> {
+        let xlib = xlib::Xlib::open()?;
+        unsafe {
+            let display = (xlib.XOpenDisplay)(ptr::null());
+            if display.is_null() {
+                return Err(WindowError::Display);
+            }
+            let screen = (xlib.XDefaultScreen)(display);
+            let depth = (xlib.XDefaultDepth)(display,screen);
+            if depth != 24 {
+                (xlib.XCloseDisplay)(display);
+                return Err(WindowError::Depth(depth));
+            }
+            let black = (xlib.XBlackPixel)(display,screen);
+            let window = (xlib.XCreateSimpleWindow)(display,(xlib.XRootWindow)(display,screen),0,0,
+                                                    width as c_uint,height as c_uint,0,black,black);
+            let title = CString::new(title.replace('\0',"")).unwrap_or_default();
+            (xlib.XStoreName)(display,window,title.as_ptr());
+            (xlib.XSelectInput)(display,window,xlib::KeyPressMask | xlib::KeyReleaseMask | xlib::StructureNotifyMask);
+
+            let name = CString::new("WM_DELETE_WINDOW").unwrap();
+            let mut delete_window = (xlib.XInternAtom)(display,name.as_ptr(),xlib::False);
+            (xlib.XSetWMProtocols)(display,window,&mut delete_window,1);
+            (xlib.XMapWindow)(display,window);
+            (xlib.XFlush)(display);
+
+            Ok(Window {
+                gc: (xlib.XDefaultGC)(display,screen),
+                visual: (xlib.XDefaultVisual)(display,screen),
+                xlib,
+                display,
+                window,
+                depth,
+                delete_window,
+                width,
+                height,
+                pixels: Vec::new(),
+                input: WindowInput::default(),
+                next_frame: Instant::now(),
+            })
+        }
+    }
+
+    /// Handle the events since the last poll and say what the keyboard is doing.
+    pub fn poll(self: &mut Window) -> WindowInput {
+        unsafe {
+            while (self.xlib.XPending)(self.display) > 0 {
+                let mut event: xlib::XEvent = mem::zeroed();
+                (self.xlib.XNextEvent)(self.display,&mut event);
+                match event.get_type() {
+                    xlib::KeyPress | xlib::KeyRelease => {
+                        let held = event.get_type() == xlib::KeyPress;
+                        let key = (self.xlib.XLookupKeysym)(&mut event.key,0) as c_uint;
+                        self.press(key,held);
+                    },
+                    xlib::ConfigureNotify => {
+                        self.width = event.configure.width.max(1) as usize;
+                        self.height = event.configure.height.max(1) as usize;
+                    },
+                    xlib::ClientMessage if event.client_message.data.get_long(0) as xlib::Atom == self.delete_window => {
+                        self.input.closed = true;
+                    },
+                    xlib::DestroyNotify => self.input.closed = true,
+                    _ => { },
+                }
+            }
+        }
+        self.input.clone()
+    }
+
+    fn press(self: &mut Window, key: c_uint, held: bool) {
+        let buttons = &mut self.input.buttons;
+        match key {
+            keysym::XK_Up      => buttons.up = held,
+            keysym::XK_Down    => buttons.down = held,
+            keysym::XK_Left    => buttons.left = held,
+            keysym::XK_Right   => buttons.right = held,
+            keysym::XK_x       => buttons.a = held,
+            keysym::XK_z       => buttons.b = held,
+            keysym::XK_Return  => buttons.start = held,
+            keysym::XK_Shift_R => buttons.select = held,
+            keysym::XK_Escape  => self.input.closed = true,
+            _ => { },
+        }
+    }
+
+    /// Draw the image, stretched to fill the window.
+    pub fn show(self: &mut Window, image: &Image) {
+        let (width,height) = (self.width,self.height);
+        self.pixels.clear();
+        for y in 0..height {
+            let row = y * image.height / height * image.width;
+            for x in 0..width {
+                let rgb = &image.rgb[(row + x * image.width / width) * 3..][..3];
+                self.pixels.push((rgb[0] as u32) << 16 | (rgb[1] as u32) << 8 | rgb[2] as u32);
+            }
+        }
+        unsafe {
+            let ximage = (self.xlib.XCreateImage)(self.display,self.visual,self.depth as c_uint,xlib::ZPixmap,0,
+                                                  self.pixels.as_mut_ptr() as *mut c_char,width as c_uint,height as c_uint,32,0);
+            if ximage.is_null() {
+                return;
+            }
+            (self.xlib.XPutImage)(self.display,self.window,self.gc,ximage,0,0,0,0,width as c_uint,height as c_uint);
+            // the pixels are ours, only free the XImage itself
+            (self.xlib.XFree)(ximage as *mut _);
+            (self.xlib.XFlush)(self.display);
+        }
+    }
+
+    /// Sleep until it's time for the next frame, so the console runs at its own speed.  If the
+    /// emulation has fallen more than a frame behind it starts counting again from now instead of
+    /// rushing to catch up.
+    pub fn wait_for_next_frame(self: &mut Window) {
+        self.next_frame += FRAME_DURATION;
+        let now = Instant::now();
+        if self.next_frame > now {
+            thread::sleep(self.next_frame - now);
+        } else if now - self.next_frame > FRAME_DURATION {
+            self.next_frame = now;
+        }
+    }
+}
+
+impl Drop for Window {
+    fn drop(self: &mut Window) {
+        unsafe {
+            (self.xlib.XDestroyWindow)(self.display,self.window);
+            (self.xlib.XCloseDisplay)(self.display);
+        }
+    }
+}
@@ -1,3 +1,7 @@
+// the address_mode tests call .is_ok() on fetch_and_decode and leave it, their asserts check
+// what was decoded
+#![allow(unused_must_use)]
+
 extern crate trustines;
 
 mod memory {
@@ -63,16 +67,81 @@ mod memory {
     }
 }
 
+mod opcode {
+    use trustines::cpu;
+    use trustines::cpu::{OpcodeClass,AddressMode};
+
+    #[test]
+    fn builtin_matches_csv() {
+        let (exec,debug) = cpu::opcode::builtin();
+        let (csv_exec,csv_debug) = cpu::opcode::load_from_file("resources/opcodes.csv").unwrap();
+        assert_eq!(256,exec.len());
+        assert_eq!(256,debug.len());
+
+        for i in 0..256 {
+            assert_eq!(csv_exec[i].opcode,exec[i].opcode);
+            assert_eq!(csv_exec[i].opcode_class,exec[i].opcode_class);
+            assert_eq!(csv_exec[i].address_mode,exec[i].address_mode);
+            assert_eq!(csv_exec[i].len,exec[i].len);
+            assert_eq!(csv_exec[i].cycles,exec[i].cycles);
+            assert_eq!(csv_exec[i].page_cycles,exec[i].page_cycles);
+            assert_eq!(csv_debug[i].opcode,debug[i].opcode);
+            assert_eq!(csv_debug[i].name,debug[i].name);
+            assert_eq!(csv_debug[i].mnemonic,debug[i].mnemonic);
+            assert_eq!(csv_debug[i].address_mode_name,debug[i].address_mode_name);
+            assert_eq!(csv_debug[i].notes,debug[i].notes);
+        }
+    }
+    #[test]
+    fn builtin_entries() {
+        let (exec,debug) = cpu::opcode::builtin();
+        assert_eq!(OpcodeClass::ADC,exec[0x6D].opcode_class);
+        assert_eq!(AddressMode::Absolute,exec[0x6D].address_mode);
+        assert_eq!(3,exec[0x6D].len);
+        assert_eq!(4,exec[0x6D].cycles);
+        assert_eq!("ADC",debug[0x6D].name);
+        assert_eq!("ADC",debug[0x6D].mnemonic);
+        assert_eq!("ILL_ISC",debug[0xE7].name);
+        assert_eq!("ISB",debug[0xE7].mnemonic);
+        assert!(exec[0xE7].opcode_class.is_illegal());
+        assert!(!exec[0x6D].opcode_class.is_illegal());
+        assert_eq!("documented as unstable",debug[0xAB].notes);
+    }
+    #[test]
+    fn load_without_mnemonic_column() {
+        // the layout from before the Mnemonic column
+        let csv = ::std::fs::read_to_string("resources/opcodes.csv").unwrap();
+        let old: Vec<String> = csv.lines().map(|line| {
+            let mut fields: Vec<&str> = line.split(',').collect();
+            if fields.len() > 2 { fields.remove(2); }
+            fields.join(",")
+        }).collect();
+        let path = ::std::env::temp_dir().join("trustines_opcodes_old.csv");
+        ::std::fs::write(&path,old.join("\n")).unwrap();
+
+        let (exec,debug) = cpu::opcode::load_from_file(&path).unwrap();
+        let (builtin_exec,_) = cpu::opcode::builtin();
+        for i in 0..256 {
+            assert_eq!(builtin_exec[i].opcode_class,exec[i].opcode_class);
+            assert_eq!(builtin_exec[i].address_mode,exec[i].address_mode);
+        }
+        assert_eq!("ADC",debug[0x6D].mnemonic);
+        assert_eq!("LAX",debug[0xA7].mnemonic);
+        assert_eq!("ISC",debug[0xE7].mnemonic);
+
+        ::std::fs::write(&path,"Opcode,Name,Len,Cycles,PageCycles,Notes\n0x6D,ADC,3,4,0,\n").unwrap();
+        assert!(matches!(cpu::opcode::load_from_file(&path),Err(cpu::opcode::OpcodeLoadError::MissingColumn(_))));
+    }
+}
+
 mod address_mode {
-    use std::{u8,u16};
     use trustines::cpu;
     use trustines::memory::Memory;
     use trustines::cpu::OpcodeClass;
     use trustines::cpu::AddressMode;
 
     fn build_executor() -> cpu::CpuExecutor {
-        let opcode_info = cpu::opcode::load_from_file("resources/opcodes.csv").unwrap();
-        return cpu::CpuExecutor::new(opcode_info.0);
+        Default::default()
     }
 
     // NOTE: the opcodes specified in build_memory are not being executed, so the
@@ -687,5 +756,2937 @@ mod address_mode {
         assert_eq!(1,cpu.decode_register.addr_final.unwrap());
         assert_eq!(5,cpu.decode_register.value_final.unwrap());
     }
+
+// ------------------ Hardware Wraparound ------------------ //
+    // http://wiki.nesdev.com/w/index.php/CPU_addressing_modes
+    #[test]
+    fn indirect_page_wraparound() {
+        let mut cpu: cpu::CpuState = Default::default();
+        let mut mem = Memory::new();
+        let exec = build_executor();
+
+        // JMP ($02FF) takes the high byte from $0200, not $0300
+        let _ = mem.write8(0,0x6C); // 0x6C = JMP Indirect
+        let _ = mem.write16(1,0x02FF);
+        let _ = mem.write8(0x02FF,0x34);
+        let _ = mem.write8(0x0200,0x12);
+        let _ = mem.write8(0x0300,0x56);
+
+        cpu.pc = 0;
+        exec.fetch_and_decode(&mut cpu,&mut mem).unwrap();
+
+        assert_eq!(0x02FF,cpu.decode_register.addr_intermediate.unwrap());
+        assert_eq!(0x1234,cpu.decode_register.addr_final.unwrap());
+    }
+    #[test]
+    fn indexed_indirect_wraparound() {
+        let mut cpu: cpu::CpuState = Default::default();
+        let mut mem = Memory::new();
+        let exec = build_executor();
+
+        // ADC ($FE,X) where X = 3 reads the pointer from $01, not $0101
+        let _ = mem.write8(0x0400,0x61); // 0x61 = ADC IndexedIndirect
+        let _ = mem.write8(0x0401,0xFE);
+        let _ = mem.write16(0x01,0x0300);
+        let _ = mem.write8(0x0300,15);
+
+        cpu.pc = 0x0400;
+        cpu.x = 3;
+        exec.fetch_and_decode(&mut cpu,&mut mem).unwrap();
+
+        assert_eq!(0x01,cpu.decode_register.addr_intermediate.unwrap());
+        assert_eq!(0x0300,cpu.decode_register.addr_final.unwrap());
+        assert_eq!(15,cpu.decode_register.value_final.unwrap());
+    }
+    #[test]
+    fn indexed_indirect_pointer_wraparound() {
+        let mut cpu: cpu::CpuState = Default::default();
+        let mut mem = Memory::new();
+        let exec = build_executor();
+
+        // ADC ($FF,X) where X = 0 takes the high byte of the pointer from $00, not $0100
+        let _ = mem.write8(0x0400,0x61); // 0x61 = ADC IndexedIndirect
+        let _ = mem.write8(0x0401,0xFF);
+        let _ = mem.write8(0xFF,0x00);
+        let _ = mem.write8(0x00,0x03);
+        let _ = mem.write8(0x0100,0x05);
+        let _ = mem.write8(0x0300,15);
+
+        cpu.pc = 0x0400;
+        exec.fetch_and_decode(&mut cpu,&mut mem).unwrap();
+
+        assert_eq!(0xFF,cpu.decode_register.addr_intermediate.unwrap());
+        assert_eq!(0x0300,cpu.decode_register.addr_final.unwrap());
+        assert_eq!(15,cpu.decode_register.value_final.unwrap());
+    }
+    #[test]
+    fn indirect_indexed_pointer_wraparound() {
+        let mut cpu: cpu::CpuState = Default::default();
+        let mut mem = Memory::new();
+        let exec = build_executor();
+
+        // ADC ($FF),Y where Y = 5 takes the high byte of the pointer from $00, not $0100
+        let _ = mem.write8(0x0400,0x71); // 0x71 = ADC IndirectIndexed
+        let _ = mem.write8(0x0401,0xFF);
+        let _ = mem.write8(0xFF,0x00);
+        let _ = mem.write8(0x00,0x03);
+        let _ = mem.write8(0x0100,0x05);
+        let _ = mem.write8(0x0305,10);
+
+        cpu.pc = 0x0400;
+        cpu.y = 5;
+        exec.fetch_and_decode(&mut cpu,&mut mem).unwrap();
+
+        assert_eq!(0xFF,cpu.decode_register.addr_init.unwrap());
+        assert_eq!(0x0300,cpu.decode_register.addr_intermediate.unwrap());
+        assert_eq!(0x0305,cpu.decode_register.addr_final.unwrap());
+        assert_eq!(10,cpu.decode_register.value_final.unwrap());
+    }
+    #[test]
+    fn relative_wraparound_forward() {
+        let mut cpu: cpu::CpuState = Default::default();
+        let mut mem = Memory::new();
+        let exec = build_executor();
+
+        let _ = mem.write8(0xFFF0,0x90); // 0x90 = BCC Relative
+        let _ = mem.write8(0xFFF1,0x10);
+
+        cpu.pc = 0xFFF0;
+        exec.fetch_and_decode(&mut cpu,&mut mem).unwrap();
+
+        assert_eq!(0x0002,cpu.decode_register.addr_final.unwrap());
+    }
+    #[test]
+    fn relative_wraparound_backward() {
+        let mut cpu: cpu::CpuState = Default::default();
+        let mut mem = Memory::new();
+        let exec = build_executor();
+
+        let _ = mem.write8(0,0x90); // 0x90 = BCC Relative
+        let _ = mem.write8(1,0xFC);
+
+        cpu.pc = 0;
+        exec.fetch_and_decode(&mut cpu,&mut mem).unwrap();
+
+        assert_eq!(0xFFFE,cpu.decode_register.addr_final.unwrap());
+    }
+    #[test]
+    fn taken_branch_wraparound() {
+        let mut cpu: cpu::CpuState = Default::default();
+        let mut mem = Memory::new();
+        let exec = build_executor();
+
+        let _ = mem.write8(0xFFF0,0x90); // 0x90 = BCC Relative
+        let _ = mem.write8(0xFFF1,0x10);
+
+        cpu.pc = 0xFFF0;
+        cpu.C = false;
+        exec.step(&mut cpu,&mut mem).unwrap();
+
+        // 2 cycles, plus 1 for the taken branch and 1 for landing on a different page
+        assert_eq!(0x0002,cpu.pc);
+        assert_eq!(4,cpu.cycles);
+    }
+    #[test]
+    fn operand_wraparound() {
+        let mut cpu: cpu::CpuState = Default::default();
+        let mut mem = Memory::new();
+        let exec = build_executor();
+
+        // the operand of an instruction at $FFFF is at $0000
+        let _ = mem.write8(0xFFFF,0x69); // 0x69 = ADC Immediate
+        let _ = mem.write8(0,0x42);
+
+        cpu.pc = 0xFFFF;
+        exec.step(&mut cpu,&mut mem).unwrap();
+
+        assert_eq!(0,cpu.decode_register.addr_final.unwrap());
+        assert_eq!(0x42,cpu.a);
+        assert_eq!(1,cpu.pc);
+    }
+    #[test]
+    fn absolute_operand_wraparound() {
+        let mut cpu: cpu::CpuState = Default::default();
+        let mut mem = Memory::new();
+        let exec = build_executor();
+
+        // LDA $0300 at $FFFE, the high byte of the address is at $0000
+        let _ = mem.write8(0xFFFE,0xAD); // 0xAD = LDA Absolute
+        let _ = mem.write8(0xFFFF,0x00);
+        let _ = mem.write8(0,0x03);
+        let _ = mem.write8(0x0300,0x42);
+
+        cpu.pc = 0xFFFE;
+        exec.step(&mut cpu,&mut mem).unwrap();
+
+        assert_eq!(0x0300,cpu.decode_register.addr_final.unwrap());
+        assert_eq!(0x42,cpu.a);
+        assert_eq!(1,cpu.pc);
+    }
+}
+
+mod instructions {
+    use trustines::cpu;
+    use trustines::cpu::assembler;
+    use trustines::memory::Memory;
+
+    #[test]
+    fn brk() {
+        let mut cpu: cpu::CpuState = Default::default();
+        let mut mem = Memory::new();
+        let exec: cpu::CpuExecutor = Default::default();
+
+        mem.write(0x0200,&[0x00,0xFF]); // BRK and its padding byte
+        mem.write(0xFFFE,&[0x00,0x03]);
+        cpu.pc = 0x0200;
+        cpu.sp = 0xFD;
+        cpu.pack_flags(0x24 | 0x01);
+        exec.step(&mut cpu,&mut mem).unwrap();
+
+        assert_eq!(0x0300,cpu.pc);
+        assert_eq!(0xFA,cpu.sp);
+        assert!(cpu.I);
+        assert_eq!(0x35,mem.read8(0x01FB).unwrap()); // B and bit 5 are set in the pushed flags
+        assert_eq!(0x0202,mem.read16(0x01FC).unwrap());
+    }
+    #[test]
+    fn read_modify_write_memory() {
+        let mut cpu: cpu::CpuState = Default::default();
+        let mut mem = Memory::new();
+        let exec: cpu::CpuExecutor = Default::default();
+
+        assembler::assemble(".org $0200\nASL $10\nROR $10").unwrap().load(&mut mem);
+        mem.write(0x0010,&[0x81]);
+        cpu.pc = 0x0200;
+        exec.step(&mut cpu,&mut mem).unwrap();
+        assert_eq!(0x02,mem.read8(0x10).unwrap());
+        assert!(cpu.C);
+
+        exec.step(&mut cpu,&mut mem).unwrap();
+        assert_eq!(0x81,mem.read8(0x10).unwrap());
+        assert!(!cpu.C);
+        assert!(cpu.S);
+    }
+}
+
+mod input {
+    use trustines::memory::Memory;
+    use trustines::input::ButtonState;
+
+    fn strobe(mem: &mut Memory) {
+        let _ = mem.write8(0x4016,1);
+        let _ = mem.write8(0x4016,0);
+    }
+
+    #[test]
+    fn button_state_roundtrip() {
+        let buttons = ButtonState { a: true, start: true, left: true, ..Default::default() };
+        assert_eq!(0x49,buttons.to_byte());
+        assert_eq!(buttons,ButtonState::from_byte(0x49));
+    }
+    #[test]
+    fn read_order() {
+        let mut mem = Memory::new();
+        mem.input.set_buttons(0,ButtonState { a: true, select: true, up: true, right: true, ..Default::default() });
+        strobe(&mut mem);
+
+        // A, B, Select, Start, Up, Down, Left, Right
+        let expected = [1,0,1,0,1,0,0,1];
+        for bit in expected.iter() {
+            assert_eq!(*bit,mem.read8(0x4016).unwrap() & 1);
+        }
+    }
+    #[test]
+    fn reads_past_8_return_1() {
+        let mut mem = Memory::new();
+        strobe(&mut mem);
+
+        for _ in 0..8 {
+            assert_eq!(0,mem.read8(0x4016).unwrap() & 1);
+        }
+        for _ in 0..8 {
+            assert_eq!(1,mem.read8(0x4016).unwrap() & 1);
+        }
+    }
+    #[test]
+    fn strobe_held_high_returns_a() {
+        let mut mem = Memory::new();
+        mem.input.set_buttons(0,ButtonState { a: true, ..Default::default() });
+        let _ = mem.write8(0x4016,1);
+
+        for _ in 0..10 {
+            assert_eq!(1,mem.read8(0x4016).unwrap() & 1);
+        }
+        mem.input.set_buttons(0,ButtonState { b: true, ..Default::default() });
+        assert_eq!(0,mem.read8(0x4016).unwrap() & 1);
+    }
+    #[test]
+    fn ports_are_independent() {
+        let mut mem = Memory::new();
+        mem.input.set_buttons(0,ButtonState { a: true, ..Default::default() });
+        mem.input.set_buttons(1,ButtonState { b: true, ..Default::default() });
+        strobe(&mut mem);
+
+        assert_eq!(1,mem.read8(0x4016).unwrap() & 1);
+        assert_eq!(0,mem.read8(0x4017).unwrap() & 1);
+        assert_eq!(0,mem.read8(0x4016).unwrap() & 1);
+        assert_eq!(1,mem.read8(0x4017).unwrap() & 1);
+    }
+    #[test]
+    fn open_bus_upper_bits() {
+        let mut mem = Memory::new();
+        mem.input.set_buttons(0,ButtonState { a: true, ..Default::default() });
+        strobe(&mut mem);
+
+        // LDA $4016 leaves the high byte of the address ($40) on the bus before the read
+        let _ = mem.write(0x0200,&[0xAD,0x16,0x40]);
+        let _ = mem.read8(0x0202);
+        assert_eq!(0x41,mem.read8(0x4016).unwrap());
+    }
+    #[test]
+    fn peek_does_not_shift() {
+        let mut mem = Memory::new();
+        mem.input.set_buttons(0,ButtonState { a: true, ..Default::default() });
+        strobe(&mut mem);
+
+        assert_eq!(1,mem.peek8(0x4016).unwrap() & 1);
+        assert_eq!(1,mem.peek8(0x4016).unwrap() & 1);
+        assert_eq!(1,mem.read8(0x4016).unwrap() & 1);
+        assert_eq!(0,mem.read8(0x4016).unwrap() & 1);
+    }
+}
+
+mod expansion_devices {
+    use trustines::memory::Memory;
+    use trustines::input::{ButtonState,InputPorts,ExpansionDevice};
+
+    fn build_memory(device: ExpansionDevice) -> Memory {
+        let mut mem = Memory::new();
+        mem.input = InputPorts::for_expansion_device(device);
+        mem
+    }
+    fn strobe(mem: &mut Memory) {
+        let _ = mem.write8(0x4016,1);
+        let _ = mem.write8(0x4016,0);
+    }
+    fn read_serial(mem: &mut Memory, addr: u16, bit: u8, count: usize) -> Vec<u8> {
+        (0..count).map(|_| (mem.read8(addr).unwrap() >> bit) & 1).collect()
+    }
+
+    #[test]
+    fn nes2_ids() {
+        assert_eq!(ExpansionDevice::StandardControllers,ExpansionDevice::from_nes2_id(0x01));
+        assert_eq!(ExpansionDevice::FourScore,ExpansionDevice::from_nes2_id(0x02));
+        assert_eq!(ExpansionDevice::Zapper,ExpansionDevice::from_nes2_id(0x08));
+        assert_eq!(ExpansionDevice::PowerPadSideB,ExpansionDevice::from_nes2_id(0x0C));
+        assert_eq!(ExpansionDevice::ArkanoidNes,ExpansionDevice::from_nes2_id(0x0F));
+        assert_eq!(ExpansionDevice::Unspecified,ExpansionDevice::from_nes2_id(0x2A));
+    }
+    #[test]
+    fn four_score() {
+        let mut mem = build_memory(ExpansionDevice::FourScore);
+        mem.input.set_buttons(0,ButtonState { a: true, ..Default::default() });
+        mem.input.set_buttons(2,ButtonState { b: true, ..Default::default() });
+        mem.input.set_buttons(3,ButtonState { right: true, ..Default::default() });
+        strobe(&mut mem);
+
+        let port1 = read_serial(&mut mem,0x4016,0,24);
+        assert_eq!(vec![1,0,0,0,0,0,0,0, 0,1,0,0,0,0,0,0, 0,0,0,1,0,0,0,0],port1);
+
+        let port2 = read_serial(&mut mem,0x4017,0,24);
+        assert_eq!(vec![0,0,0,0,0,0,0,0, 0,0,0,0,0,0,0,1, 0,0,1,0,0,0,0,0],port2);
+    }
+    #[test]
+    fn famicom_four_players_simple() {
+        let mut mem = build_memory(ExpansionDevice::FamicomFourPlayers);
+        mem.input.set_buttons(0,ButtonState { a: true, ..Default::default() });
+        mem.input.set_buttons(2,ButtonState { a: true, b: true, ..Default::default() });
+        strobe(&mut mem);
+
+        assert_eq!(0x03,mem.read8(0x4016).unwrap() & 0x03);
+        assert_eq!(0x02,mem.read8(0x4016).unwrap() & 0x03);
+        assert_eq!(0x00,mem.read8(0x4016).unwrap() & 0x03);
+    }
+    #[test]
+    fn zapper() {
+        // reads sample whatever the ppu has drawn so far
+        fn beam(mem: &mut Memory, scanline: u16, dot: u16) {
+            mem.ppu.scanline = scanline;
+            mem.ppu.dot = dot;
+        }
+        let mut mem = build_memory(ExpansionDevice::Zapper);
+        mem.ppu.framebuffer = vec![0x0Fu16; 256*240];
+        mem.ppu.framebuffer[100*256 + 50] = 0x30; // white
+
+        mem.input.set_zapper(1,Some((50,100)),true);
+
+        // beam hasn't reached the pixel yet, no light but the trigger is pulled
+        beam(&mut mem,99,0);
+        assert_eq!(0x18,mem.read8(0x4017).unwrap() & 0x18);
+
+        // just drawn, light is sensed (D3 low)
+        beam(&mut mem,100,60);
+        assert_eq!(0x10,mem.read8(0x4017).unwrap() & 0x18);
+
+        // long after it was drawn the photodiode has decayed
+        beam(&mut mem,200,0);
+        assert_eq!(0x18,mem.read8(0x4017).unwrap() & 0x18);
+
+        // dark pixels are never sensed
+        mem.ppu.framebuffer[100*256 + 50] = 0x0D;
+        beam(&mut mem,100,60);
+        assert_eq!(0x18,mem.read8(0x4017).unwrap() & 0x18);
+
+        // off screen
+        mem.ppu.framebuffer[100*256 + 50] = 0x30;
+        mem.input.set_zapper(1,None,false);
+        beam(&mut mem,100,60);
+        assert_eq!(0x08,mem.read8(0x4017).unwrap() & 0x18);
+    }
+    #[test]
+    fn arkanoid_paddle() {
+        let mut mem = build_memory(ExpansionDevice::ArkanoidNes);
+        mem.input.set_paddle(1,0xA5,true);
+        strobe(&mut mem);
+
+        // !0xA5 = 0x5A, shifted out MSB first on D4
+        let bits = read_serial(&mut mem,0x4017,4,8);
+        assert_eq!(vec![0,1,0,1,1,0,1,0],bits);
+        assert_eq!(0x08,mem.read8(0x4017).unwrap() & 0x08);
+    }
+    #[test]
+    fn power_pad() {
+        let mut mem = build_memory(ExpansionDevice::PowerPadSideB);
+        // buttons 1, 9 and 12
+        mem.input.set_power_pad(1,(1 << 0) | (1 << 8) | (1 << 11));
+        strobe(&mut mem);
+
+        let d3 = read_serial(&mut mem,0x4017,3,8);
+        assert_eq!(vec![0,1,0,1,0,0,0,0],d3);
+
+        strobe(&mut mem);
+        let d4 = read_serial(&mut mem,0x4017,4,8);
+        assert_eq!(vec![0,0,1,0,1,1,1,1],d4);
+    }
+}
+
+mod cartridge {
+    use trustines::rom_loader;
+    use trustines::cartridge::Mirroring;
+
+    #[test]
+    fn load_nestest() {
+        let cart = rom_loader::load_cartridge("roms/nestest.nes").unwrap();
+        assert_eq!(0x4000,cart.prg_rom.len());
+        assert_eq!(0x2000,cart.chr.len());
+        assert!(!cart.chr_is_ram);
+        assert_eq!(0,cart.mapper);
+        assert_eq!(Mirroring::Horizontal,cart.mirroring);
+
+        // 16KB boards are mirrored into $C000
+        assert_eq!(cart.cpu_read(0x8000),cart.cpu_read(0xC000));
+        assert_eq!(Some(0x4C),cart.cpu_read(0xC000)); // JMP $C5F5
+    }
+}
+
+mod ppu {
+    use trustines::memory::Memory;
+    use trustines::cartridge::{Cartridge,Mirroring};
+
+    fn build_memory(mirroring: Mirroring) -> Memory {
+        let mut mem = Memory::new();
+        mem.cartridge = Some(Cartridge {
+            prg_rom: vec![0;0x4000],
+            prg_ram: vec![0;0x2000],
+            chr: vec![0;0x2000],
+            chr_is_ram: true,
+            mirroring,
+            mapper: 0,
+            expansion_device: 0,
+        });
+        mem
+    }
+    fn set_vram_addr(mem: &mut Memory, addr: u16) {
+        let _ = mem.write8(0x2006,(addr >> 8) as u8);
+        let _ = mem.write8(0x2006,addr as u8);
+    }
+
+    #[test]
+    fn buffered_vram_read() {
+        let mut mem = build_memory(Mirroring::Horizontal);
+        set_vram_addr(&mut mem,0x2000);
+        let _ = mem.write8(0x2007,0x11);
+        let _ = mem.write8(0x2007,0x22);
+
+        set_vram_addr(&mut mem,0x2000);
+        let _ = mem.read8(0x2007); // stale buffer
+        assert_eq!(0x11,mem.read8(0x2007).unwrap());
+        assert_eq!(0x22,mem.read8(0x2007).unwrap());
+    }
+    #[test]
+    fn palette_reads_are_immediate() {
+        let mut mem = build_memory(Mirroring::Horizontal);
+        set_vram_addr(&mut mem,0x3F01);
+        let _ = mem.write8(0x2007,0x2A);
+
+        set_vram_addr(&mut mem,0x3F01);
+        assert_eq!(0x2A,mem.read8(0x2007).unwrap() & 0x3F);
+    }
+    #[test]
+    fn palette_backdrop_mirrors() {
+        let mut mem = build_memory(Mirroring::Horizontal);
+        set_vram_addr(&mut mem,0x3F10);
+        let _ = mem.write8(0x2007,0x15);
+        assert_eq!(0x15,mem.ppu.palette[0]);
+    }
+    #[test]
+    fn nametable_mirroring() {
+        let mut mem = build_memory(Mirroring::Vertical);
+        set_vram_addr(&mut mem,0x2000);
+        let _ = mem.write8(0x2007,0x33);
+
+        set_vram_addr(&mut mem,0x2800);
+        let _ = mem.read8(0x2007);
+        assert_eq!(0x33,mem.read8(0x2007).unwrap());
+
+        let mut mem = build_memory(Mirroring::Horizontal);
+        set_vram_addr(&mut mem,0x2000);
+        let _ = mem.write8(0x2007,0x44);
+
+        set_vram_addr(&mut mem,0x2400);
+        let _ = mem.read8(0x2007);
+        assert_eq!(0x44,mem.read8(0x2007).unwrap());
+    }
+    #[test]
+    fn status_read_clears_vblank_and_latch() {
+        let mut mem = build_memory(Mirroring::Horizontal);
+        mem.ppu.status = 0x80;
+        let _ = mem.write8(0x2006,0x21);
+
+        assert_eq!(0x80,mem.read8(0x2002).unwrap() & 0x80);
+        assert_eq!(0x00,mem.read8(0x2002).unwrap() & 0x80);
+
+        // the first $2006 write was forgotten
+        set_vram_addr(&mut mem,0x2345);
+        assert_eq!(0x2345,mem.ppu.v);
+    }
+    #[test]
+    fn registers_are_mirrored() {
+        let mut mem = build_memory(Mirroring::Horizontal);
+        let _ = mem.write8(0x3FF8,0x80); // $2000
+        assert_eq!(0x80,mem.ppu.ctrl);
+    }
+    #[test]
+    fn oam_dma() {
+        let mut mem = build_memory(Mirroring::Horizontal);
+        for i in 0..256 {
+            let _ = mem.write8(0x0200 + i,i as u8);
+        }
+        let _ = mem.write8(0x4014,0x02);
+        assert_eq!(0x00,mem.ppu.oam[0]);
+        assert_eq!(0xFF,mem.ppu.oam[255]);
+        assert_eq!(513,mem.dma_cycles);
+    }
+}
+
+mod nes {
+    use trustines::nes::Nes;
+    use trustines::cartridge::{Cartridge,Mirroring};
+    use test_console;
+
+    // $C000: LDA #$80
+    //        STA $2000  ; enable NMI
+    // loop:  JMP loop
+    //
+    // $C010: LDX $10    ; NMI handler, counts NMIs in $10
+    //        INX
+    //        STX $10
+    //        RTI
+    //
+    fn build_nes() -> Nes {
+        let mut prg = vec![0xEA;0x4000];
+        prg[0x0000..0x0008].clone_from_slice(&[0xA9,0x80, 0x8D,0x00,0x20, 0x4C,0x05,0xC0]);
+        prg[0x0010..0x0016].clone_from_slice(&[0xA6,0x10, 0xE8, 0x86,0x10, 0x40]);
+        prg[0x3FFA..0x4000].clone_from_slice(&[0x10,0xC0, 0x00,0xC0, 0x00,0xC0]);
+
+        let cartridge = Cartridge {
+            prg_rom: prg,
+            prg_ram: vec![0;0x2000],
+            chr: vec![0;0x2000],
+            chr_is_ram: true,
+            mirroring: Mirroring::Horizontal,
+            mapper: 0,
+            expansion_device: 0,
+        };
+        let mut nes = Nes::new(cartridge);
+        nes.power_cycle().unwrap();
+        nes
+    }
+
+    #[test]
+    fn power_cycle_uses_reset_vector() {
+        let nes = build_nes();
+        assert_eq!(0xC000,nes.cpu.pc);
+        assert_eq!(0xFD,nes.cpu.sp);
+    }
+    #[test]
+    fn step_instruction() {
+        let mut nes = build_nes();
+        let cycles = nes.cpu.cycles;
+        nes.step_instruction().unwrap();
+        assert_eq!(0xC002,nes.cpu.pc);
+        assert_eq!(cycles+2,nes.cpu.cycles);
+        // 21 dots for the reset sequence and 6 for LDA #
+        assert_eq!(27,nes.mem.ppu.dot);
+    }
+    #[test]
+    fn step_cycle() {
+        let mut nes = build_nes();
+        nes.step_instruction().unwrap();
+
+        // STA absolute takes 4 cycles, the instruction runs on the first one
+        nes.step_cycle().unwrap();
+        assert_eq!(0xC005,nes.cpu.pc);
+        let dot = nes.mem.ppu.dot;
+        nes.step_cycle().unwrap();
+        nes.step_cycle().unwrap();
+        nes.step_cycle().unwrap();
+        assert_eq!(0xC005,nes.cpu.pc);
+        assert_eq!(dot+9,nes.mem.ppu.dot);
+
+        nes.step_cycle().unwrap();
+        assert_eq!(0xC005,nes.cpu.pc); // JMP to itself
+    }
+    #[test]
+    fn run_frame_timing() {
+        let mut nes = build_nes();
+        nes.run_frame().unwrap();
+        let start = nes.cpu.cycles;
+        nes.run_frame().unwrap();
+
+        // 341 dots * 262 scanlines / 3 dots per cycle, give or take an instruction
+        let elapsed = nes.cpu.cycles - start;
+        assert!((29780 - 7..=29781 + 7).contains(&elapsed), "frame took {} cycles",elapsed);
+    }
+    #[test]
+    fn nmi() {
+        let mut nes = build_nes();
+        nes.run_frame().unwrap();
+        nes.run_frame().unwrap();
+        nes.run_frame().unwrap();
+        assert_eq!(2,nes.mem.mem[0x10]);
+        assert_eq!(3,nes.mem.ppu.frame);
+    }
+    #[test]
+    fn reset() {
+        let mut nes = build_nes();
+        nes.run_frame().unwrap();
+        nes.reset().unwrap();
+        assert_eq!(0xC000,nes.cpu.pc);
+        assert_eq!(0xFA,nes.cpu.sp);
+        assert!(nes.cpu.I);
+        assert_eq!(0,nes.mem.ppu.ctrl);
+    }
+    #[test]
+    fn cpu_test_mode_registers() {
+        // $4018-$401F ignore writes and read back open bus, the high byte of the address
+        let mut nes = test_console::powered("LDA #$42
+                                             STA $401F
+                                             LDA $4018","");
+        for _ in 0..3 {
+            nes.step_instruction().unwrap();
+        }
+        assert_eq!(0x40,nes.cpu.a);
+    }
+    #[test]
+    fn oam_dma_alignment() {
+        // NOP takes 2 cycles and LDA $00 takes 3, so the writes to $4014 land on cycles of
+        // different parity
+        let mut taken = Vec::new();
+        for &cycle_core in &[false,true] {
+            for lead_in in &["NOP","LDA $00"] {
+                let cartridge = test_console::cartridge(&format!("{}\n STA $4014",lead_in),"");
+                let mut nes = if cycle_core { Nes::with_cycle_executor(cartridge,Default::default()) } else { Nes::new(cartridge) };
+                nes.power_cycle().unwrap();
+                nes.step_instruction().unwrap();
+                let start = nes.clock();
+                nes.step_instruction().unwrap();
+                taken.push(nes.clock() - start);
+            }
+        }
+        // STA absolute, then 513 cycles of DMA and one more to align on an odd cycle
+        assert_eq!(4+513+4+514,taken[0]+taken[1]);
+        assert!(taken[0] != taken[1]);
+        assert_eq!(taken[..2],taken[2..]);
+    }
+}
+
+mod cli {
+    use std::fs::File;
+    use std::io::Read;
+    use trustines::cli;
+    use trustines::cli::{Command,Options,TraceFormat,CliError};
+    use trustines::headless;
+    use trustines::headless::Checkpoints;
+    use trustines::nes::Nes;
+    use trustines::rom_loader;
+    use trustines::video::{Image,Palette,BuiltinPalette,Connection};
+    use trustines::logger::diff::Column;
+
+    fn parse(args: &[&str]) -> Result<Command,CliError> {
+        cli::parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn rom_only() {
+        assert_eq!(Command::Run(Options::new("game.nes")),parse(&["game.nes"]).unwrap());
+    }
+    #[test]
+    fn all_options() {
+        let command = parse(&["--pc","$C000","--instructions","10","--cycles","20","--frames","30",
+                              "--trace","out.log","--trace-format","nestest","--headless","game.nes"]).unwrap();
+        let mut expected = Options::new("game.nes");
+        expected.start_pc = Some(0xC000);
+        expected.max_instructions = Some(10);
+        expected.max_cycles = Some(20);
+        expected.max_frames = Some(30);
+        expected.trace = Some("out.log".to_string());
+        expected.trace_format = TraceFormat::Nestest;
+        assert_eq!(Command::Run(expected),command);
+    }
+    #[test]
+    fn trace_gating_options() {
+        let command = parse(&["--trace","-","--trace-range","C000-$C0FF","--trace-after","0xC5F5",
+                              "--crash-dump","50","--cycle-accurate","game.nes"]).unwrap();
+        let mut expected = Options::new("game.nes");
+        expected.cycle_accurate = true;
+        expected.trace = Some("-".to_string());
+        expected.trace_range = Some((0xC000,0xC0FF));
+        expected.trace_after = Some(0xC5F5);
+        expected.crash_dump = Some(50);
+        assert_eq!(Command::Run(expected),command);
+    }
+    #[test]
+    fn headless_options() {
+        let command = parse(&["--movie","run.fm2","--hash-every","10","--frames","60","game.nes"]).unwrap();
+        let mut expected = Options::new("game.nes");
+        expected.movie = Some("run.fm2".to_string());
+        expected.hash = Some(Checkpoints::Every(10));
+        expected.max_frames = Some(60);
+        assert_eq!(Command::Run(expected),command);
+
+        match parse(&["--hash-at","60, 120","--screenshot-at","5,6","--screenshot","{frame}.ppm","--palette","pal",
+                      "--filter","rgb","game.nes"]).unwrap() {
+            Command::Run(options) => {
+                assert_eq!(Some("pal".to_string()),options.palette);
+                assert_eq!(Some(Connection::Rgb),options.filter);
+                assert_eq!(Some(Checkpoints::At(vec![60,120])),options.hash);
+                assert_eq!(vec![5,6],options.screenshot_at);
+                assert_eq!(Some("{frame}.ppm".to_string()),options.screenshot);
+            },
+            _ => panic!("expected Run"),
+        }
+        assert_eq!(cli::EXIT_USAGE,parse(&["--hash-at","60,x","game.nes"]).unwrap_err().exit_code());
+        assert_eq!(cli::EXIT_USAGE,parse(&["--filter","scart","game.nes"]).unwrap_err().exit_code());
+    }
+    #[test]
+    fn run_with_hashes() {
+        let mut options = Options::new("roms/nestest.nes");
+        options.max_frames = Some(60);
+        options.hash = Some(Checkpoints::Every(20));
+        let summary = cli::run(&options).unwrap();
+        assert_eq!(60,summary.frames);
+        assert_eq!(headless::read_golden(headless::golden_path("roms/nestest.nes")).unwrap(),summary.hashes);
+
+        for (pc,frames) in &[(Some(0xC000),Some(60)),(None,None)] {
+            options.start_pc = *pc;
+            options.max_frames = *frames;
+            assert_eq!(cli::EXIT_USAGE,cli::run(&options).unwrap_err().exit_code());
+        }
+    }
+    #[test]
+    fn run_with_screenshots() {
+        let template = ::std::env::temp_dir().join("trustines_cli_{frame}.ppm");
+        let mut options = Options::new("roms/nestest.nes");
+        options.screenshot = Some(template.to_str().unwrap().to_string());
+        options.screenshot_at = vec![10,30];
+        let summary = cli::run(&options).unwrap();
+        assert_eq!(30,summary.frames);
+        assert_eq!(2,summary.screenshots.len());
+
+        let mut nes = Nes::new(rom_loader::load_cartridge("roms/nestest.nes").unwrap());
+        headless::run(&mut nes,None,30,&Checkpoints::Every(30)).unwrap();
+        let mut expected = Vec::new();
+        Image::from_framebuffer(nes.framebuffer(),&Palette::default()).write_ppm(&mut expected).unwrap();
+        assert!(summary.screenshots[1].ends_with("trustines_cli_30.ppm"));
+        assert!(expected == ::std::fs::read(&summary.screenshots[1]).unwrap());
+
+        options.palette = Some("2c03".to_string());
+        let summary = cli::run(&options).unwrap();
+        let mut expected = Vec::new();
+        Image::from_framebuffer(nes.framebuffer(),&Palette::builtin(BuiltinPalette::Ppu2C03)).write_ppm(&mut expected).unwrap();
+        assert!(expected == ::std::fs::read(&summary.screenshots[1]).unwrap());
+
+        options.palette = Some("vga".to_string());
+        assert_eq!(cli::EXIT_USAGE,cli::run(&options).unwrap_err().exit_code());
+        options.palette = Some("missing.pal".to_string());
+        assert_eq!(cli::EXIT_LOAD_ERROR,cli::run(&options).unwrap_err().exit_code());
+        options.palette = None;
+        options.filter = Some(Connection::SVideo);
+        let summary = cli::run(&options).unwrap();
+        let ppm = ::std::fs::read(&summary.screenshots[0]).unwrap();
+        assert_eq!(&b"P6\n512 240\n"[..],&ppm[..11]);
+        options.palette = Some("2c02".to_string());
+        assert_eq!(cli::EXIT_USAGE,cli::run(&options).unwrap_err().exit_code());
+
+        options.palette = None;
+        options.filter = None;
+        options.screenshot = Some("shot.bmp".to_string());
+        assert_eq!(cli::EXIT_USAGE,cli::run(&options).unwrap_err().exit_code());
+    }
+    #[test]
+    #[cfg(feature = "windowed")]
+    fn windowed_options() {
+        let mut expected = Options::new("game.nes");
+        expected.windowed = true;
+        assert_eq!(Command::Run(expected.clone()),parse(&["--windowed","game.nes"]).unwrap());
+        expected.windowed = false;
+        assert_eq!(Command::Run(expected),parse(&["--windowed","--headless","game.nes"]).unwrap());
+
+        // the window runs whole frames, without opening it
+        let mut options = Options::new("roms/nestest.nes");
+        options.windowed = true;
+        options.max_instructions = Some(100);
+        assert_eq!(cli::EXIT_USAGE,cli::run(&options).unwrap_err().exit_code());
+    }
+    #[test]
+    #[cfg(not(feature = "windowed"))]
+    fn windowed_not_built_in() {
+        assert_eq!(cli::EXIT_USAGE,parse(&["--windowed","game.nes"]).unwrap_err().exit_code());
+        let mut options = Options::new("roms/nestest.nes");
+        options.windowed = true;
+        assert_eq!(cli::EXIT_USAGE,cli::run(&options).unwrap_err().exit_code());
+    }
+    #[test]
+    fn address_formats() {
+        for pc in &["C000","$C000","0xC000"] {
+            match parse(&["--pc",pc,"game.nes"]).unwrap() {
+                Command::Run(options) => assert_eq!(Some(0xC000),options.start_pc),
+                _ => panic!("expected Run"),
+            }
+        }
+    }
+    #[test]
+    fn help() {
+        assert_eq!(Command::Help,parse(&["game.nes","--help"]).unwrap());
+        assert_eq!(Command::Help,parse(&["-h"]).unwrap());
+    }
+    #[test]
+    fn usage_errors() {
+        for args in &[&[][..], &["--bogus","game.nes"][..], &["--pc"][..], &["--pc","G000","game.nes"][..],
+                      &["--frames","ten","game.nes"][..], &["--trace-format","xml","game.nes"][..],
+                      &["--trace-range","C000","game.nes"][..], &["--trace-range","C100-C000","game.nes"][..],
+                      &["one.nes","two.nes"][..]] {
+            let err = parse(args).unwrap_err();
+            assert_eq!(cli::EXIT_USAGE,err.exit_code(),"{:?}",args);
+        }
+    }
+    #[test]
+    fn run_to_instruction_limit() {
+        let mut options = Options::new("roms/nestest.nes");
+        options.start_pc = Some(0xC000);
+        options.max_instructions = Some(100);
+        let summary = cli::run(&options).unwrap();
+        assert_eq!(100,summary.instructions);
+        assert!(summary.cycles > 200);
+    }
+    #[test]
+    fn run_to_cycle_limit() {
+        let mut options = Options::new("roms/nestest.nes");
+        options.start_pc = Some(0xC000);
+        options.max_cycles = Some(1000);
+        let summary = cli::run(&options).unwrap();
+        assert!(summary.cycles >= 1000 && summary.cycles < 1010);
+    }
+    #[test]
+    fn run_with_gated_trace() {
+        let path = ::std::env::temp_dir().join("trustines_cli_gated.log");
+        let mut options = Options::new("roms/nestest.nes");
+        options.start_pc = Some(0xC000);
+        options.max_instructions = Some(100);
+        options.trace = Some(path.to_str().unwrap().to_string());
+        options.trace_range = Some((0xC72D,0xC7FF));
+        options.crash_dump = Some(10);
+        cli::run(&options).unwrap();
+
+        let mut text = String::new();
+        File::open(&path).unwrap().read_to_string(&mut text).unwrap();
+        assert!(!text.is_empty());
+        let in_range = |line: &str| (0xC72D..=0xC7FF).contains(&u16::from_str_radix(&line[0..4],16).unwrap());
+        assert!(text.lines().all(in_range),"{}",text);
+    }
+    #[test]
+    fn diff_args() {
+        let command = parse(&["diff","--ignore","cyc,P","--context","2","--actual-format","fceux","a.log","b.log"]).unwrap();
+        let mut expected = cli::DiffOptions::new("a.log","b.log");
+        expected.ignore = vec![Column::Cycles,Column::P];
+        expected.context = 2;
+        expected.actual_format = Some(TraceFormat::Fceux);
+        assert_eq!(Command::Diff(expected),command);
+
+        for args in &[&["diff","a.log"][..], &["diff","--ignore","flags","a.log","b.log"][..], &["diff","--bogus","a.log","b.log"][..]] {
+            assert_eq!(cli::EXIT_USAGE,parse(args).unwrap_err().exit_code(),"{:?}",args);
+        }
+    }
+    #[test]
+    fn diff_traces() {
+        let path = ::std::env::temp_dir().join("trustines_cli_diff.log");
+        let mut options = Options::new("roms/nestest.nes");
+        options.start_pc = Some(0xC000);
+        options.max_instructions = Some(8991);
+        options.trace = Some(path.to_str().unwrap().to_string());
+        options.trace_format = TraceFormat::Mesen;
+        cli::run(&options).unwrap();
+
+        let result = cli::diff(&cli::DiffOptions::new("resources/nestest.log",path.to_str().unwrap())).unwrap();
+        assert!(result.is_same(),"{}",result.report());
+        assert_eq!(cli::EXIT_LOAD_ERROR,cli::diff(&cli::DiffOptions::new("missing.log","missing.log")).unwrap_err().exit_code());
+    }
+    #[test]
+    fn run_errors() {
+        assert_eq!(cli::EXIT_LOAD_ERROR,cli::run(&Options::new("roms/missing.nes")).unwrap_err().exit_code());
+    }
 }
 
+mod nestest {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use trustines::logger::{NesTest,RingBufferSink,TraceFormat};
+    use trustines::logger::diff;
+    use trustines::logger::diff::Trace;
+    use trustines::nes::Nes;
+    use trustines::rom_loader;
+
+    const CONTEXT_LINES: usize = 5;
+
+    // run nestest's automated tests, returning the trace and the console
+    fn run_nestest(instructions: usize) -> (Vec<String>,Nes) {
+        run_nestest_on(Nes::new(rom_loader::load_cartridge("roms/nestest.nes").unwrap()),instructions)
+    }
+
+    fn run_nestest_on(mut nes: Nes, instructions: usize) -> (Vec<String>,Nes) {
+        let trace = Rc::new(RefCell::new(RingBufferSink::new(instructions,NesTest::default())));
+        nes.set_trace_sink(trace.clone());
+        nes.power_cycle().unwrap();
+        // nestest runs its automated tests when started at $C000 instead of the reset vector
+        nes.cpu.pc = 0xC000;
+        for _ in 0..instructions {
+            nes.step_instruction().unwrap();
+        }
+        nes.take_trace_sink();
+        let lines = trace.borrow().lines().cloned().collect();
+        (lines,nes)
+    }
+
+    #[test]
+    fn nestest_log() {
+        check_nestest_log(|| Nes::new(rom_loader::load_cartridge("roms/nestest.nes").unwrap()));
+    }
+    #[test]
+    fn nestest_log_cycle_accurate() {
+        check_nestest_log(|| Nes::with_cycle_executor(rom_loader::load_cartridge("roms/nestest.nes").unwrap(),Default::default()));
+    }
+
+    fn check_nestest_log<F: Fn() -> Nes>(new_nes: F) {
+        let expected = Trace::read("resources/nestest.log",None).unwrap();
+        let (lines,nes) = run_nestest_on(new_nes(),expected.lines.len());
+
+        // nestest leaves its error codes in $02 and $03
+        assert_eq!(0,nes.mem.mem[0x02]);
+        assert_eq!(0,nes.mem.mem[0x03]);
+
+        let actual = Trace::parse(lines.iter().map(|line| line.as_ref()),TraceFormat::Nestest);
+        let result = diff::diff(&expected,&actual,&[],CONTEXT_LINES);
+        assert!(result.is_same(),"{}",result.report());
+    }
+
+    // resources/nestest.log predates the PPU and CYC columns, these are from the current log
+    #[test]
+    fn timing_columns() {
+        let (actual,_) = run_nestest(8991);
+        check_timing_columns(&actual);
+
+        let nes = Nes::with_cycle_executor(rom_loader::load_cartridge("roms/nestest.nes").unwrap(),Default::default());
+        let (cycle_accurate,_) = run_nestest_on(nes,8991);
+        assert_eq!(actual,cycle_accurate);
+    }
+
+    fn check_timing_columns(actual: &[String]) {
+        assert_eq!("C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",actual[0]);
+        assert_eq!("C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10",actual[1]);
+        assert_eq!("C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12",actual[2]);
+        assert_eq!("C66E  60        RTS                             A:00 X:FF Y:15 P:27 SP:FD PPU:233,209 CYC:26554",actual[8990]);
+    }
+}
+
+mod trace_sink {
+    use std::cell::RefCell;
+    use std::io;
+    use std::io::Write;
+    use std::rc::Rc;
+    use trustines::cpu::ExecutionError;
+    use trustines::logger::{NesTest,Fceux,Mesen,TraceSink,WriteSink,RingBufferSink,NullSink,GatedSink};
+    use trustines::logger::{BinarySink,BinaryReader};
+    use trustines::logger::{LineFormat,TraceRecord};
+    use trustines::logger::binary;
+    use trustines::nes::Nes;
+    use trustines::rom_loader;
+
+    // nestest from $C000, stepping the given number of instructions with sink attached
+    fn run<S: TraceSink + 'static>(sink: S, instructions: usize) -> Result<(),ExecutionError> {
+        let mut nes = Nes::new(rom_loader::load_cartridge("roms/nestest.nes").unwrap());
+        nes.set_trace_sink(sink);
+        nes.power_cycle().unwrap();
+        nes.cpu.pc = 0xC000;
+        for _ in 0..instructions {
+            nes.step_instruction()?;
+        }
+        Ok(())
+    }
+
+    fn pcs(ring: &Rc<RefCell<RingBufferSink>>) -> Vec<String> {
+        ring.borrow().lines().map(|line| line[0..4].to_string()).collect()
+    }
+
+    #[test]
+    fn ring_buffer_keeps_last() {
+        let ring = Rc::new(RefCell::new(RingBufferSink::new(3,NesTest::default())));
+        run(ring.clone(),9).unwrap();
+        assert_eq!(vec!["C72D","C72E","C72F"],pcs(&ring));
+
+        let mut dump = Vec::new();
+        ring.borrow().dump(&mut dump).unwrap();
+        assert_eq!(3,String::from_utf8(dump).unwrap().lines().count());
+    }
+    #[test]
+    fn write_sink() {
+        let sink = Rc::new(RefCell::new(WriteSink::new(Vec::new(),NesTest::default())));
+        run(sink.clone(),2).unwrap();
+        let sink = Rc::try_unwrap(sink).ok().unwrap().into_inner();
+        let text = String::from_utf8(sink.into_inner().unwrap()).unwrap();
+        assert_eq!("C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7\n\
+                    C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10\n",text);
+    }
+    #[test]
+    fn null_sink() {
+        run(NullSink,10).unwrap();
+    }
+    #[test]
+    fn apu_registers_only_ff_in_nestest() {
+        // LDA $4015 reading 0F
+        let record = TraceRecord { pc: 0xC000, bytes: [0xAD,0x15,0x40], len: 3, addr: 0x4015, value: 0x0F, ..Default::default() };
+        assert!(NesTest::default().format_record(&record).contains("LDA $4015 = FF"));
+        assert!(Fceux::default().format_record(&record).contains("LDA $4015 = #$0F"));
+    }
+    #[test]
+    fn pc_range() {
+        let ring = Rc::new(RefCell::new(RingBufferSink::new(100,NesTest::default())));
+        run(GatedSink::new(ring.clone()).pc_range(0xC5F5,0xC5F7),10).unwrap();
+        assert_eq!(vec!["C5F5","C5F7"],pcs(&ring));
+    }
+    #[test]
+    fn trigger() {
+        let ring = Rc::new(RefCell::new(RingBufferSink::new(100,NesTest::default())));
+        run(GatedSink::new(ring.clone()).trigger_at(0xC72D),9).unwrap();
+        assert_eq!(vec!["C72D","C72E","C72F"],pcs(&ring));
+
+        // the trigger stays fired once the condition is false again
+        let ring = Rc::new(RefCell::new(RingBufferSink::new(100,NesTest::default())));
+        run(GatedSink::new(ring.clone()).trigger(|cpu,_| cpu.x == 0 && cpu.pc.wrapping_sub(1) == 0xC5F7),4).unwrap();
+        assert_eq!(vec!["C5F7","C5F9"],pcs(&ring));
+    }
+    #[test]
+    fn trigger_and_range() {
+        let ring = Rc::new(RefCell::new(RingBufferSink::new(100,NesTest::default())));
+        run(GatedSink::new(ring.clone()).trigger_at(0xC5F7).pc_range(0xC5F5,0xC5F9),10).unwrap();
+        assert_eq!(vec!["C5F7","C5F9"],pcs(&ring));
+    }
+
+    struct BrokenWriter;
+    impl Write for BrokenWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> { Err(io::Error::new(io::ErrorKind::Other,"disk full")) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn write_errors_stop_execution() {
+        match run(WriteSink::new(BrokenWriter,NesTest::default()),1) {
+            Err(ExecutionError::Trace(_)) => { },
+            other => panic!("expected a trace error, got {:?}",other),
+        }
+    }
+
+    #[test]
+    fn fceux_format() {
+        let ring = Rc::new(RefCell::new(RingBufferSink::new(100,Fceux::default())));
+        run(ring.clone(),4).unwrap();
+        let lines: Vec<String> = ring.borrow().lines().cloned().collect();
+        assert_eq!("c7          A:00 X:00 Y:00 S:FD P:nvUbdIzc  $C000:4C F5 C5  JMP $C5F5",lines[0]);
+        assert_eq!("c12         A:00 X:00 Y:00 S:FD P:nvUbdIZc  $C5F7:86 00     STX $00 = #$00",lines[2]);
+    }
+    #[test]
+    fn mesen_format() {
+        let ring = Rc::new(RefCell::new(RingBufferSink::new(100,Mesen::default())));
+        run(ring.clone(),4).unwrap();
+        let lines: Vec<String> = ring.borrow().lines().cloned().collect();
+        assert_eq!("C000  $4C $F5 $C5  JMP $C5F5                  A:00 X:00 Y:00 P:24 SP:FD CYC:21  SL:0   CPU Cycle:7",lines[0]);
+        assert_eq!("C5F7  $86 $00      STX $00 = $00              A:00 X:00 Y:00 P:26 SP:FD CYC:36  SL:0   CPU Cycle:12",lines[2]);
+    }
+
+    // run with a binary sink and a nestest ring buffer side by side
+    fn binary_and_text(instructions: usize) -> (Vec<u8>,Vec<String>) {
+        let bin = Rc::new(RefCell::new(BinarySink::new(Vec::new()).unwrap()));
+        let ring = Rc::new(RefCell::new(RingBufferSink::new(instructions,NesTest::default())));
+        let sinks: Vec<Box<dyn TraceSink>> = vec![Box::new(bin.clone()),Box::new(ring.clone())];
+        run(sinks,instructions).unwrap();
+
+        let bytes = Rc::try_unwrap(bin).ok().unwrap().into_inner().into_inner().unwrap();
+        let lines = ring.borrow().lines().cloned().collect();
+        (bytes,lines)
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let (bytes,expected) = binary_and_text(3000);
+        assert_eq!(binary::MAGIC.len() + 3000*28,bytes.len());
+
+        let mut text = Vec::new();
+        let count = binary::convert(BinaryReader::new(&bytes[..]).unwrap(),&mut text,&NesTest::default()).unwrap();
+        assert_eq!(3000,count);
+        let actual: Vec<String> = String::from_utf8(text).unwrap().lines().map(|l| l.to_string()).collect();
+        assert_eq!(expected,actual);
+    }
+    #[test]
+    fn binary_reader_errors() {
+        assert!(BinaryReader::new(&b"NOTATRACE"[..]).is_err());
+
+        let (bytes,_) = binary_and_text(2);
+        let mut records = BinaryReader::new(&bytes[..bytes.len()-1]).unwrap();
+        assert!(records.next().unwrap().is_ok());
+        assert!(records.next().unwrap().is_err());
+    }
+}
+
+mod trace_diff {
+    use trustines::logger::TraceFormat;
+    use trustines::logger::diff;
+    use trustines::logger::diff::{Column,DiffResult,Trace};
+
+    const NESTEST: [&str;4] = [
+        "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+        "C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10",
+        "C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12",
+        "C5F9  86 10     STX $10 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 45 CYC:15",
+    ];
+    const FCEUX: [&str;4] = [
+        "c7          A:00 X:00 Y:00 S:FD P:nvUbdIzc  $C000:4C F5 C5  JMP $C5F5",
+        "c10         A:00 X:00 Y:00 S:FD P:nvUbdIzc  $C5F5:A2 00     LDX #$00",
+        "c12         A:00 X:00 Y:00 S:FD P:nvUbdIZc  $C5F7:86 00     STX $00 = #$00",
+        "c15         A:00 X:00 Y:00 S:FD P:nvUbdIZc  $C5F9:86 10     STX $10 = #$00",
+    ];
+    const MESEN: [&str;4] = [
+        "C000  $4C $F5 $C5  JMP $C5F5                  A:00 X:00 Y:00 P:24 SP:FD CYC:21  SL:0   CPU Cycle:7",
+        "C5F5  $A2 $00      LDX #$00                   A:00 X:00 Y:00 P:24 SP:FD CYC:30  SL:0   CPU Cycle:10",
+        "C5F7  $86 $00      STX $00 = $00              A:00 X:00 Y:00 P:26 SP:FD CYC:36  SL:0   CPU Cycle:12",
+        "C5F9  $86 $10      STX $10 = $00              A:00 X:00 Y:00 P:26 SP:FD CYC:45  SL:0   CPU Cycle:15",
+    ];
+
+    fn trace(lines: &[&str], format: TraceFormat) -> Trace {
+        Trace::parse(lines.iter().cloned(),format)
+    }
+    fn field(trace: &Trace, line: usize, column: Column) -> String {
+        trace.lines[line].fields[&column].clone()
+    }
+
+    #[test]
+    fn detect_format() {
+        assert_eq!(TraceFormat::Nestest,diff::detect_format(NESTEST[0]));
+        assert_eq!(TraceFormat::Fceux,diff::detect_format(FCEUX[0]));
+        assert_eq!(TraceFormat::Mesen,diff::detect_format(MESEN[0]));
+    }
+    #[test]
+    fn formats_normalize_the_same() {
+        let traces = [trace(&NESTEST,TraceFormat::Nestest),trace(&FCEUX,TraceFormat::Fceux),trace(&MESEN,TraceFormat::Mesen)];
+        for t in &traces {
+            assert_eq!("C5F7",field(t,2,Column::Pc));
+            assert_eq!("86 00",field(t,2,Column::Bytes));
+            assert_eq!("26",field(t,2,Column::P));
+            assert_eq!("FD",field(t,2,Column::Sp));
+            assert_eq!("12",field(t,2,Column::Cycles));
+        }
+        assert_eq!("36",field(&traces[0],2,Column::Dot));
+        assert_eq!("36",field(&traces[2],2,Column::Dot));
+        assert_eq!("STX $00 = 00",field(&traces[0],2,Column::Disassembly));
+
+        for pair in &[(0,1),(0,2),(1,2)] {
+            assert!(diff::diff(&traces[pair.0],&traces[pair.1],&[],5).is_same());
+        }
+    }
+    #[test]
+    fn older_nestest_columns() {
+        let t = trace(&["C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:  0 SL:241"],TraceFormat::Nestest);
+        assert_eq!("0",field(&t,0,Column::Dot));
+        assert_eq!("241",field(&t,0,Column::Scanline));
+        assert!(!t.lines[0].fields.contains_key(&Column::Cycles));
+    }
+    #[test]
+    fn first_divergence() {
+        let mut lines = NESTEST.to_vec();
+        lines[2] = "C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:A7 SP:FD PPU:  0, 36 CYC:13";
+        let result = diff::diff(&trace(&NESTEST,TraceFormat::Nestest),&trace(&lines,TraceFormat::Nestest),&[],1);
+        match result {
+            DiffResult::Diverged(ref d) => {
+                assert_eq!(2,d.instruction);
+                assert_eq!(vec![Column::P,Column::Cycles],d.columns);
+                assert_eq!(vec!['N','C'],d.flags);
+                assert_eq!(3,d.expected.number);
+                assert_eq!(vec![NESTEST[1].to_string()],d.before.iter().map(|l| l.text.clone()).collect::<Vec<_>>());
+                assert_eq!(1,d.expected_after.len());
+            },
+            ref other => panic!("expected a divergence, got {:?}",other),
+        }
+        assert!(result.report().contains("P (N, C), CYC"),"{}",result.report());
+
+        let ignored = diff::diff(&trace(&NESTEST,TraceFormat::Nestest),&trace(&lines,TraceFormat::Nestest),&[Column::P,Column::Cycles],1);
+        assert!(ignored.is_same());
+    }
+    #[test]
+    fn disassembly_only_compared_within_a_style() {
+        let nestest = trace(&NESTEST,TraceFormat::Nestest);
+        let mut lines = NESTEST.to_vec();
+        lines[1] = "C5F5  A2 00     LDX #$01                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10";
+        match diff::diff(&nestest,&trace(&lines,TraceFormat::Nestest),&[],5) {
+            DiffResult::Diverged(d) => assert_eq!(vec![Column::Disassembly],d.columns),
+            other => panic!("expected a divergence, got {:?}",other),
+        }
+    }
+    #[test]
+    fn alignment_and_length() {
+        // the actual trace starts earlier and stops sooner
+        let mut lines = vec!["FFF0  EA        NOP                             A:00 X:00 Y:00 P:24 SP:FD"];
+        lines.extend_from_slice(&NESTEST[..3]);
+        let result = diff::diff(&trace(&NESTEST,TraceFormat::Nestest),&trace(&lines,TraceFormat::Nestest),&[],5);
+        assert_eq!(DiffResult::LengthDiffers { instructions: 3, expected_remaining: 1, actual_remaining: 0 },result);
+    }
+    #[test]
+    fn skips_non_instruction_lines() {
+        let mut lines = vec!["Log Start",""];
+        lines.extend_from_slice(&FCEUX);
+        let t = trace(&lines,TraceFormat::Fceux);
+        assert_eq!(4,t.lines.len());
+        assert_eq!(3,t.lines[0].number);
+    }
+    #[test]
+    fn skips_lines_that_start_like_a_pc() {
+        let mut lines = vec!["000A: garbage"];
+        lines.extend_from_slice(&NESTEST);
+        let t = trace(&lines,TraceFormat::Nestest);
+        assert_eq!(4,t.lines.len());
+        assert_eq!(2,t.lines[0].number);
+    }
+}
+
+mod disassembler {
+    use trustines::cpu::AddressMode;
+    use trustines::cpu::disassembler;
+    use trustines::cpu::disassembler::Disassembler;
+    use trustines::rom_loader;
+
+    fn texts(bytes: &[u8], origin: u16) -> Vec<String> {
+        disassembler::disassemble(bytes,origin).iter().map(|i| i.text()).collect()
+    }
+
+    #[test]
+    fn address_modes() {
+        let bytes = [
+            0xEA,             // implied
+            0x0A,             // accumulator
+            0xA9,0x10,        // immediate
+            0xA5,0x10,        // zero page
+            0xB5,0x10,        // zero page,x
+            0xB6,0x10,        // zero page,y
+            0xAD,0x00,0x03,   // absolute
+            0xBD,0x00,0x03,   // absolute,x
+            0xB9,0x00,0x03,   // absolute,y
+            0x6C,0xFF,0x02,   // indirect
+            0xA1,0x10,        // (zero page,x)
+            0xB1,0x10,        // (zero page),y
+            0xAD,0x10,0x00,   // absolute below $100
+            0xA7,0x10,        // illegal LAX zero page
+        ];
+        assert_eq!(vec!["NOP","ASL A","LDA #$10","LDA $10","LDA $10,X","LDX $10,Y","LDA $0300","LDA $0300,X",
+                        "LDA $0300,Y","JMP ($02FF)","LDA ($10,X)","LDA ($10),Y","LDA a:$0010","LAX $10"],texts(&bytes,0x8000));
+
+        let instructions = disassembler::disassemble(&bytes,0x8000);
+        assert_eq!(AddressMode::Immediate,instructions[2].address_mode);
+        assert_eq!(0x8002,instructions[2].address);
+        assert!(instructions[13].illegal);
+        assert!(!instructions[12].illegal);
+    }
+    #[test]
+    fn branch_targets() {
+        // a forward and a backward branch, and a jump and call
+        let bytes = [0xD0,0x02, 0xF0,0xFC, 0x4C,0x34,0x12, 0x20,0x00,0x80];
+        let instructions = disassembler::disassemble(&bytes,0x8000);
+        assert_eq!(vec!["BNE $8004","BEQ $8000","JMP $1234","JSR $8000"],instructions.iter().map(|i| i.text()).collect::<Vec<_>>());
+        assert_eq!(vec![Some(0x8004),Some(0x8000),Some(0x1234),Some(0x8000)],instructions.iter().map(|i| i.target).collect::<Vec<_>>());
+    }
+    #[test]
+    fn symbols() {
+        let mut d = Disassembler::default();
+        d.add_symbol(0x2000,"PPUCTRL");
+        d.add_symbol(0x0010,"temp");
+        d.add_symbol(0x8000,"reset");
+        let bytes = [0x8D,0x00,0x20, 0xA5,0x10, 0xB1,0x10, 0xAD,0x10,0x00, 0xD0,0xF4, 0x4C,0x00,0x80];
+        let texts: Vec<String> = d.linear_sweep(&bytes,0x8000).iter().map(|i| i.text()).collect();
+        assert_eq!(vec!["STA PPUCTRL","LDA temp","LDA (temp),Y","LDA a:temp","BNE reset","JMP reset"],texts);
+    }
+    #[test]
+    fn truncated_instruction_is_data() {
+        let instructions = disassembler::disassemble(&[0xEA,0xAD,0x00],0x8000);
+        assert_eq!(vec!["NOP",".byte $AD,$00"],instructions.iter().map(|i| i.text()).collect::<Vec<_>>());
+        assert!(instructions[1].is_data());
+    }
+    #[test]
+    fn display() {
+        let instructions = disassembler::disassemble(&[0x4C,0xF5,0xC5,0xEA],0xC000);
+        assert_eq!("C000  4C F5 C5  JMP $C5F5",instructions[0].to_string());
+        assert_eq!("C003  EA        NOP",instructions[1].to_string());
+    }
+    #[test]
+    fn recursive_descent_skips_data() {
+        // jmp over two bytes of data, which a linear sweep decodes as LDA ($FF,X)
+        let bytes = [0x4C,0x05,0x80, 0xA1,0xFF, 0xA9,0x01, 0x60];
+        assert_eq!(vec!["JMP $8005","LDA ($FF,X)","LDA #$01","RTS"],texts(&bytes,0x8000));
+
+        let lines = Disassembler::default().recursive_descent(&bytes,0x8000,&[0x8000]);
+        let texts: Vec<String> = lines.iter().map(|i| i.text()).collect();
+        assert_eq!(vec!["JMP $8005",".byte $A1,$FF","LDA #$01","RTS"],texts);
+        assert_eq!(0x8003,lines[1].address);
+    }
+    #[test]
+    fn whole_bank() {
+        let cartridge = rom_loader::load_cartridge("roms/nestest.nes").unwrap();
+        let prg = &cartridge.prg_rom;
+        assert_eq!(0x4000,prg.len());
+
+        let vectors = disassembler::vectors(prg,0xC000);
+        assert_eq!(3,vectors.len());
+        assert!(disassembler::vectors(prg,0x8000).is_empty());
+
+        // nestest's automated tests start at $C000
+        let mut entry_points = vectors.clone();
+        entry_points.push(0xC000);
+        let lines = Disassembler::default().recursive_descent(prg,0xC000,&entry_points);
+        assert_eq!("JMP $C5F5",lines[0].text());
+
+        // every byte is covered exactly once, in order
+        let mut next = 0xC000u32;
+        for line in &lines {
+            assert_eq!(next,line.address as u32);
+            next += line.bytes.len() as u32;
+        }
+        assert_eq!(0x10000,next);
+        assert!(lines.iter().filter(|line| !line.is_data()).count() > 1000);
+    }
+}
+
+mod assembler {
+    use trustines::cpu;
+    use trustines::cpu::assembler;
+    use trustines::cpu::assembler::AssembleError;
+    use trustines::cpu::disassembler;
+    use trustines::memory::Memory;
+    use trustines::rom_loader;
+
+    fn bytes(source: &str) -> Vec<u8> {
+        assembler::assemble(source).unwrap().bytes()
+    }
+
+    #[test]
+    fn address_modes() {
+        let source = "
+            NOP
+            ASL A
+            ASL
+            LDA #$10
+            LDA $10
+            LDA $10,X
+            LDX $10,Y
+            LDA $0300
+            LDA $0300,X
+            LDA $0300,Y
+            JMP ($02FF)
+            LDA ($10,X)
+            LDA ($10),Y
+            LDA a:$10
+            LDA z:label
+            LAX $10
+        label:
+        ";
+        assert_eq!(vec![0xEA, 0x0A, 0x0A, 0xA9,0x10, 0xA5,0x10, 0xB5,0x10, 0xB6,0x10, 0xAD,0x00,0x03, 0xBD,0x00,0x03,
+                        0xB9,0x00,0x03, 0x6C,0xFF,0x02, 0xA1,0x10, 0xB1,0x10, 0xAD,0x10,0x00, 0xA5,0x22, 0xA7,0x10],bytes(source));
+    }
+    #[test]
+    fn zero_page_y_falls_back_to_absolute() {
+        // LDA has no zero page,Y and STX has no absolute,Y
+        assert_eq!(vec![0xB9,0x10,0x00, 0x96,0x10],bytes("LDA $10,Y\nSTX $10,Y"));
+        assert_eq!(AssembleError::InvalidAddressMode(1,"STX doesn't have that addressing mode".to_string()),
+                   assembler::assemble("STX $0300,Y").unwrap_err());
+    }
+    #[test]
+    fn labels_and_branches() {
+        let program = assembler::assemble("
+                .org $8000
+        start:  LDX #3
+        @loop:  DEX
+                BNE @loop
+                BEQ done
+                JMP start
+        other:
+        @loop:  BCC @loop
+        done:   RTS
+        ").unwrap();
+        assert_eq!(vec![0xA2,0x03, 0xCA, 0xD0,0xFD, 0xF0,0x05, 0x4C,0x00,0x80, 0x90,0xFE, 0x60],program.bytes());
+        assert_eq!(Some(0x8002),program.symbol("start@loop"));
+        assert_eq!(Some(0x800A),program.symbol("other@loop"));
+        assert_eq!(Some(0x800C),program.symbol("done"));
+        assert_eq!(0x8000,program.segments[0].origin);
+    }
+    #[test]
+    fn expressions() {
+        let source = "
+            base = $1234
+            .byte <base, >base, base & $F0 | 1, 1 << 4, -1, %1010, 'A', (2 + 3) * 4, 20 / 3, ~0 & $FF, $F0 ^ $FF, 256 >> 4
+            .word base + 2, * , end - base
+        end:
+        ";
+        assert_eq!(vec![0x34,0x12,0x31,0x10,0xFF,0x0A,0x41,20,6,0xFF,0x0F,0x10, 0x36,0x12, 0x0C,0x00, 0xDE,0xED],bytes(source));
+    }
+    #[test]
+    fn directives() {
+        let program = assembler::assemble("
+            .org $10
+            .byte \"Hi; there\", 0  ; the ; in the string isn't a comment
+            .res 3, $EA
+            .res 1
+            .org $FFFC
+            .addr reset, 0
+        reset = $C000
+        ").unwrap();
+        assert_eq!(2,program.segments.len());
+        assert_eq!(b"Hi; there\0\xEA\xEA\xEA\0".to_vec(),program.segments[0].bytes);
+        assert_eq!(0xFFFC,program.segments[1].origin);
+        assert_eq!(vec![0x00,0xC0,0x00,0x00],program.segments[1].bytes);
+    }
+    #[test]
+    fn illegal_mnemonics() {
+        // ISC is the OpcodeClass name for ISB, and the documented SBC and NOP win over their copies
+        assert_eq!(vec![0xE7,0x10, 0xE7,0x10, 0xE9,0x01, 0xEA, 0x04,0x10, 0x02],bytes("ISB $10\nISC $10\nSBC #1\nNOP\nNOP $10\nKIL"));
+    }
+    #[test]
+    fn errors() {
+        let error = |source: &str| assembler::assemble(source).unwrap_err();
+        assert_eq!(AssembleError::UnknownMnemonic(2,"FOO".to_string()),error("NOP\nfoo"));
+        assert_eq!(AssembleError::UnknownSymbol(1,"nowhere".to_string()),error("JMP nowhere"));
+        assert_eq!(AssembleError::DuplicateSymbol(2,"a".to_string()),error("a: NOP\na: NOP"));
+        assert_eq!(AssembleError::InvalidAddressMode(1,"JSR doesn't have that addressing mode".to_string()),error("JSR #1"));
+        assert_eq!(AssembleError::OutOfRange(1,"256 doesn't fit in a byte".to_string()),error("LDA #256"));
+        assert!(matches!(error(".res 200\nBNE 0"),AssembleError::OutOfRange(2,_)));
+        assert!(matches!(error("LDA $10,Z"),AssembleError::Syntax(1,_)));
+        assert!(matches!(error(".bogus 1"),AssembleError::Syntax(1,_)));
+        assert!(matches!(error("LDA #(1"),AssembleError::Syntax(1,_)));
+        assert!(matches!(error("LDA #-(-2147483647-1)"),AssembleError::OutOfRange(1,_)));
+    }
+    #[test]
+    fn non_ascii_source() {
+        // a no-break space is whitespace, and case folding mustn't move byte offsets
+        assert_eq!(vec![0xA5,0x03],bytes(".org $0200\nLDA 1+\u{a0}2"));
+        assert!(assembler::assemble("JMP (ſſ)").is_err());
+        assert!(assembler::assemble("LDA (ſſſ),y").is_err());
+    }
+    #[test]
+    fn run_assembled_program() {
+        let program = assembler::assemble("
+                .org $0200
+                LDX #0
+        @loop:  LDA text,X
+                BEQ @done
+                STA $0300,X
+                INX
+                BNE @loop
+        @done:  JMP @done
+        text:   .byte \"6502\", 0
+        ").unwrap();
+
+        let mut cpu: cpu::CpuState = Default::default();
+        let mut mem = Memory::new();
+        let exec: cpu::CpuExecutor = Default::default();
+        program.load(&mut mem);
+        cpu.pc = 0x0200;
+        for _ in 0..30 {
+            exec.step(&mut cpu,&mut mem).unwrap();
+        }
+        assert_eq!(b"6502".to_vec(),(0..4).map(|i| mem.read8(0x0300 + i).unwrap()).collect::<Vec<u8>>());
+        assert_eq!(4,cpu.x);
+    }
+    #[test]
+    fn disassembly_round_trip() {
+        // nestest's bank disassembled, assembled and disassembled again comes out the same.  Only
+        // opcodes that are the one way to write their instruction come back as the same bytes.
+        let cartridge = rom_loader::load_cartridge("roms/nestest.nes").unwrap();
+        let prg = &cartridge.prg_rom;
+        let lines = disassembler::disassemble(prg,0xC000);
+        let source: Vec<String> = lines.iter().map(|line| line.text()).collect();
+        let source = format!(".org $C000\n{}",source.join("\n"));
+
+        let assembled = assembler::assemble(&source).unwrap().bytes();
+        assert_eq!(prg.len(),assembled.len());
+        let again: Vec<String> = disassembler::disassemble(&assembled,0xC000).iter().map(|line| line.text()).collect();
+        let first: Vec<String> = lines.iter().map(|line| line.text()).collect();
+        assert_eq!(first,again);
+
+        let mut same = 0;
+        for line in lines.iter().filter(|line| !line.is_data() && !line.illegal) {
+            let offset = (line.address - 0xC000) as usize;
+            assert_eq!(line.bytes[..],assembled[offset..offset+line.bytes.len()]);
+            same += 1;
+        }
+        assert!(same > 1000);
+    }
+}
+
+#[cfg(feature = "processor-tests")]
+mod processor_tests {
+    use std::env;
+    use std::fs;
+    use trustines::cpu;
+    use trustines::cpu::processor_tests;
+    use trustines::cpu::processor_tests::{Checks,TestError};
+    use trustines::memory::BusCycle;
+
+    // LDA #$10 and NOP, in the corpus' format
+    const CASES: &str = r#"[
+        { "name": "a9 10 ea",
+          "initial": { "pc": 4096, "s": 253, "a": 0, "x": 0, "y": 0, "p": 38, "ram": [ [4096, 169], [4097, 16] ] },
+          "final":   { "pc": 4098, "s": 253, "a": 16, "x": 0, "y": 0, "p": 36, "ram": [ [4096, 169], [4097, 16] ] },
+          "cycles":  [ [4096, 169, "read"], [4097, 16, "read"] ] },
+        { "name": "ea 00 00",
+          "initial": { "pc": 8192, "s": 0, "a": 1, "x": 2, "y": 3, "p": 228, "ram": [ [8192, 234], [8193, 0] ] },
+          "final":   { "pc": 8193, "s": 0, "a": 1, "x": 2, "y": 3, "p": 228, "ram": [ [8192, 234], [8193, 0] ] },
+          "cycles":  [ [8192, 234, "read"], [8193, 0, "read"] ] }
+    ]"#;
+
+    #[test]
+    fn parse() {
+        let tests = processor_tests::parse(CASES).unwrap();
+        assert_eq!(2,tests.len());
+        assert_eq!("a9 10 ea",tests[0].name);
+        assert_eq!(0x1000,tests[0].initial.pc);
+        assert_eq!(vec![(0x1000,0xA9),(0x1001,0x10)],tests[0].expected.ram);
+        assert_eq!(BusCycle { addr: 0x1001, value: 0x10, write: false },tests[0].cycles[1]);
+
+        assert!(matches!(processor_tests::parse("{}"),Err(TestError::Format(_))));
+        assert!(matches!(processor_tests::parse("[{\"name\": \"x\"}]"),Err(TestError::Format(_))));
+        assert!(matches!(processor_tests::parse("["),Err(TestError::Json(_))));
+        assert_eq!("not a ProcessorTests case: expected an array of tests",processor_tests::parse("{}").unwrap_err().to_string());
+    }
+    #[test]
+    fn run() {
+        let exec: cpu::CpuExecutor = Default::default();
+        let tests = processor_tests::parse(CASES).unwrap();
+        assert_eq!(Vec::<String>::new(),processor_tests::run(&exec,&tests[0],&Checks::default()));
+        assert_eq!(Vec::<String>::new(),processor_tests::run(&exec,&tests[0],&Checks { cycle_count: true, bus: true }));
+
+        let mut wrong = tests[0].clone();
+        wrong.expected.a = 0x11;
+        wrong.expected.ram[1].1 = 0x20;
+        wrong.cycles.pop();
+        assert_eq!(vec!["A is $10, expected $11","$1001 is $10, expected $20","took 2 cycles, expected 1"],
+                   processor_tests::run(&exec,&wrong,&Checks::default()));
+    }
+    #[test]
+    fn bus_cycles() {
+        // NOP's second cycle is a dummy read of the next byte, which only the cycle accurate cpu makes
+        let exec: cpu::CpuExecutor = Default::default();
+        let nop = processor_tests::parse(CASES).unwrap().remove(1);
+        assert!(processor_tests::run(&exec,&nop,&Checks { cycle_count: false, bus: false }).is_empty());
+        let differences = processor_tests::run(&exec,&nop,&Checks { cycle_count: false, bus: true });
+        assert_eq!(vec!["cycle 2 was nothing, expected read $2001 = $00"],differences);
+
+        let exec: cpu::CycleExecutor = Default::default();
+        assert!(processor_tests::run(&exec,&nop,&Checks { cycle_count: true, bus: true }).is_empty());
+    }
+    #[test]
+    fn run_dir() {
+        let dir = env::temp_dir().join("trustines_processor_tests");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("1.json"),CASES).unwrap();
+        fs::write(dir.join("2.json"),CASES.replace("\"a\": 16","\"a\": 17")).unwrap();
+        fs::write(dir.join("notes.txt"),"not a test").unwrap();
+
+        let exec: cpu::CpuExecutor = Default::default();
+        let results = processor_tests::run_dir(&exec,&dir,&Checks::default()).unwrap();
+        assert_eq!(2,results.len());
+        assert_eq!(0xA9,results[0].opcode);
+        assert!(results[0].is_pass());
+        assert_eq!("a9: 2/2 passed",results[0].to_string());
+        assert!(!results[1].is_pass());
+        assert_eq!("a9: 1/2 passed, first failure \"a9 10 ea\": A is $10, expected $11",results[1].to_string());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn corpus() {
+        // point PROCESSOR_TESTS at a checkout's nes6502/v1 directory to run the whole corpus
+        let dir = match env::var("PROCESSOR_TESTS") {
+            Ok(dir) => dir,
+            Err(_)  => return,
+        };
+        let exec: cpu::CpuExecutor = Default::default();
+        let results = processor_tests::run_dir(&exec,&dir,&Checks::default()).unwrap();
+        for result in &results {
+            println!("{}",result);
+        }
+        let passed = results.iter().filter(|result| result.is_pass()).count();
+        println!("{}/{} opcodes passed",passed,results.len());
+    }
+}
+
+mod cycle_executor {
+    use trustines::cpu;
+    use trustines::cpu::assembler;
+    use trustines::memory::{BusCycle,Memory};
+
+    fn read(addr: u16, value: u8) -> BusCycle {
+        BusCycle { addr, value, write: false }
+    }
+    fn write(addr: u16, value: u8) -> BusCycle {
+        BusCycle { addr, value, write: true }
+    }
+
+    // assemble at $0200 on a bare bus, with handlers for every interrupt
+    fn load(source: &str) -> (cpu::CpuState,Memory) {
+        let program = assembler::assemble(&format!("
+                .org $0200
+                {}
+                .org $0300
+        nmi:    RTI
+        irq:    RTI
+                .org $FFFA
+                .word nmi, $0200, irq
+        ",source)).unwrap();
+        let mut mem = Memory::flat();
+        program.load(&mut mem);
+        let mut cpu: cpu::CpuState = Default::default();
+        cpu::CycleExecutor::default().power_on(&mut cpu,&mut mem).unwrap();
+        (cpu,mem)
+    }
+
+    // the bus accesses of the next instruction
+    fn step(exec: &cpu::CycleExecutor, cpu: &mut cpu::CpuState, mem: &mut Memory) -> Vec<BusCycle> {
+        mem.bus_log = Some(Vec::new());
+        let before = cpu.cycles;
+        exec.step(cpu,mem).unwrap();
+        let log = mem.bus_log.take().unwrap();
+        assert_eq!(log.len() as u64,cpu.cycles - before);
+        log
+    }
+
+    #[test]
+    fn indexed_reads() {
+        let exec = cpu::CycleExecutor::default();
+        let (mut cpu,mut mem) = load("LDX #$10\nLDA $12F8,X\nLDA $1200,X");
+        mem.mem[0x1308] = 0x42;
+        step(&exec,&mut cpu,&mut mem);
+
+        // crossing a page reads the address before the high byte is fixed first
+        assert_eq!(vec![read(0x0202,0xBD),read(0x0203,0xF8),read(0x0204,0x12),read(0x1208,0x00),read(0x1308,0x42)],
+                   step(&exec,&mut cpu,&mut mem));
+        assert_eq!(0x42,cpu.a);
+        assert_eq!(vec![read(0x0205,0xBD),read(0x0206,0x00),read(0x0207,0x12),read(0x1210,0x00)],step(&exec,&mut cpu,&mut mem));
+    }
+    #[test]
+    fn indexed_writes_always_read_first() {
+        let exec = cpu::CycleExecutor::default();
+        let (mut cpu,mut mem) = load("LDA #$33\nLDX #$10\nSTA $1200,X");
+        step(&exec,&mut cpu,&mut mem);
+        step(&exec,&mut cpu,&mut mem);
+        assert_eq!(vec![read(0x0204,0x9D),read(0x0205,0x00),read(0x0206,0x12),read(0x1210,0x00),write(0x1210,0x33)],
+                   step(&exec,&mut cpu,&mut mem));
+    }
+    #[test]
+    fn read_modify_write_writes_twice() {
+        let exec = cpu::CycleExecutor::default();
+        let (mut cpu,mut mem) = load("INC $10\nASL A");
+        mem.mem[0x10] = 5;
+        assert_eq!(vec![read(0x0200,0xE6),read(0x0201,0x10),read(0x0010,5),write(0x0010,5),write(0x0010,6)],
+                   step(&exec,&mut cpu,&mut mem));
+        // implied and accumulator instructions read the next byte and ignore it
+        assert_eq!(vec![read(0x0202,0x0A),read(0x0203,0x00)],step(&exec,&mut cpu,&mut mem));
+    }
+    #[test]
+    fn subroutines() {
+        let exec = cpu::CycleExecutor::default();
+        let (mut cpu,mut mem) = load("JSR sub\nNOP\nsub: RTS");
+        assert_eq!(vec![read(0x0200,0x20),read(0x0201,0x04),read(0x01FD,0x00),write(0x01FD,0x02),write(0x01FC,0x02),read(0x0202,0x02)],
+                   step(&exec,&mut cpu,&mut mem));
+        assert_eq!(0x0204,cpu.pc);
+        assert_eq!(vec![read(0x0204,0x60),read(0x0205,0x00),read(0x01FB,0x00),read(0x01FC,0x02),read(0x01FD,0x02),read(0x0202,0x02)],
+                   step(&exec,&mut cpu,&mut mem));
+        assert_eq!(0x0203,cpu.pc);
+        assert_eq!(0xFD,cpu.sp);
+    }
+    #[test]
+    fn branches() {
+        let exec = cpu::CycleExecutor::default();
+        // not taken, taken on the same page, taken onto the next page
+        let (mut cpu,mut mem) = load("LDX #1\nBEQ *\nBNE next\nnext: .res $F6, $EA\nBNE far\n.res 8, $EA\nfar: NOP");
+        step(&exec,&mut cpu,&mut mem);
+        assert_eq!(2,step(&exec,&mut cpu,&mut mem).len());
+        assert_eq!(3,step(&exec,&mut cpu,&mut mem).len());
+        assert_eq!(0x0206,cpu.pc);
+        for _ in 0..0xF6 {
+            step(&exec,&mut cpu,&mut mem);
+        }
+        assert_eq!(0x02FC,cpu.pc);
+        let log = step(&exec,&mut cpu,&mut mem);
+        // the dummy read before the high byte is fixed
+        assert_eq!(read(0x0206,0xEA),log[3]);
+        assert_eq!(0x0306,cpu.pc);
+    }
+    #[test]
+    fn interrupt_sequence() {
+        let exec = cpu::CycleExecutor::default();
+        let (mut cpu,mut mem) = load("CLI\nNOP\nNOP");
+        cpu.I = true;
+        exec.set_irq(&mut cpu,true);
+
+        // CLI takes effect after the instruction following it
+        step(&exec,&mut cpu,&mut mem);
+        step(&exec,&mut cpu,&mut mem);
+        assert_eq!(0x0202,cpu.pc);
+        let log = step(&exec,&mut cpu,&mut mem);
+        assert_eq!(vec![read(0x0202,0xEA),read(0x0202,0xEA),write(0x01FD,0x02),write(0x01FC,0x02),write(0x01FB,0x20),
+                        read(0xFFFE,0x01),read(0xFFFF,0x03)],log);
+        assert_eq!(0x0301,cpu.pc);
+        assert!(cpu.I);
+        exec.set_irq(&mut cpu,false);
+
+        // the handler's RTI comes back to the NOP that was interrupted
+        step(&exec,&mut cpu,&mut mem);
+        assert_eq!(0x0202,cpu.pc);
+    }
+    #[test]
+    fn nmi_hijacks_brk() {
+        let exec = cpu::CycleExecutor::default();
+        let (mut cpu,mut mem) = load("BRK\nNOP");
+        for _ in 0..3 {
+            exec.tick(&mut cpu,&mut mem).unwrap();
+        }
+        exec.nmi(&mut cpu);
+        mem.bus_log = Some(Vec::new());
+        exec.step(&mut cpu,&mut mem).unwrap();
+
+        // BRK's B flag is still pushed, but it goes to the nmi handler
+        assert_eq!(write(0x01FB,0x34),mem.bus_log.as_ref().unwrap()[1]);
+        assert_eq!(0x0300,cpu.pc);
+        assert!(!cpu.micro_op_register.nmi_pending);
+    }
+    #[test]
+    fn same_results_as_cpu_executor() {
+        let source = "
+                LDX #8
+        @loop:  LDA table,X
+                ADC #$40
+                STA $0400,X
+                ROR $0400,X
+                DEX
+                BPL @loop
+                PHP
+                PLA
+                JSR sub
+                LAX ($10),Y
+                DCP $20
+                BRK
+        sub:    SEC
+                SBC #3
+                RTS
+        table:  .byte 1,2,3,4,$80,$81,$FF,0,7
+        ";
+        let (mut cycle_cpu,mut cycle_mem) = load(source);
+        let (mut cpu,mut mem) = load(source);
+        let cycle_exec = cpu::CycleExecutor::default();
+        let exec = cpu::CpuExecutor::default();
+        for _ in 0..60 {
+            cycle_exec.step(&mut cycle_cpu,&mut cycle_mem).unwrap();
+            exec.step(&mut cpu,&mut mem).unwrap();
+            assert_eq!((cpu.pc,cpu.a,cpu.x,cpu.y,cpu.sp,cpu.unpack_flags(),cpu.cycles),
+                       (cycle_cpu.pc,cycle_cpu.a,cycle_cpu.x,cycle_cpu.y,cycle_cpu.sp,cycle_cpu.unpack_flags(),cycle_cpu.cycles));
+        }
+        assert_eq!(mem.mem,cycle_mem.mem);
+    }
+}
+
+mod cpu_variants {
+    use trustines::cpu;
+    use trustines::cpu::{CpuExecutor,OpcodeTables,Variant};
+    use trustines::cpu::assembler::Assembler;
+    use trustines::cpu::disassembler::Disassembler;
+    use trustines::memory::Memory;
+
+    // assemble at $0200 for the variant and run up to the done label
+    fn run(variant: Variant, source: &str) -> (cpu::CpuState,Memory) {
+        let assembler = Assembler::new(&OpcodeTables::for_variant(variant));
+        let program = assembler.assemble(&format!("
+                .org $0200
+                {}
+        done:   NOP
+                .org $0400
+        brk:    RTI
+                .org $FFFE
+                .word brk
+        ",source)).unwrap();
+        let mut mem = Memory::flat();
+        program.load(&mut mem);
+        let mut cpu = cpu::CpuState { pc: 0x0200, sp: 0xFD, ..Default::default() };
+        let exec = CpuExecutor::with_variant(variant);
+        while cpu.pc != program.symbol("done").unwrap() {
+            exec.step(&mut cpu,&mut mem).unwrap();
+        }
+        (cpu,mem)
+    }
+
+    // http://www.6502.org/tutorials/decimal_mode.html
+    #[test]
+    fn decimal_mode_only_off_the_2a03() {
+        let source = "SED\nCLC\nLDA #$09\nADC #$01";
+        assert_eq!(0x0A,run(Variant::Ricoh2A03,source).0.a);
+        assert_eq!(0x10,run(Variant::Nmos6502,source).0.a);
+        assert_eq!(0x10,run(Variant::Cmos65C02,source).0.a);
+    }
+    #[test]
+    fn decimal_adc() {
+        for &variant in &[Variant::Nmos6502,Variant::Cmos65C02] {
+            let (cpu,_) = run(variant,"SED\nSEC\nLDA #$58\nADC #$46");
+            assert_eq!((0x05,true),(cpu.a,cpu.C));
+            let (cpu,_) = run(variant,"SED\nSEC\nLDA #$79\nADC #$00");
+            assert_eq!((0x80,false,true),(cpu.a,cpu.C,cpu.V));
+        }
+        // the NMOS 6502 takes Z from the binary sum and N before the high digit is adjusted
+        let (cpu,_) = run(Variant::Nmos6502,"SED\nCLC\nLDA #$99\nADC #$01");
+        assert_eq!((0x00,true,false,true),(cpu.a,cpu.C,cpu.Z,cpu.S));
+        let (cpu,_) = run(Variant::Cmos65C02,"SED\nCLC\nLDA #$99\nADC #$01");
+        assert_eq!((0x00,true,true,false),(cpu.a,cpu.C,cpu.Z,cpu.S));
+    }
+    #[test]
+    fn decimal_sbc() {
+        for &variant in &[Variant::Nmos6502,Variant::Cmos65C02] {
+            let (cpu,_) = run(variant,"SED\nSEC\nLDA #$46\nSBC #$12");
+            assert_eq!((0x34,true),(cpu.a,cpu.C));
+            let (cpu,_) = run(variant,"SED\nSEC\nLDA #$12\nSBC #$21");
+            assert_eq!((0x91,false),(cpu.a,cpu.C));
+            let (cpu,_) = run(variant,"SED\nCLC\nLDA #$01\nSBC #$00");
+            assert_eq!((0x00,true,true),(cpu.a,cpu.C,cpu.Z));
+        }
+        // N from the binary difference $FF on the NMOS 6502, from $99 on the 65C02
+        let (cpu,_) = run(Variant::Nmos6502,"SED\nSEC\nLDA #$00\nSBC #$01");
+        assert_eq!((0x99,false,true,false),(cpu.a,cpu.C,cpu.S,cpu.Z));
+        let (cpu,_) = run(Variant::Cmos65C02,"SED\nSEC\nLDA #$00\nSBC #$01");
+        assert_eq!((0x99,false,true,false),(cpu.a,cpu.C,cpu.S,cpu.Z));
+    }
+    #[test]
+    fn reset_clears_decimal_on_65c02() {
+        let mut mem = Memory::flat();
+        mem.write(0xFFFC,&[0x00,0x02]);
+        for &(variant,decimal) in &[(Variant::Nmos6502,true),(Variant::Cmos65C02,false)] {
+            let exec = CpuExecutor::with_variant(variant);
+            let mut cpu: cpu::CpuState = Default::default();
+            exec.power_on(&mut cpu,&mut mem).unwrap();
+            assert!(!cpu.D);
+            cpu.D = true;
+            exec.reset(&mut cpu,&mut mem).unwrap();
+            assert_eq!((0x0200,decimal),(cpu.pc,cpu.D),"{:?}",variant);
+        }
+    }
+    #[test]
+    fn decimal_cycle_on_65c02() {
+        // SED 2, CLC 2, ADC # 2, plus one for decimal mode on the 65C02
+        assert_eq!(6,run(Variant::Nmos6502,"SED\nCLC\nADC #$01").0.cycles);
+        assert_eq!(7,run(Variant::Cmos65C02,"SED\nCLC\nADC #$01").0.cycles);
+    }
+    #[test]
+    fn jmp_indirect_page_wrap() {
+        // the pointer at $05FF takes its high byte from $0500 on the NMOS 6502
+        let source = "
+                JMP ($05FF)
+                .org $0500
+                .byte >nmos
+                .org $05FF
+                .byte <nmos, >cmos
+                .org $0700
+        nmos:   LDX #1
+                JMP done
+                .org $0800
+        cmos:   LDX #2
+                JMP done
+                .org $0900
+        ";
+        assert_eq!(1,run(Variant::Nmos6502,source).0.x);
+        assert_eq!(2,run(Variant::Cmos65C02,source).0.x);
+    }
+    #[test]
+    fn cmos_instructions() {
+        let (cpu,mem) = run(Variant::Cmos65C02,"
+                LDA #$FF
+                STA $10
+                STZ $10
+                LDX #$12
+                PHX
+                PLY
+                LDA #$0F
+                STA $11
+                LDA #$3C
+                TSB $11
+                TRB $11
+                STY $20
+                LDA #$03
+                STA $21
+                LDA #$42
+                STA $0312
+                LDA ($20)
+                INC A
+                BRA skip
+                LDA #0
+        skip:   LDX #2
+                JMP (table,X)
+        table:  .word 0, done
+        ");
+        assert_eq!(0,mem.mem[0x10]);
+        assert_eq!(0x12,cpu.y);
+        // TSB set the bits, TRB cleared them again, with Z from the bits in common with A each time
+        assert_eq!(0x03,mem.mem[0x11]);
+        assert!(!cpu.Z);
+        assert_eq!(0x43,cpu.a);
+    }
+    #[test]
+    fn bit_immediate_only_sets_z() {
+        let (cpu,_) = run(Variant::Cmos65C02,"CLV\nLDA #$01\nBIT #$C0");
+        assert!(cpu.Z);
+        assert!(!cpu.V);
+        assert!(!cpu.S);
+    }
+    #[test]
+    fn brk_clears_decimal_on_65c02() {
+        // break at the RTI, before it restores the flags
+        for &(variant,decimal) in &[(Variant::Nmos6502,true),(Variant::Cmos65C02,false)] {
+            let mut mem = Memory::flat();
+            mem.mem[0x0200] = 0xF8; // SED
+            mem.mem[0x0201] = 0x00; // BRK
+            mem.mem[0xFFFE] = 0x00;
+            mem.mem[0xFFFF] = 0x04;
+            let mut cpu = cpu::CpuState { pc: 0x0200, sp: 0xFD, ..Default::default() };
+            let exec = CpuExecutor::with_variant(variant);
+            exec.step(&mut cpu,&mut mem).unwrap();
+            exec.step(&mut cpu,&mut mem).unwrap();
+            assert_eq!(0x0400,cpu.pc);
+            assert_eq!(decimal,cpu.D);
+        }
+    }
+    #[test]
+    fn cmos_illegal_opcodes_are_nops() {
+        let mut mem = Memory::flat();
+        mem.mem[0x0200] = 0x03; // one byte, one cycle
+        mem.mem[0x0201] = 0x02; // two bytes, two cycles
+        let mut cpu = cpu::CpuState { pc: 0x0200, ..Default::default() };
+        let exec = CpuExecutor::with_variant(Variant::Cmos65C02);
+        exec.step(&mut cpu,&mut mem).unwrap();
+        assert_eq!((0x0201,1),(cpu.pc,cpu.cycles));
+        exec.step(&mut cpu,&mut mem).unwrap();
+        assert_eq!((0x0203,3),(cpu.pc,cpu.cycles));
+    }
+    #[test]
+    fn cmos_disassembly() {
+        let disassembler = Disassembler::new(OpcodeTables::for_variant(Variant::Cmos65C02));
+        let text: Vec<String> = disassembler.linear_sweep(&[0xB2,0x12,0x7C,0x34,0x12,0x80,0xFE,0x64,0x10],0x0200).iter().map(|i| i.text()).collect();
+        assert_eq!(vec!["LDA ($12)","JMP ($1234,X)","BRA $0205","STZ $10"],text);
+    }
+    #[test]
+    fn variant_names() {
+        assert_eq!(Variant::Cmos65C02,"65c02".parse().unwrap());
+        assert_eq!(Variant::Nmos6502,"6502".parse().unwrap());
+        assert_eq!("2A03",Variant::default().to_string());
+        assert!("z80".parse::<Variant>().is_err());
+    }
+}
+
+mod errors {
+    use std::error::Error;
+    use trustines::cpu;
+    use trustines::cpu::{AddressMode,ExecutionError};
+    use trustines::memory::{Memory,MemoryError};
+    use trustines::rom_loader;
+
+    #[test]
+    fn bus_error_has_the_instruction() {
+        let err = ExecutionError::Bus { pc: 0x0201, opcode: 0x8D, addr: 0x401F, err: MemoryError::APUAccessViolation(0x401F) };
+        assert_eq!("$0201: opcode $8D couldn't access $401F, $401F is an APU/IO register that is not implemented",err.to_string());
+        assert!(err.source().is_some());
+    }
+    #[test]
+    fn missing_operand() {
+        // LDA # without an addressing mode decodes no value to load
+        let (mut exec_info,_) = cpu::opcode::builtin();
+        exec_info[0xA9].address_mode = AddressMode::Implied;
+        let exec = cpu::CpuExecutor::new(exec_info);
+        let mut cpu = cpu::CpuState { pc: 0x0200, ..Default::default() };
+        let mut mem = Memory::new();
+
+        mem.write(0x0200,&[0xA9,0x42]);
+        let err = exec.step(&mut cpu,&mut mem).unwrap_err();
+        assert!(matches!(err,ExecutionError::MissingOperand { pc: 0x0200, opcode: 0xA9 }),"{:?}",err);
+    }
+    #[test]
+    fn display() {
+        match rom_loader::load_cartridge("resources/opcodes.csv") {
+            Err(err) => assert!(err.to_string().starts_with("not an ines rom"),"{}",err),
+            Ok(_)    => panic!("loaded a csv file as a rom"),
+        }
+        match cpu::opcode::load_from_file("no/such/table.csv") {
+            Err(err) => assert!(err.source().is_some(),"{}",err),
+            Ok(_)    => panic!("loaded a missing file"),
+        }
+
+        let err: Box<dyn Error> = Box::new(MemoryError::PPUAccessViolation(0x2000));
+        assert_eq!("$2000 is a PPU register",err.to_string());
+    }
+}
+
+mod stack {
+    use trustines::cpu;
+    use trustines::cpu::Executor;
+    use trustines::memory::Memory;
+
+    // run the program at $0200 with the stack pointer at sp, on both cpu cores
+    fn run(program: &[u8], sp: u8, instructions: usize, check: &dyn Fn(&cpu::CpuState,&Memory)) {
+        let cores: [&dyn Executor;2] = [&cpu::CpuExecutor::default(),&cpu::CycleExecutor::default()];
+        for exec in cores.iter() {
+            let mut mem = Memory::flat();
+            mem.write(0x0200,program);
+            mem.write(0xFFFE,&[0x00,0x03]);
+            let mut cpu = cpu::CpuState { pc: 0x0200, sp, ..Default::default() };
+            for _ in 0..instructions {
+                exec.step(&mut cpu,&mut mem).unwrap();
+            }
+            check(&cpu,&mem);
+        }
+    }
+
+    #[test]
+    fn jsr_rts_across_the_bottom() {
+        // JSR $0300 with the return address $0202 going on at $0100 and $01FF, then RTS
+        let program = [0x20,0x00,0x03];
+        run(&program,0x00,1,&|cpu,mem| {
+            assert_eq!(0x0300,cpu.pc);
+            assert_eq!(0xFE,cpu.sp);
+            assert_eq!(0x02,mem.mem[0x0100]);
+            assert_eq!(0x02,mem.mem[0x01FF]);
+        });
+        let mut program = vec![0;0x101];
+        program[..3].copy_from_slice(&[0x20,0x00,0x03]);
+        program[0x100] = 0x60; // RTS at $0300
+        run(&program,0x00,2,&|cpu,_| {
+            assert_eq!(0x0203,cpu.pc);
+            assert_eq!(0x00,cpu.sp);
+        });
+    }
+    #[test]
+    fn pha_pla_at_the_bottom() {
+        // LDA #$42, PHA, LDA #0, PLA
+        let program = [0xA9,0x42,0x48,0xA9,0x00,0x68];
+        run(&program,0x00,2,&|cpu,mem| {
+            assert_eq!(0x42,mem.mem[0x0100]);
+            assert_eq!(0xFF,cpu.sp);
+        });
+        run(&program,0x00,4,&|cpu,_| {
+            assert_eq!(0x42,cpu.a);
+            assert_eq!(0x00,cpu.sp);
+        });
+    }
+    #[test]
+    fn pla_at_the_top() {
+        // PLA with an empty stack reads $0100
+        let program = [0x68];
+        run(&program,0xFF,1,&|cpu,_| {
+            assert_eq!(0x00,cpu.sp);
+            assert_eq!(0x00,cpu.a);
+            assert!(cpu.Z);
+        });
+    }
+    #[test]
+    fn brk_rti_across_the_bottom() {
+        // BRK pushes $0202 at $0101 and $0100 and the flags at $01FF, RTI at $0300 takes them back
+        let mut program = vec![0;0x101];
+        program[..2].copy_from_slice(&[0x00,0xEA]);
+        program[0x100] = 0x40;
+        run(&program,0x01,1,&|cpu,mem| {
+            assert_eq!(0x0300,cpu.pc);
+            assert_eq!(0xFE,cpu.sp);
+            assert_eq!((0x02,0x02),(mem.mem[0x0101],mem.mem[0x0100]));
+            assert_eq!(0x30,mem.mem[0x01FF] & 0x30);
+        });
+        run(&program,0x01,2,&|cpu,_| {
+            assert_eq!(0x0202,cpu.pc);
+            assert_eq!(0x01,cpu.sp);
+        });
+    }
+}
+
+// Consoles for the tests of whole-console features.  They run a program assembled into an NROM
+// cartridge that enables NMI and spins, and the tests compare what they can observe.
+mod test_console {
+    use trustines::cartridge::{Cartridge,Mirroring};
+    use trustines::cpu::assembler;
+    use trustines::nes::Nes;
+
+    /// NMI handler body counting frames in $10 and the frames A was held on controller 1 in $11.
+    pub const COUNT_A: &str = "
+                LDA #1
+                STA $4016
+                LDA #0
+                STA $4016
+                LDA $4016
+                AND #1
+                CLC
+                ADC $11
+                STA $11
+                INC $10";
+
+    // setup runs at reset before NMI is enabled, nmi is the body of the NMI handler
+    fn program(setup: &str, nmi: &str) -> String {
+        format!("
+                .org $C000
+        reset:  {}
+                LDA #$80
+                STA $2000
+        spin:   JMP spin
+
+                .org $C020
+        nmi:    {}
+                RTI
+
+                .org $FFFA
+                .word nmi, reset, reset
+        ",setup,nmi)
+    }
+
+    /// 16KB of PRG at $C000 holding the program, NOP everywhere else.
+    pub fn cartridge(setup: &str, nmi: &str) -> Cartridge {
+        let program = assembler::assemble(&program(setup,nmi)).unwrap();
+        let mut prg = vec![0xEA;0x4000];
+        for segment in &program.segments {
+            let start = segment.origin as usize - 0xC000;
+            prg[start..start+segment.bytes.len()].clone_from_slice(&segment.bytes);
+        }
+        Cartridge {
+            prg_rom: prg,
+            prg_ram: vec![0;0x2000],
+            chr: vec![0;0x2000],
+            chr_is_ram: true,
+            mirroring: Mirroring::Horizontal,
+            mapper: 0,
+            expansion_device: 0,
+        }
+    }
+
+    /// A console with the program's cartridge in it, turned off.
+    pub fn nes(setup: &str, nmi: &str) -> Nes {
+        Nes::new(cartridge(setup,nmi))
+    }
+
+    /// A console with the program's cartridge in it, turned on.
+    pub fn powered(setup: &str, nmi: &str) -> Nes {
+        let mut nes = nes(setup,nmi);
+        nes.power_cycle().unwrap();
+        nes
+    }
+
+    /// Everything a test can see of the console.
+    #[derive(PartialEq)]
+    pub struct Observed {
+        clock: u64,
+        instructions: u64,
+        cycles: u64,
+        step: u8,
+        pc: u16,
+        a: u8,
+        frame: u64,
+        scanline: u16,
+        dot: u16,
+        ram: Vec<u8>,
+        prg_ram: Vec<u8>,
+        framebuffer: Vec<u16>,
+    }
+
+    pub fn observe(nes: &Nes) -> Observed {
+        Observed {
+            clock: nes.clock(),
+            instructions: nes.instructions(),
+            cycles: nes.cpu.cycles,
+            step: nes.cpu.micro_op_register.step,
+            pc: nes.cpu.pc,
+            a: nes.cpu.a,
+            frame: nes.mem.ppu.frame,
+            scanline: nes.mem.ppu.scanline,
+            dot: nes.mem.ppu.dot,
+            ram: nes.mem.mem[..0x800].to_vec(),
+            prg_ram: nes.mem.cartridge.as_ref().unwrap().prg_ram.clone(),
+            framebuffer: nes.framebuffer().to_vec(),
+        }
+    }
+}
+
+mod savestate {
+    use trustines::nes::Nes;
+    use trustines::cartridge::Cartridge;
+    use trustines::savestate::SaveStateError;
+    use test_console;
+    use test_console::observe;
+
+    // sets the backdrop to $21 and turns on the background
+    const BACKDROP: &str = "
+                LDA #$3F
+                STA $2006
+                LDA #$00
+                STA $2006
+                LDA #$21
+                STA $2007
+                LDA #$0A
+                STA $2001";
+    // counts NMIs in $10 and $6000
+    const COUNT_NMIS: &str = "
+                LDX $10
+                INX
+                STX $10
+                STX $6000";
+
+    // save partway through a frame, run on, then go back and run on again
+    fn round_trip(cycle_core: bool) {
+        let turn_on = || {
+            let cartridge = test_console::cartridge(BACKDROP,COUNT_NMIS);
+            let mut nes = if cycle_core { Nes::with_cycle_executor(cartridge,Default::default()) } else { Nes::new(cartridge) };
+            nes.power_cycle().unwrap();
+            nes
+        };
+        let mut nes = turn_on();
+        nes.run_frame().unwrap();
+        nes.run_frame().unwrap();
+        for _ in 0..1234 {
+            nes.step_cycle().unwrap();
+        }
+        while cycle_core && nes.cpu.micro_op_register.step == 0 {
+            nes.step_cycle().unwrap();
+        }
+        let mut state = Vec::new();
+        nes.save_state(&mut state).unwrap();
+
+        nes.run_frame().unwrap();
+        nes.run_frame().unwrap();
+        let expected = observe(&nes);
+        assert_eq!(3,nes.mem.mem[0x10]);
+
+        nes.load_state(&mut &state[..]).unwrap();
+        nes.run_frame().unwrap();
+        nes.run_frame().unwrap();
+        assert!(expected == observe(&nes));
+
+        // a console that's just been turned on ends up in the same place
+        let mut other = turn_on();
+        other.load_state(&mut &state[..]).unwrap();
+        other.run_frame().unwrap();
+        other.run_frame().unwrap();
+        assert!(expected == observe(&other));
+    }
+
+    #[test]
+    fn round_trip_mid_frame() {
+        round_trip(false);
+    }
+    #[test]
+    fn round_trip_mid_instruction() {
+        round_trip(true);
+    }
+    #[test]
+    fn rejects_other_rom() {
+        let nes = test_console::powered(BACKDROP,COUNT_NMIS);
+        let mut state = Vec::new();
+        nes.save_state(&mut state).unwrap();
+
+        let mut other_cartridge = test_console::cartridge(BACKDROP,COUNT_NMIS);
+        other_cartridge.prg_rom[0x100] = 0x00;
+        let mut other = Nes::new(other_cartridge);
+        other.power_cycle().unwrap();
+        match other.load_state(&mut &state[..]) {
+            Err(SaveStateError::RomMismatch { expected, found }) => {
+                assert_eq!(other.rom_hash(),expected);
+                assert_eq!(nes.rom_hash(),found);
+            },
+            _ => panic!("loaded a state for another rom"),
+        }
+    }
+    #[test]
+    fn rejects_other_core() {
+        let mut nes = Nes::with_cycle_executor(test_console::cartridge(BACKDROP,COUNT_NMIS),Default::default());
+        nes.power_cycle().unwrap();
+        let mut state = Vec::new();
+        nes.save_state(&mut state).unwrap();
+        let mut other = test_console::powered(BACKDROP,COUNT_NMIS);
+        assert!(matches!(other.load_state(&mut &state[..]),Err(SaveStateError::Incompatible(_))));
+    }
+    #[test]
+    fn rejects_garbage() {
+        let mut nes = test_console::powered(BACKDROP,COUNT_NMIS);
+        assert!(matches!(nes.load_state(&mut &b"not a save state"[..]),Err(SaveStateError::NotASaveState)));
+
+        let mut state = Vec::new();
+        nes.save_state(&mut state).unwrap();
+        state[8] = 99;
+        assert!(matches!(nes.load_state(&mut &state[..]),Err(SaveStateError::UnsupportedVersion(99))));
+    }
+    #[test]
+    fn bad_chunk_leaves_console_alone() {
+        let mut nes = test_console::powered(BACKDROP,COUNT_NMIS);
+        nes.run_frame().unwrap();
+        let mut state = Vec::new();
+        nes.save_state(&mut state).unwrap();
+
+        // same rom, but a zapper in port 2
+        let mut other = Nes::new(Cartridge { expansion_device: 0x08, ..test_console::cartridge(BACKDROP,COUNT_NMIS) });
+        other.power_cycle().unwrap();
+        for _ in 0..3 {
+            other.run_frame().unwrap();
+        }
+        let before = observe(&other);
+        match other.load_state(&mut &state[..]) {
+            Err(SaveStateError::BadChunk { ref tag, .. }) => assert_eq!("INPT",tag),
+            _ => panic!("loaded a state with different input devices"),
+        }
+        assert!(before == observe(&other));
+    }
+    #[test]
+    fn chunk_length_past_the_end() {
+        let mut nes = test_console::powered(BACKDROP,COUNT_NMIS);
+        let mut state = Vec::new();
+        nes.save_state(&mut state).unwrap();
+
+        // keep the header, then a chunk claiming to be 4GB long
+        state.truncate(16);
+        state.extend_from_slice(b"CPU \xFF\xFF\xFF\xFF\x01\x02");
+        match nes.load_state(&mut &state[..]) {
+            Err(SaveStateError::BadChunk { ref tag, .. }) => assert_eq!("CPU",tag),
+            _ => panic!("loaded a state with a chunk past the end"),
+        }
+    }
+}
+
+mod rewind {
+    use trustines::nes::Nes;
+    use trustines::input::ButtonState;
+    use trustines::rewind::{Rewind,RewindError};
+    use test_console;
+    use test_console::observe;
+
+    fn buttons(frame: u64) -> ButtonState {
+        ButtonState { a: frame % 3 == 0, ..Default::default() }
+    }
+
+    fn run_frame(rewind: &mut Rewind, nes: &mut Nes) {
+        let frame = nes.mem.ppu.frame;
+        rewind.set_buttons(nes,0,buttons(frame));
+        rewind.run_frame(nes).unwrap();
+    }
+
+    #[test]
+    fn rewind_frames() {
+        let mut nes = test_console::powered("",test_console::COUNT_A);
+        let mut rewind = Rewind::new(4,1 << 24);
+        let mut history = Vec::new();
+        for _ in 0..20 {
+            run_frame(&mut rewind,&mut nes);
+            history.push(observe(&nes));
+        }
+        let end = observe(&nes);
+        assert_ne!(0,nes.mem.mem[0x11]);
+
+        rewind.rewind_frames(&mut nes,7).unwrap();
+        assert!(history[12] == observe(&nes));
+
+        // the same input gets to the same place
+        for _ in 0..7 {
+            run_frame(&mut rewind,&mut nes);
+        }
+        assert!(end == observe(&nes));
+    }
+    #[test]
+    fn rewind_to_instruction() {
+        let mut nes = test_console::powered("",test_console::COUNT_A);
+        let mut rewind = Rewind::new(2,1 << 24);
+        for _ in 0..5 {
+            run_frame(&mut rewind,&mut nes);
+        }
+        // stop partway through a frame
+        for _ in 0..1000 {
+            nes.step_instruction().unwrap();
+        }
+        let instruction = nes.instructions();
+        let expected = observe(&nes);
+        for _ in 0..6 {
+            run_frame(&mut rewind,&mut nes);
+        }
+
+        rewind.rewind_to_instruction(&mut nes,instruction).unwrap();
+        assert!(expected == observe(&nes));
+        assert!(matches!(rewind.rewind_to_instruction(&mut nes,instruction+1),Err(RewindError::OutOfRange(_))));
+    }
+    #[test]
+    fn new_history_after_rewinding() {
+        let mut nes = test_console::powered("",test_console::COUNT_A);
+        let mut rewind = Rewind::new(1,1 << 24);
+        for _ in 0..12 {
+            run_frame(&mut rewind,&mut nes);
+        }
+        rewind.rewind_frames(&mut nes,6).unwrap();
+        let checkpoints = rewind.len();
+        let held = nes.mem.mem[0x11];
+
+        // hold A from here on, then go back into the new history
+        for _ in 0..6 {
+            rewind.set_buttons(&mut nes,0,ButtonState { a: true, ..Default::default() });
+            rewind.run_frame(&mut nes).unwrap();
+        }
+        assert_eq!(held + 6,nes.mem.mem[0x11]);
+        assert!(rewind.len() > checkpoints);
+        rewind.rewind_frames(&mut nes,2).unwrap();
+        assert_eq!(held + 4,nes.mem.mem[0x11]);
+    }
+    #[test]
+    fn stays_within_budget() {
+        let mut nes = test_console::powered("",test_console::COUNT_A);
+        let mut whole = Vec::new();
+        nes.save_state(&mut whole).unwrap();
+
+        let budget = whole.len() * 4;
+        let mut rewind = Rewind::new(1,budget);
+        for _ in 0..100 {
+            run_frame(&mut rewind,&mut nes);
+        }
+        assert!(rewind.size() <= budget);
+        // deltas fit many more checkpoints than whole states would
+        assert!(rewind.len() > 20);
+
+        let oldest = rewind.oldest_frame().unwrap();
+        assert!(oldest > 0);
+        let back = nes.mem.ppu.frame - oldest;
+        assert!(matches!(rewind.rewind_frames(&mut nes,back+1),Err(RewindError::OutOfRange(_))));
+        rewind.rewind_frames(&mut nes,back).unwrap();
+        assert_eq!(oldest,nes.mem.ppu.frame);
+    }
+}
+
+mod movie {
+    use trustines::input::ButtonState;
+    use trustines::movie::{fm2,Movie,MovieError,MovieStart,FrameInput};
+    use test_console;
+    use test_console::observe;
+
+    fn input(frame: usize) -> FrameInput {
+        let mut input = FrameInput { reset: frame == 10, ..Default::default() };
+        input.buttons[0] = ButtonState { a: frame % 4 == 1, right: frame > 20, ..Default::default() };
+        input.buttons[1] = ButtonState { start: frame == 3, ..Default::default() };
+        input
+    }
+
+    #[test]
+    fn record_and_play() {
+        let mut nes = test_console::nes("",test_console::COUNT_A);
+        let mut movie = Movie::new();
+        movie.begin(&mut nes).unwrap();
+        for frame in 0..30 {
+            movie.record_frame(&mut nes,input(frame)).unwrap();
+        }
+        let expected = observe(&nes);
+        assert_ne!(0,nes.mem.mem[0x11]);
+
+        let mut other = test_console::nes("",test_console::COUNT_A);
+        movie.play(&mut other).unwrap();
+        assert!(expected == observe(&other));
+    }
+    #[test]
+    fn record_from_save_state() {
+        let mut nes = test_console::nes("",test_console::COUNT_A);
+        nes.power_cycle().unwrap();
+        for _ in 0..5 {
+            nes.run_frame().unwrap();
+        }
+        let mut movie = Movie::from_save_state(&nes).unwrap();
+        for frame in 0..15 {
+            movie.record_frame(&mut nes,input(frame)).unwrap();
+        }
+        let expected = observe(&nes);
+
+        for _ in 0..5 {
+            nes.run_frame().unwrap();
+        }
+        movie.play(&mut nes).unwrap();
+        assert!(expected == observe(&nes));
+
+        assert!(matches!(fm2::write(&movie,&mut Vec::new()),Err(MovieError::Unsupported(_))));
+    }
+    #[test]
+    fn fm2_round_trip() {
+        let mut movie = Movie::new();
+        movie.frames = (0..30).map(input).collect();
+        movie.rerecord_count = 12;
+        movie.extra_header.push(("comment".to_string(),"author somebody".to_string()));
+
+        let mut text = Vec::new();
+        fm2::write(&movie,&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.starts_with("version 3\n"));
+        assert!(text.contains("\ncomment author somebody\n"));
+        assert!(text.contains("\n|0|.......A|........||\n"));
+        assert!(text.contains("\n|1|........|........||\n"));
+        assert!(text.contains("\n|0|........|....T...||\n"));
+
+        assert_eq!(movie,fm2::read(text.as_bytes()).unwrap());
+
+        movie.four_score = true;
+        movie.frames[0].buttons[3].b = true;
+        let mut text = Vec::new();
+        fm2::write(&movie,&mut text).unwrap();
+        assert!(String::from_utf8(text.clone()).unwrap().contains("\n|0|........|........|........|......B.||\n"));
+        assert_eq!(movie,fm2::read(&text[..]).unwrap());
+    }
+    #[test]
+    fn fm2_import() {
+        let text = "version 3\n\
+                    emuVersion 20604\n\
+                    rerecordCount 3\n\
+                    palFlag 0\n\
+                    romFilename smb\n\
+                    romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==\n\
+                    guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B\n\
+                    fourscore 0\n\
+                    port0 1\n\
+                    port1 0\n\
+                    port2 0\n\
+                    |2|........|||\n\
+                    |0|RLDUTSBA|||\n\
+                    |1|R...T..A|||\n";
+        let movie = fm2::read(text.as_bytes()).unwrap();
+        assert_eq!(MovieStart::PowerOn,movie.start);
+        assert_eq!(3,movie.rerecord_count);
+        assert_eq!(3,movie.frames.len());
+        assert_eq!(("romFilename".to_string(),"smb".to_string()),movie.extra_header[0]);
+        assert_eq!(3,movie.extra_header.len());
+
+        assert!(movie.frames[0].power && !movie.frames[0].reset);
+        assert_eq!(0xFF,movie.frames[1].buttons[0].to_byte());
+        assert!(movie.frames[2].reset);
+        assert_eq!(ButtonState { right: true, start: true, a: true, ..Default::default() },movie.frames[2].buttons[0]);
+        assert_eq!(ButtonState::default(),movie.frames[2].buttons[1]);
+    }
+    #[test]
+    fn fm2_errors() {
+        match fm2::read("version 3\nport0 1\n|0|...|........||\n".as_bytes()) {
+            Err(MovieError::Parse { line, .. }) => assert_eq!(3,line),
+            _ => panic!("read a gamepad with 3 buttons"),
+        }
+        assert!(matches!(fm2::read("version 3\nport0 2\n".as_bytes()),Err(MovieError::Unsupported(_))));
+        assert!(matches!(fm2::read("version 3\npalFlag 1\n".as_bytes()),Err(MovieError::Unsupported(_))));
+        assert!(matches!(fm2::read("version 3\n|4|........|........||\n".as_bytes()),Err(MovieError::Parse { .. })));
+        assert!(matches!(fm2::read("version 3\nrerecordCount lots\n".as_bytes()),Err(MovieError::Parse { line: 2, .. })));
+    }
+}
+
+mod headless {
+    use trustines::input::ButtonState;
+    use trustines::movie::{Movie,FrameInput};
+    use trustines::headless;
+    use trustines::headless::{Checkpoints,FrameHash,HeadlessError};
+    use test_console;
+
+    // turns on red emphasis while A is held on controller 1
+    const EMPHASIZE_RED_ON_A: &str = "
+                LDA #1
+                STA $4016
+                LDA #0
+                STA $4016
+                LDA $4016
+                AND #1
+                ASL A
+                ASL A
+                ASL A
+                ASL A
+                ASL A
+                STA $2001";
+
+    fn movie() -> Movie {
+        let mut movie = Movie::new();
+        movie.frames = (0..20).map(|frame| {
+            let mut input = FrameInput::default();
+            input.buttons[0] = ButtonState { a: frame >= 10, ..Default::default() };
+            input
+        }).collect();
+        movie
+    }
+
+    #[test]
+    fn deterministic() {
+        let movie = movie();
+        let first = headless::run(&mut test_console::nes("",EMPHASIZE_RED_ON_A),Some(&movie),30,&Checkpoints::Every(1)).unwrap();
+        let second = headless::run(&mut test_console::nes("",EMPHASIZE_RED_ON_A),Some(&movie),30,&Checkpoints::Every(1)).unwrap();
+        assert_eq!(30,first.len());
+        assert_eq!(first,second);
+
+        // running it again on the same console starts over from power on
+        let mut nes = test_console::nes("",EMPHASIZE_RED_ON_A);
+        headless::run(&mut nes,Some(&movie),5,&Checkpoints::Every(1)).unwrap();
+        assert_eq!(first,headless::run(&mut nes,Some(&movie),30,&Checkpoints::Every(1)).unwrap());
+    }
+    #[test]
+    fn input_changes_the_video_hash() {
+        let with_movie = headless::run(&mut test_console::nes("",EMPHASIZE_RED_ON_A),Some(&movie()),30,&Checkpoints::Every(1)).unwrap();
+        let without = headless::run(&mut test_console::nes("",EMPHASIZE_RED_ON_A),None,30,&Checkpoints::Every(1)).unwrap();
+        assert_eq!(with_movie[..10],without[..10]);
+        assert!(with_movie[12..].iter().zip(without[12..].iter()).all(|(a,b)| a.video != b.video));
+        // A stays held past the end of the movie
+        assert_eq!(with_movie[20].video,with_movie[29].video);
+    }
+    #[test]
+    fn checkpoints() {
+        let every = headless::run(&mut test_console::nes("",EMPHASIZE_RED_ON_A),None,30,&Checkpoints::Every(1)).unwrap();
+        let tens = headless::run(&mut test_console::nes("",EMPHASIZE_RED_ON_A),None,30,&Checkpoints::Every(10)).unwrap();
+        assert_eq!(vec![10,20,30],tens.iter().map(|hash| hash.frame).collect::<Vec<u64>>());
+        assert!(tens.iter().all(|hash| hash.video == every[hash.frame as usize - 1].video));
+
+        let at = headless::run(&mut test_console::nes("",EMPHASIZE_RED_ON_A),None,30,&Checkpoints::At(vec![1,25,40])).unwrap();
+        assert_eq!(vec![1,25],at.iter().map(|hash| hash.frame).collect::<Vec<u64>>());
+        assert_eq!(every[0],at[0]);
+    }
+    #[test]
+    fn audio_hash_covers_every_sample() {
+        let mut nes = test_console::nes("",EMPHASIZE_RED_ON_A);
+        nes.power_cycle().unwrap();
+        nes.mem.apu.take_samples();
+        let mut samples = Vec::new();
+        for _ in 0..10 {
+            nes.run_frame().unwrap();
+            samples.extend(nes.mem.apu.take_samples());
+        }
+        assert!(!samples.is_empty());
+        let hashes = headless::run(&mut test_console::nes("",EMPHASIZE_RED_ON_A),None,10,&Checkpoints::At(vec![10])).unwrap();
+        assert_eq!(headless::hash_audio(&samples),hashes[0].audio);
+        assert_eq!(headless::hash_video(nes.framebuffer()),hashes[0].video);
+    }
+    #[test]
+    fn hash_text() {
+        let hash = FrameHash { frame: 60, video: 0x0123456789ABCDEF, audio: 0xFF };
+        assert_eq!("60 0123456789abcdef 00000000000000ff",hash.to_string());
+        assert_eq!(hash,hash.to_string().parse().unwrap());
+        for line in &["60 0123","x 00 00","60 00 zz"] {
+            assert!(line.parse::<FrameHash>().is_err(),"{}",line);
+        }
+    }
+    #[test]
+    fn golden_files() {
+        let path = ::std::env::temp_dir().join("trustines_headless.hashes");
+        let hashes = headless::run(&mut test_console::nes("",EMPHASIZE_RED_ON_A),Some(&movie()),30,&Checkpoints::Every(10)).unwrap();
+        headless::write_golden(&path,&hashes).unwrap();
+        let golden = headless::read_golden(&path).unwrap();
+        assert_eq!(hashes,golden);
+        assert_eq!(None,headless::compare(&golden,&hashes));
+
+        let mut changed = hashes.clone();
+        changed[1].video ^= 1;
+        assert_eq!(Some((Some(hashes[1]),Some(changed[1]))),headless::compare(&hashes,&changed));
+        assert_eq!(Some((Some(hashes[2]),None)),headless::compare(&hashes,&hashes[..2]));
+
+        ::std::fs::write(&path,"# comment\n\n10 00 00\nbogus\n").unwrap();
+        assert!(matches!(headless::read_golden(&path),Err(HeadlessError::Parse { line: 4, .. })));
+        assert_eq!(::std::path::PathBuf::from("roms/nestest.hashes"),headless::golden_path("roms/nestest.nes"));
+    }
+    #[test]
+    fn nestest_golden() {
+        headless::check_golden("roms/nestest.nes",None,60,&Checkpoints::Every(20)).unwrap();
+    }
+}
+
+mod video {
+    use trustines::cartridge::crc32;
+    use trustines::video::{Image,ImageFormat,Palette,BuiltinPalette,SignalSettings};
+    use trustines::video::{NtscFilter,NtscSettings,Connection};
+    use trustines::video::{palette,ntsc};
+
+    #[test]
+    fn default_palette() {
+        let palette = Palette::default();
+        assert_eq!(palette::EMPHASIS_COLORS,palette.colors().len());
+        assert_eq!([0,0,0],palette.rgb(0x0F));
+        assert_eq!([236,238,236],palette.rgb(0x30));
+        // red emphasis darkens green and blue, all three darken everything
+        let red = palette.rgb(0x30 | 0x40);
+        assert_eq!(236,red[0]);
+        assert!(red[1] < 238 && red[2] < 236);
+        let all = palette.rgb(0x30 | 0x1C0);
+        assert!(all[0] < 236 && all[1] < 238 && all[2] < 236);
+    }
+    #[test]
+    fn palette_from_colors() {
+        let colors: Vec<[u8;3]> = (0..512).map(|i| [i as u8,(i >> 8) as u8,7]).collect();
+        let palette = Palette::from_colors(&colors).unwrap();
+        assert_eq!([0x34,0x01,7],palette.rgb(0x134));
+        assert_eq!(palette.colors(),&colors[..]);
+        assert_eq!(None,Palette::from_colors(&colors[..100]));
+        assert_eq!(Palette::default().rgb(0x12),Palette::from_colors(&Palette::default().colors()[..64]).unwrap().rgb(0x12));
+    }
+    #[test]
+    fn builtin_palettes() {
+        for name in &["2c02","2C03","2c05","PAL"] {
+            let builtin: BuiltinPalette = name.parse().unwrap();
+            assert_eq!(name.to_uppercase(),builtin.to_string());
+        }
+        assert!("vga".parse::<BuiltinPalette>().is_err());
+        assert_eq!(Palette::default(),Palette::builtin(BuiltinPalette::Ppu2C02));
+
+        // the RGB ppus turn the channel all the way up for emphasis
+        let rgb = Palette::builtin(BuiltinPalette::Ppu2C03);
+        assert_eq!(rgb,Palette::builtin(BuiltinPalette::Ppu2C05));
+        assert_eq!([255,0,0],rgb.rgb(0x16));
+        assert_eq!([0,0,255],rgb.rgb(0x0F | 0x100));
+        assert_eq!([255,255,255],rgb.rgb(0x20 | 0x1C0));
+
+        // green emphasis on the 2C07 is red emphasis on the 2C02
+        let pal = Palette::builtin(BuiltinPalette::Pal);
+        let green = pal.rgb(0x30 | 0x40);
+        let red = pal.rgb(0x30 | 0x80);
+        assert!(green[1] > green[0] && red[0] > red[1],"{:?} {:?}",green,red);
+    }
+    #[test]
+    fn generated_palette() {
+        let measured = Palette::default();
+        let generated = Palette::generate(&SignalSettings::default());
+        let difference: u32 = measured.colors()[..64].iter().zip(generated.colors()[..64].iter())
+            .map(|(a,b)| (0..3).map(|i| (a[i] as i32 - b[i] as i32).unsigned_abs()).sum::<u32>()).sum();
+        assert!(difference / (64*3) < 20,"{}",difference / (64*3));
+
+        let dominant = |rgb: [u8;3]| (0..3).max_by_key(|i| rgb[*i]).unwrap();
+        assert_eq!(0,dominant(generated.rgb(0x16)));
+        assert_eq!(1,dominant(generated.rgb(0x2A)));
+        assert_eq!(2,dominant(generated.rgb(0x12)));
+        assert_eq!([0,0,0],generated.rgb(0x0F));
+        let red = generated.rgb(0x30 | 0x40);
+        assert!(red[0] > red[1] && red[0] > red[2],"{:?}",red);
+
+        let grey = Palette::generate(&SignalSettings { saturation: 0.0, ..Default::default() }).rgb(0x16);
+        assert!(grey[0] == grey[1] && grey[1] == grey[2],"{:?}",grey);
+        assert_eq!([255,255,255],Palette::generate(&SignalSettings { brightness: 1.0, ..Default::default() }).rgb(0x0F));
+        assert_eq!([0,0,0],Palette::generate(&SignalSettings { contrast: 0.0, ..Default::default() }).rgb(0x30));
+        let turned = Palette::generate(&SignalSettings { hue: 180.0, ..Default::default() }).rgb(0x16);
+        assert!(turned[2] > turned[0] && turned[1] > turned[0],"{:?}",turned);
+    }
+    #[test]
+    fn pal_files() {
+        let bytes: Vec<u8> = (0..64*3).map(|i| i as u8).collect();
+        let palette = Palette::read(&mut &bytes[..]).unwrap();
+        assert_eq!([3,4,5],palette.rgb(1));
+        assert!(palette.rgb(1 | 0x40)[1] < 4);
+
+        let bytes: Vec<u8> = (0..512*3).map(|i| (i / 3) as u8).collect();
+        let palette = Palette::read(&mut &bytes[..]).unwrap();
+        assert_eq!([0x41,0x41,0x41],palette.rgb(0x41));
+
+        for len in &[0,100,65*3] {
+            assert!(Palette::read(&mut &vec![0u8;*len][..]).is_err(),"{}",len);
+        }
+        let path = ::std::env::temp_dir().join("trustines_video.pal");
+        ::std::fs::write(&path,vec![7u8;64*3]).unwrap();
+        assert_eq!([7,7,7],Palette::load(&path).unwrap().rgb(0x3F));
+    }
+
+    fn image() -> Image {
+        let mut framebuffer = vec![0x0Fu16;256*240];
+        for (i,pixel) in framebuffer.iter_mut().enumerate() {
+            if (i / 256) % 16 < 8 && (i % 256) % 3 != 0 {
+                *pixel = (i % 64) as u16 | ((i / 4096) as u16 & 7) << 6;
+            }
+        }
+        Image::from_framebuffer(&framebuffer,&Palette::default())
+    }
+
+    #[test]
+    fn ppm() {
+        let image = image();
+        let mut ppm = Vec::new();
+        image.write_ppm(&mut ppm).unwrap();
+        let header = b"P6\n256 240\n255\n";
+        assert_eq!(&header[..],&ppm[..header.len()]);
+        assert_eq!(image.rgb,ppm[header.len()..].to_vec());
+        assert_eq!(Palette::default().rgb(0x0F),[image.rgb[0],image.rgb[1],image.rgb[2]]);
+    }
+
+    // just enough of an inflater to read back the fixed Huffman blocks the png writer makes
+    struct Bits<'a> { data: &'a [u8], pos: usize }
+    impl<'a> Bits<'a> {
+        fn bits(&mut self, count: usize) -> usize {
+            let mut value = 0;
+            for i in 0..count {
+                value |= ((self.data[self.pos / 8] >> (self.pos % 8)) as usize & 1) << i;
+                self.pos += 1;
+            }
+            value
+        }
+        fn code(&mut self, count: usize) -> usize {
+            (0..count).fold(0,|code,_| (code << 1) | self.bits(1))
+        }
+        fn symbol(&mut self) -> usize {
+            let code = self.code(7);
+            if code < 0x18 { return code + 256; }
+            let code = (code << 1) | self.bits(1);
+            if code < 0xC0 { return code - 0x30; }
+            if code < 0xC8 { return code - 0xC0 + 280; }
+            ((code << 1) | self.bits(1)) - 0x190 + 144
+        }
+    }
+    fn inflate_fixed(data: &[u8]) -> Vec<u8> {
+        let lengths = [3,4,5,6,7,8,9,10,11,13,15,17,19,23,27,31,35,43,51,59,67,83,99,115,131,163,195,227,258];
+        let length_extra = [0,0,0,0,0,0,0,0,1,1,1,1,2,2,2,2,3,3,3,3,4,4,4,4,5,5,5,5,0];
+        let distances = [1,2,3,4,5,7,9,13,17,25,33,49,65,97,129,193,257,385,513,769,1025,1537,2049,3073,4097,6145,8193,12289,16385,24577];
+        let mut bits = Bits { data, pos: 0 };
+        assert_eq!(1,bits.bits(1));
+        assert_eq!(1,bits.bits(2));
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            let symbol = bits.symbol();
+            if symbol < 256 { out.push(symbol as u8); continue; }
+            if symbol == 256 { return out; }
+            let code = symbol - 257;
+            let length = lengths[code] + bits.bits(length_extra[code]);
+            let code = bits.code(5);
+            let distance = distances[code] + bits.bits(if code < 4 { 0 } else { code / 2 - 1 });
+            for _ in 0..length {
+                let byte = out[out.len() - distance];
+                out.push(byte);
+            }
+        }
+    }
+
+    #[test]
+    fn png() {
+        let image = image();
+        let mut png = Vec::new();
+        image.write_png(&mut png).unwrap();
+        assert_eq!(b"\x89PNG\r\n\x1A\n",&png[..8]);
+
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes([rest[0],rest[1],rest[2],rest[3]]) as usize;
+            let crc = u32::from_be_bytes([rest[8+len],rest[9+len],rest[10+len],rest[11+len]]);
+            assert_eq!(crc32(rest[4..8+len].iter()),crc);
+            chunks.push((rest[4..8].to_vec(),rest[8..8+len].to_vec()));
+            rest = &rest[12+len..];
+        }
+        let tags: Vec<&[u8]> = chunks.iter().map(|chunk| &chunk.0[..]).collect();
+        assert_eq!(vec![&b"IHDR"[..],&b"IDAT"[..],&b"IEND"[..]],tags);
+        assert_eq!(vec![0,0,1,0, 0,0,0,240, 8,2,0,0,0],chunks[0].1);
+
+        let zlib = &chunks[1].1;
+        assert_eq!(&[0x78,0x01],&zlib[..2]);
+        assert!(zlib.len() < image.rgb.len() / 4);
+        let scanlines = inflate_fixed(&zlib[2..zlib.len()-4]);
+        assert_eq!(240*(1 + 256*3),scanlines.len());
+        for (y,row) in scanlines.chunks(1 + 256*3).enumerate() {
+            assert_eq!(0,row[0]);
+            assert_eq!(&image.rgb[y*256*3..(y+1)*256*3],&row[1..]);
+        }
+    }
+    #[test]
+    fn formats() {
+        assert_eq!(Some(ImageFormat::Png),ImageFormat::from_path("shots/frame.PNG"));
+        assert_eq!(Some(ImageFormat::Ppm),ImageFormat::from_path("frame.ppm"));
+        assert_eq!(None,ImageFormat::from_path("frame.bmp"));
+        assert_eq!(None,ImageFormat::from_path("frame"));
+
+        let path = ::std::env::temp_dir().join("trustines_video.ppm");
+        image().save(&path).unwrap();
+        assert_eq!(b'P',::std::fs::read(&path).unwrap()[0]);
+        assert!(image().save(::std::env::temp_dir().join("trustines_video.bmp")).is_err());
+    }
+
+    fn pixel(image: &Image, x: usize, y: usize) -> [u8;3] {
+        let i = (y*image.width + x)*3;
+        [image.rgb[i],image.rgb[i+1],image.rgb[i+2]]
+    }
+
+    #[test]
+    fn connections() {
+        for name in &["composite","s-video","rgb"] {
+            let connection: Connection = name.parse().unwrap();
+            assert_eq!(*name,connection.to_string());
+        }
+        assert_eq!(Connection::SVideo,"SVideo".parse().unwrap());
+        assert!("scart".parse::<Connection>().is_err());
+        assert_eq!(Connection::Composite,NtscSettings::default().connection);
+
+        assert_eq!(vec![0,4,0,4],(0..4).map(|frame| ntsc::frame_phase(frame,true)).collect::<Vec<usize>>());
+        assert_eq!(vec![0,4,8,0],(0..4).map(|frame| ntsc::frame_phase(frame,false)).collect::<Vec<usize>>());
+    }
+    #[test]
+    fn ntsc_flat_colors() {
+        let generated = Palette::generate(&SignalSettings::default());
+        for connection in &[Connection::Composite,Connection::SVideo,Connection::Rgb] {
+            let filter = NtscFilter::new(NtscSettings::preset(*connection));
+            for color in &[0x16u16,0x2A,0x12,0x30 | 0x40,0x0F] {
+                for phase in 0..12 {
+                    // a couple of lines is enough
+                    let image = filter.filter(&vec![*color;256*2],phase);
+                    assert_eq!((ntsc::OUTPUT_WIDTH,2),(image.width,image.height));
+                    let rgb = pixel(&image,256,1);
+                    let expected = generated.rgb(*color);
+                    assert!((0..3).all(|i| (rgb[i] as i32 - expected[i] as i32).abs() <= 1),"{} {:02X} {:?} {:?}",connection,color,rgb,expected);
+                }
+            }
+        }
+
+        let mut settings = NtscSettings::preset(Connection::Composite);
+        settings.signal.saturation = 0.0;
+        let grey = pixel(&NtscFilter::new(settings).filter(&vec![0x16;256*240],0),256,120);
+        assert!(grey[0] == grey[1] && grey[1] == grey[2],"{:?}",grey);
+    }
+    #[test]
+    fn dot_crawl() {
+        // thin vertical stripes, where chroma gets into the luma
+        let framebuffer: Vec<u16> = (0..256*240).map(|i| if (i % 256) % 4 < 2 { 0x30 } else { 0x16 }).collect();
+        let composite = NtscFilter::new(NtscSettings::preset(Connection::Composite));
+        let frames: Vec<Image> = (0..3).map(|frame| composite.filter(&framebuffer,ntsc::frame_phase(frame,true))).collect();
+        assert!(frames[0] != frames[1]);
+        assert!(frames[0] == frames[2]);
+        // and differs from one line to the next
+        assert_ne!(&frames[0].rgb[..512*3],&frames[0].rgb[512*3..1024*3]);
+
+        let rgb = NtscFilter::new(NtscSettings::preset(Connection::Rgb));
+        assert!(rgb.filter(&framebuffer,0) == rgb.filter(&framebuffer,4));
+        assert_eq!(pixel(&rgb.filter(&framebuffer,0),0,0),pixel(&rgb.filter(&framebuffer,0),1,0));
+    }
+}